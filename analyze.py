@@ -5,7 +5,7 @@ import pandas as pd
 KEY_TYPES = {
     'basic-heads': 'build', 'basic-prefix': 'build', 'basic-use-hint': 'build', 'branch-cache': 'build', 'data': 'run',
     'descend-adapt-inner': 'build', 'dynamic-prefix': 'build', 'hash': 'build', 'hash-leaf-simd': 'build',
-    'head-early-abort-create': 'build', 'host': 'run', 'inner': 'build', 'leaf': 'build', 'op': 'run',
+    'host': 'run', 'inner': 'build', 'leaf': 'build', 'op': 'run',
     'op_count': 'val',
     'op_rates': 'run', 'range_len': 'run', 'revision': 'build', 'run_start': 'aux', 'strip-prefix': 'build',
     'time': 'val', 'total_count': 'run', 'value_len': 'run', 'zipf_exponent': 'run', 'branch_misses': 'val',
@@ -9,20 +9,58 @@ extern "C" {
 
 struct RustBTree;
 
+// functions returning std::uint8_t report a status: 0 on success, 1 for a null pointer, 2 for an entry or buffer that is too large,
+// 3 if an insert needed a split that could not make a node smaller
 RustBTree *btree_new();
-void btree_insert(RustBTree *b_tree, std::uint8_t *key, std::uint64_t keyLen, std::uint8_t *payload,
-                  std::uint64_t payloadLen);
+std::uint8_t btree_insert(RustBTree *b_tree, std::uint8_t *key, std::uint64_t keyLen, std::uint8_t *payload,
+                          std::uint64_t payloadLen);
+// returns null if the key is not present
 std::uint8_t *btree_lookup(RustBTree *b_tree, std::uint8_t *key, std::uint64_t keyLen, std::uint64_t *payloadLenOut);
 bool btree_remove(RustBTree *b_tree, std::uint8_t *key, std::uint64_t keyLen);
+// does nothing if b_tree is null
 void btree_destroy(RustBTree *b_tree);
+// does nothing if b_tree is null
 void btree_print_info(RustBTree *b_tree);
+// number of entries in the tree
+std::uint64_t btree_len(RustBTree *b_tree);
+// BTreeNodeTag of the root node, 255 if b_tree is null
+std::uint8_t btree_root_tag(RustBTree *b_tree);
+// writes the number of nodes of each type to out_counts[tag] for the first len tags, returns the number of tags.
+// returns 0 if b_tree is null or out_counts is null and len is not
+std::uint64_t btree_node_tag_counts(RustBTree *b_tree, std::uint64_t *out_counts, std::uint64_t len);
 void print_tpcc_result(double time_sec, std::uint64_t tx_count, std::uint64_t warehouse_count);
 
-// key_buffer and key must not be null, even if zero length
-void btree_scan_asc(RustBTree *b_tree, std::uint8_t const *key, std::uint64_t key_len, std::uint8_t *key_buffer,
-                    bool (*continue_callback)(std::uint8_t const *));
-void btree_scan_desc(RustBTree *b_tree, std::uint8_t const *key, std::uint64_t key_len, std::uint8_t *key_buffer,
-                     bool (*continue_callback)(std::uint8_t const *));
+// conversion counters are shared by all trees of the process, node counts are for the given tree
+struct AdaptStats {
+    std::uint64_t basic_to_hash;
+    std::uint64_t hash_to_basic;
+    std::uint64_t hash_to_basic_failed;
+    std::uint64_t inner_adaptions;
+    std::uint64_t basic_leaves;
+    std::uint64_t hash_leaves;
+    std::uint64_t hybrid_leaves;
+    std::uint64_t inner_nodes;
+};
+
+std::uint8_t btree_adaptation_stats(RustBTree *b_tree, AdaptStats *out);
+
+// key_buffer must not be null, key may only be null if key_len is zero
+std::uint8_t btree_scan_asc(RustBTree *b_tree, std::uint8_t const *key, std::uint64_t key_len, std::uint8_t *key_buffer,
+                            bool (*continue_callback)(std::uint8_t const *));
+// like btree_scan_asc, but stops before the first key past end
+std::uint8_t btree_scan_range(RustBTree *b_tree, std::uint8_t const *start, std::uint64_t start_len,
+                              std::uint8_t const *end, std::uint64_t end_len, bool end_inclusive,
+                              std::uint8_t *key_buffer, bool (*continue_callback)(std::uint8_t const *));
+std::uint8_t btree_scan_desc(RustBTree *b_tree, std::uint8_t const *key, std::uint64_t key_len, std::uint8_t *key_buffer,
+                             bool (*continue_callback)(std::uint8_t const *));
+
+// fills out_buffer with records for keys >= key: uint32 key length, key, uint32 payload length, payload.
+// lengths use native byte order, records are not aligned.
+// writes the key to continue from to next_key_buffer, next_key_len is zero if the scan is complete.
+// next_key_buffer must hold 1024 bytes, a buffer_cap of 1032 bytes always fits at least one record.
+std::uint8_t btree_scan_chunk(RustBTree *b_tree, std::uint8_t const *key, std::uint64_t key_len,
+                              std::uint8_t *out_buffer, std::uint64_t buffer_cap, std::uint64_t *out_count,
+                              std::uint8_t *next_key_buffer, std::uint64_t *next_key_len);
 
 }
 #endif //BTREE_BTREE_RUST_H
@@ -1,2 +1,2 @@
-auto BUILD_CSV_HEADER = ",head-early-abort-create,inner,leaf,hash-leaf-simd,strip-prefix,hash,descend-adapt-inner,branch-cache,dynamic-prefix,hash-variant,basic-use-hint,basic-prefix,basic-heads";
-auto BUILD_CSV_VALUES = ",false,explicit_length,adapt,32,false,crc32,none,false,false,head,true,true,true";
+auto BUILD_CSV_HEADER = ",inner,leaf,hash-leaf-simd,strip-prefix,hash,descend-adapt-inner,branch-cache,dynamic-prefix,hash-variant,basic-use-hint,basic-prefix,basic-heads";
+auto BUILD_CSV_VALUES = ",explicit_length,adapt,32,false,crc32,none,false,false,head,true,true,true";
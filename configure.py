@@ -10,11 +10,9 @@ HOST = "cascade-01"
 TPCC = True
 
 FEATURES = {
-    # "head-early-abort-create": ["true", "false"],
-    "head-early-abort-create": ["false"],
-    "inner": ["basic", "padded", "explicit_length", "ascii", "art"],
+    "inner": ["basic", "padded", "explicit_length", "ascii", "art", "runtime"],
     # "inner": ["basic"],
-    "leaf": ["basic", "hash", "adapt"],
+    "leaf": ["basic", "hash", "adapt", "hybrid"],
     # "leaf" : ["hash","basic"],
     # "hash-leaf-simd": ["32", "64"],
     "hash-leaf-simd": ["32"],
@@ -31,8 +29,23 @@ FEATURES = {
     "basic-use-hint": ["false", "true", "naive"],
     "basic-prefix": ["false", "true"],
     "basic-heads": ["false", "true"],
+    "hash-leaf-tombstone": ["false", "true"],
+    "leaf-cache": ["false", "true"],
+    "depth-profile": ["false", "true"],
+    "adapt-inner-art": ["false", "true"],
+    "split-balance-bytes": ["false", "true"],
+    "value-arena": ["false", "true"],
+    "trace-structural": ["false", "true"],
+    "value-align": ["1", "8", "16"],
+    "hash-leaf-layout": ["split", "interleaved"],
+    "leaf-generation": ["false", "true"],
+    "hash-batch": ["false", "true"],
+    "lazy-zero": ["false", "true"],
 }
 
+# features that are not a build dimension, written after the configured ones
+EXTRA_FEATURES = ["test-util"]
+
 
 def configure(chosen_features, revision=None):
     shutil.copyfile("Cargo.toml", "Cargo.toml.old")
@@ -47,6 +60,8 @@ def configure(chosen_features, revision=None):
             for feature in FEATURES.keys():
                 for option in FEATURES[feature]:
                     dst.write(f"{feature}_{option} = []\n")
+            for feature in EXTRA_FEATURES:
+                dst.write(f"{feature} = []\n")
     if revision is not None:
         chosen_features = chosen_features.copy()
         chosen_features['revision'] = revision
@@ -1,6 +1,8 @@
 use once_cell::sync::Lazy;
 use rand::{Rng, SeedableRng};
-use crate::BTreeNode;
+use crate::{node_stats, BTreeNode, PAGE_SIZE};
+use crate::util::{common_prefix_len, trailing_bytes};
+use crate::art_node::ArtNode;
 use crate::head_node::{U32ExplicitHeadNode, U32ZeroPaddedHeadNode, U64ExplicitHeadNode, U64ZeroPaddedHeadNode};
 use crate::node_traits::{InnerConversionSink};
 use crate::vtables::BTreeNodeTag;
@@ -19,6 +21,21 @@ pub fn gen_random() -> u32 {
     unsafe { &mut *RAND }.gen()
 }
 
+#[cfg(feature = "adapt-inner-art_true")]
+const ADAPT_INNER_ART: bool = true;
+#[cfg(feature = "adapt-inner-art_false")]
+const ADAPT_INNER_ART: bool = false;
+
+/// ART pays off for long string keys that share prefixes or vary in length, short keys are better served by head nodes.
+/// `prefix_len` is the length of the prefix shared by all keys, whether stripped or not
+pub fn inner_art_benefits(prefix_len: usize, key_count: usize, length_sum: usize, max_len: usize) -> bool {
+    if key_count == 0 || max_len <= 8 {
+        return false;
+    }
+    let average_len = length_sum / key_count;
+    average_len >= 8 && (prefix_len >= 4 || max_len >= 2 * average_len)
+}
+
 pub fn adapt_inner(node: &mut BTreeNode) {
     unsafe {
         let tag = node.tag();
@@ -56,10 +73,74 @@ pub fn adapt_inner(node: &mut BTreeNode) {
                     break 'try_nodes true;
                 }
             }
+            if ADAPT_INNER_ART && tag != BTreeNodeTag::ArtInner {
+                let length_sum = dyn_node.get_key_length_sum(0..key_count);
+                let mut shared_prefix = dyn_node.fences().prefix_len;
+                if key_count > 0 {
+                    let mut first = [0u8; PAGE_SIZE / 4];
+                    let mut last = [0u8; PAGE_SIZE / 4];
+                    let first_len = dyn_node.get_key(0, &mut first, 0).unwrap();
+                    let last_len = dyn_node.get_key(key_count - 1, &mut last, 0).unwrap();
+                    shared_prefix += common_prefix_len(trailing_bytes(&first, first_len), trailing_bytes(&last, last_len));
+                }
+                if inner_art_benefits(shared_prefix, key_count, length_sum, max_len) {
+                    if ArtNode::create(&mut tmp, dyn_node).is_ok() {
+                        break 'try_nodes true;
+                    }
+                }
+            }
             false
         };
         if copy_back {
             *node = tmp;
+            node_stats::INNER_ADAPTIONS.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::ManualInnerSource;
+
+    #[test]
+    fn art_benefits_long_shared_prefix_keys() {
+        assert!(!inner_art_benefits(0, 0, 0, 0));
+        // short keys fit head nodes
+        assert!(!inner_art_benefits(6, 100, 800, 8));
+        // uniform medium length keys without a shared prefix
+        assert!(!inner_art_benefits(0, 100, 1_200, 12));
+        assert!(inner_art_benefits(12, 100, 1_800, 18));
+        // strongly varying lengths
+        assert!(inner_art_benefits(0, 100, 1_200, 40));
+    }
+
+    /// builds a basic inner node from `keys`, adapts it and returns the resulting tag
+    fn adapted_tag(keys: Vec<Vec<u8>>) -> BTreeNodeTag {
+        let children: Vec<*mut BTreeNode> = (0..keys.len() + 1).map(|_| BTreeNode::new_leaf()).collect();
+        let source = ManualInnerSource { lower_fence: Vec::new(), upper_fence: Vec::new(), keys, children: children.clone() };
+        unsafe {
+            let node = source.build(BTreeNodeTag::BasicInner).unwrap();
+            assert_eq!((*node).tag(), BTreeNodeTag::BasicInner);
+            adapt_inner(&mut *node);
+            let tag = (*node).tag();
+            assert!(source.matches((*node).to_inner()), "{tag:?} lost keys");
+            for child in children.into_iter().chain([node]) {
+                BTreeNode::dealloc(child);
+            }
+            tag
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn adaption_picks_representation_by_key_shape() {
+        crate::ensure_init();
+        let short = (1..50u32).map(|i| format!("{i:03}").into_bytes()).collect();
+        assert_eq!(adapted_tag(short), BTreeNodeTag::U32ExplicitHead);
+        let shared_prefix = (1..50u32).map(|i| format!("user/profile/{i:05}").into_bytes()).collect();
+        let expected = if ADAPT_INNER_ART { BTreeNodeTag::ArtInner } else { BTreeNodeTag::BasicInner };
+        assert_eq!(adapted_tag(shared_prefix), expected);
+        let unclassified = (1..50u64).map(|i| format!("{:012}", i * 19_999_999_999).into_bytes()).collect();
+        assert_eq!(adapted_tag(unclassified), BTreeNodeTag::BasicInner);
+    }
+}
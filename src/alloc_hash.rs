@@ -1,13 +1,14 @@
 use crate::find_separator::find_separator;
-use crate::util::{common_prefix_len, MergeFences, partial_restore, short_slice, SplitFences};
+use crate::util::{common_prefix_len, KeyBuffer, MergeFences, partial_restore, short_slice, SplitFences};
 use crate::{BTreeNode, PrefixTruncatedKey, PAGE_SIZE, FatTruncatedKey};
 use rustc_hash::FxHasher;
 use std::hash::Hasher;
 use std::io::Write;
 use std::mem::{size_of, transmute, ManuallyDrop, align_of};
+use std::sync::atomic::Ordering;
 use std::simd::{Simd, SimdPartialEq};
-use crate::btree_node::{AdaptionState, BTreeNodeHead};
-use crate::node_traits::{FenceData, FenceRef, InnerNode, LeafNode, Node};
+use crate::btree_node::{AdaptionState, BTreeNodeHead, SIMD_FIND, VALUE_ALIGN};
+use crate::node_traits::{FenceData, FenceRef, InnerNode, LeafNode, Node, NodeOpError, debug_assert_split_conserves_keys, debug_assert_merge_conserves_keys};
 use crate::vtables::BTreeNodeTag;
 
 #[derive(Clone, Copy)]
@@ -55,12 +56,18 @@ pub struct HashLeaf {
     data: [u8; PAGE_SIZE - size_of::<HashLeafHead>()],
 }
 
+const _: () = assert!(size_of::<HashLeaf>() == PAGE_SIZE);
+
 struct LayoutInfo {
     slots_start: usize,
     data_start: usize,
 }
 
 const USE_SIMD: bool = true;
+
+const _: () = assert!(VALUE_ALIGN == 1, "value alignment is not implemented for this hash leaf variant");
+const _: () = assert!(!cfg!(feature = "leaf_hybrid"), "hybrid leaves are not implemented for this hash leaf variant");
+
 const SIMD_WIDTH: usize = 64;
 const SIMD_ALIGN: usize = align_of::<Simd<u8, SIMD_WIDTH>>();
 
@@ -70,6 +77,22 @@ impl HashLeaf {
         key_length - self.head.prefix_len as usize + payload_length + hash_space + size_of::<HashSlot>()
     }
 
+    /// number of uniformly sized entries an empty leaf with empty fences can hold
+    pub fn leaf_capacity(prefix_len: usize, key_length: usize, payload_length: usize) -> usize {
+        let entry_len = key_length - prefix_len + payload_length;
+        let mut count = 0;
+        let mut hash_area_len = 0;
+        loop {
+            let hash_space = if count == hash_area_len { Self::hash_capacity(count + 1) } else { 0 };
+            let needed = entry_len + hash_space + size_of::<HashSlot>();
+            if needed + Self::layout(count).data_start + count * entry_len + hash_area_len > PAGE_SIZE {
+                return count;
+            }
+            count += 1;
+            hash_area_len = hash_area_len.max(Self::hash_capacity(count));
+        }
+    }
+
     fn layout(count: usize) -> LayoutInfo {
         let slots_start = size_of::<HashLeafHead>();
         let data_start = slots_start + size_of::<HashSlot>() * count;
@@ -79,7 +102,7 @@ impl HashLeaf {
         }
     }
 
-    fn free_space(&self) -> usize {
+    pub fn free_space(&self) -> usize {
         self.head.data_offset as usize - Self::layout(self.head.count as usize).data_start
     }
 
@@ -90,12 +113,10 @@ impl HashLeaf {
     }
 
     pub fn as_bytes(&self) -> &[u8; PAGE_SIZE] {
-        assert_eq!(PAGE_SIZE, size_of::<Self>());
         unsafe { transmute(self as *const Self) }
     }
 
     unsafe fn as_bytes_mut(&mut self) -> &mut [u8; PAGE_SIZE] {
-        assert_eq!(PAGE_SIZE, size_of::<Self>());
         transmute(self as *mut Self)
     }
 
@@ -133,7 +154,7 @@ impl HashLeaf {
         }
     }
 
-    pub fn request_space(&mut self, space: usize) -> Result<(), ()> {
+    pub fn request_space(&mut self, space: usize) -> Result<(), NodeOpError> {
         if space <= self.free_space() {
             Ok(())
         } else {
@@ -149,7 +170,7 @@ impl HashLeaf {
                 self.compactify();
                 Ok(())
             } else {
-                Err(())
+                Err(NodeOpError::NoSpace)
             }
         }
     }
@@ -219,8 +240,9 @@ impl HashLeaf {
         self.hashes_mut()[slot_id] = Self::compute_hash(prefix_truncated_key);
     }
 
-    fn insert_truncated(&mut self, key: PrefixTruncatedKey, payload: &[u8]) -> Result<(), ()> {
-        let index = if let Some(found) = self.find_index(key) {
+    fn insert_truncated(&mut self, key: PrefixTruncatedKey, payload: &[u8]) -> Result<bool, NodeOpError> {
+        let existing = self.find_index(key);
+        let index = if let Some(found) = existing {
             let s = &mut self.slots_mut()[found];
             let old_use = s.key_len + s.val_len;
             s.key_len = 0;
@@ -236,7 +258,7 @@ impl HashLeaf {
         self.store_key_value(index, key, payload);
         // self.print();
         self.validate();
-        Ok(())
+        Ok(existing.is_none())
     }
 
     fn hash_capacity(size: usize) -> usize {
@@ -343,7 +365,7 @@ impl HashLeaf {
         assert_eq!(align_of::<Self>(), SIMD_ALIGN);
         HashLeaf {
             head: HashLeafHead {
-                head: BTreeNodeHead { tag: BTreeNodeTag::HashLeaf, adaption_state: AdaptionState::new() },
+                head: BTreeNodeHead::new(BTreeNodeTag::HashLeaf, AdaptionState::new()),
                 count: 0,
                 sorted_count: 0,
                 lower_fence: FenceKeySlot { offset: 0, len: 0 },
@@ -363,7 +385,7 @@ impl HashLeaf {
     fn find_index(&self, key: PrefixTruncatedKey) -> Option<usize> {
         let needle_hash = Self::compute_hash(key);
         //eprintln!("find {:?} -> {}",key,needle_hash);
-        if USE_SIMD {
+        if USE_SIMD && SIMD_FIND.load(Ordering::Relaxed) {
             debug_assert_eq!(self.find_simd(key, needle_hash), self.find_no_simd(key, needle_hash));
             self.find_simd(key, needle_hash)
         } else {
@@ -437,22 +459,33 @@ impl HashLeaf {
         for (s, h) in self.slots().iter().zip(self.hashes().iter()) {
             debug_assert_eq!(Self::compute_hash(s.key(self.as_bytes())), *h);
         }
-        debug_assert_eq!(
-            self.head.space_used as usize,
-            self.head.lower_fence.len as usize
-                + self.head.upper_fence.len as usize
-                + self.head.hash_area.len as usize
-                + self
-                .slots()
-                .iter()
-                .map(|s| (s.key_len + s.val_len) as usize)
-                .sum::<usize>()
-        );
+        debug_assert!(self.check_space_accounting().is_ok());
         debug_assert!(self.head.sorted_count <= self.head.count);
         debug_assert!(self.slots()[..self.head.sorted_count as usize].is_sorted_by_key(|s| s.key(self.as_bytes())));
     }
 
-    pub fn try_merge_right(&self, right: &mut Self, separator: FatTruncatedKey) -> Result<(), ()> {
+    /// checks that `space_used` matches the space taken by fences, hash area, keys and payloads
+    pub fn check_space_accounting(&self) -> Result<(), ()> {
+        let used = self.head.lower_fence.len as usize
+            + self.head.upper_fence.len as usize
+            + self.head.hash_area.len as usize
+            + self
+            .slots()
+            .iter()
+            .map(|s| (s.key_len + s.val_len) as usize)
+            .sum::<usize>();
+        if used == self.head.space_used as usize { Ok(()) } else { Err(()) }
+    }
+
+    /// calls `callback` with the prefix truncated key and value of every slot in physical slot order.
+    /// Unlike `range_lookup`, this does not sort the node.
+    pub fn for_each(&self, callback: &mut dyn FnMut(PrefixTruncatedKey, &[u8])) {
+        for s in self.slots() {
+            callback(s.key(self.as_bytes()), s.value(self.as_bytes()));
+        }
+    }
+
+    pub fn try_merge_right(&self, right: &mut Self, separator: FatTruncatedKey) -> Result<(), NodeOpError> {
         //eprintln!("### {:?} merge right {:?}",self as *const Self,right as *const Self);
         // self.print();
         // right.print();
@@ -469,7 +502,7 @@ impl HashLeaf {
         tmp.head.sorted_count = self.head.sorted_count;
         tmp.validate();
         // tmp.print();
-        debug_assert_eq!(tmp.head.count, self.head.count + right.head.count);
+        debug_assert_merge_conserves_keys(true, self.head.count as usize, right.head.count as usize, tmp.head.count as usize);
         *right = tmp;
         Ok(())
     }
@@ -544,7 +577,7 @@ unsafe impl Node for HashLeaf {
         parent: &mut dyn InnerNode,
         index_in_parent: usize,
         key_in_self: &[u8],
-    ) -> Result<(), ()> {
+    ) -> Result<(), NodeOpError> {
         //TODO if prefix length does not change, hashes can be copied
         self.sort();
 
@@ -554,7 +587,7 @@ unsafe impl Node for HashLeaf {
                 self.slots()[i].key(self.as_bytes())
             });
         let full_sep_key_len = truncated_sep_key.0.len() + self.head.prefix_len as usize;
-        let parent_prefix_len = parent.request_space_for_child(full_sep_key_len)?;
+        let parent_prefix_len = parent.request_space_for_child(full_sep_key_len).map_err(|_| NodeOpError::NeedsSplit)?;
         let node_left_raw;
         let node_left = unsafe {
             node_left_raw = BTreeNode::alloc();
@@ -567,9 +600,9 @@ unsafe impl Node for HashLeaf {
         let mut node_right = Self::new();
         node_right.set_fences(split_fences.upper());
         unsafe {
-            if let Err(()) = parent.insert_child(index_in_parent, split_fences.separator(), node_left_raw) {
+            if let Err(_) = parent.insert_child(index_in_parent, split_fences.separator(), node_left_raw) {
                 BTreeNode::dealloc(node_left_raw);
-                return Err(());
+                return Err(NodeOpError::NeedsSplit);
             }
         }
         self.copy_key_value_range(&self.slots()[..=sep_slot], node_left);
@@ -580,7 +613,7 @@ unsafe impl Node for HashLeaf {
         node_right.validate();
         // node_left.print();
         // node_right.print();
-        debug_assert_eq!(self.head.count, node_left.head.count + node_right.head.count);
+        debug_assert_split_conserves_keys(true, self.head.count as usize, node_left.head.count as usize, node_right.head.count as usize);
         *self = node_right;
         Ok(())
     }
@@ -607,7 +640,7 @@ unsafe impl Node for HashLeaf {
 }
 
 unsafe impl LeafNode for HashLeaf {
-    fn insert(&mut self, key: &[u8], payload: &[u8]) -> Result<(), ()> {
+    fn insert(&mut self, key: &[u8], payload: &[u8]) -> Result<bool, NodeOpError> {
         // self.print();
         //eprintln!("{:?} insert {:?}",self as *const Self,key);
         let key = self.truncate(key);
@@ -615,7 +648,7 @@ unsafe impl LeafNode for HashLeaf {
     }
 
 
-    fn lookup(&mut self, key: &[u8]) -> Option<&mut [u8]> {
+    fn lookup_mut(&mut self, key: &[u8]) -> Option<&mut [u8]> {
         self.validate();
         self.find_index(self.truncate(key)).map(|i| {
             let slot = self.slots()[i];
@@ -656,30 +689,32 @@ unsafe impl LeafNode for HashLeaf {
     }
 
 
-    unsafe fn range_lookup(&mut self, start: &[u8], key_out: *mut u8, callback: &mut dyn FnMut(usize, &[u8]) -> bool) -> bool {
+    fn compactify(&mut self) {
+        HashLeaf::compactify(self)
+    }
+
+    fn range_lookup(&mut self, start: &[u8], key_out: &mut KeyBuffer, callback: &mut dyn FnMut(&[u8], &[u8]) -> bool) -> bool {
         self.sort();
-        debug_assert!(!key_out.is_null());
-        key_out.copy_from_nonoverlapping(start.as_ptr(), self.head.prefix_len as usize);
+        key_out.set_suffix(0, &start[..self.head.prefix_len as usize]);
         let start_index = self.lower_bound(self.truncate(start)).0;
         for s in &self.slots()[start_index..] {
-            let k = s.key(self.as_bytes());
-            key_out.offset(self.head.prefix_len as isize).copy_from_nonoverlapping(k.0.as_ptr(), k.0.len());
-            if !callback((s.key_len + self.head.prefix_len) as usize, s.value(self.as_bytes())) {
+            let key = key_out.set_suffix(self.head.prefix_len as usize, s.key(self.as_bytes()).0);
+            if !callback(key, s.value(self.as_bytes())) {
                 return false;
             }
         }
         true
     }
 
-    unsafe fn range_lookup_desc(&mut self, start: &[u8], key_out: *mut u8, callback: &mut dyn FnMut(usize, &[u8]) -> bool) -> bool {
+    fn range_lookup_desc(&mut self, start: &[u8], key_out: &mut KeyBuffer, callback: &mut dyn FnMut(&[u8], &[u8]) -> bool) -> bool {
         self.sort();
-        debug_assert!(!key_out.is_null());
-        key_out.copy_from_nonoverlapping(start.as_ptr(), self.head.prefix_len as usize);
-        let start_index = self.lower_bound(self.truncate(start)).0.min(self.head.count as usize - 1);
-        for s in self.slots()[..=start_index].iter().rev() {
-            let k = s.key(self.as_bytes());
-            key_out.offset(self.head.prefix_len as isize).copy_from_nonoverlapping(k.0.as_ptr(), k.0.len());
-            if !callback((s.key_len + self.head.prefix_len) as usize, s.value(self.as_bytes())) {
+        key_out.set_suffix(0, &start[..self.head.prefix_len as usize]);
+        let (lower_bound, found) = self.lower_bound(self.truncate(start));
+        // keys from lower_bound on are greater than start, unless it is start itself
+        let end = if found { lower_bound + 1 } else { lower_bound };
+        for s in self.slots()[..end].iter().rev() {
+            let key = key_out.set_suffix(self.head.prefix_len as usize, s.key(self.as_bytes()).0);
+            if !callback(key, s.value(self.as_bytes())) {
                 return false;
             }
         }
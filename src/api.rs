@@ -0,0 +1,470 @@
+//! checked entry points, the `extern "C"` functions are thin adapters over these
+
+use crate::b_tree::BTree;
+use crate::btree_node::PAGE_SIZE;
+use crate::node_traits::NodeOpError;
+use std::ops::{Bound, RangeBounds};
+use std::slice;
+
+/// upper bound on key length plus payload length of a single entry
+pub const MAX_ENTRY_SIZE: usize = PAGE_SIZE / 4;
+
+/// returned by `btree_root_tag` for a null tree, no `BTreeNodeTag` has this value
+pub const INVALID_TAG: u8 = u8::MAX;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(u8)]
+pub enum ApiError {
+    NullPointer = 1,
+    TooLarge = 2,
+    /// the entry could not be placed without a degenerate split, see `NodeOpError::DegenerateSplit`
+    DegenerateSplit = 3,
+}
+
+/// status code returned by the `extern "C"` functions, zero on success and the `ApiError` discriminant otherwise
+pub fn status(result: Result<(), ApiError>) -> u8 {
+    match result {
+        Ok(()) => 0,
+        Err(e) => e as u8,
+    }
+}
+
+/// converts a pointer and length passed over FFI to a slice.
+/// A null pointer is accepted for empty slices.
+/// # Safety
+/// if non-null, `data` must be valid for reads of `len` bytes for `'a`.
+pub unsafe fn raw_slice<'a>(data: *const u8, len: u64) -> Result<&'a [u8], ApiError> {
+    if len > isize::MAX as u64 {
+        return Err(ApiError::TooLarge);
+    }
+    if data.is_null() {
+        return if len == 0 { Ok(&[]) } else { Err(ApiError::NullPointer) };
+    }
+    Ok(slice::from_raw_parts(data, len as usize))
+}
+
+/// # Safety
+/// if non-null, `tree` must point to a live tree not otherwise borrowed for `'a`.
+pub unsafe fn raw_tree<'a>(tree: *mut BTree) -> Result<&'a mut BTree, ApiError> {
+    tree.as_mut().ok_or(ApiError::NullPointer)
+}
+
+pub fn insert(tree: &mut BTree, key: &[u8], payload: &[u8]) -> Result<(), ApiError> {
+    if key.len() + payload.len() > MAX_ENTRY_SIZE {
+        return Err(ApiError::TooLarge);
+    }
+    tree.insert(key, payload).map_err(|e| match e {
+        NodeOpError::TooLarge => ApiError::TooLarge,
+        _ => ApiError::DegenerateSplit,
+    })
+}
+
+/// keys too large to be inserted are not found
+pub fn lookup<'a>(tree: &'a mut BTree, key: &[u8]) -> Result<Option<&'a mut [u8]>, ApiError> {
+    if key.len() > MAX_ENTRY_SIZE {
+        return Ok(None);
+    }
+    let mut payload_len = 0u64;
+    unsafe {
+        let payload = tree.lookup(&mut payload_len, key);
+        Ok(if payload.is_null() { None } else { Some(slice::from_raw_parts_mut(payload, payload_len as usize)) })
+    }
+}
+
+/// returns true if the key was present
+pub fn remove(tree: &mut BTree, key: &[u8]) -> Result<bool, ApiError> {
+    if key.len() > MAX_ENTRY_SIZE {
+        return Ok(false);
+    }
+    Ok(unsafe { tree.remove(key) })
+}
+
+/// calls `callback` with key and payload for keys >= start in ascending order until it returns false
+pub fn scan_asc(tree: &mut BTree, start: &[u8], callback: &mut dyn FnMut(&[u8], &[u8]) -> bool) -> Result<(), ApiError> {
+    tree.range_lookup(start, callback);
+    Ok(())
+}
+
+/// like `scan_asc`, but stops at `end` without calling `callback` for keys past it.
+/// `end` itself is included if `end_inclusive` is set. Nothing is scanned if `end` is below `start`.
+pub fn scan_range(tree: &mut BTree, start: &[u8], end: &[u8], end_inclusive: bool, callback: &mut dyn FnMut(&[u8], &[u8]) -> bool) -> Result<(), ApiError> {
+    if end.len() > MAX_ENTRY_SIZE {
+        return Err(ApiError::TooLarge);
+    }
+    if end < start {
+        return Ok(());
+    }
+    scan_asc(tree, start, &mut |key, payload| {
+        let in_range = if end_inclusive { key <= end } else { key < end };
+        in_range && callback(key, payload)
+    })
+}
+
+/// calls `callback` with key and payload for keys <= start in descending order until it returns false
+pub fn scan_desc(tree: &mut BTree, start: &[u8], callback: &mut dyn FnMut(&[u8], &[u8]) -> bool) -> Result<(), ApiError> {
+    tree.range_lookup_desc(start, callback);
+    Ok(())
+}
+
+/// size of the key and payload length fields in `scan_chunk` records
+pub const CHUNK_LEN_SIZE: usize = 4;
+
+pub struct ScanChunk {
+    /// number of records written
+    pub count: usize,
+    /// number of bytes written
+    pub len: usize,
+    /// first key not written, none if all keys were written
+    pub next: Option<Vec<u8>>,
+}
+
+/// writes records for keys >= start in ascending order to `out` until the next record does not fit.
+/// Each record is a `u32` key length, the key, a `u32` payload length and the payload, lengths use native byte order.
+/// Fails if not even one record fits, `MAX_ENTRY_SIZE + 2 * CHUNK_LEN_SIZE` bytes are always sufficient.
+pub fn scan_chunk(tree: &mut BTree, start: &[u8], out: &mut [u8]) -> Result<ScanChunk, ApiError> {
+    let mut chunk = ScanChunk { count: 0, len: 0, next: None };
+    scan_asc(tree, start, &mut |key, payload| {
+        let record_len = 2 * CHUNK_LEN_SIZE + key.len() + payload.len();
+        if chunk.len + record_len > out.len() {
+            chunk.next = Some(key.to_vec());
+            return false;
+        }
+        let mut write = &mut out[chunk.len..];
+        for field in [key, payload] {
+            write[..CHUNK_LEN_SIZE].copy_from_slice(&(field.len() as u32).to_ne_bytes());
+            write[CHUNK_LEN_SIZE..][..field.len()].copy_from_slice(field);
+            write = &mut write[CHUNK_LEN_SIZE + field.len()..];
+        }
+        chunk.len += record_len;
+        chunk.count += 1;
+        true
+    })?;
+    if chunk.count == 0 && chunk.next.is_some() {
+        return Err(ApiError::TooLarge);
+    }
+    Ok(chunk)
+}
+
+/// safe map over byte strings owning a `BTree`, pages are freed when it is dropped.
+/// `get` does not adapt nodes, so it works through a shared reference.
+///
+/// ```
+/// use btree::api::Map;
+///
+/// let mut map = Map::new();
+/// map.insert(b"apple", b"red").unwrap();
+/// map.insert(b"banana", b"yellow").unwrap();
+/// assert_eq!(map.get(b"apple"), Some(&b"red"[..]));
+/// assert_eq!(map.len(), 2);
+/// assert!(map.remove(b"apple"));
+/// assert_eq!(map.get(b"apple"), None);
+/// ```
+pub struct Map {
+    tree: BTree,
+}
+
+impl Map {
+    pub fn new() -> Self {
+        crate::ensure_init();
+        Map { tree: BTree::new() }
+    }
+
+    /// inserts or replaces the payload of `key`, fails if key and payload exceed `MAX_ENTRY_SIZE`
+    pub fn insert(&mut self, key: &[u8], payload: &[u8]) -> Result<(), ApiError> {
+        insert(&mut self.tree, key, payload)
+    }
+
+    /// payload of `key` without copying it out of the tree
+    ///
+    /// ```
+    /// # use btree::api::Map;
+    /// let mut map = Map::new();
+    /// map.insert(b"key", b"value").unwrap();
+    /// let (a, b) = (&map, &map);
+    /// assert_eq!(a.get(b"key"), b.get(b"key"));
+    /// assert_eq!(map.get(&[0; 2000]), None);
+    /// ```
+    pub fn get(&self, key: &[u8]) -> Option<&[u8]> {
+        // keys that are too large can not be present
+        if key.len() > MAX_ENTRY_SIZE {
+            return None;
+        }
+        self.tree.get(key)
+    }
+
+    /// returns true if the key was present
+    pub fn remove(&mut self, key: &[u8]) -> bool {
+        remove(&mut self.tree, key).unwrap_or(false)
+    }
+
+    pub fn len(&self) -> usize {
+        self.tree.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// owned key payload pairs in `range` in ascending key order
+    ///
+    /// ```
+    /// # use btree::api::Map;
+    /// use std::ops::Bound;
+    ///
+    /// let mut map = Map::new();
+    /// for key in [&b"a"[..], b"b", b"c", b"d"] {
+    ///     map.insert(key, b"").unwrap();
+    /// }
+    /// let range = (Bound::Included(&b"b"[..]), Bound::Excluded(&b"d"[..]));
+    /// let keys: Vec<Vec<u8>> = map.range(range).map(|(key, _)| key).collect();
+    /// assert_eq!(keys, [b"b".to_vec(), b"c".to_vec()]);
+    /// ```
+    pub fn range(&mut self, range: impl RangeBounds<[u8]>) -> impl Iterator<Item = (Vec<u8>, Vec<u8>)> + '_ {
+        let (start, skip_start) = match range.start_bound() {
+            Bound::Included(start) => (start, false),
+            Bound::Excluded(start) => (start, true),
+            Bound::Unbounded => (&[][..], false),
+        };
+        let end = match range.end_bound() {
+            Bound::Included(end) => Bound::Included(end.to_vec()),
+            Bound::Excluded(end) => Bound::Excluded(end.to_vec()),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        let start = start.to_vec();
+        self.tree.iter_from(&start)
+            .skip_while(move |(key, _)| skip_start && *key == start)
+            .take_while(move |(key, _)| match &end {
+                Bound::Included(end) => key <= end,
+                Bound::Excluded(end) => key < end,
+                Bound::Unbounded => true,
+            })
+    }
+}
+
+impl Default for Map {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{btree_adaptation_stats, btree_destroy, btree_insert, btree_lookup, btree_new, btree_node_tag_counts, btree_print_info, btree_remove, btree_root_tag, btree_scan_asc, btree_scan_chunk};
+    use std::ptr;
+
+    #[test]
+    fn safe_api_errors() {
+        crate::ensure_init();
+        let mut tree = BTree::new();
+        let big = vec![u8::MAX; MAX_ENTRY_SIZE + 1];
+        assert_eq!(insert(&mut tree, &big, b""), Err(ApiError::TooLarge));
+        assert_eq!(insert(&mut tree, b"key", &big), Err(ApiError::TooLarge));
+        insert(&mut tree, b"key", b"value").unwrap();
+        assert_eq!(lookup(&mut tree, b"key").unwrap().map(|v| v.to_vec()), Some(b"value".to_vec()));
+        assert_eq!(lookup(&mut tree, &big), Ok(None));
+        assert_eq!(remove(&mut tree, &big), Ok(false));
+        let mut scanned = Vec::new();
+        scan_asc(&mut tree, &big[..], &mut |k, _| { scanned.push(k.to_vec()); true }).unwrap();
+        assert!(scanned.is_empty());
+        scan_desc(&mut tree, &big[..], &mut |k, _| { scanned.push(k.to_vec()); true }).unwrap();
+        assert_eq!(scanned, vec![b"key".to_vec()]);
+        assert_eq!(scan_chunk(&mut tree, b"", &mut [0u8; 4]).err(), Some(ApiError::TooLarge));
+        assert!(remove(&mut tree, b"key").unwrap());
+        unsafe {
+            assert_eq!(raw_tree(ptr::null_mut()).err(), Some(ApiError::NullPointer));
+            assert_eq!(raw_slice(ptr::null(), 0), Ok(&[][..]));
+            assert_eq!(raw_slice(ptr::null(), 1), Err(ApiError::NullPointer));
+            assert_eq!(raw_slice(b"x".as_ptr(), u64::MAX), Err(ApiError::TooLarge));
+        }
+    }
+
+    #[test]
+    fn scan_range_matches_map_range() {
+        use std::collections::BTreeMap;
+        use std::ops::Bound;
+
+        crate::ensure_init();
+        let mut tree = BTree::new();
+        let mut reference = BTreeMap::new();
+        for i in 0..5_000u32 {
+            let key = format!("{:04}", i * 2).into_bytes();
+            insert(&mut tree, &key, &i.to_le_bytes()).unwrap();
+            reference.insert(key, i.to_le_bytes().to_vec());
+        }
+        // stored and absent bounds, empty ranges with end below start and ends past the last key
+        let bounds: Vec<Vec<u8>> = ["", "0000", "0001", "0500", "0501", "5000", "9998", "9999", "z"].iter().map(|b| b.as_bytes().to_vec()).collect();
+        for start in &bounds {
+            for end in &bounds {
+                for end_inclusive in [false, true] {
+                    let mut scanned = Vec::new();
+                    scan_range(&mut tree, start, end, end_inclusive, &mut |k, v| {
+                        scanned.push((k.to_vec(), v.to_vec()));
+                        true
+                    }).unwrap();
+                    let end_bound = if end_inclusive { Bound::Included(end.clone()) } else { Bound::Excluded(end.clone()) };
+                    let expected: Vec<_> = if end < start {
+                        Vec::new()
+                    } else {
+                        reference.range((Bound::Included(start.clone()), end_bound)).map(|(k, v)| (k.clone(), v.clone())).collect()
+                    };
+                    assert_eq!(scanned, expected, "{start:?} {end:?} {end_inclusive}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn scan_chunks_reassemble() {
+        crate::ensure_init();
+        let mut tree = BTree::new();
+        let mut expected = Vec::new();
+        for i in 0..5_000u32 {
+            let key = format!("{:x}", i.wrapping_mul(2654435761)).into_bytes();
+            let payload = vec![i as u8; i as usize % 50];
+            insert(&mut tree, &key, &payload).unwrap();
+            expected.push((key, payload));
+        }
+        expected.sort();
+        let mut scanned = Vec::new();
+        let mut out = [0u8; 1000];
+        let mut start = Vec::new();
+        loop {
+            let chunk = scan_chunk(&mut tree, &start, &mut out).unwrap();
+            assert!(chunk.count > 0);
+            let mut read = &out[..chunk.len];
+            for _ in 0..chunk.count {
+                let mut fields = [&[][..]; 2];
+                for field in &mut fields {
+                    let len = u32::from_ne_bytes(read[..CHUNK_LEN_SIZE].try_into().unwrap()) as usize;
+                    *field = &read[CHUNK_LEN_SIZE..][..len];
+                    read = &read[CHUNK_LEN_SIZE + len..];
+                }
+                scanned.push((fields[0].to_vec(), fields[1].to_vec()));
+            }
+            assert!(read.is_empty());
+            match chunk.next {
+                Some(next) => start = next,
+                None => break,
+            }
+        }
+        assert_eq!(scanned, expected);
+    }
+
+    extern "C" fn continue_scan(_: *const u8) -> bool {
+        true
+    }
+
+    #[test]
+    fn ffi_reports_errors() {
+        unsafe {
+            let tree = btree_new();
+            let big = vec![1u8; MAX_ENTRY_SIZE + 1];
+            assert_eq!(btree_insert(tree, b"k".as_ptr(), 1, b"v".as_ptr(), 1), 0);
+            assert_eq!(btree_insert(tree, big.as_ptr(), big.len() as u64, ptr::null(), 0), ApiError::TooLarge as u8);
+            assert_eq!(btree_insert(ptr::null_mut(), b"k".as_ptr(), 1, ptr::null(), 0), ApiError::NullPointer as u8);
+            assert_eq!(btree_insert(tree, ptr::null(), 1, ptr::null(), 0), ApiError::NullPointer as u8);
+            let mut len = 0;
+            assert!(btree_lookup(tree, big.as_ptr(), big.len() as u64, &mut len).is_null());
+            assert!(btree_lookup(ptr::null_mut(), b"k".as_ptr(), 1, &mut len).is_null());
+            assert!(!btree_lookup(tree, b"k".as_ptr(), 1, &mut len).is_null());
+            assert_eq!(len, 1);
+            assert!(!btree_remove(tree, big.as_ptr(), big.len() as u64));
+            let mut key_buffer = [0u8; MAX_ENTRY_SIZE];
+            assert_eq!(btree_scan_asc(tree, big.as_ptr(), big.len() as u64, key_buffer.as_mut_ptr(), continue_scan), 0);
+            assert_eq!(btree_scan_asc(tree, ptr::null(), 0, ptr::null_mut(), continue_scan), ApiError::NullPointer as u8);
+            let (mut out, mut count, mut next_len) = ([0u8; 4], 0, 0);
+            assert_eq!(btree_scan_chunk(tree, ptr::null(), 0, out.as_mut_ptr(), 4, &mut count, key_buffer.as_mut_ptr(), &mut next_len), ApiError::TooLarge as u8);
+            let mut out = [0u8; 64];
+            assert_eq!(btree_scan_chunk(tree, ptr::null(), 0, out.as_mut_ptr(), 64, &mut count, key_buffer.as_mut_ptr(), &mut next_len), 0);
+            assert_eq!((count, next_len), (1, 0));
+            assert!(btree_remove(tree, b"k".as_ptr(), 1));
+            btree_destroy(tree);
+            // null trees are ignored
+            btree_print_info(ptr::null_mut());
+            btree_destroy(ptr::null_mut());
+        }
+    }
+
+    #[test]
+    fn ffi_reports_node_types() {
+        unsafe {
+            let tree = btree_new();
+            for i in 0..20_000u32 {
+                assert_eq!(btree_insert(tree, i.to_be_bytes().as_ptr(), 4, b"value".as_ptr(), 5), 0);
+            }
+            let expected = crate::node_stats::node_tag_counts(&*tree);
+            assert_eq!(btree_root_tag(tree), (*tree).root_tag() as u8);
+            assert!((*tree).root_tag().is_inner());
+            // a larger array is zero filled past the last tag, a smaller one only receives its prefix
+            let mut counts = vec![u64::MAX; expected.len() + 3];
+            assert_eq!(btree_node_tag_counts(tree, counts.as_mut_ptr(), counts.len() as u64), expected.len() as u64);
+            assert_eq!(&counts[..expected.len()], &expected[..]);
+            assert_eq!(&counts[expected.len()..], &[0; 3]);
+            let mut counts = [u64::MAX; 2];
+            assert_eq!(btree_node_tag_counts(tree, counts.as_mut_ptr(), 2), expected.len() as u64);
+            assert_eq!(counts, [expected[0], expected[1]]);
+            assert_eq!(btree_node_tag_counts(tree, ptr::null_mut(), 0), expected.len() as u64);
+            assert!(expected.iter().sum::<u64>() > 1);
+            // invalid arguments are reported instead of panicking across the FFI boundary
+            assert_eq!(btree_root_tag(ptr::null_mut()), INVALID_TAG);
+            assert!(crate::vtables::BTreeNodeTag::try_from(INVALID_TAG).is_err());
+            assert_eq!(btree_node_tag_counts(ptr::null_mut(), counts.as_mut_ptr(), 2), 0);
+            assert_eq!(btree_node_tag_counts(tree, ptr::null_mut(), 2), 0);
+            assert_eq!(counts, [expected[0], expected[1]]);
+            btree_destroy(tree);
+        }
+    }
+
+    #[test]
+    fn ffi_reports_adaptation_stats() {
+        use crate::btree_node::AccessPattern;
+        use crate::node_stats::AdaptStats;
+        use crate::vtables::BTreeNodeTag;
+
+        crate::ensure_init();
+        unsafe {
+            let tree = btree_new();
+            let stats = || {
+                let mut stats = AdaptStats::default();
+                assert_eq!(btree_adaptation_stats(tree, &mut stats), 0);
+                stats
+            };
+            assert_eq!(btree_adaptation_stats(ptr::null_mut(), &mut AdaptStats::default()), ApiError::NullPointer as u8);
+            assert_eq!(btree_adaptation_stats(tree, ptr::null_mut()), ApiError::NullPointer as u8);
+            for i in 0..20_000u32 {
+                assert_eq!(btree_insert(tree, i.to_be_bytes().as_ptr(), 4, b"value".as_ptr(), 5), 0);
+            }
+            // the counters are shared with concurrently running tests, so conversions in this tree are lower bounds of their growth
+            let before = stats();
+            // hinting converts every basic leaf to a hash leaf and back
+            (*tree).hint_access_pattern(b"", AccessPattern::Point);
+            let to_hash = stats();
+            assert!(to_hash.basic_to_hash - before.basic_to_hash >= before.basic_leaves);
+            assert_eq!(to_hash.basic_leaves, 0);
+            assert_eq!(to_hash.hash_leaves, before.basic_leaves + before.hash_leaves);
+            assert_eq!(to_hash.hybrid_leaves, before.hybrid_leaves);
+            (*tree).hint_access_pattern(b"", AccessPattern::Range);
+            let to_basic = stats();
+            let converted = |s: &AdaptStats| s.hash_to_basic + s.hash_to_basic_failed;
+            assert!(converted(&to_basic) - converted(&to_hash) >= to_hash.hash_leaves);
+            assert_eq!(to_basic.basic_leaves + to_basic.hash_leaves, to_hash.hash_leaves);
+            if !cfg!(feature = "leaf_hybrid") {
+                // only hybrid leaves serve both access patterns without converting
+                assert!(to_hash.hash_leaves > 1);
+                assert!(to_basic.basic_leaves > 0);
+            }
+            assert!(to_basic.inner_adaptions >= before.inner_adaptions);
+            let counts = crate::node_stats::node_tag_counts(&*tree);
+            let count = |tag: BTreeNodeTag| counts[tag as usize];
+            assert_eq!(to_basic.basic_leaves, count(BTreeNodeTag::BasicLeaf));
+            assert_eq!(to_basic.hash_leaves, count(BTreeNodeTag::HashLeaf));
+            assert_eq!(to_basic.hybrid_leaves, count(BTreeNodeTag::HybridLeaf));
+            let inner: u64 = (0..counts.len()).filter(|&i| BTreeNodeTag::try_from(i as u8).map_or(false, |tag| tag.is_inner())).map(|i| counts[i]).sum();
+            assert_eq!(to_basic.inner_nodes, inner);
+            assert!(to_basic.inner_nodes > 0);
+            assert_eq!(to_basic.basic_leaves + to_basic.hash_leaves + to_basic.hybrid_leaves + to_basic.inner_nodes, counts.iter().sum::<u64>());
+            btree_destroy(tree);
+        }
+    }
+}
@@ -4,11 +4,12 @@ use std::mem::{align_of, size_of};
 use std::ops::Range;
 use std::ptr;
 use std::sync::atomic::AtomicUsize;
+use bumpalo::Bump;
 use smallvec::SmallVec;
-use crate::{BTreeNode, PAGE_SIZE, PrefixTruncatedKey};
+use crate::{BTreeNode, FatTruncatedKey, PAGE_SIZE, PrefixTruncatedKey};
 use crate::branch_cache::BranchCacheAccessor;
 use crate::find_separator::find_separator;
-use crate::node_traits::{FenceData, FenceRef, InnerConversionSink, InnerConversionSource, InnerInsertSource, InnerNode, Node, SeparableInnerConversionSource, split_in_place};
+use crate::node_traits::{debug_assert_children_valid, merge_candidates, FenceData, FenceRef, InnerConversionSink, InnerConversionSource, InnerInsertSource, InnerNode, InnerRemoveSource, Node, SeparableInnerConversionSource, split_in_place, NodeOpError, inner_key_cap_reached};
 use crate::util::{common_prefix_len, get_key_from_slice, partial_restore, reinterpret, reinterpret_mut, SmallBuff};
 use crate::vtables::BTreeNodeTag;
 
@@ -20,6 +21,8 @@ pub struct ArtNode {
     data: ArtNodeData,
 }
 
+const _: () = assert!(size_of::<ArtNode>() == PAGE_SIZE);
+
 union ArtNodeData {
     range_array: [u16; (PAGE_SIZE - size_of::<ArtNodeHead>()) / 2],
     _bytes: [u8; PAGE_SIZE - size_of::<ArtNodeHead>()],
@@ -44,6 +47,7 @@ struct LayoutInfo {
     page_indirection_vector: usize,
 }
 
+/// keys are stored without the span bytes on the radix path to their range
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
 struct PageIndirectionVectorEntry {
     key_offset: u16,
@@ -51,9 +55,9 @@ struct PageIndirectionVectorEntry {
 }
 
 impl PageIndirectionVectorEntry {
-    fn key<'a>(&self, page: &'a ArtNode) -> PrefixTruncatedKey<'a> {
+    fn suffix<'a>(&self, page: &'a ArtNode) -> &'a [u8] {
         unsafe {
-            PrefixTruncatedKey(&reinterpret::<ArtNode, [u8; PAGE_SIZE]>(page)[self.key_offset as usize..][..self.key_len as usize])
+            &reinterpret::<ArtNode, [u8; PAGE_SIZE]>(page)[self.key_offset as usize..][..self.key_len as usize]
         }
     }
 }
@@ -93,31 +97,26 @@ impl ArtNode {
         (node_bytes, extra)
     }
 
-    fn construct<F: Fn(&Self, usize) -> PrefixTruncatedKey>(&mut self, keys: &F, key_range: Range<usize>, prefix_len: usize) -> Result<u16, ()> {
+    /// `skips` receives the number of span bytes on the path to each key's range
+    fn construct<'k, F: Fn(usize) -> PrefixTruncatedKey<'k>>(&mut self, keys: &F, key_range: Range<usize>, prefix_len: usize, skips: &mut [u16]) -> Result<u16, NodeOpError> {
         let original_start = key_range.start;
         if key_range.len() <= MIN_SUBRANGE_SIZE {
+            skips[key_range.clone()].fill(prefix_len as u16);
             return Ok(self.push_range_array_entry(key_range)? | NODE_REF_IS_RANGE);
         }
-        let full_prefix = prefix_len + common_prefix_len(&keys(self, key_range.start).0[prefix_len..], &keys(self, key_range.end - 1).0[prefix_len..]);
+        let full_prefix = prefix_len + common_prefix_len(&keys(key_range.start).0[prefix_len..], &keys(key_range.end - 1).0[prefix_len..]);
         let ret = if full_prefix > prefix_len {
             self.push_range_array_entry(original_start..key_range.start)?;
-            self.construct_inner_decision_node::<F>(&keys, key_range.clone(), full_prefix)?;
+            self.construct_inner_decision_node::<F>(&keys, key_range.clone(), full_prefix, skips)?;
             self.push_range_array_entry(key_range.end..key_range.end)?;
             let span_len = full_prefix - prefix_len;
             self.set_heap_write_pos_mod_2(span_len as u16)?;
-            unsafe {
-                // key_ptr may point into self, but will never overlap destination
-                let src = &keys(self, key_range.start)[prefix_len..full_prefix];
-                let src_ptr = src.as_ptr();
-                let src_len = src.len();
-                let offset = self.heap_alloc(src_len)?;
-                std::ptr::copy_nonoverlapping(src_ptr, (self as *mut Self as *mut u8).offset(offset as isize), src_len);
-            }
+            self.heap_write(&keys(key_range.start)[prefix_len..full_prefix])?;
             self.assert_heap_write_aligned();
             self.heap_write((span_len as u16).to_ne_bytes().as_slice())?;
             Ok(self.heap_write(NODE_TAG_SPAN.to_ne_bytes().as_slice())? as u16)
         } else {
-            self.construct_inner_decision_node::<F>(&keys, key_range, full_prefix)
+            self.construct_inner_decision_node::<F>(&keys, key_range, full_prefix, skips)
         };
         ret
     }
@@ -186,7 +185,12 @@ impl ArtNode {
 
     fn partition<F: Fn(usize) -> Option<u8>>(keys: &F, key_range: Range<usize>) -> SmallVec<[u16; MAX_CHILDREN - 1]> {
         assert!(MAX_CHILDREN == 4);
-        let is_candidate = |i| i == key_range.start + 1 && keys(i - 1).is_none() || keys(i - 1).unwrap() != keys(i).unwrap();
+        // only the first key of a range can end at the decision byte, it is split off into its own child
+        let is_candidate = |i: usize| match (keys(i - 1), keys(i)) {
+            (None, _) => true,
+            (Some(a), Some(b)) => a != b,
+            (Some(_), None) => unreachable!("keys are not sorted"),
+        };
         let find_best_split = |r: Range<usize>| {
             let mut low = (r.start + r.end) / 2;
             let mut high = low + 1;
@@ -230,20 +234,22 @@ impl ArtNode {
         splits
     }
 
-    // TODO: how to handle [[],[0,...],[0,...],...]?
-    //       0 key cannot split -> cannot create span node in next step
-    //       decision four cases: less, equal, greater, empty?
-    //       span four cases: prefix less, prefix greater, same as prefix, prefix is prefix of key
-    fn construct_inner_decision_node<F: Fn(&Self, usize) -> PrefixTruncatedKey>(&mut self, keys: &F, key_range: Range<usize>, prefix_len: usize) -> Result<u16, ()> {
+    /// keys ending at `prefix_len` and keys that are prefixes of others are handled as follows:
+    /// a key ending at the decision byte can only be the first in the range and always gets its own child,
+    /// so `[[],[0,...],[0,...],...]` splits into `[[]]` and `[[0,...],[0,...],...]`, which continues with a span node.
+    /// Searches with keys ending before the decision byte go to the first child.
+    /// Span nodes are only created if the first and last key share bytes, so every key in them is at least as long as the span,
+    /// searches with keys that differ from the span or are a prefix of it resolve to the empty ranges around the span's subtree.
+    fn construct_inner_decision_node<'k, F: Fn(usize) -> PrefixTruncatedKey<'k>>(&mut self, keys: &F, key_range: Range<usize>, prefix_len: usize, skips: &mut [u16]) -> Result<u16, NodeOpError> {
         let mut children = SmallVec::<[u16; MAX_CHILDREN]>::new();
-        let subrange_boundries = Self::partition(&|i| keys(self, i).get(prefix_len).copied(), key_range.clone());
+        let subrange_boundries = Self::partition(&|i| keys(i).get(prefix_len).copied(), key_range.clone());
         {
             let mut range_start = key_range.start;
             for i in subrange_boundries.iter().map(|x| *x as usize) {
-                children.push(self.construct::<F>(keys, range_start..i, prefix_len)?);
+                children.push(self.construct::<F>(keys, range_start..i, prefix_len, skips)?);
                 range_start = i;
             }
-            children.push(self.construct::<F>(keys, range_start..key_range.end, prefix_len)?);
+            children.push(self.construct::<F>(keys, range_start..key_range.end, prefix_len, skips)?);
         }
         let key_count = children.len() - 1;
         let key_array_size = key_count.next_multiple_of(2);
@@ -255,7 +261,7 @@ impl ArtNode {
             {
                 let mut next_write = 0;
                 for i in subrange_boundries.iter().map(|x| *x as usize) {
-                    reinterpret_mut::<Self, [u8; PAGE_SIZE]>(self)[pos + 4 + next_write] = keys(self, i)[prefix_len];
+                    reinterpret_mut::<Self, [u8; PAGE_SIZE]>(self)[pos + 4 + next_write] = keys(i)[prefix_len];
                     next_write += 1;
                 }
             }
@@ -265,7 +271,7 @@ impl ArtNode {
         Ok(pos as u16)
     }
 
-    fn heap_write(&mut self, data: &[u8]) -> Result<usize, ()> {
+    fn heap_write(&mut self, data: &[u8]) -> Result<usize, NodeOpError> {
         unsafe {
             let pos = self.heap_alloc(data.len())?;
             self.write_to(pos, data);
@@ -281,7 +287,7 @@ impl ArtNode {
         debug_assert_eq!(self.head.data_write % 2, 0)
     }
 
-    fn set_heap_write_pos_mod_2(&mut self, m: u16) -> Result<usize, ()> {
+    fn set_heap_write_pos_mod_2(&mut self, m: u16) -> Result<usize, NodeOpError> {
         if self.head.data_write % 2 != m % 2 {
             self.heap_alloc(1)
         } else {
@@ -293,9 +299,9 @@ impl ArtNode {
         self.head.data_write as usize - (Self::layout(self.head.range_array_len as usize).page_indirection_vector + size_of::<PageIndirectionVectorEntry>() * self.head.key_count as usize)
     }
 
-    fn heap_alloc(&mut self, len: usize) -> Result<usize, ()> {
+    fn heap_alloc(&mut self, len: usize) -> Result<usize, NodeOpError> {
         if self.free_space() < len {
-            Err(())
+            Err(NodeOpError::NoSpace)
         } else {
             self.head.data_write -= len as u16;
             Ok(self.head.data_write as usize)
@@ -303,9 +309,9 @@ impl ArtNode {
     }
 
     /// overwrites page indirection vector
-    fn push_range_array_entry(&mut self, range: Range<usize>) -> Result<u16, ()> {
+    fn push_range_array_entry(&mut self, range: Range<usize>) -> Result<u16, NodeOpError> {
         if self.free_space() < 2 {
-            return Err(());
+            return Err(NodeOpError::NoSpace);
         } else {
             let pos = self.head.range_array_len;
             unsafe {
@@ -339,6 +345,139 @@ impl ArtNode {
             std::slice::from_raw_parts(ptr, self.key_count())
         }
     }
+
+    fn page_indirection_vector_mut(&mut self) -> &mut [PageIndirectionVectorEntry] {
+        unsafe {
+            let ptr = (self as *mut Self as *mut u8).offset(Self::layout(self.head.range_array_len as usize).page_indirection_vector as isize) as *mut PageIndirectionVectorEntry;
+            std::slice::from_raw_parts_mut(ptr, self.key_count())
+        }
+    }
+
+    fn search_child_index(&self, key: PrefixTruncatedKey) -> usize {
+        unsafe {
+            let (range_index, remaining_key_len) = self.find_key_range_unchecked(key.0, self.head.root_node);
+            let range_index = range_index as usize;
+            let key_skip = key.len() - remaining_key_len;
+            let range = self.range_array()[range_index - 1] as usize..self.range_array()[range_index] as usize;
+            {
+                static COUNT: AtomicUsize = AtomicUsize::new(0);
+                static LEN_SUM: AtomicUsize = AtomicUsize::new(0);
+                let sum = LEN_SUM.fetch_add(range.len(), std::sync::atomic::Ordering::Relaxed);
+                let count = COUNT.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                if count % (1 << 12) == 0 {
+                    eprintln!("{}", sum as f64 / count as f64);
+                }
+            }
+            debug_assert!(range.end == self.head.key_count as usize || key.0 <= &self.restore_key(range.end)[..]);
+            debug_assert!(range.start == 0 || &self.restore_key(range.start - 1)[..] < key.0);
+            // keys within a range share the key_skip bytes consumed by span nodes, which are not stored
+            let index = range.start as usize + match self.page_indirection_vector()[range].binary_search_by_key(&&key.0[key_skip..], |e: &PageIndirectionVectorEntry| {
+                e.suffix(self)
+            }) {
+                Ok(i) | Err(i) => i
+            };
+            index
+        }
+    }
+
+    /// appends the span bytes on the radix path to range `range_index` to `out`
+    fn restore_range_prefix(&self, range_index: u16, out: &mut SmallBuff) {
+        let mut node = self.head.root_node;
+        unsafe {
+            while node & NODE_REF_IS_RANGE == 0 {
+                let (node_bytes, extra) = self.read_node(node);
+                match extra {
+                    Ok(children) => {
+                        node = *children.iter().find(|&&c| self.node_max(c) >= range_index).unwrap();
+                    }
+                    Err(successor) => {
+                        out.extend_from_slice(node_bytes);
+                        node = successor;
+                    }
+                }
+            }
+        }
+        debug_assert_eq!(node & !NODE_REF_IS_RANGE, range_index);
+    }
+
+    /// index into the range array of the range holding key `index`
+    fn range_index_of(&self, index: usize) -> usize {
+        self.range_array().partition_point(|&end| end as usize <= index)
+    }
+
+    /// prefix truncated key at `index`
+    fn restore_key(&self, index: usize) -> SmallBuff {
+        let mut key = SmallBuff::new();
+        self.restore_range_prefix(self.range_index_of(index) as u16, &mut key);
+        key.extend_from_slice(self.piv_entry(index).suffix(self));
+        key
+    }
+
+    /// calls `f` with the length of the restored range prefix and the entries of each range overlapping `keys`.
+    /// Each range prefix is restored once.
+    fn for_each_range_in(&self, keys: Range<usize>, mut f: impl FnMut(usize, &[PageIndirectionVectorEntry])) {
+        if keys.is_empty() {
+            return;
+        }
+        let range_array = self.range_array();
+        let mut prefix = SmallBuff::new();
+        for range_index in self.range_index_of(keys.start)..range_array.len() {
+            let start = (range_array[range_index - 1] as usize).max(keys.start);
+            let end = (range_array[range_index] as usize).min(keys.end);
+            if start >= keys.end {
+                break;
+            }
+            if start < end {
+                prefix.clear();
+                self.restore_range_prefix(range_index as u16, &mut prefix);
+                f(prefix.len(), &self.page_indirection_vector()[start..end]);
+            }
+        }
+    }
+
+    /// true if child `index` is responsible for `key`, i.e. key `index - 1` < `key` <= key `index`.
+    /// Both keys are usually in the same range, whose prefix is then restored once.
+    fn child_bounds_contain(&self, index: usize, key: PrefixTruncatedKey) -> bool {
+        let mut prefix = SmallBuff::new();
+        let mut prefix_range = None;
+        let mut cmp_key = |i: usize| {
+            let range_index = self.range_index_of(i);
+            if prefix_range != Some(range_index) {
+                prefix.clear();
+                self.restore_range_prefix(range_index as u16, &mut prefix);
+                prefix_range = Some(range_index);
+            }
+            prefix.iter().chain(self.piv_entry(i).suffix(self)).cmp(key.0)
+        };
+        (index == 0 || cmp_key(index - 1).is_lt()) && (index >= self.key_count() || cmp_key(index).is_ge())
+    }
+
+    /// removes key and child at `index`.
+    /// Removing a key keeps the radix tree valid if all range ends behind it are decremented.
+    /// If this empties a range, the node is rebuilt instead to not accumulate empty ranges.
+    /// The space of removed keys is only reclaimed by a rebuild.
+    pub fn remove_slot(&mut self, index: usize) {
+        debug_assert!(index < self.key_count());
+        let range_array = self.range_array();
+        let range_index = self.range_index_of(index);
+        if range_array[range_index] - range_array[range_index - 1] == 1 {
+            let mut tmp = unsafe { BTreeNode::new_uninit() };
+            if Self::create(&mut tmp, &InnerRemoveSource::new(&*self, index)).is_ok() {
+                unsafe {
+                    ptr::copy_nonoverlapping(&tmp as *const BTreeNode as *const Self, self, 1);
+                }
+                return;
+            }
+        }
+        let range_array_len = self.head.range_array_len as usize;
+        for end in unsafe { &mut self.data.range_array[..range_array_len] } {
+            if *end as usize > index {
+                *end -= 1;
+            }
+        }
+        self.page_indirection_vector_mut().copy_within(index + 1.., index);
+        self.head.key_count -= 1;
+    }
 }
 
 struct NodeDebugWrapper<'a> {
@@ -398,16 +537,17 @@ unsafe impl Node for ArtNode {
             lower_fence: FenceRef(lower),
             upper_fence: FenceRef(upper),
         }.restrip());
+        debug_assert_children_valid(self);
         let mut current_lower: SmallBuff = lower.into();
-        for (i, e) in self.page_indirection_vector().iter().enumerate() {
-            let current_upper = partial_restore(0, &[&lower[..self.head.prefix_len as usize], e.key(self).0], 0);
+        for i in 0..self.key_count() {
+            let current_upper = partial_restore(0, &[&lower[..self.head.prefix_len as usize], &self.restore_key(i)], 0);
             unsafe { &mut *self.get_child(i) }.validate_tree(&current_lower, &current_upper);
             current_lower = current_upper;
         }
         unsafe { &mut *self.get_child(self.head.key_count as usize) }.validate_tree(&current_lower, upper);
     }
 
-    fn split_node(&mut self, parent: &mut dyn InnerNode, index_in_parent: usize, key_in_node: &[u8]) -> Result<(), ()> {
+    fn split_node(&mut self, parent: &mut dyn InnerNode, index_in_parent: usize, key_in_node: &[u8]) -> Result<(), NodeOpError> {
         unsafe {
             split_in_place::<Self, Self, Self>(reinterpret_mut::<Self, BTreeNode>(self), parent, index_in_parent, key_in_node)
         }
@@ -415,12 +555,24 @@ unsafe impl Node for ArtNode {
 }
 
 impl InnerNode for ArtNode {
-    fn merge_children_check(&mut self, _child_index: usize) -> Result<(), ()> {
-        //TODO
-        return Err(());
+    fn merge_children(&mut self, child_index: usize) -> Result<(), NodeOpError> {
+        unsafe {
+            let (child_index, left, right) = merge_candidates(self, child_index)?;
+            let sep_key = self.restore_key(child_index);
+            left.try_merge_right(
+                right,
+                FatTruncatedKey {
+                    remainder: &sep_key,
+                    prefix_len: self.head.prefix_len as usize,
+                },
+            )?;
+            BTreeNode::dealloc(self.get_child(child_index));
+            self.remove_slot(child_index);
+            Ok(())
+        }
     }
 
-    unsafe fn insert_child(&mut self, index: usize, key: PrefixTruncatedKey, child: *mut BTreeNode) -> Result<(), ()> {
+    unsafe fn insert_child(&mut self, index: usize, key: PrefixTruncatedKey, child: *mut BTreeNode) -> Result<(), NodeOpError> {
         let mut tmp = BTreeNode::new_uninit();
 
         Self::create(&mut tmp, &InnerInsertSource::new(self, index, key, child))?;
@@ -430,52 +582,31 @@ impl InnerNode for ArtNode {
         Ok(())
     }
 
-    fn request_space_for_child(&mut self, key_length: usize) -> Result<usize, ()> {
+    fn request_space_for_child(&mut self, key_length: usize) -> Result<usize, NodeOpError> {
         let size = size_of::<PageIndirectionVectorEntry>() + size_of::<usize>() + key_length;
-        if self.free_space() >= size {
+        if self.free_space() >= size && !inner_key_cap_reached(self.key_count()) {
             Ok(self.head.prefix_len as usize)
         } else {
-            Err(())
+            Err(NodeOpError::NoSpace)
         }
     }
 
     fn find_child_index(&mut self, key: &[u8], bc: &mut BranchCacheAccessor) -> usize {
         let key = PrefixTruncatedKey(&key[self.head.prefix_len as usize..]);
-        let index = bc.predict().filter(|&i| {
-            i <= self.key_count()
-                && (i == 0 || self.piv_entry(i - 1).key(self) < key)
-                && (i >= self.key_count() || key <= self.piv_entry(i).key(self))
-        })
-            .unwrap_or_else(|| unsafe {
-                let (range_index, remaining_key_len) = self.find_key_range_unchecked(key.0, self.head.root_node);
-                let range_index = range_index as usize;
-                let key_skip = key.len() - remaining_key_len;
-                let range = self.range_array()[range_index - 1] as usize..self.range_array()[range_index] as usize;
-                {
-                    static COUNT: AtomicUsize = AtomicUsize::new(0);
-                    static LEN_SUM: AtomicUsize = AtomicUsize::new(0);
-                    let sum = LEN_SUM.fetch_add(range.len(), std::sync::atomic::Ordering::Relaxed);
-                    let count = COUNT.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-                    if count % (1 << 12) == 0 {
-                        eprintln!("{}", sum as f64 / count as f64);
-                    }
-                }
-                debug_assert!(range.end == self.head.key_count as usize || key <= self.page_indirection_vector()[range.end].key(self));
-                debug_assert!(range.start == 0 || self.page_indirection_vector()[range.start - 1].key(self) < key);
-                let index = range.start as usize + match self.page_indirection_vector()[range].binary_search_by_key(&&key.0[key_skip..], |e: &PageIndirectionVectorEntry| {
-                    &&e.key(self).0[key_skip..]
-                }) {
-                    Ok(i) | Err(i) => i
-                };
-                index
-            });
+        let predicted = bc.predict().filter(|&i| i <= self.key_count() && self.child_bounds_contain(i, key));
+        let index = predicted.unwrap_or_else(|| self.search_child_index(key));
+        debug_assert!(predicted.is_none() || index == self.search_child_index(key), "accepted branch cache prediction {index} disagrees with search");
         bc.store(index);
         index
     }
+
+    fn lookup_child_index(&self, key: &[u8]) -> usize {
+        self.search_child_index(PrefixTruncatedKey(&key[self.head.prefix_len as usize..]))
+    }
 }
 
 unsafe impl InnerConversionSink for ArtNode {
-    fn create(dst: &mut BTreeNode, src: &(impl InnerConversionSource + ?Sized)) -> Result<(), ()> {
+    fn create(dst: &mut BTreeNode, src: &(impl InnerConversionSource + ?Sized)) -> Result<(), NodeOpError> {
         let key_count = src.key_count();
         let piv_space = key_count * size_of::<PageIndirectionVectorEntry>();
         let this = dst.write_inner(ArtNode {
@@ -498,31 +629,43 @@ unsafe impl InnerConversionSink for ArtNode {
         this.head.upper_fence = this.heap_write(fences.upper_fence.0)? as u16;
         this.head.lower_fence = this.heap_write(fences.lower_fence.0)? as u16;
         this.head.prefix_len = fences.prefix_len as u16;
+        // full keys are needed to construct the radix tree, only suffixes are stored afterwards.
+        // Keys that do not fit into a page together are treated like a node without space for them.
+        if src.get_key_length_sum(0..key_count) > PAGE_SIZE {
+            return Err(NodeOpError::NoSpace);
+        }
+        let mut key_bytes = [0u8; PAGE_SIZE];
+        let mut key_bytes_len = 0;
+        let mut key_ranges = SmallVec::<[Range<usize>; 256]>::new();
+        for ki in 0..key_count {
+            let len = src.get_key(ki, &mut key_bytes[key_bytes_len..], 0)?;
+            // get_key writes to the end of the buffer, move the key down to the previous one
+            let written = key_bytes.len() - len;
+            key_bytes.copy_within(written.., key_bytes_len);
+            key_ranges.push(key_bytes_len..key_bytes_len + len);
+            key_bytes_len += len;
+        }
+        let keys = |index: usize| PrefixTruncatedKey(&key_bytes[key_ranges[index].clone()]);
+        let mut skips = SmallVec::<[u16; 256]>::from_elem(0, key_count);
+        this.push_range_array_entry(0..0)?;
+        this.head.root_node = this.construct(&keys, 0..key_count, 0, &mut skips)?;
         let mut key_entries = SmallVec::<[PageIndirectionVectorEntry; 256]>::new();
         for ki in 0..key_count {
             this.heap_write((src.get_child(ki) as usize).to_ne_bytes().as_slice())?;
-            let data_write = this.head.data_write as usize;
-            let written = src.get_key(ki, unsafe { &mut reinterpret_mut::<Self, [u8; PAGE_SIZE]>(this)[size_of::<ArtNodeHead>()..data_write] }, 0)?;
-            this.head.data_write -= written as u16;
+            let suffix = &keys(ki).0[skips[ki] as usize..];
             key_entries.push(PageIndirectionVectorEntry {
-                key_len: written as u16,
-                key_offset: (data_write - written) as u16,
+                key_len: suffix.len() as u16,
+                key_offset: this.heap_write(suffix)? as u16,
             });
         }
+        // free_space accounts for the indirection vector once key_count is set
+        if this.free_space() < piv_space {
+            return Err(NodeOpError::NoSpace);
+        }
         this.head.key_count = key_count as u16;
-        this.push_range_array_entry(0..0)?;
-        this.head.root_node = this.construct(&|node, index| {
-            let s = key_entries[index];
-            PrefixTruncatedKey(unsafe {
-                &reinterpret::<Self, [u8; PAGE_SIZE]>(node)[s.key_offset as usize..][..s.key_len as usize]
-            })
-        }, 0..key_count, 0)?;
         let indirection_vector_offset = Self::layout(this.head.range_array_len as usize).page_indirection_vector as usize;
         unsafe {
             let piv = (this as *mut Self as *mut u8).offset(indirection_vector_offset as isize) as *mut PageIndirectionVectorEntry;
-            if this.free_space() < piv_space {
-                return Err(());
-            }
             std::slice::from_raw_parts_mut(piv, key_entries.len()).copy_from_slice(&key_entries[..]);
         }
         Ok(())
@@ -556,30 +699,198 @@ impl InnerConversionSource for ArtNode {
         }
     }
 
-    fn get_key(&self, index: usize, dst: &mut [u8], strip_prefix: usize) -> Result<usize, ()> {
-        get_key_from_slice(self.piv_entry(index).key(self), dst, strip_prefix)
+    fn get_key(&self, index: usize, dst: &mut [u8], strip_prefix: usize) -> Result<usize, NodeOpError> {
+        get_key_from_slice(PrefixTruncatedKey(&self.restore_key(index)), dst, strip_prefix)
     }
 
-    fn get_key_length_sum(&self, _range: Range<usize>) -> usize {
-        unimplemented!()
+    fn get_key_length_sum(&self, range: Range<usize>) -> usize {
+        let mut sum = 0;
+        self.for_each_range_in(range, |prefix_len, entries| {
+            sum += entries.iter().map(|e| prefix_len + e.key_len as usize).sum::<usize>();
+        });
+        sum
     }
 
-    fn get_key_length_max(&self, _range: Range<usize>) -> usize {
-        self.page_indirection_vector().iter().map(|e| e.key_len as usize).max().unwrap_or(0)
+    fn get_key_length_max(&self, range: Range<usize>) -> usize {
+        let mut max = 0;
+        self.for_each_range_in(range, |prefix_len, entries| {
+            max = entries.iter().map(|e| prefix_len + e.key_len as usize).fold(max, usize::max);
+        });
+        max
     }
 }
 
 impl SeparableInnerConversionSource for ArtNode {
-    type Separator<'a> = PrefixTruncatedKey<'a>;
+    type Separator<'a> = SmallBuff;
 
     fn find_separator<'a>(&'a self) -> (usize, Self::Separator<'a>) {
-        find_separator(
+        // only the few keys inspected by find_separator are restored
+        let restored = Bump::new();
+        let (sep_slot, sep) = find_separator(
             self.head.key_count as usize,
             self.head.tag.is_leaf(),
-            |i: usize| {
-                let e = self.page_indirection_vector()[i];
-                PrefixTruncatedKey(unsafe { &reinterpret::<Self, [u8; PAGE_SIZE]>(self)[e.key_offset as usize..][..e.key_len as usize] })
-            },
-        )
+            |i: usize| PrefixTruncatedKey(restored.alloc_slice_copy(&self.restore_key(i))),
+        );
+        (sep_slot, sep.0.into())
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::b_tree::BTree;
+    use crate::test_util::ManualInnerSource;
+    use rand::{Rng, RngCore, SeedableRng};
+    use rand::prelude::SliceRandom;
+    use rand_xoshiro::Xoshiro128PlusPlus;
+    use std::collections::BTreeSet;
+
+    /// sorted distinct keys starting with one of a few bytes, so the radix tree has ranges of different sizes
+    fn clustered_keys(count: usize, rng: &mut Xoshiro128PlusPlus) -> Vec<Vec<u8>> {
+        let mut keys = BTreeSet::new();
+        while keys.len() < count {
+            let mut key = vec![0u8; rng.gen_range(1..8)];
+            rng.fill_bytes(&mut key);
+            key[0] = [b'a', b'b', b'c', b'x'][rng.gen_range(0..4)];
+            keys.insert(key);
+        }
+        keys.into_iter().collect()
+    }
+
+    /// index of the child responsible for `key` by comparing against every separator
+    fn linear_child_index(keys: &[Vec<u8>], key: &[u8]) -> usize {
+        keys.iter().position(|k| key <= &k[..]).unwrap_or(keys.len())
+    }
+
+    /// every key, and keys directly before and after each of them
+    fn probe_keys(keys: &[Vec<u8>]) -> Vec<Vec<u8>> {
+        keys.iter().flat_map(|k| [k.clone(), k[..k.len() - 1].to_vec(), [&k[..], &[0]].concat()]).collect()
+    }
+
+    #[test]
+    fn in_place_remove_matches_rebuild() {
+        crate::ensure_init();
+        let mut rng = Xoshiro128PlusPlus::seed_from_u64(13);
+        let keys = clustered_keys(60, &mut rng);
+        let children: Vec<*mut BTreeNode> = (0..keys.len() + 1).map(|_| BTreeNode::new_leaf()).collect();
+        let source = ManualInnerSource { lower_fence: Vec::new(), upper_fence: Vec::new(), keys, children };
+        let mut original = unsafe { BTreeNode::new_uninit() };
+        ArtNode::create(&mut original, &source).unwrap();
+        let original = unsafe { &original.art_node };
+        let mut in_place_count = 0;
+        for index in 0..source.keys.len() {
+            let range_array = original.range_array();
+            let range_index = range_array.partition_point(|&end| end as usize <= index);
+            in_place_count += (range_array[range_index] - range_array[range_index - 1] > 1) as usize;
+            let mut expected = ManualInnerSource {
+                lower_fence: Vec::new(),
+                upper_fence: Vec::new(),
+                keys: source.keys.clone(),
+                children: source.children.clone(),
+            };
+            expected.keys.remove(index);
+            expected.children.remove(index);
+
+            let mut in_place: BTreeNode = unsafe { ptr::read(reinterpret::<ArtNode, BTreeNode>(original)) };
+            unsafe { (*in_place.art_node).remove_slot(index) };
+            let mut rebuilt = unsafe { BTreeNode::new_uninit() };
+            ArtNode::create(&mut rebuilt, &InnerRemoveSource::new(&**original, index)).unwrap();
+            for node in [&in_place, &rebuilt] {
+                let node = unsafe { &node.art_node };
+                assert!(expected.matches(&**node), "removing {index}");
+                for probe in probe_keys(&source.keys) {
+                    assert_eq!(node.lookup_child_index(&probe), linear_child_index(&expected.keys, &probe), "removing {index}, probe {probe:?}");
+                }
+            }
+        }
+        // both the in place path and the rebuild of emptied ranges are covered
+        assert!(in_place_count > 0 && in_place_count < source.keys.len());
+        for child in source.children {
+            unsafe { BTreeNode::dealloc(child) };
+        }
+    }
+
+    #[test]
+    fn child_index_matches_linear_search() {
+        crate::ensure_init();
+        let mut rng = Xoshiro128PlusPlus::seed_from_u64(14);
+        // the fences share a prefix which is truncated from the stored keys
+        let keys: Vec<Vec<u8>> = clustered_keys(80, &mut rng).into_iter().map(|k| [&b"http://"[..], &k].concat()).collect();
+        let children: Vec<*mut BTreeNode> = (0..keys.len() + 1).map(|_| BTreeNode::new_leaf()).collect();
+        let source = ManualInnerSource { lower_fence: b"http://".to_vec(), upper_fence: b"http:0".to_vec(), keys, children };
+        let mut node = unsafe { BTreeNode::new_uninit() };
+        ArtNode::create(&mut node, &source).unwrap();
+        let node = unsafe { &mut *node.art_node };
+        let prefix_len = node.head.prefix_len as usize;
+        assert_eq!(prefix_len, 5);
+        assert!(node.range_array().len() > 3, "keys should be spread over several ranges");
+        for probe in probe_keys(&source.keys) {
+            let expected = linear_child_index(&source.keys, &probe);
+            assert_eq!(node.find_child_index(&probe, &mut BranchCacheAccessor::new()), expected, "probe {probe:?}");
+            assert_eq!(node.lookup_child_index(&probe), expected, "probe {probe:?}");
+            for i in 0..=source.keys.len() {
+                assert_eq!(node.child_bounds_contain(i, PrefixTruncatedKey(&probe[prefix_len..])), i == expected, "probe {probe:?}, child {i}");
+            }
+        }
+        let lengths: Vec<usize> = source.keys.iter().map(|k| k.len() - prefix_len).collect();
+        for range in [0..0, 0..lengths.len(), 3..4, 10..50, 25..lengths.len()] {
+            assert_eq!(node.get_key_length_sum(range.clone()), lengths[range.clone()].iter().sum::<usize>(), "{range:?}");
+            assert_eq!(node.get_key_length_max(range.clone()), lengths[range.clone()].iter().copied().max().unwrap_or(0), "{range:?}");
+        }
+        let (sep_slot, separator) = node.find_separator();
+        assert_eq!(sep_slot, source.keys.len() / 2);
+        assert_eq!(&separator[..], &source.keys[sep_slot][prefix_len..]);
+        for child in source.children {
+            unsafe { BTreeNode::dealloc(child) };
+        }
+    }
+
+    #[test]
+    fn empty_and_prefix_keys_match_linear_search() {
+        crate::ensure_init();
+        // the empty key, chains of zero bytes and keys that are prefixes of their successors
+        let keys: Vec<Vec<u8>> = [
+            &b""[..], b"\0", b"\0\0", b"\0\0\0", b"\0a", b"a", b"a\0", b"a\0\0", b"ab", b"ab\0", b"abc", b"abcd", b"abd", b"b", b"ba", b"c\0",
+        ].iter().map(|k| k.to_vec()).collect();
+        assert!(keys.windows(2).all(|w| w[0] < w[1]));
+        let children: Vec<*mut BTreeNode> = (0..keys.len() + 1).map(|_| BTreeNode::new_leaf()).collect();
+        let source = ManualInnerSource { lower_fence: Vec::new(), upper_fence: Vec::new(), keys, children };
+        let mut node = unsafe { BTreeNode::new_uninit() };
+        ArtNode::create(&mut node, &source).unwrap();
+        let node = unsafe { &mut *node.art_node };
+        assert!(source.matches(&*node));
+        let mut probes: Vec<Vec<u8>> = source.keys.iter().flat_map(|k| [k.clone(), [&k[..], &[0]].concat(), [&k[..], &[1]].concat()]).collect();
+        probes.extend(source.keys.iter().filter(|k| !k.is_empty()).map(|k| k[..k.len() - 1].to_vec()));
+        probes.extend([b"\xff".to_vec(), b"abz".to_vec(), b"c".to_vec()]);
+        for probe in probes {
+            let expected = linear_child_index(&source.keys, &probe);
+            assert_eq!(node.find_child_index(&probe, &mut BranchCacheAccessor::new()), expected, "probe {probe:?}");
+            assert_eq!(node.lookup_child_index(&probe), expected, "probe {probe:?}");
+        }
+        for child in source.children {
+            unsafe { BTreeNode::dealloc(child) };
+        }
+    }
+
+    #[test]
+    fn remove_merges_art_children() {
+        crate::ensure_init();
+        let mut rng = Xoshiro128PlusPlus::seed_from_u64(13);
+        let mut tree = BTree::new_with(BTreeNodeTag::BasicLeaf, BTreeNodeTag::ArtInner);
+        let mut keys: Vec<Vec<u8>> = (0..20_000u32).map(|i| format!("key{i:08}").into_bytes()).collect();
+        keys.shuffle(&mut rng);
+        for key in &keys {
+            tree.insert(key, key).unwrap();
+        }
+        assert_eq!(tree.root_tag(), BTreeNodeTag::ArtInner);
+        let (removed, kept) = keys.split_at(keys.len() * 9 / 10);
+        for (i, key) in removed.iter().enumerate() {
+            assert!(unsafe { tree.remove(key) });
+            if i % 1000 == 0 {
+                unsafe { (*tree.root).validate_tree(&[], &[]) };
+            }
+        }
+        unsafe { (*tree.root).validate_tree(&[], &[]) };
+        assert!(removed.iter().all(|key| tree.get(key).is_none()));
+        assert!(kept.iter().all(|key| tree.get(key) == Some(&key[..])));
+    }
+}
@@ -1,15 +1,545 @@
-use crate::{BTreeNode, op_count, PAGE_SIZE};
+use crate::{BTreeNode, op_count, PAGE_SIZE, PrefixTruncatedKey};
+use crate::btree_node::{AccessPattern, create_inner_of, LEAF_GENERATION, MAX_KEY_LEN, MAX_TREE_HEIGHT, STRIP_PREFIX};
+use std::collections::HashSet;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use rand::Rng;
+use std::mem::{self, ManuallyDrop, size_of};
 use std::ptr;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::ops::{Bound, RangeBounds};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use crate::branch_cache::BranchCacheAccessor;
-use crate::util::trailing_bytes;
+use crate::util::{common_prefix_len, KeyBuffer, trailing_bytes};
 use op_count::count_op;
-use crate::hash_leaf::HashLeaf;
+use crate::node_traits::{ConcatPart, debug_assert_children_valid, FenceData, FenceRef, InnerConcatSource, InnerConversionSource, InnerReplaceKeySource, LeafNode, NodeOpError, Rebalance};
+use crate::vtables::BTreeNodeTag;
+use crate::iter::{GroupByPrefix, Iter, Keys, SymmetricDifference};
+use crate::value_arena::{self, ValueArena};
+use crate::node_stats::{self, FragmentationStats};
 
 
 pub struct BTree {
     pub root: *mut BTreeNode,
     branch_cache: BranchCacheAccessor,
+    /// node type of newly created roots, `None` uses `DefaultInnerNodeConversionSink`
+    inner_tag: Option<BTreeNodeTag>,
+    /// distinguishes trees for cursors, unique within the process
+    id: u64,
+    /// incremented whenever nodes are split, merged or freed
+    generation: u64,
+    leaf_cache: LeafCache,
+    /// number of lookups by number of nodes visited, only recorded if depth profiling is enabled
+    depth_profile: Vec<u64>,
+    /// holds all values if `VALUE_ARENA` is enabled, leaves only store references
+    value_arena: ValueArena,
+    /// shortest and longest key ever inserted, removes do not shrink the bounds
+    key_length_bounds: (usize, usize),
+    /// number of entries, maintained by `insert` and `remove`
+    pub count: usize,
+    structural_ops: StructuralOps,
+}
+
+/// reason `BTree::merge` did not take over the entries of the other tree, which is handed back with all of them.
+/// Self keeps the entries it had before.
+pub enum MergeError {
+    /// a key of the other tree is not greater than every key of self
+    Overlap(BTree),
+    /// grafting failed and an entry could not be reinserted into self
+    Insert(NodeOpError, BTree),
+}
+
+impl MergeError {
+    /// the tree that was not merged
+    pub fn into_tree(self) -> BTree {
+        match self {
+            MergeError::Overlap(tree) | MergeError::Insert(_, tree) => tree,
+        }
+    }
+}
+
+/// number of structural modifications since the tree was created
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct StructuralOps {
+    pub splits: u64,
+    pub merges: u64,
+    pub borrows: u64,
+}
+
+#[cfg(feature = "leaf-cache_true")]
+const LEAF_CACHE: bool = true;
+#[cfg(feature = "leaf-cache_false")]
+const LEAF_CACHE: bool = false;
+
+#[cfg(feature = "depth-profile_true")]
+const DEPTH_PROFILE: bool = true;
+#[cfg(feature = "depth-profile_false")]
+const DEPTH_PROFILE: bool = false;
+
+/// emit `tracing` events with node types and sizes at each split and merge.
+/// Like all events, they are compiled out of release builds by `release_max_level_off`.
+#[cfg(feature = "trace-structural_true")]
+const TRACE_STRUCTURAL: bool = true;
+#[cfg(feature = "trace-structural_false")]
+const TRACE_STRUCTURAL: bool = false;
+
+/// number of keys in a leaf or number of separators in an inner node
+fn node_key_count(node: &BTreeNode) -> usize {
+    unsafe {
+        match node.tag() {
+            BTreeNodeTag::BasicLeaf => node.basic.slots().len(),
+            BTreeNodeTag::HashLeaf | BTreeNodeTag::HybridLeaf => node.hash_leaf.slots().len(),
+            _ => node.to_inner().key_count(),
+        }
+    }
+}
+
+/// fences of a basic or hash leaf
+fn leaf_fences(node: &BTreeNode) -> FenceData {
+    match node.tag() {
+        BTreeNodeTag::BasicLeaf => unsafe { node.basic.fences() },
+        BTreeNodeTag::HashLeaf | BTreeNodeTag::HybridLeaf => unsafe { node.hash_leaf.fences() },
+        tag => panic!("not a leaf type: {tag:?}"),
+    }
+}
+
+/// calls `callback` with full key and stored value of every entry of a leaf, hash leaves are not sorted.
+/// The key prefix is restored from `key_in_node`, which may be any key within the leaf's fences.
+fn leaf_for_each(node: &BTreeNode, key_in_node: &[u8], key_buffer: &mut Vec<u8>, callback: &mut dyn FnMut(&[u8], &[u8])) {
+    let prefix_len = leaf_fences(node).prefix_len;
+    key_buffer.clear();
+    key_buffer.extend_from_slice(&key_in_node[..prefix_len]);
+    leaf_for_each_truncated(node, &mut |key, value| {
+        key_buffer.truncate(prefix_len);
+        key_buffer.extend_from_slice(key.0);
+        callback(key_buffer, value);
+    });
+}
+
+/// calls `callback` with prefix truncated key and stored value of every entry of a leaf, hash leaves are not sorted
+fn leaf_for_each_truncated(node: &BTreeNode, callback: &mut dyn FnMut(PrefixTruncatedKey, &[u8])) {
+    if node.tag() == BTreeNodeTag::BasicLeaf {
+        let basic = unsafe { &node.basic };
+        for s in basic.slots() {
+            callback(s.key(basic.as_bytes()), s.value(basic.as_bytes()));
+        }
+    } else {
+        unsafe { node.hash_leaf.for_each(callback) }
+    }
+}
+
+/// bytes the contents of `node` would take up in a basic node: fences, keys, values or child pointers and one slot per key.
+/// Independent of the node type, so a split can be checked for making progress even if the halves are converted.
+fn node_content_bytes(node: &BTreeNode) -> usize {
+    let fences = node_fences(node);
+    let fence_bytes = fences.lower_fence.0.len() + fences.upper_fence.0.len();
+    let slot_size = size_of::<crate::basic_node::BasicSlot>();
+    if node.tag().is_leaf() {
+        let mut entry_bytes = 0;
+        leaf_for_each_truncated(node, &mut |key, value| entry_bytes += key.0.len() + value.len() + slot_size);
+        fence_bytes + entry_bytes
+    } else {
+        let inner = node.to_inner();
+        let count = inner.key_count();
+        fence_bytes + inner.get_key_length_sum(0..count) + count * slot_size + (count + 1) * size_of::<*mut BTreeNode>()
+    }
+}
+
+/// fences of a leaf or inner node
+fn node_fences(node: &BTreeNode) -> FenceData {
+    if node.tag().is_leaf() { leaf_fences(node) } else { node.to_inner().fences() }
+}
+
+/// lower and upper fence of `node` as full keys, an empty upper fence is infinite.
+/// `key_in_node` restores the prefix and may be any key within the fences.
+fn full_fences(node: &BTreeNode, key_in_node: &[u8]) -> (Vec<u8>, Vec<u8>) {
+    let fences = node_fences(node);
+    let prefix = &key_in_node[..fences.prefix_len];
+    let lower = [prefix, fences.lower_fence.to_stripped(fences.prefix_len).0].concat();
+    let upper = if fences.upper_fence.0.is_empty() && fences.prefix_len == 0 {
+        Vec::new()
+    } else {
+        [prefix, fences.upper_fence.to_stripped(fences.prefix_len).0].concat()
+    };
+    (lower, upper)
+}
+
+/// fences for a node holding keys in (lower, upper], given as full keys
+fn fences_between<'a>(lower: &'a [u8], upper: &'a [u8]) -> FenceData<'a> {
+    FenceData { prefix_len: 0, lower_fence: FenceRef(lower), upper_fence: FenceRef(upper) }.restrip()
+}
+
+/// builds a leaf of type `tag` from the entries of `parts` with keys in `range`.
+/// Each part is a leaf and any key within its fences to restore its prefix.
+fn leaf_from_parts(tag: BTreeNodeTag, fences: FenceData, parts: &[(&BTreeNode, &[u8])], range: (Bound<&[u8]>, Bound<&[u8]>)) -> Result<BTreeNode, NodeOpError> {
+    BTreeNode::leaf_from_fn(tag, fences, |basic| {
+        let mut result = Ok(());
+        let mut key_buffer = Vec::new();
+        for &(leaf, key_in_leaf) in parts {
+            leaf_for_each(leaf, key_in_leaf, &mut key_buffer, &mut |key, value| {
+                if result.is_ok() && RangeBounds::<[u8]>::contains(&range, key) {
+                    result = basic.insert(key, value).map(|_| ());
+                }
+            });
+        }
+        result
+    })
+}
+
+/// copy of `node` with new fences, which must share the prefix of the old ones up to the new prefix length.
+/// `key_in_node` restores the old prefix.
+fn refence(node: &BTreeNode, key_in_node: &[u8], fences: FenceData) -> Result<BTreeNode, NodeOpError> {
+    if node.tag().is_leaf() {
+        leaf_from_parts(node.tag(), fences, &[(node, key_in_node)], (Bound::Unbounded, Bound::Unbounded))
+    } else {
+        let inner = node.to_inner();
+        let parts = [ConcatPart::Node { src: inner, prefix: key_in_node, children: 0..inner.key_count() + 1 }];
+        inner_from_parts(node.tag(), fences, &parts, &[])
+    }
+}
+
+/// overwrites `node` with `page`, which inherits the adaption state
+unsafe fn replace_node(node: *mut BTreeNode, mut page: BTreeNode) {
+    *page.adaption_state() = *(*node).adaption_state();
+    if matches!((*node).tag(), BTreeNodeTag::HashLeaf | BTreeNodeTag::HybridLeaf) {
+        ManuallyDrop::drop(&mut (*node).hash_leaf);
+    }
+    ptr::write(node, page);
+}
+
+/// builds an inner node of type `tag`, see `InnerConcatSource`
+fn inner_from_parts(tag: BTreeNodeTag, fences: FenceData, parts: &[ConcatPart], separators: &[&[u8]]) -> Result<BTreeNode, NodeOpError> {
+    let mut page = unsafe { BTreeNode::new_uninit() };
+    create_inner_of(tag, &mut page, &InnerConcatSource::new(fences, parts, separators))?;
+    Ok(page)
+}
+
+/// moves entries or children at the boundary from the neighbor of the underfull child at `index` into it,
+/// about half of the difference in count or more if it is still underfull, and replaces the separator between both.
+/// Fails without modifying anything if the neighbor would become underfull, a node does not fit or `parent` would change its type.
+/// `key` restores the prefix of `parent` and may be any key within its fences.
+pub unsafe fn borrow_from_sibling(parent: *mut BTreeNode, key: &[u8], index: usize) -> Result<(), NodeOpError> {
+    let parent_inner = (*parent).to_inner();
+    let key_count = parent_inner.key_count();
+    if key_count == 0 {
+        return Err(NodeOpError::NotApplicable);
+    }
+    let left_index = index.min(key_count - 1);
+    let (left, right) = (parent_inner.get_child(left_index), parent_inner.get_child(left_index + 1));
+    let from_right = left_index == index;
+    let donor = if from_right { right } else { left };
+    let parent_prefix_len = parent_inner.fences().prefix_len;
+    let mut key_buffer = [0u8; MAX_KEY_LEN];
+    let key_len = parent_inner.get_key(left_index, &mut key_buffer, 0)?;
+    let separator = [&key[..parent_prefix_len], trailing_bytes(&key_buffer, key_len)].concat();
+    let (lower, _) = full_fences(&*left, &separator);
+    let (_, upper) = full_fences(&*right, &separator);
+
+    // candidates for the new separator in the order of moving one more entry or child, the neighbor keeps at least one
+    let mut candidates = Vec::new();
+    if (*donor).tag().is_leaf() {
+        leaf_for_each(&*donor, &separator, &mut Vec::new(), &mut |key, _| candidates.push(key.to_vec()));
+        candidates.sort_unstable();
+        candidates.pop();
+        if !from_right {
+            candidates.reverse();
+        }
+    } else {
+        let donor_inner = (*donor).to_inner();
+        let donor_prefix = &separator[..donor_inner.fences().prefix_len];
+        for i in 0..donor_inner.key_count() {
+            let i = if from_right { i } else { donor_inner.key_count() - 1 - i };
+            let key_len = donor_inner.get_key(i, &mut key_buffer, 0)?;
+            candidates.push([donor_prefix, trailing_bytes(&key_buffer, key_len)].concat());
+        }
+    }
+
+    // start from evening out the entry counts, so the receiver does not become underfull again right away
+    let receiver_count = node_key_count(&*(if from_right { left } else { right }));
+    let even = node_key_count(&*donor).saturating_sub(receiver_count) / 2;
+    for (moved, new_separator) in candidates.iter().enumerate().map(|(i, k)| (i + 1, &k[..])).skip(even.saturating_sub(1)) {
+        let (left_fences, right_fences) = (fences_between(&lower, new_separator), fences_between(new_separator, &upper));
+        let (new_left, new_right) = if (*left).tag().is_leaf() {
+            let parts = [(&*left, &separator[..]), (&*right, &separator[..])];
+            (
+                leaf_from_parts((*left).tag(), left_fences, &parts, (Bound::Unbounded, Bound::Included(new_separator)))?,
+                leaf_from_parts((*right).tag(), right_fences, &parts, (Bound::Excluded(new_separator), Bound::Unbounded))?,
+            )
+        } else {
+            let (left_inner, right_inner) = ((*left).to_inner(), (*right).to_inner());
+            let (left_children, right_children) = (left_inner.key_count() + 1, right_inner.key_count() + 1);
+            let left_part = |children| ConcatPart::Node { src: left_inner, prefix: &separator, children };
+            let right_part = |children| ConcatPart::Node { src: right_inner, prefix: &separator, children };
+            let (left_parts, right_parts) = if from_right {
+                (vec![left_part(0..left_children), right_part(0..moved)], vec![right_part(moved..right_children)])
+            } else {
+                let kept = left_children - moved;
+                (vec![left_part(0..kept)], vec![left_part(kept..left_children), right_part(0..right_children)])
+            };
+            let seam = [&separator[..]];
+            let separators = |parts: &[ConcatPart]| &seam[..parts.len() - 1];
+            (
+                inner_from_parts((*left).tag(), left_fences, &left_parts, separators(&left_parts))?,
+                inner_from_parts((*right).tag(), right_fences, &right_parts, separators(&right_parts))?,
+            )
+        };
+        let (new_receiver, new_donor) = if from_right { (&new_left, &new_right) } else { (&new_right, &new_left) };
+        if new_receiver.is_underfull() {
+            continue;
+        }
+        if new_donor.is_underfull() {
+            return Err(NodeOpError::NotApplicable);
+        }
+        let mut new_parent = BTreeNode::new_uninit();
+        create_inner_of(
+            (*parent).tag(),
+            &mut new_parent,
+            &InnerReplaceKeySource::new(parent_inner, left_index, PrefixTruncatedKey(&new_separator[parent_prefix_len..])),
+        )?;
+        if new_parent.tag() != (*parent).tag() {
+            // callers may hold a reference to parent as its current type
+            return Err(NodeOpError::NotApplicable);
+        }
+        replace_node(left, new_left);
+        replace_node(right, new_right);
+        replace_node(parent, new_parent);
+        debug_assert_children_valid((*parent).to_inner());
+        return Ok(());
+    }
+    Err(NodeOpError::NotApplicable)
+}
+
+/// nodes along the first or last child of each level, from `root` down to a leaf
+fn spine(root: *mut BTreeNode, last: bool) -> Vec<*mut BTreeNode> {
+    let mut spine = vec![root];
+    unsafe {
+        while (**spine.last().unwrap()).tag().is_inner() {
+            let inner = (**spine.last().unwrap()).to_inner();
+            spine.push(inner.get_child(if last { inner.key_count() } else { 0 }));
+        }
+    }
+    spine
+}
+
+/// one side of a node split by `BTree::split_path`, usually a single node.
+/// Holds the new nodes of a level and the full separator keys between them.
+#[derive(Default)]
+struct SplitPart {
+    nodes: Vec<*mut BTreeNode>,
+    separators: Vec<Vec<u8>>,
+    /// nodes allocated for this side on this level and the levels below, freed if the split fails
+    allocated: Vec<*mut BTreeNode>,
+}
+
+impl SplitPart {
+    fn push_node(&mut self, page: BTreeNode, adapt_from: *mut BTreeNode) {
+        unsafe {
+            let node = BTreeNode::alloc();
+            ptr::write(node, page);
+            *(*node).adaption_state() = *(*adapt_from).adaption_state();
+            self.nodes.push(node);
+            self.allocated.push(node);
+        }
+    }
+
+    /// adds the entries of `leaf` in `range` as one leaf or, if they do not fit, as several split at median keys
+    fn push_leaves(&mut self, leaf: &BTreeNode, key_in_leaf: &[u8], lower: &[u8], upper: &[u8], range: (Bound<&[u8]>, Bound<&[u8]>)) -> Result<(), NodeOpError> {
+        let parts = [(leaf, key_in_leaf)];
+        if let Ok(page) = leaf_from_parts(leaf.tag(), fences_between(lower, upper), &parts, range) {
+            self.push_node(page, leaf as *const BTreeNode as *mut BTreeNode);
+            return Ok(());
+        }
+        let mut keys = Vec::new();
+        leaf_for_each(leaf, key_in_leaf, &mut Vec::new(), &mut |key, _| {
+            if RangeBounds::<[u8]>::contains(&range, key) {
+                keys.push(key.to_vec());
+            }
+        });
+        if keys.len() < 2 {
+            return Err(NodeOpError::NoSpace);
+        }
+        keys.sort_unstable();
+        let median = &keys[(keys.len() - 1) / 2];
+        self.push_leaves(leaf, key_in_leaf, lower, median, (range.0, Bound::Included(median)))?;
+        self.separators.push(median.clone());
+        self.push_leaves(leaf, key_in_leaf, median, upper, (Bound::Excluded(median), range.1))
+    }
+
+    /// adds an inner node of the type of `like` holding `parts` or, if it does not fit, several holding part of the children each
+    fn push_inner(&mut self, like: &BTreeNode, lower: &[u8], upper: &[u8], parts: &[ConcatPart], separators: &[&[u8]]) -> Result<(), NodeOpError> {
+        let fences = fences_between(lower, upper);
+        if let Ok(page) = inner_from_parts(like.tag(), fences, parts, separators) {
+            self.push_node(page, like as *const BTreeNode as *mut BTreeNode);
+            return Ok(());
+        }
+        let source = InnerConcatSource::new(fences, parts, separators);
+        if source.key_count() == 0 {
+            return Err(NodeOpError::NoSpace);
+        }
+        let split_index = source.key_count() / 2;
+        let mut key_buffer = [0u8; MAX_KEY_LEN];
+        let key_len = source.get_key(split_index, &mut key_buffer, 0)?;
+        let median = [&lower[..fences.prefix_len], trailing_bytes(&key_buffer, key_len)].concat();
+        let (left, right) = split_parts(parts, separators, split_index + 1);
+        self.push_inner(like, lower, &median, &left.0, &left.1)?;
+        self.separators.push(median.clone());
+        self.push_inner(like, &median, upper, &right.0, &right.1)
+    }
+
+    /// adds the nodes as children to a part of the level above
+    fn append_to<'a>(&'a self, parts: &mut Vec<ConcatPart<'a>>, separators: &mut Vec<&'a [u8]>) {
+        for (i, &node) in self.nodes.iter().enumerate() {
+            if i > 0 {
+                separators.push(&self.separators[i - 1]);
+            }
+            parts.push(ConcatPart::Child(node));
+        }
+    }
+
+    /// frees all allocated nodes, but not the reused subtrees below them
+    fn free(self) {
+        for node in self.allocated {
+            unsafe {
+                if matches!((*node).tag(), BTreeNodeTag::HashLeaf | BTreeNodeTag::HybridLeaf) {
+                    ManuallyDrop::drop(&mut (*node).hash_leaf);
+                }
+                BTreeNode::dealloc(node);
+            }
+        }
+    }
+
+    /// the root of a tree holding the nodes, a new one if there are several
+    fn into_root(&mut self, inner_tag: Option<BTreeNodeTag>) -> Result<*mut BTreeNode, NodeOpError> {
+        let last = *self.nodes.last().unwrap();
+        if self.nodes.len() == 1 {
+            return Ok(last);
+        }
+        unsafe {
+            let root = BTreeNode::new_inner_of(inner_tag, last);
+            self.allocated.push(root);
+            let root_inner = (*root).to_inner_mut();
+            for (separator, &child) in self.separators.iter().zip(&self.nodes).rev() {
+                let prefix_len = root_inner.request_space_for_child(separator.len())?;
+                root_inner.insert_child(0, PrefixTruncatedKey(&separator[prefix_len..]), child)?;
+            }
+            Ok(root)
+        }
+    }
+}
+
+/// splits a concatenation of children before child `split_child`, the key between both halves is dropped
+fn split_parts<'a>(parts: &[ConcatPart<'a>], separators: &[&'a [u8]], split_child: usize) -> ((Vec<ConcatPart<'a>>, Vec<&'a [u8]>), (Vec<ConcatPart<'a>>, Vec<&'a [u8]>)) {
+    let (mut left, mut right) = ((Vec::new(), Vec::new()), (Vec::new(), Vec::new()));
+    let mut start = 0;
+    for (i, part) in parts.iter().enumerate() {
+        let child_count = match part {
+            ConcatPart::Node { children, .. } => children.len(),
+            ConcatPart::Child(_) => 1,
+        };
+        let end = start + child_count;
+        if end <= split_child {
+            left.0.push(part.clone());
+            if end < split_child {
+                left.1.push(separators[i]);
+            }
+        } else {
+            if start < split_child {
+                let ConcatPart::Node { src, prefix, children } = part else { unreachable!() };
+                let mid = children.start + (split_child - start);
+                left.0.push(ConcatPart::Node { src: *src, prefix, children: children.start..mid });
+                right.0.push(ConcatPart::Node { src: *src, prefix, children: mid..children.end });
+            } else {
+                right.0.push(part.clone());
+            }
+            if i + 1 < parts.len() {
+                right.1.push(separators[i]);
+            }
+        }
+        start = end;
+    }
+    (left, right)
+}
+
+/// store values out of line so pointers returned by `lookup` survive modification of other keys
+#[cfg(feature = "value-arena_true")]
+const VALUE_ARENA: bool = true;
+#[cfg(feature = "value-arena_false")]
+const VALUE_ARENA: bool = false;
+
+/// leaf found by the last cached descent, only valid while `generation` matches the tree's generation
+struct LeafCache {
+    leaf: *mut BTreeNode,
+    generation: u64,
+    /// the key used to find `leaf`, restores the prefix of stripped fences
+    key: Vec<u8>,
+}
+
+impl LeafCache {
+    fn new() -> Self {
+        LeafCache { leaf: ptr::null_mut(), generation: 0, key: Vec::new() }
+    }
+}
+
+static NEXT_TREE_ID: AtomicU64 = AtomicU64::new(0);
+
+/// position of an entry that survives modification of the tree, see `BTree::cursor`.
+/// The slot is only read directly while neither the tree's nor the leaf's generation changed, otherwise the cursor descends again.
+/// A cursor used with a tree other than the one it was created from always descends again.
+pub struct Cursor {
+    key: Vec<u8>,
+    leaf: *mut BTreeNode,
+    slot: usize,
+    tree_id: u64,
+    tree_generation: u64,
+    leaf_generation: u32,
+}
+
+impl Cursor {
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+}
+
+/// ascending scans continue after a separator by appending a zero byte, which may follow a key of `MAX_KEY_LEN` bytes
+const RANGE_START_BUFFER_LEN: usize = MAX_KEY_LEN + 1;
+
+/// route of a key from the root to its leaf, see `BTree::debug_find_key_path`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyPath {
+    /// type of each inner node passed and the index of the child taken
+    pub inner: Vec<(BTreeNodeTag, usize)>,
+    pub leaf_tag: BTreeNodeTag,
+    /// slot holding the key, `None` if the leaf does not contain it
+    pub slot: Option<usize>,
+}
+
+impl KeyPath {
+    pub fn found(&self) -> bool {
+        self.slot.is_some()
+    }
+}
+
+/// All nodes reachable from `root` are owned exclusively by this tree, so moving it to another thread is fine.
+/// Operations still use the process global adaption rng `RAND`, so trees must not be operated on from multiple threads concurrently.
+/// The vtables are only written once by `ensure_init` before any tree exists.
+/// `BTree` is deliberately not `Sync`, even lookups mutate the tree.
+unsafe impl Send for BTree {}
+
+impl Drop for BTree {
+    fn drop(&mut self) {
+        /// frees the subtree below `node` before `node` itself, so children are read from a live page
+        unsafe fn free_subtree(node: *mut BTreeNode) {
+            if (*node).tag().is_inner() {
+                let inner = (*node).to_inner();
+                for i in 0..inner.key_count() + 1 {
+                    free_subtree(inner.get_child(i));
+                }
+            } else if matches!((*node).tag(), BTreeNodeTag::HashLeaf | BTreeNodeTag::HybridLeaf) {
+                ManuallyDrop::drop(&mut (*node).hash_leaf);
+            }
+            BTreeNode::dealloc(node);
+        }
+        unsafe { free_subtree(self.root) }
+    }
 }
 
 impl BTree {
@@ -18,33 +548,136 @@ impl BTree {
         BTree {
             root: BTreeNode::new_leaf(),
             branch_cache: BranchCacheAccessor::new(),
+            inner_tag: None,
+            id: NEXT_TREE_ID.fetch_add(1, Ordering::Relaxed),
+            generation: 0,
+            leaf_cache: LeafCache::new(),
+            depth_profile: Vec::new(),
+            value_arena: ValueArena::new(),
+            key_length_bounds: (usize::MAX, 0),
+            count: 0,
+            structural_ops: StructuralOps::default(),
         }
     }
 
-    #[tracing::instrument(skip(self))]
-    pub fn insert(&mut self, key: &[u8], payload: &[u8]) {
+    /// overrides the configured node types.
+    /// `inner` is used for new roots, inner nodes split in place keep their type or use `DefaultInnerNodeConversionSink` for basic nodes.
+    pub fn new_with(leaf: BTreeNodeTag, inner: BTreeNodeTag) -> Self {
         count_op();
-        assert!((key.len() + payload.len()) as usize <= PAGE_SIZE / 4);
+        assert!(leaf.is_leaf() && inner.is_inner());
+        BTree {
+            root: BTreeNode::new_leaf_of(leaf),
+            branch_cache: BranchCacheAccessor::new(),
+            inner_tag: Some(inner),
+            id: NEXT_TREE_ID.fetch_add(1, Ordering::Relaxed),
+            generation: 0,
+            leaf_cache: LeafCache::new(),
+            depth_profile: Vec::new(),
+            value_arena: ValueArena::new(),
+            key_length_bounds: (usize::MAX, 0),
+            count: 0,
+            structural_ops: StructuralOps::default(),
+        }
+    }
+
+    #[tracing::instrument(skip(self, key, payload), fields(key = ?key.as_ref(), payload = ?payload.as_ref()))]
+    /// fails if key and payload together are longer than `MAX_KEY_LEN` or a node needs a split that can not make it smaller,
+    /// the contents are unchanged then
+    ///
+    /// ```
+    /// use btree::b_tree::BTree;
+    ///
+    /// btree::ensure_init();
+    /// let mut tree = BTree::new();
+    /// tree.insert("apple", String::from("red")).unwrap();
+    /// tree.insert(b"banana", vec![1, 2, 3]).unwrap();
+    /// assert_eq!(tree.get("apple"), Some(&b"red"[..]));
+    /// assert_eq!(tree.get(String::from("banana")), Some(&[1, 2, 3][..]));
+    /// assert!(unsafe { tree.remove("apple") });
+    /// assert_eq!(tree.get("apple"), None);
+    /// ```
+    pub fn insert(&mut self, key: impl AsRef<[u8]>, payload: impl AsRef<[u8]>) -> Result<(), NodeOpError> {
+        let (key, payload) = (key.as_ref(), payload.as_ref());
+        count_op();
+        let result = if VALUE_ARENA {
+            self.insert_arena_value(key, payload)
+        } else {
+            self.insert_in_leaf(key, payload).map(|_| ())
+        };
+        // only keys that made it into the tree count
+        if result.is_ok() {
+            self.key_length_bounds = (self.key_length_bounds.0.min(key.len()), self.key_length_bounds.1.max(key.len()));
+        }
+        result
+    }
+
+    /// the leaf holds a reference to the value, which is only stored once the leaf insert succeeded
+    fn insert_arena_value(&mut self, key: &[u8], payload: &[u8]) -> Result<(), NodeOpError> {
         unsafe {
-            let (node, parent, pos) = (&mut *self.root).descend(key, |_| false, &mut self.branch_cache);
-            let node = &mut *node;
-            node.leave_notify_point_op();
-            if node.to_leaf_mut().insert(key, payload).is_ok() {
-                return;
+            if let Some(reference) = self.arena_reference(key) {
+                if value_arena::resolve(&*reference) == payload {
+                    // value unchanged, avoid rewriting
+                    return Ok(());
+                }
+                let replaced = self.value_arena.replace(&*reference, payload);
+                (*reference).copy_from_slice(&replaced);
+                return Ok(());
             }
-            self.split_node(node, parent, key, pos);
-            self.insert(key, payload);
+            // the reference has a fixed size, so the placeholder is overwritten in place
+            self.insert_in_leaf(key, &[0u8; value_arena::ARENA_REF_SIZE])?;
+            let stored = self.value_arena.store(payload);
+            (*self.arena_reference(key).unwrap()).copy_from_slice(&stored);
         }
+        Ok(())
     }
 
-    #[tracing::instrument(skip(self))]
-    pub unsafe fn lookup(&mut self, payload_len_out: *mut u64, key: &[u8]) -> *mut u8 {
+    /// the arena reference stored for key, valid until the leaf holding it is modified
+    unsafe fn arena_reference(&mut self, key: &[u8]) -> Option<*mut [u8]> {
+        let (node, _, _) = (*self.root).descend(key, |_| false, &mut self.branch_cache);
+        (*node).to_leaf_mut().lookup_mut(key).map(|reference| reference as *mut [u8])
+    }
+
+    /// inserts `payload` as it is stored in the leaf, returns whether the key is new
+    fn insert_in_leaf(&mut self, key: &[u8], payload: &[u8]) -> Result<bool, NodeOpError> {
+        if key.len() + payload.len() > MAX_KEY_LEN {
+            return Err(NodeOpError::TooLarge);
+        }
+        for attempt in 0.. {
+            // each failed attempt splits a node on the path, so this is bounded by tree height
+            debug_assert!(attempt <= MAX_TREE_HEIGHT, "insert did not succeed after {attempt} splits, key: {key:?}");
+            unsafe {
+                let (node, parent, pos) = (&mut *self.root).descend(key, |_| false, &mut self.branch_cache);
+                let node = &mut *node;
+                node.leave_notify_point_op();
+                if let Ok(created) = node.to_leaf_mut().insert(key, payload) {
+                    node.head_mut().touch();
+                    self.count += created as usize;
+                    return Ok(created);
+                }
+                self.split_node(node, parent, key, pos)?;
+            }
+        }
+        unreachable!()
+    }
+
+    /// payload of key through a shared reference.
+    /// Unlike `lookup`, this bypasses the leaf and branch caches and never adapts nodes.
+    pub fn get(&self, key: impl AsRef<[u8]>) -> Option<&[u8]> {
+        let key = key.as_ref();
+        let leaf = unsafe { (*self.root).find_leaf(key) };
+        let data = leaf.to_leaf().lookup(key)?;
+        Some(if VALUE_ARENA { unsafe { &*value_arena::resolve(data) } } else { data })
+    }
+
+    #[tracing::instrument(skip(self, key), fields(key = ?key.as_ref()))]
+    pub unsafe fn lookup(&mut self, payload_len_out: *mut u64, key: impl AsRef<[u8]>) -> *mut u8 {
+        let key = key.as_ref();
         count_op();
         tracing::info!("lookup {key:?}");
-        let (node, _, _) = (*self.root).descend(key, |_| false, &mut self.branch_cache);
-        let node = &mut *node;
+        let node = &mut *self.get_or_descend_cached(key);
         node.leave_notify_point_op();
-        if let Some(data) = node.to_leaf_mut().lookup(key) {
+        if let Some(data) = node.to_leaf_mut().lookup_mut(key) {
+            let data = if VALUE_ARENA { value_arena::resolve(data) } else { data };
             ptr::write(payload_len_out, data.len() as u64);
             data.as_mut_ptr()
         } else {
@@ -52,6 +685,712 @@ impl BTree {
         }
     }
 
+    /// cursor at the entry for `key`, `None` if there is none
+    pub fn cursor(&mut self, key: impl AsRef<[u8]>) -> Option<Cursor> {
+        let key = key.as_ref();
+        let (leaf, _, _) = unsafe { (*self.root).descend(key, |_| false, &mut self.branch_cache) };
+        let leaf_ref = unsafe { &*leaf };
+        let slot = leaf_ref.to_leaf().lookup_slot(key)?;
+        Some(Cursor {
+            key: key.to_vec(),
+            leaf,
+            slot,
+            tree_id: self.id,
+            tree_generation: self.generation,
+            leaf_generation: leaf_ref.leaf_generation(),
+        })
+    }
+
+    /// true if `cursor` can read its slot without descending.
+    /// Always false without leaf generations, as moved slots could not be detected.
+    pub fn cursor_is_current(&self, cursor: &Cursor) -> bool {
+        // the leaf may belong to another tree or have been freed if the tree id or generation changed, so they must be checked first
+        LEAF_GENERATION
+            && cursor.tree_id == self.id
+            && cursor.tree_generation == self.generation
+            && unsafe { (*cursor.leaf).leaf_generation() } == cursor.leaf_generation
+    }
+
+    /// value of the entry at `cursor`, repositioning it if the tree changed since it was created.
+    /// Returns `None` if the entry was removed.
+    pub fn cursor_value(&mut self, cursor: &mut Cursor) -> Option<&[u8]> {
+        if !self.cursor_is_current(cursor) {
+            *cursor = self.cursor(&cursor.key)?;
+        }
+        let value = unsafe { (*cursor.leaf).to_leaf().value_at(cursor.slot) };
+        Some(if VALUE_ARENA { unsafe { &*value_arena::resolve(value) } } else { value })
+    }
+
+    /// child indices taken while descending to the leaf responsible for `key`, for diagnosing misrouted lookups.
+    /// Uses its own branch cache and does not adapt nodes, so the tree is left as it was.
+    pub fn debug_find_key_path(&mut self, key: impl AsRef<[u8]>) -> KeyPath {
+        let key = key.as_ref();
+        let mut bc = BranchCacheAccessor::new();
+        bc.reset();
+        let mut inner = Vec::new();
+        let mut node = unsafe { &mut *self.root };
+        while node.tag().is_inner() {
+            debug_assert!(inner.len() < MAX_TREE_HEIGHT, "descent exceeded {MAX_TREE_HEIGHT} levels, key: {key:?}");
+            let index = node.to_inner_mut().find_child_index(key, &mut bc);
+            inner.push((node.tag(), index));
+            node = unsafe { &mut *node.to_inner().get_child(index) };
+        }
+        KeyPath { inner, leaf_tag: node.tag(), slot: node.to_leaf().lookup_slot(key) }
+    }
+
+    /// returns the leaf responsible for key.
+    /// If leaf caching is enabled, the descent is skipped if the leaf found by the previous call is still valid and its fences contain key.
+    pub fn get_or_descend_cached(&mut self, key: &[u8]) -> *mut BTreeNode {
+        if LEAF_CACHE && self.leaf_cache.generation == self.generation && !self.leaf_cache.leaf.is_null() {
+            let leaf = unsafe { &*self.leaf_cache.leaf };
+            if leaf_fences(leaf).contains(key, &self.leaf_cache.key) {
+                self.record_depth(0);
+                return self.leaf_cache.leaf;
+            }
+        }
+        let mut inner_visited = 0;
+        let (node, _, _) = unsafe {
+            (*self.root).descend(
+                key,
+                |_| {
+                    inner_visited += 1;
+                    false
+                },
+                &mut self.branch_cache,
+            )
+        };
+        self.record_depth(inner_visited + 1);
+        if LEAF_CACHE {
+            self.leaf_cache.leaf = node;
+            self.leaf_cache.generation = self.generation;
+            self.leaf_cache.key.clear();
+            self.leaf_cache.key.extend_from_slice(key);
+        }
+        node
+    }
+
+    fn record_depth(&mut self, depth: usize) {
+        if DEPTH_PROFILE {
+            if self.depth_profile.len() <= depth {
+                self.depth_profile.resize(depth + 1, 0);
+            }
+            self.depth_profile[depth] += 1;
+        }
+    }
+
+    /// number of lookups indexed by the number of nodes visited, lookups served by the leaf cache are counted at depth 0.
+    /// Empty unless depth profiling is enabled.
+    pub fn depth_profile(&self) -> &[u64] {
+        &self.depth_profile
+    }
+
+    pub fn reset_depth_profile(&mut self) {
+        self.depth_profile.clear();
+    }
+
+    /// average number of nodes visited per lookup, `NaN` if no lookups were recorded
+    pub fn average_lookup_depth(&self) -> f64 {
+        let count: u64 = self.depth_profile.iter().sum();
+        let total: u64 = self.depth_profile.iter().enumerate().map(|(depth, &n)| depth as u64 * n).sum();
+        total as f64 / count as f64
+    }
+
+    /// splits, merges and borrows performed on this tree
+    pub fn structural_ops(&self) -> StructuralOps {
+        self.structural_ops
+    }
+
+    /// number of levels including the leaf level
+    pub fn height(&self) -> usize {
+        let mut height = 1;
+        let mut node = self.root;
+        unsafe {
+            while (*node).tag().is_inner() {
+                node = (*node).to_inner().get_child(0);
+                height += 1;
+            }
+        }
+        height
+    }
+
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    /// number of entries, counted by visiting every leaf
+    fn count_entries(&self) -> usize {
+        fn visit(node: *mut BTreeNode) -> usize {
+            let node = unsafe { &*node };
+            match node.tag() {
+                BTreeNodeTag::BasicLeaf => unsafe { node.basic.slots().len() },
+                BTreeNodeTag::HashLeaf | BTreeNodeTag::HybridLeaf => unsafe { node.hash_leaf.live_count() },
+                _ => {
+                    let inner = node.to_inner();
+                    (0..inner.key_count() + 1).map(|i| visit(inner.get_child(i))).sum()
+                }
+            }
+        }
+        visit(self.root)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// type of the root node, a leaf type while the tree consists of a single node
+    pub fn root_tag(&self) -> BTreeNodeTag {
+        unsafe { (*self.root).tag() }
+    }
+
+    /// outcomes of node space requests, counted across all trees
+    pub fn fragmentation_stats(&self) -> FragmentationStats {
+        node_stats::fragmentation_stats()
+    }
+
+    /// shortest and longest key length ever inserted, `(0, 0)` if nothing was inserted
+    pub fn key_length_bounds(&self) -> (usize, usize) {
+        let (min, max) = self.key_length_bounds;
+        (min.min(max), max)
+    }
+
+    /// checks that all leaves are at the same depth
+    pub fn verify_balanced(&self) -> Result<(), ()> {
+        fn leaf_depth(node: *mut BTreeNode, depth: usize, expected: &mut Option<usize>) -> Result<(), ()> {
+            let node = unsafe { &*node };
+            if node.tag().is_leaf() {
+                return match *expected {
+                    None => {
+                        *expected = Some(depth);
+                        Ok(())
+                    }
+                    Some(expected) if expected == depth => Ok(()),
+                    Some(_) => Err(()),
+                };
+            }
+            let inner = node.to_inner();
+            for i in 0..inner.key_count() + 1 {
+                leaf_depth(inner.get_child(i), depth + 1, expected)?;
+            }
+            Ok(())
+        }
+        leaf_depth(self.root, 0, &mut None)
+    }
+
+    /// checks the `space_used` bookkeeping of every basic and hash node, other node types have no such field
+    pub fn check_space_accounting(&self) -> Result<(), ()> {
+        fn check(node: *mut BTreeNode) -> Result<(), ()> {
+            let node = unsafe { &*node };
+            match node.tag() {
+                BTreeNodeTag::BasicLeaf | BTreeNodeTag::BasicInner => unsafe { node.basic.check_space_accounting()? },
+                BTreeNodeTag::HashLeaf | BTreeNodeTag::HybridLeaf => unsafe { node.hash_leaf.check_space_accounting()? },
+                _ => {}
+            }
+            if node.tag().is_inner() {
+                let inner = node.to_inner();
+                for i in 0..inner.key_count() + 1 {
+                    check(inner.get_child(i))?;
+                }
+            }
+            Ok(())
+        }
+        check(self.root)
+    }
+
+    /// checks that every node is referenced exactly once, so the nodes form a tree rather than a DAG or a cycle.
+    /// Unlike `validate_tree`, this does not dereference a pointer a second time, but freed nodes can only be detected once reused.
+    pub fn audit_pointers(&self) -> Result<(), ()> {
+        fn visit(node: *mut BTreeNode, seen: &mut HashSet<*mut BTreeNode>) -> Result<(), ()> {
+            if node.is_null() || !seen.insert(node) {
+                return Err(());
+            }
+            let node = unsafe { &*node };
+            if node.tag().is_inner() {
+                let inner = node.to_inner();
+                for i in 0..inner.key_count() + 1 {
+                    visit(inner.get_child(i), seen)?;
+                }
+            }
+            Ok(())
+        }
+        visit(self.root, &mut HashSet::new())
+    }
+
+    /// calls `callback` with key and value of every entry in no particular order.
+    /// Hash leaves are visited in slot order without sorting them, use `iter` or `range_lookup` if order matters.
+    pub fn for_each_unordered(&self, callback: &mut dyn FnMut(&[u8], &[u8])) {
+        // the key prefix of a leaf is restored from its fences
+        assert!(!STRIP_PREFIX, "for_each_unordered requires unstripped fences");
+        fn visit(node: *mut BTreeNode, key_buffer: &mut Vec<u8>, callback: &mut dyn FnMut(&[u8], &[u8])) {
+            let node = unsafe { &*node };
+            if node.tag().is_leaf() {
+                let fences = leaf_fences(node);
+                let fence = if fences.lower_fence.0.is_empty() { fences.upper_fence.0 } else { fences.lower_fence.0 };
+                leaf_for_each(node, fence, key_buffer, &mut |key, value| {
+                    let value = if VALUE_ARENA { unsafe { &*value_arena::resolve(value) } } else { value };
+                    callback(key, value)
+                });
+            } else {
+                let inner = node.to_inner();
+                for i in 0..inner.key_count() + 1 {
+                    visit(inner.get_child(i), key_buffer, callback);
+                }
+            }
+        }
+        visit(self.root, &mut Vec::new(), callback)
+    }
+
+    /// uniform random sample of `n` keys in unspecified order, all keys if the tree holds at most `n`.
+    /// Reservoir samples during a single pass over the leaves.
+    pub fn sample_keys(&self, n: usize, rng: &mut impl Rng) -> Vec<Vec<u8>> {
+        let mut sample = Vec::new();
+        let mut seen = 0usize;
+        self.for_each_unordered(&mut |key, _| {
+            if sample.len() < n {
+                sample.push(key.to_vec());
+            } else {
+                let index = rng.gen_range(0..=seen);
+                if index < n {
+                    sample[index] = key.to_vec();
+                }
+            }
+            seen += 1;
+        });
+        sample
+    }
+
+    pub fn iter(&mut self) -> Iter {
+        Iter::new(self)
+    }
+
+    /// ascending entries from the first key >= `key`
+    pub fn iter_from(&mut self, key: impl AsRef<[u8]>) -> Iter {
+        Iter::starting_at(self, key.as_ref())
+    }
+
+    /// descending entries from the last key <= `key`
+    pub fn iter_from_back(&mut self, key: impl AsRef<[u8]>) -> Iter {
+        Iter::starting_at_back(self, key.as_ref())
+    }
+
+    pub fn keys(&mut self) -> Keys {
+        Keys::new(self)
+    }
+
+    pub fn group_by_prefix(&mut self, prefix_len: usize) -> GroupByPrefix {
+        GroupByPrefix::new(self, prefix_len)
+    }
+
+    /// keys present in only one of the trees or with different payloads, in ascending order
+    pub fn symmetric_difference<'a>(&'a mut self, other: &'a mut BTree) -> SymmetricDifference<'a, 'a> {
+        SymmetricDifference::new(self, other)
+    }
+
+    /// true if both trees contain the same key value pairs, regardless of node layout
+    pub fn contents_eq(&mut self, other: &mut BTree) -> bool {
+        self.iter().eq(other.iter())
+    }
+
+    /// hash of all key value pairs in ascending order, equal for trees with equal contents regardless of node layout or configuration
+    pub fn content_checksum(&mut self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        for entry in self.iter() {
+            entry.hash(&mut hasher);
+        }
+        hasher.finish()
+    }
+
+    /// true if any key starts with `prefix`
+    pub fn contains_prefix(&mut self, prefix: impl AsRef<[u8]>) -> bool {
+        let prefix = prefix.as_ref();
+        let mut found = false;
+        // the first key >= prefix is the smallest key that can start with prefix
+        self.range_lookup(prefix, &mut |key, _| {
+            found = key.starts_with(prefix);
+            false
+        });
+        found
+    }
+
+    /// largest key in the tree
+    pub fn last_key(&mut self) -> Option<Vec<u8>> {
+        // no key can be larger than this
+        let start = [u8::MAX; PAGE_SIZE / 4];
+        let mut last = None;
+        self.range_lookup_desc(&start, &mut |key, _| {
+            last = Some(key.to_vec());
+            false
+        });
+        last
+    }
+
+    /// moves all entries of `other` into self if all keys of `other` are greater than those in self.
+    /// Otherwise, or if an entry can not be reinserted, `other` is returned with all its entries, see `MergeError`.
+    /// The root of the shorter tree is grafted into the taller one, or both roots below a new one, so only the nodes along the seam are rebuilt.
+    /// Entries are reinserted if no separator fits between the keys along the seam or a seam node can not hold the new fences.
+    pub fn merge(&mut self, mut other: BTree) -> Result<(), MergeError> {
+        let Some(other_first) = other.keys().next() else {
+            return Ok(());
+        };
+        let Some(self_last) = self.last_key() else {
+            mem::swap(&mut self.root, &mut other.root);
+            mem::swap(&mut self.value_arena, &mut other.value_arena);
+            self.key_length_bounds = other.key_length_bounds;
+            (self.count, other.count) = (other.count, 0);
+            self.generation += 1;
+            return Ok(());
+        };
+        if self_last >= other_first {
+            return Err(MergeError::Overlap(other));
+        }
+        if unsafe { self.graft(&mut other) }.is_ok() {
+            return Ok(());
+        }
+        // a failed graft leaves the contents of other untouched, its arena still owns the values read here
+        let mut inserted = Vec::new();
+        let mut result = Ok(());
+        other.for_each_in_range(.., &mut |key, value| {
+            if result.is_ok() {
+                result = self.insert(key, value);
+                if result.is_ok() {
+                    inserted.push(key.to_vec());
+                }
+            }
+        });
+        if let Err(e) = result {
+            for key in &inserted {
+                unsafe { self.remove(key) };
+            }
+            return Err(MergeError::Insert(e, other));
+        }
+        Ok(())
+    }
+
+    /// joins the trees below a common node, all keys and separators of `other` must be greater than those of self.
+    /// The nodes along the right spine of self and the left spine of `other` get the separator between the trees as a fence,
+    /// the seam is rebalanced afterwards.
+    /// Fails before changing the contents of either tree if no such separator exists or a seam node does not fit its new fences.
+    unsafe fn graft(&mut self, other: &mut BTree) -> Result<(), NodeOpError> {
+        let mut key_buffer = [0u8; MAX_KEY_LEN];
+        for attempt in 0.. {
+            // each failed attempt splits the node receiving the grafted root, so this is bounded by tree height
+            debug_assert!(attempt <= MAX_TREE_HEIGHT, "graft did not succeed after {attempt} splits");
+            let (lower_spine, upper_spine) = (spine(self.root, true), spine(other.root, false));
+            // spine nodes have an infinite fence on the seam side, so their prefix is empty and keys are stored in full
+            let mut lo = self.last_key().unwrap();
+            // inner nodes left with a single child by merges have no separators
+            for &node in &lower_spine[..lower_spine.len() - 1] {
+                let inner = (*node).to_inner();
+                if inner.key_count() > 0 {
+                    let key_len = inner.get_key(inner.key_count() - 1, &mut key_buffer, 0)?;
+                    lo = lo.max(trailing_bytes(&key_buffer, key_len).to_vec());
+                }
+            }
+            let mut hi = other.keys().next().unwrap();
+            for &node in &upper_spine[..upper_spine.len() - 1] {
+                let inner = (*node).to_inner();
+                if inner.key_count() > 0 {
+                    let key_len = inner.get_key(0, &mut key_buffer, 0)?;
+                    hi = hi.min(trailing_bytes(&key_buffer, key_len).to_vec());
+                }
+            }
+            // the shortest key in (lo, hi), lo is exceeded so the key's path follows the right spine of self
+            let common = common_prefix_len(&lo, &hi);
+            let separator = if hi.len() > common + 1 {
+                hi[..common + 1].to_vec()
+            } else {
+                [&lo[..], &[0]].concat()
+            };
+            if separator >= hi || separator.len() > MAX_KEY_LEN {
+                return Err(NodeOpError::NotApplicable);
+            }
+            let above_separator = [&separator[..], &[0]].concat();
+
+            let (lower_height, upper_height) = (lower_spine.len(), upper_spine.len());
+            let mut rebuilt = Vec::new();
+            for &node in &lower_spine[lower_height.saturating_sub(upper_height)..] {
+                let fences = node_fences(&*node);
+                rebuilt.push((node, refence(&*node, &[], fences_between(fences.lower_fence.0, &separator))?));
+            }
+            for &node in &upper_spine[upper_height.saturating_sub(lower_height)..] {
+                let fences = node_fences(&*node);
+                rebuilt.push((node, refence(&*node, &[], fences_between(&separator, fences.upper_fence.0))?));
+            }
+            let separators = [&separator[..]];
+            if lower_height > upper_height {
+                let target = lower_spine[lower_height - upper_height - 1];
+                let inner = (*target).to_inner();
+                let parts = [ConcatPart::Node { src: inner, prefix: &[], children: 0..inner.key_count() + 1 }, ConcatPart::Child(other.root)];
+                match inner_from_parts((*target).tag(), inner.fences(), &parts, &separators) {
+                    Ok(page) => rebuilt.push((target, page)),
+                    Err(_) => {
+                        let (_, parent, index) = (*self.root).descend_to_node(&separator, target, &mut self.branch_cache);
+                        self.split_node(target, parent, &separator, index)?;
+                        continue;
+                    }
+                }
+            } else if lower_height < upper_height {
+                let target = upper_spine[upper_height - lower_height - 1];
+                let inner = (*target).to_inner();
+                let parts = [ConcatPart::Child(self.root), ConcatPart::Node { src: inner, prefix: &[], children: 0..inner.key_count() + 1 }];
+                match inner_from_parts((*target).tag(), inner.fences(), &parts, &separators) {
+                    Ok(page) => rebuilt.push((target, page)),
+                    Err(_) => {
+                        let (_, parent, index) = (*other.root).descend_to_node(&above_separator, target, &mut other.branch_cache);
+                        other.split_node(target, parent, &above_separator, index)?;
+                        continue;
+                    }
+                }
+            }
+
+            // nothing below fails, the trees are joined
+            for (node, page) in rebuilt {
+                replace_node(node, page);
+            }
+            if lower_height == upper_height {
+                let root = BTreeNode::new_inner_of(self.inner_tag, other.root);
+                let root_inner = (*root).to_inner_mut();
+                let prefix_len = root_inner.request_space_for_child(separator.len()).unwrap();
+                root_inner.insert_child(0, PrefixTruncatedKey(&separator[prefix_len..]), self.root).unwrap();
+                self.root = root;
+            } else if lower_height < upper_height {
+                self.root = other.root;
+            }
+            other.root = BTreeNode::new_leaf();
+            self.count += mem::take(&mut other.count);
+            self.value_arena.append(&mut other.value_arena);
+            self.key_length_bounds = (self.key_length_bounds.0.min(other.key_length_bounds.0), self.key_length_bounds.1.max(other.key_length_bounds.1));
+            self.generation += 1;
+            other.generation += 1;
+            self.validate();
+
+            // nodes below the joining node may have become underfull when their sibling moved into the other tree
+            for level in 0..lower_height.min(upper_height) {
+                for key in [&separator[..], &above_separator[..]] {
+                    if let Some((node, parent, index)) = self.node_at_level(key, level) {
+                        if (*node).is_underfull() {
+                            self.rebalance(key, node, parent, index);
+                        }
+                    }
+                }
+            }
+            return Ok(());
+        }
+        unreachable!()
+    }
+
+    /// node at `level` above the leaves on the path of `key` with its parent and index in the parent, `None` if the tree is not that high
+    unsafe fn node_at_level(&mut self, key: &[u8], level: usize) -> Option<(*mut BTreeNode, *mut BTreeNode, usize)> {
+        let height = self.height();
+        if level >= height {
+            return None;
+        }
+        let (mut node, mut parent, mut index) = (self.root, ptr::null_mut(), 0);
+        for _ in 0..height - 1 - level {
+            let inner = (*node).to_inner();
+            parent = node;
+            index = inner.lookup_child_index(key);
+            node = inner.get_child(index);
+        }
+        Some((node, parent, index))
+    }
+
+    /// moves all entries with keys >= `key` into a new tree using the same inner node type.
+    /// The nodes on the path of `key` are split into a lower and an upper part, all other subtrees are moved as a whole.
+    /// Entries are moved one by one if the parts of the path do not fit below a single new root.
+    /// Fails without changing self if one of them can not be inserted into the new tree.
+    pub fn split_off(&mut self, key: impl AsRef<[u8]>) -> Result<BTree, NodeOpError> {
+        let key = key.as_ref();
+        let mut upper = BTree::new();
+        upper.inner_tag = self.inner_tag;
+        upper.key_length_bounds = self.key_length_bounds;
+        if self.last_key().map_or(true, |last| &last[..] < key) {
+            return Ok(upper);
+        }
+        if self.keys().next().is_some_and(|first| key <= &first[..]) {
+            mem::swap(&mut self.root, &mut upper.root);
+            mem::swap(&mut self.value_arena, &mut upper.value_arena);
+            (upper.count, self.count) = (self.count, 0);
+            self.generation += 1;
+            return Ok(upper);
+        }
+        if unsafe { self.split_path(&mut upper, key) }.is_err() {
+            let mut moved = Vec::new();
+            let mut result = Ok(());
+            self.for_each_in_range((Bound::Included(key), Bound::Unbounded), &mut |key, value| {
+                if result.is_ok() {
+                    result = upper.insert(key, value);
+                    moved.push(key.to_vec());
+                }
+            });
+            // entries are only removed from self once upper holds all of them
+            result?;
+            for key in &moved {
+                unsafe { self.remove(key) };
+            }
+        }
+        Ok(upper)
+    }
+
+    /// moves the subtrees right of the path of `key` into the empty tree `upper`, splitting the nodes on the path.
+    /// The parts of path nodes lose the fence on the side of the split, so they may need several pages.
+    /// Fails before changing either tree if a part does not fit.
+    unsafe fn split_path(&mut self, upper: &mut BTree, key: &[u8]) -> Result<(), NodeOpError> {
+        let mut path = vec![(self.root, 0)];
+        while (*path.last().unwrap().0).tag().is_inner() {
+            let inner = (*path.last().unwrap().0).to_inner();
+            let index = inner.lookup_child_index(key);
+            path.last_mut().unwrap().1 = index;
+            path.push((inner.get_child(index), 0));
+        }
+        // parts of the level below, both in key order with full separator keys
+        let mut lower_part = SplitPart::default();
+        let mut upper_part = SplitPart::default();
+        let result = (|| {
+            let mut key_buffer = [0u8; MAX_KEY_LEN];
+            for &(node, index) in path.iter().rev() {
+                let (lower_fence, upper_fence) = full_fences(&*node, key);
+                let prefix = &key[..node_fences(&*node).prefix_len];
+                if (*node).tag().is_leaf() {
+                    lower_part.push_leaves(&*node, key, &lower_fence, &[], (Bound::Unbounded, Bound::Excluded(key)))?;
+                    upper_part.push_leaves(&*node, key, &[], &upper_fence, (Bound::Included(key), Bound::Unbounded))?;
+                    continue;
+                }
+                let inner = (*node).to_inner();
+                let mut full_key = |i: usize| {
+                    let key_len = inner.get_key(i, &mut key_buffer, 0).unwrap();
+                    [prefix, trailing_bytes(&key_buffer, key_len)].concat()
+                };
+                let (lower_below, upper_below) = (mem::take(&mut lower_part), mem::take(&mut upper_part));
+                let before = (index > 0).then(|| full_key(index - 1));
+                let after = (index < inner.key_count()).then(|| full_key(index));
+                let mut built = {
+                    let (mut parts, mut separators) = (Vec::new(), Vec::new());
+                    if let Some(before) = &before {
+                        parts.push(ConcatPart::Node { src: inner, prefix: key, children: 0..index });
+                        separators.push(&before[..]);
+                    }
+                    lower_below.append_to(&mut parts, &mut separators);
+                    lower_part.push_inner(&*node, &lower_fence, &[], &parts, &separators)
+                };
+                if built.is_ok() {
+                    let (mut parts, mut separators) = (Vec::new(), Vec::new());
+                    upper_below.append_to(&mut parts, &mut separators);
+                    if let Some(after) = &after {
+                        separators.push(&after[..]);
+                        parts.push(ConcatPart::Node { src: inner, prefix: key, children: index + 1..inner.key_count() + 1 });
+                    }
+                    built = upper_part.push_inner(&*node, &[], &upper_fence, &parts, &separators);
+                }
+                lower_part.allocated.extend(lower_below.allocated);
+                upper_part.allocated.extend(upper_below.allocated);
+                built?;
+            }
+            Ok(())
+        })().and_then(|()| Ok((lower_part.into_root(self.inner_tag)?, upper_part.into_root(self.inner_tag)?)));
+        let (lower_root, upper_root) = match result {
+            Ok(roots) => roots,
+            Err(e) => {
+                lower_part.free();
+                upper_part.free();
+                return Err(e);
+            }
+        };
+
+        // the path nodes were split into the parts, everything else is reused
+        for &(node, _) in &path {
+            if matches!((*node).tag(), BTreeNodeTag::HashLeaf | BTreeNodeTag::HybridLeaf) {
+                ManuallyDrop::drop(&mut (*node).hash_leaf);
+            }
+            BTreeNode::dealloc(node);
+        }
+        BTreeNode::dealloc(upper.root);
+        self.root = lower_root;
+        upper.root = upper_root;
+        upper.count = upper.count_entries();
+        self.count -= upper.count;
+        upper.value_arena = self.value_arena.share();
+        self.generation += 1;
+        upper.generation += 1;
+
+        // parts along the split may be underfull or hold a single child
+        for level in 0..self.height().max(upper.height()) {
+            for tree in [&mut *self, &mut *upper] {
+                if let Some((node, parent, index)) = tree.node_at_level(key, level) {
+                    if (*node).is_underfull() {
+                        tree.rebalance(key, node, parent, index);
+                    }
+                }
+            }
+        }
+        self.validate();
+        upper.validate();
+        Ok(())
+    }
+
+    /// sets the access pattern hint of all leaves that may contain keys starting with `prefix`.
+    /// Hinted leaves are converted immediately, splits and merges pass the hint on.
+    pub fn hint_access_pattern(&mut self, prefix: impl AsRef<[u8]>, pattern: AccessPattern) {
+        let prefix = prefix.as_ref();
+        if prefix.len() > MAX_KEY_LEN {
+            // no key can start with prefix
+            return;
+        }
+        let mut get_key_buffer = [0u8; MAX_KEY_LEN];
+        let mut start_key_buffer = [0u8; RANGE_START_BUFFER_LEN];
+        start_key_buffer[..prefix.len()].copy_from_slice(prefix);
+        let mut start_key_len = prefix.len();
+        loop {
+            self.branch_cache.reset();
+            let mut node = unsafe { &mut *self.root };
+            let mut parent = None;
+            let mut index = 0;
+            while node.tag().is_inner() {
+                let node_inner = node.to_inner_mut();
+                index = node_inner.find_child_index(&start_key_buffer[..start_key_len], &mut self.branch_cache);
+                let child = unsafe { &mut *node_inner.get_child(index) };
+                parent = Some(node_inner);
+                node = child;
+            }
+            node.leave_set_access_pattern(pattern);
+            let Some(p) = parent else {
+                return;
+            };
+            let fence_data = p.fences();
+            let upper = if index < p.key_count() {
+                let upper_len = p.get_key(index, &mut get_key_buffer, 0).unwrap();
+                trailing_bytes(&get_key_buffer, upper_len)
+            } else {
+                fence_data.upper_fence.to_stripped(fence_data.prefix_len).0
+            };
+            if upper.is_empty() {
+                return;
+            }
+            start_key_buffer[fence_data.prefix_len..][..upper.len()].copy_from_slice(upper);
+            let upper_full = &start_key_buffer[..fence_data.prefix_len + upper.len()];
+            // no later leaf can hold keys starting with prefix
+            if upper_full > prefix && !upper_full.starts_with(prefix) {
+                return;
+            }
+            start_key_buffer[fence_data.prefix_len + upper.len()] = 0;
+            start_key_len = fence_data.prefix_len + upper.len() + 1;
+        }
+    }
+
+    /// compacts the leaf responsible for key
+    pub fn compact_leaf_for(&mut self, key: impl AsRef<[u8]>) {
+        unsafe {
+            let (leaf, _, _) = (*self.root).descend(key.as_ref(), |_| false, &mut self.branch_cache);
+            (*leaf).to_leaf_mut().compactify();
+            (*leaf).head_mut().touch();
+        }
+    }
+
+    /// copy of the page of the leaf responsible for key, see `node_stats::decode_leaf_page`.
+    /// Unused space and padding read as zero. Does not modify the tree.
+    pub fn dump_leaf_for(&self, key: impl AsRef<[u8]>) -> [u8; PAGE_SIZE] {
+        unsafe { (*self.root).find_leaf(key.as_ref()).written_bytes() }
+    }
+
     #[tracing::instrument(skip(self))]
     unsafe fn split_node(
         &mut self,
@@ -59,24 +1398,62 @@ impl BTree {
         mut parent: *mut BTreeNode,
         key: &[u8],
         index_in_parent: usize,
-    ) {
+    ) -> Result<(), NodeOpError> {
+        let (tag_before, key_count_before) = ((*node).tag(), node_key_count(&*node));
+        // a degenerate split would fail again after every parent split, report it instead of looping
+        if key_count_before <= 1 {
+            return Err(NodeOpError::DegenerateSplit);
+        }
+        let bytes_before = node_content_bytes(&*node);
         count_op();
+        self.generation += 1;
         if parent.is_null() {
-            parent = BTreeNode::new_inner(node);
+            parent = BTreeNode::new_inner_of(self.inner_tag, node);
             self.root = parent;
         }
         let success = (*node).split_node((&mut *parent).to_inner_mut(), index_in_parent, key);
+        debug_assert_children_valid((*parent).to_inner());
+        if TRACE_STRUCTURAL {
+            let parent_tag = (*parent).tag();
+            let parent = (*parent).to_inner();
+            if success.is_ok() {
+                let left = &*parent.get_child(index_in_parent);
+                let mut separator_buffer = [0u8; PAGE_SIZE / 4];
+                let separator_len = parent.fences().prefix_len + parent.get_key(index_in_parent, &mut separator_buffer, 0).unwrap();
+                tracing::info!(
+                    target: "structural",
+                    tag_before = ?tag_before,
+                    key_count_before,
+                    left_tag = ?left.tag(),
+                    left_key_count = node_key_count(left),
+                    right_tag = ?(*node).tag(),
+                    right_key_count = node_key_count(&*node),
+                    separator_len,
+                    converted = left.tag() != tag_before,
+                    "split"
+                );
+            } else {
+                tracing::info!(target: "structural", tag_before = ?tag_before, key_count_before, parent_tag = ?parent_tag, "split needs parent space");
+            }
+        }
         self.validate();
-        if success.is_err() {
-            self.ensure_space(parent, key);
+        if success.is_ok() {
+            self.structural_ops.splits += 1;
+            // the tree is valid, but a half that did not shrink may need the same split again
+            let left = &*(*parent).to_inner().get_child(index_in_parent);
+            if node_content_bytes(left) >= bytes_before || node_content_bytes(&*node) >= bytes_before {
+                return Err(NodeOpError::DegenerateSplit);
+            }
+        } else {
+            self.ensure_space(parent, key)?;
         }
+        Ok(())
     }
 
     #[tracing::instrument(skip(self))]
-    unsafe fn ensure_space(&mut self, to_split: *mut BTreeNode, key: &[u8]) {
-        let (node, parent, pos) = (*self.root).descend(key, |n| n == to_split, &mut self.branch_cache);
-        debug_assert!(node == to_split);
-        self.split_node(to_split, parent, key, pos);
+    unsafe fn ensure_space(&mut self, to_split: *mut BTreeNode, key: &[u8]) -> Result<(), NodeOpError> {
+        let (_, parent, pos) = (*self.root).descend_to_node(key, to_split, &mut self.branch_cache);
+        self.split_node(to_split, parent, key, pos)
     }
 
     unsafe fn validate(&self) {
@@ -94,51 +1471,109 @@ impl BTree {
     #[tracing::instrument(skip(self), level = "debug")]
     unsafe fn force_validate(&self) {
         (*self.root).validate_tree(&[], &[]);
+        debug_assert_eq!(self.count, self.count_entries());
     }
 
-    #[tracing::instrument(skip(self))]
-    pub unsafe fn remove(&mut self, key: &[u8]) -> bool {
+    #[tracing::instrument(skip(self, key), fields(key = ?key.as_ref()))]
+    pub unsafe fn remove(&mut self, key: impl AsRef<[u8]>) -> bool {
+        let key = key.as_ref();
         count_op();
-        let mut merge_target: *mut BTreeNode = ptr::null_mut();
+        let (node, parent, index) = (&mut *self.root).descend(key, |_| false, &mut self.branch_cache);
+        (&mut *node).leave_notify_point_op();
+        let arena_ref: Option<[u8; value_arena::ARENA_REF_SIZE]> = if VALUE_ARENA {
+            (*node).to_leaf().lookup(key).map(|reference| reference.try_into().unwrap())
+        } else {
+            None
+        };
+        let not_found = (&mut *node).to_leaf_mut().remove(key).is_none();
+        self.validate();
+        if not_found {
+            return false;
+        }
+        (*node).head_mut().touch();
+        if let Some(reference) = arena_ref {
+            self.value_arena.free(&reference);
+        }
+        self.count -= 1;
+        if (*node).is_underfull() {
+            self.rebalance(key, node, parent, index);
+        }
+        true
+    }
+
+    /// borrows entries or children from a neighbor of the underfull `node` or, if it has none to spare, merges both.
+    /// Continues with the parent while merges leave it underfull.
+    /// `node` must lie on the path of `key`, `parent` and `index` locate it as returned by a descent.
+    unsafe fn rebalance(&mut self, key: &[u8], mut node: *mut BTreeNode, mut parent: *mut BTreeNode, mut index: usize) {
         loop {
-            let (node, parent, index) = (&mut *self.root).descend(key, |n| n == merge_target, &mut self.branch_cache);
-            if merge_target.is_null() {
-                (&mut *node).leave_notify_point_op();
-                let not_found = (&mut *node).to_leaf_mut().remove(key).is_none();
-                self.validate();
-                if not_found {
-                    return false; // todo validate
-                }
-                if (*node).is_underfull() {
-                    merge_target = node;
-                } else {
-                    return true;
-                }
-            }
-            debug_assert!(merge_target == node);
             if parent.is_null() {
-                break;
+                // an inner root left with a single child is replaced by it, so removing every key leaves a single empty leaf
+                while (*self.root).tag().is_inner() && node_key_count(&*self.root) == 0 {
+                    let root = self.root;
+                    self.root = (*root).to_inner().get_child(0);
+                    BTreeNode::dealloc(root);
+                    self.generation += 1;
+                    self.validate();
+                }
+                return;
             }
             debug_assert!((*node).is_underfull());
-            if (*parent).to_inner_mut().merge_children_check(index).is_ok() && (*parent).is_underfull() {
-                (&mut *parent).adaption_state().set_adapted(false);
-                self.validate();
-                merge_target = parent;
-                continue;
-            } else {
-                self.validate();
-                break;
+            let (tag_before, key_count_before) = ((*node).tag(), node_key_count(&*node));
+            let rebalanced = (*parent).to_inner_mut().merge_children_check(index, key);
+            let merged = rebalanced == Ok(Rebalance::Merged);
+            if TRACE_STRUCTURAL {
+                tracing::info!(
+                    target: "structural",
+                    tag_before = ?tag_before,
+                    key_count_before,
+                    parent_tag = ?(*parent).tag(),
+                    parent_key_count = node_key_count(&*parent),
+                    merged,
+                    "merge"
+                );
+            }
+            if merged {
+                self.generation += 1;
+                self.structural_ops.merges += 1;
+                debug_assert_children_valid((*parent).to_inner());
+            } else if rebalanced == Ok(Rebalance::Borrowed) {
+                if TRACE_STRUCTURAL {
+                    tracing::info!(target: "structural", tag_before = ?tag_before, key_count_before, "borrow");
+                }
+                self.generation += 1;
+                self.structural_ops.borrows += 1;
+            }
+            self.validate();
+            if !(merged && (*parent).is_underfull()) {
+                return;
             }
+            (&mut *parent).adaption_state().set_adapted(false);
+            (node, parent, index) = (&mut *self.root).descend_to_node(key, parent, &mut self.branch_cache);
         }
-        true
     }
 
-    pub fn range_lookup(&mut self, initial_start: &[u8], key_out: *mut u8, callback: &mut dyn FnMut(usize, &[u8]) -> bool) {
+    /// calls `callback` with key and value for keys >= start in ascending order until it returns false
+    pub fn range_lookup(&mut self, initial_start: impl AsRef<[u8]>, callback: &mut dyn FnMut(&[u8], &[u8]) -> bool) {
         count_op();
-        let mut get_key_buffer = [0u8; PAGE_SIZE / 4];
-        let mut start_key_buffer = [0u8; PAGE_SIZE / 4];
-        start_key_buffer[..initial_start.len()].copy_from_slice(initial_start);
-        let mut start_key_len = initial_start.len();
+        let initial_start = initial_start.as_ref();
+        let mut resolving;
+        let callback: &mut dyn FnMut(&[u8], &[u8]) -> bool = if VALUE_ARENA {
+            resolving = move |key: &[u8], arena_ref: &[u8]| callback(key, unsafe { value_arena::resolve(arena_ref) });
+            &mut resolving
+        } else {
+            callback
+        };
+        let mut key_out = KeyBuffer::new();
+        let mut get_key_buffer = [0u8; MAX_KEY_LEN];
+        let mut start_key_buffer = [0u8; RANGE_START_BUFFER_LEN];
+        let mut start_key_len = if initial_start.len() > MAX_KEY_LEN {
+            // no key can be longer than MAX_KEY_LEN, so the first key after the truncated start is the first one after start
+            start_key_buffer[..MAX_KEY_LEN].copy_from_slice(&initial_start[..MAX_KEY_LEN]);
+            MAX_KEY_LEN + 1
+        } else {
+            start_key_buffer[..initial_start.len()].copy_from_slice(initial_start);
+            initial_start.len()
+        };
 
         loop {
             self.branch_cache.reset();
@@ -154,28 +1589,26 @@ impl BTree {
                     node = child;
                 } else {
                     (&mut *node).leave_notify_range_op();
-                    unsafe {
-                        if !node.to_leaf_mut().range_lookup(&start_key_buffer[..start_key_len], key_out, callback) {
-                            return;
-                        }
-                        if let Some(p) = parent {
-                            let fence_data = p.fences();
-                            let count = p.key_count();
-                            let upper = if index < count {
-                                let upper_len = p.get_key(index, &mut get_key_buffer, 0).unwrap();
-                                trailing_bytes(&get_key_buffer, upper_len)
-                            } else {
-                                fence_data.upper_fence.to_stripped(fence_data.prefix_len).0
-                            };
-                            if upper.is_empty() {
-                                return;
-                            }
-                            start_key_buffer[fence_data.prefix_len..][..upper.len()].copy_from_slice(upper);
-                            start_key_buffer[fence_data.prefix_len + upper.len()] = 0;
-                            start_key_len = fence_data.prefix_len + upper.len() + 1;
+                    if !node.to_leaf_mut().range_lookup(&start_key_buffer[..start_key_len], &mut key_out, callback) {
+                        return;
+                    }
+                    if let Some(p) = parent {
+                        let fence_data = p.fences();
+                        let count = p.key_count();
+                        let upper = if index < count {
+                            let upper_len = p.get_key(index, &mut get_key_buffer, 0).unwrap();
+                            trailing_bytes(&get_key_buffer, upper_len)
                         } else {
+                            fence_data.upper_fence.to_stripped(fence_data.prefix_len).0
+                        };
+                        if upper.is_empty() {
                             return;
                         }
+                        start_key_buffer[fence_data.prefix_len..][..upper.len()].copy_from_slice(upper);
+                        start_key_buffer[fence_data.prefix_len + upper.len()] = 0;
+                        start_key_len = fence_data.prefix_len + upper.len() + 1;
+                    } else {
+                        return;
                     }
                     break;
                 }
@@ -183,12 +1616,47 @@ impl BTree {
         }
     }
 
-    pub fn range_lookup_desc(&mut self, initial_start: &[u8], key_out: *mut u8, callback: &mut dyn FnMut(usize, &[u8]) -> bool) {
+    /// calls `callback` with key and value of every entry in `range` in ascending key order
+    pub fn for_each_in_range(&mut self, range: impl RangeBounds<[u8]>, callback: &mut dyn FnMut(&[u8], &[u8])) {
+        let start = match range.start_bound() {
+            Bound::Included(start) | Bound::Excluded(start) => start,
+            Bound::Unbounded => &[],
+        };
+        self.range_lookup(start, &mut |key, value| {
+            if let Bound::Excluded(start) = range.start_bound() {
+                if key == start {
+                    return true;
+                }
+            }
+            let in_range = match range.end_bound() {
+                Bound::Included(end) => key <= end,
+                Bound::Excluded(end) => key < end,
+                Bound::Unbounded => true,
+            };
+            if in_range {
+                callback(key, value);
+            }
+            in_range
+        });
+    }
+
+    /// calls `callback` with key and value for keys <= start in descending order until it returns false
+    pub fn range_lookup_desc(&mut self, initial_start: impl AsRef<[u8]>, callback: &mut dyn FnMut(&[u8], &[u8]) -> bool) {
         count_op();
-        let mut get_key_buffer = [0u8; PAGE_SIZE / 4];
-        let mut start_key_buffer = [0u8; PAGE_SIZE / 4];
-        start_key_buffer[..initial_start.len()].copy_from_slice(initial_start);
-        let mut start_key_len = initial_start.len();
+        let initial_start = initial_start.as_ref();
+        let mut resolving;
+        let callback: &mut dyn FnMut(&[u8], &[u8]) -> bool = if VALUE_ARENA {
+            resolving = move |key: &[u8], arena_ref: &[u8]| callback(key, unsafe { value_arena::resolve(arena_ref) });
+            &mut resolving
+        } else {
+            callback
+        };
+        let mut key_out = KeyBuffer::new();
+        let mut get_key_buffer = [0u8; MAX_KEY_LEN];
+        let mut start_key_buffer = [0u8; MAX_KEY_LEN];
+        // no key can be longer than MAX_KEY_LEN, so no key lies between the truncated start and start
+        let mut start_key_len = initial_start.len().min(MAX_KEY_LEN);
+        start_key_buffer[..start_key_len].copy_from_slice(&initial_start[..start_key_len]);
 
         loop {
             self.branch_cache.reset();
@@ -204,27 +1672,25 @@ impl BTree {
                     node = child;
                 } else {
                     (&mut *node).leave_notify_range_op();
-                    unsafe {
-                        if !node.to_leaf_mut().range_lookup_desc(&start_key_buffer[..start_key_len], key_out, callback) {
-                            return;
-                        }
-                        if let Some(p) = parent {
-                            let fence_data = p.fences();
-                            let count = p.key_count();
-                            let lower = if index > 0 {
-                                let upper_len = p.get_key(index - 1, &mut get_key_buffer, 0).unwrap();
-                                trailing_bytes(&get_key_buffer, upper_len)
-                            } else {
-                                fence_data.lower_fence.to_stripped(fence_data.prefix_len).0
-                            };
-                            if lower.is_empty() {
-                                return;
-                            }
-                            start_key_buffer[fence_data.prefix_len..][..lower.len()].copy_from_slice(lower);
-                            start_key_len = fence_data.prefix_len + lower.len();
+                    if !node.to_leaf_mut().range_lookup_desc(&start_key_buffer[..start_key_len], &mut key_out, callback) {
+                        return;
+                    }
+                    if let Some(p) = parent {
+                        let fence_data = p.fences();
+                        let lower = if index > 0 {
+                            let upper_len = p.get_key(index - 1, &mut get_key_buffer, 0).unwrap();
+                            trailing_bytes(&get_key_buffer, upper_len)
                         } else {
+                            fence_data.lower_fence.to_stripped(fence_data.prefix_len).0
+                        };
+                        // a lower fence equal to the prefix strips to nothing, only an empty full fence ends the scan
+                        if lower.is_empty() && fence_data.prefix_len == 0 {
                             return;
                         }
+                        start_key_buffer[fence_data.prefix_len..][..lower.len()].copy_from_slice(lower);
+                        start_key_len = fence_data.prefix_len + lower.len();
+                    } else {
+                        return;
                     }
                     break;
                 }
@@ -232,3 +1698,6 @@ impl BTree {
         }
     }
 }
+
+#[cfg(test)]
+mod test;
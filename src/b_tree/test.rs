@@ -0,0 +1,20 @@
+//! tests for `BTree`, one module per feature, fixtures shared between them are kept here
+
+use super::*;
+
+mod basics;
+mod descent;
+mod graft;
+mod leaf_storage;
+mod rebalance;
+mod scan;
+mod split;
+mod validation;
+
+fn integer_tree(range: std::ops::Range<u64>, leaf: BTreeNodeTag, inner: BTreeNodeTag) -> BTree {
+    let mut tree = BTree::new_with(leaf, inner);
+    for i in range {
+        tree.insert(i.to_be_bytes(), (i as u32).to_le_bytes()).unwrap();
+    }
+    tree
+}
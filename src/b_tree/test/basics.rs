@@ -0,0 +1,157 @@
+//! tests for insertion, removal and whole tree comparisons
+
+use super::*;
+use crate::test_util::{INNER_TAGS, LEAF_TAGS};
+use rand::SeedableRng;
+use rand::seq::SliceRandom;
+use rand_xoshiro::Xoshiro128PlusPlus;
+use std::collections::{BTreeMap, BTreeSet};
+
+#[test]
+fn key_length_bounds_track_inserts() {
+    crate::ensure_init();
+    let mut tree = BTree::new();
+    assert_eq!(tree.key_length_bounds(), (0, 0));
+    tree.insert(b"12345", b"").unwrap();
+    assert_eq!(tree.key_length_bounds(), (5, 5));
+    for len in [7, 3, 200, 40] {
+        tree.insert(vec![b'k'; len], b"v").unwrap();
+    }
+    assert_eq!(tree.key_length_bounds(), (3, 200));
+    // removes do not shrink the bounds
+    assert!(unsafe { tree.remove(vec![b'k'; 200]) });
+    assert!(unsafe { tree.remove(vec![b'k'; 3]) });
+    assert_eq!(tree.key_length_bounds(), (3, 200));
+    tree.insert(b"", b"empty").unwrap();
+    assert_eq!(tree.key_length_bounds(), (0, 200));
+    // a rejected insert leaves the bounds alone
+    let long_key = vec![b'k'; MAX_KEY_LEN + 1];
+    assert_eq!(tree.insert(&long_key, b"v"), Err(NodeOpError::TooLarge));
+    assert_eq!(tree.key_length_bounds(), (0, 200));
+}
+
+#[test]
+fn len_tracks_entries() {
+    crate::ensure_init();
+    let mut rng = Xoshiro128PlusPlus::seed_from_u64(5);
+    let mut tree = BTree::new();
+    let mut reference = BTreeSet::new();
+    for i in 0..50_000u32 {
+        let key = format!("k{}", rng.gen_range(0..10_000u32)).into_bytes();
+        if rng.gen_bool(if i < 25_000 { 0.7 } else { 0.35 }) {
+            // payloads vary so updates rewrite existing slots
+            tree.insert(&key, &vec![i as u8; rng.gen_range(0..20)]).unwrap();
+            reference.insert(key);
+        } else {
+            assert_eq!(unsafe { tree.remove(&key) }, reference.remove(&key));
+        }
+        assert_eq!(tree.len(), reference.len());
+    }
+    assert_eq!(tree.count_entries(), reference.len());
+    assert_eq!(unsafe { crate::btree_len(&mut tree) }, reference.len() as u64);
+}
+
+#[test]
+fn contains_prefix_matches_sorted_map() {
+    crate::ensure_init();
+    let mut rng = Xoshiro128PlusPlus::seed_from_u64(1);
+    let mut tree = BTree::new();
+    let mut reference = BTreeMap::new();
+    assert!(!tree.contains_prefix(b""));
+    for _ in 0..5_000 {
+        let key: Vec<u8> = (0..rng.gen_range(1..6)).map(|_| rng.gen_range(b'a'..b'e')).collect();
+        tree.insert(&key, [1]).unwrap();
+        reference.insert(key, ());
+    }
+    let expected = |prefix: &[u8]| reference.range(prefix.to_vec()..).next().map_or(false, |(k, _)| k.starts_with(prefix));
+    // empty prefix, stored keys as prefixes and prefixes with and without matches
+    for _ in 0..5_000 {
+        let prefix: Vec<u8> = (0..rng.gen_range(0..7)).map(|_| rng.gen_range(b'a'..b'f')).collect();
+        assert_eq!(tree.contains_prefix(&prefix), expected(&prefix), "{prefix:?}");
+    }
+    for key in reference.keys() {
+        assert!(tree.contains_prefix(key));
+    }
+    assert!(tree.contains_prefix(b""));
+    assert!(!tree.contains_prefix(b"e"));
+    assert!(!tree.contains_prefix(b"aaaaaa"));
+}
+
+#[test]
+fn tree_moves_to_another_thread() {
+    crate::ensure_init();
+    let tree = integer_tree(0..10_000, BTreeNodeTag::HashLeaf, BTreeNodeTag::BasicInner);
+    let tree = std::thread::spawn(move || {
+        for i in (0..10_000u64).step_by(7) {
+            assert_eq!(tree.get(i.to_be_bytes()), Some(&(i as u32).to_le_bytes()[..]));
+        }
+        assert_eq!(tree.get(10_000u64.to_be_bytes()), None);
+        tree
+    }).join().unwrap();
+    assert_eq!(tree.len(), 10_000);
+}
+
+#[test]
+fn new_with_uses_requested_node_types() {
+    crate::ensure_init();
+    let mut tree = BTree::new_with(BTreeNodeTag::HashLeaf, BTreeNodeTag::ArtInner);
+    assert_eq!(tree.root_tag(), BTreeNodeTag::HashLeaf);
+    for i in 0..20_000u64 {
+        tree.insert(format!("key{i}"), i.to_le_bytes()).unwrap();
+    }
+    assert_eq!(tree.root_tag(), BTreeNodeTag::ArtInner);
+    let counts = node_stats::node_tag_counts(&tree);
+    let inner_count: u64 = INNER_TAGS.iter().map(|&tag| counts[tag as usize]).sum();
+    assert_eq!(counts[BTreeNodeTag::ArtInner as usize], inner_count);
+    assert!(counts[BTreeNodeTag::HashLeaf as usize] > 0);
+    for i in (0..20_000u64).step_by(13) {
+        assert_eq!(tree.get(format!("key{i}")), Some(&i.to_le_bytes()[..]));
+    }
+}
+
+#[test]
+fn checksum_ignores_layout() {
+    crate::ensure_init();
+    let mut keys: Vec<u64> = (0..10_000).collect();
+    let mut reference = integer_tree(0..10_000, BTreeNodeTag::BasicLeaf, BTreeNodeTag::BasicInner);
+    let checksum = reference.content_checksum();
+    keys.shuffle(&mut Xoshiro128PlusPlus::seed_from_u64(4));
+    for leaf in LEAF_TAGS {
+        let mut tree = BTree::new_with(leaf, BTreeNodeTag::BasicInner);
+        for &i in &keys {
+            tree.insert(i.to_be_bytes(), (i as u32).to_le_bytes()).unwrap();
+        }
+        assert_eq!(tree.content_checksum(), checksum, "{leaf:?}");
+        tree.insert(5_000u64.to_be_bytes(), [0u8; 4]).unwrap();
+        assert_ne!(tree.content_checksum(), checksum, "{leaf:?}");
+    }
+}
+
+#[test]
+fn trees_with_different_insertion_orders_have_equal_contents() {
+    crate::ensure_init();
+    let mut keys: Vec<u64> = (0..20_000).map(|i| i * 7).collect();
+    let mut ascending = BTree::new_with(BTreeNodeTag::BasicLeaf, BTreeNodeTag::BasicInner);
+    for &i in &keys {
+        ascending.insert(i.to_be_bytes(), (i as u32).to_le_bytes()).unwrap();
+    }
+    keys.shuffle(&mut Xoshiro128PlusPlus::seed_from_u64(9));
+    let mut shuffled = BTree::new_with(BTreeNodeTag::HashLeaf, BTreeNodeTag::ArtInner);
+    for &i in &keys {
+        shuffled.insert(i.to_be_bytes(), (i as u32).to_le_bytes()).unwrap();
+    }
+    assert!(ascending.contents_eq(&mut shuffled));
+    assert!(shuffled.contents_eq(&mut ascending));
+    // a different value, a missing entry and an extra entry are all detected
+    shuffled.insert(700u64.to_be_bytes(), [0u8; 4]).unwrap();
+    assert!(!ascending.contents_eq(&mut shuffled));
+    shuffled.insert(700u64.to_be_bytes(), 700u32.to_le_bytes()).unwrap();
+    assert!(unsafe { shuffled.remove(14_000u64.to_be_bytes()) });
+    assert!(!ascending.contents_eq(&mut shuffled));
+    assert!(!shuffled.contents_eq(&mut ascending));
+    shuffled.insert(14_000u64.to_be_bytes(), 14_000u32.to_le_bytes()).unwrap();
+    shuffled.insert(1u64.to_be_bytes(), 1u32.to_le_bytes()).unwrap();
+    assert!(!ascending.contents_eq(&mut shuffled));
+    assert!(unsafe { shuffled.remove(1u64.to_be_bytes()) });
+    assert!(ascending.contents_eq(&mut shuffled));
+}
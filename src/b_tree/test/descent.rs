@@ -0,0 +1,186 @@
+//! tests for descents, the height guard, the leaf cache and cursors
+
+use super::*;
+use rand::SeedableRng;
+use rand::seq::SliceRandom;
+use rand_xoshiro::Xoshiro128PlusPlus;
+
+#[test]
+fn key_path_reaches_the_leaf_holding_the_key() {
+    crate::ensure_init();
+    for leaf in [BTreeNodeTag::BasicLeaf, BTreeNodeTag::HashLeaf] {
+        let mut tree = integer_tree(0..10_000, leaf, BTreeNodeTag::BasicInner);
+        let height = tree.height();
+        assert!(height > 1);
+        for i in (0..10_000u64).step_by(37) {
+            let path = tree.debug_find_key_path(i.to_be_bytes());
+            assert!(path.found(), "{leaf:?} {i}");
+            assert_eq!(path.inner.len(), height - 1, "{leaf:?} {i}");
+            assert!(path.inner.iter().all(|(tag, _)| tag.is_inner()), "{leaf:?} {i}");
+            assert!(path.leaf_tag.is_leaf(), "{leaf:?} {i}");
+        }
+        let missing = tree.debug_find_key_path(20_000u64.to_be_bytes());
+        assert!(!missing.found(), "{leaf:?}");
+        assert_eq!(missing.inner.len(), height - 1, "{leaf:?}");
+    }
+}
+
+#[test]
+fn lookup_depths_match_height() {
+    crate::ensure_init();
+    let mut rng = Xoshiro128PlusPlus::seed_from_u64(6);
+    for (leaf, inner) in [(BTreeNodeTag::BasicLeaf, BTreeNodeTag::BasicInner), (BTreeNodeTag::HashLeaf, BTreeNodeTag::ArtInner)] {
+        let mut tree = integer_tree(0..50_000, leaf, inner);
+        let height = tree.height();
+        assert!(height >= 3);
+        let before = tree.depth_profile().to_vec();
+        let mut keys: Vec<u64> = (0..50_000).chain(100_000..110_000).collect();
+        keys.shuffle(&mut rng);
+        for &k in &keys {
+            let mut len = 0;
+            unsafe { tree.lookup(&mut len, k.to_be_bytes()) };
+        }
+        let profile = tree.depth_profile();
+        if !DEPTH_PROFILE {
+            assert!(profile.is_empty());
+            continue;
+        }
+        let added: Vec<u64> = (0..profile.len()).map(|d| profile[d] - before.get(d).copied().unwrap_or(0)).collect();
+        assert_eq!(added.iter().sum::<u64>(), keys.len() as u64);
+        for (depth, &count) in added.iter().enumerate() {
+            // lookups served by the leaf cache skip the descent and are counted at depth 0
+            if depth != height && !(LEAF_CACHE && depth == 0) {
+                assert_eq!(count, 0, "{leaf:?}/{inner:?}: lookups at depth {depth}, height is {height}");
+            }
+        }
+        assert!(added[height] > 0);
+    }
+}
+
+#[test]
+fn normal_trees_stay_below_height_guard() {
+    crate::ensure_init();
+    let mut tree = BTree::new();
+    // long keys give the lowest fanout
+    for i in 0..20_000u32 {
+        let mut key = vec![b'k'; MAX_KEY_LEN - 5];
+        key.extend_from_slice(&i.to_be_bytes());
+        tree.insert(&key, [1]).unwrap();
+        assert!(tree.get(&key).is_some());
+    }
+    assert!(tree.height() < MAX_TREE_HEIGHT);
+}
+
+#[test]
+#[cfg_attr(debug_assertions, should_panic(expected = "descent exceeded"))]
+fn degenerate_chain_trips_height_guard() {
+    crate::ensure_init();
+    let mut tree = BTree::new();
+    for _ in 0..=MAX_TREE_HEIGHT {
+        tree.root = BTreeNode::new_inner(tree.root);
+    }
+    tree.insert(b"key", b"value").unwrap();
+    assert_eq!(tree.get(b"key"), Some(&b"value"[..]));
+}
+
+#[test]
+fn leaf_cache_is_invalidated_by_structural_changes() {
+    crate::ensure_init();
+    let mut tree = integer_tree(0..20_000, BTreeNodeTag::BasicLeaf, BTreeNodeTag::BasicInner);
+    let fresh_leaf = |tree: &BTree, key: &[u8]| unsafe { (*tree.root).find_leaf(key) as *const BTreeNode as *mut BTreeNode };
+    let base = 10_000u64.to_be_bytes();
+    let grown: Vec<Vec<u8>> = (0..2_000u32).map(|i| [&base[..], &i.to_be_bytes()].concat()).collect();
+    let probes: Vec<Vec<u8>> = [10_500u64, 9_999, 10_000, 10_001].iter().map(|k| k.to_be_bytes().to_vec())
+        .chain(grown.iter().step_by(97).cloned()).collect();
+    let check_probes = |tree: &mut BTree| {
+        for key in &probes {
+            let leaf = tree.get_or_descend_cached(key);
+            assert_eq!(leaf, fresh_leaf(tree, key), "stale leaf for {key:?}");
+            // a second lookup may be served from the cache and must agree
+            let leaf = tree.get_or_descend_cached(key);
+            assert_eq!(leaf, fresh_leaf(tree, key), "stale cached leaf for {key:?}");
+        }
+    };
+    check_probes(&mut tree);
+    let generation = tree.generation;
+    // cache the leaves that are about to be split or merged away
+    tree.get_or_descend_cached(&base);
+    for key in &grown {
+        tree.insert(key, [1u8; 4]).unwrap();
+    }
+    assert!(tree.generation > generation, "splits must bump the generation");
+    check_probes(&mut tree);
+    let generation = tree.generation;
+    tree.get_or_descend_cached(&10_500u64.to_be_bytes());
+    // a node is only merged with an underfull right neighbor, so keys after the grown range are removed as well, back to front
+    let shrunk = 10_001..11_000u64;
+    for key in grown.iter().cloned().chain(shrunk.clone().map(|i| i.to_be_bytes().to_vec())).rev() {
+        assert!(unsafe { tree.remove(&key) });
+    }
+    assert!(tree.structural_ops().merges > 0);
+    assert!(tree.generation > generation, "merges must bump the generation");
+    check_probes(&mut tree);
+    for i in 0..20_000u64 {
+        let mut len = 0;
+        assert_eq!(unsafe { tree.lookup(&mut len, i.to_be_bytes()) }.is_null(), shrunk.contains(&i));
+    }
+    for key in &grown {
+        let mut len = 0;
+        assert!(unsafe { tree.lookup(&mut len, key) }.is_null());
+    }
+}
+
+#[test]
+fn cursor_recovers_after_leaf_split() {
+    crate::ensure_init();
+    for leaf in [BTreeNodeTag::BasicLeaf, BTreeNodeTag::HashLeaf] {
+        let mut tree = BTree::new_with(leaf, BTreeNodeTag::BasicInner);
+        for i in (0..2_000u64).step_by(2) {
+            tree.insert(i.to_be_bytes(), (i as u32).to_le_bytes()).unwrap();
+        }
+        let target = 1_000u64.to_be_bytes();
+        let mut cursor = tree.cursor(target).unwrap();
+        assert!(tree.cursor(1_001u64.to_be_bytes()).is_none());
+        assert_eq!(cursor.key(), &target[..]);
+        assert_eq!(tree.cursor_is_current(&cursor), LEAF_GENERATION);
+        assert_eq!(tree.cursor_value(&mut cursor), Some(&1_000u32.to_le_bytes()[..]));
+        // fill the gaps next to the target until its leaf splits
+        let splits_before = tree.structural_ops().splits;
+        let mut i = 1;
+        while tree.structural_ops().splits == splits_before {
+            tree.insert((1_000 + i).to_be_bytes(), [0u8; 4]).unwrap();
+            tree.insert((1_000 - i).to_be_bytes(), [0u8; 4]).unwrap();
+            i += 2;
+        }
+        assert!(!tree.cursor_is_current(&cursor), "{leaf:?}");
+        assert_eq!(tree.cursor_value(&mut cursor), Some(&1_000u32.to_le_bytes()[..]), "{leaf:?}");
+        assert_eq!(tree.cursor_is_current(&cursor), LEAF_GENERATION);
+        // an insert into the same leaf may move slots without a split
+        tree.insert(1_001u64.to_be_bytes(), [1u8; 4]).unwrap();
+        assert!(!tree.cursor_is_current(&cursor), "{leaf:?}");
+        assert_eq!(tree.cursor_value(&mut cursor), Some(&1_000u32.to_le_bytes()[..]), "{leaf:?}");
+        // removing a missing key leaves the leaf unchanged
+        assert!(!unsafe { tree.remove([&target[..], &[0]].concat()) });
+        assert_eq!(tree.cursor_is_current(&cursor), LEAF_GENERATION, "{leaf:?}");
+        assert!(unsafe { tree.remove(target) });
+        assert_eq!(tree.cursor_value(&mut cursor), None, "{leaf:?}");
+    }
+}
+
+#[test]
+fn cursor_from_other_tree_descends_again() {
+    crate::ensure_init();
+    let mut tree = integer_tree(0..100, BTreeNodeTag::BasicLeaf, BTreeNodeTag::BasicInner);
+    let mut cursor = {
+        let mut dropped = integer_tree(0..100, BTreeNodeTag::HashLeaf, BTreeNodeTag::BasicInner);
+        dropped.cursor(50u64.to_be_bytes()).unwrap()
+    };
+    // both trees are unchanged since their creation, so the generations match
+    assert!(!tree.cursor_is_current(&cursor));
+    assert_eq!(tree.cursor_value(&mut cursor), Some(&50u32.to_le_bytes()[..]));
+    assert_eq!(tree.cursor_is_current(&cursor), LEAF_GENERATION);
+    let mut other = integer_tree(0..100, BTreeNodeTag::BasicLeaf, BTreeNodeTag::BasicInner);
+    assert!(!other.cursor_is_current(&cursor));
+    other.insert(50u64.to_be_bytes(), b"other").unwrap();
+    assert_eq!(other.cursor_value(&mut cursor), Some(&b"other"[..]));
+}
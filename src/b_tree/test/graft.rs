@@ -0,0 +1,116 @@
+//! tests for joining trees with `merge` and `graft` and cutting them with `split_off`
+
+use super::*;
+use rand::SeedableRng;
+use rand_xoshiro::Xoshiro128PlusPlus;
+use std::collections::BTreeMap;
+
+#[test]
+fn merge_grafts_adjacent_ranges() {
+    crate::ensure_init();
+    let sizes = [0u64, 1, 300, 5_000, 60_000];
+    for (leaf, inner) in [(BTreeNodeTag::BasicLeaf, BTreeNodeTag::BasicInner), (BTreeNodeTag::HashLeaf, BTreeNodeTag::ArtInner)] {
+        for lower in sizes {
+            for upper in sizes {
+                let mut tree = integer_tree(0..lower, leaf, inner);
+                let mut other = integer_tree(lower..lower + upper, leaf, inner);
+                let heights = (tree.height(), other.height());
+                if lower > 0 && upper > 0 {
+                    unsafe { tree.graft(&mut other) }.unwrap();
+                    assert_eq!(other.len(), 0);
+                } else {
+                    assert!(tree.merge(other).is_ok());
+                }
+                unsafe { tree.force_validate() };
+                assert_eq!(tree.verify_balanced(), Ok(()));
+                assert_eq!(tree.len(), (lower + upper) as usize, "{heights:?}");
+                assert!(tree.height() <= heights.0.max(heights.1) + 1);
+                let keys: Vec<_> = tree.keys().collect();
+                assert!(keys.iter().map(|k| u64::from_be_bytes(k[..].try_into().unwrap())).eq(0..lower + upper), "{heights:?}");
+                for i in (0..lower + upper).step_by(97) {
+                    assert_eq!(tree.get(i.to_be_bytes()), Some(&(i as u32).to_le_bytes()[..]));
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn merge_skips_stale_separators() {
+    crate::ensure_init();
+    let mut tree = integer_tree(0..20_000, BTreeNodeTag::BasicLeaf, BTreeNodeTag::BasicInner);
+    let mut other = integer_tree(30_000..50_000, BTreeNodeTag::BasicLeaf, BTreeNodeTag::BasicInner);
+    // separators along the seam stay behind when the entries next to it are removed
+    for i in 19_000..20_000u64 {
+        assert!(unsafe { tree.remove(i.to_be_bytes()) });
+    }
+    for i in 30_000..31_000u64 {
+        assert!(unsafe { other.remove(i.to_be_bytes()) });
+    }
+    unsafe { tree.graft(&mut other) }.unwrap();
+    unsafe { tree.force_validate() };
+    assert!(tree.keys().map(|k| u64::from_be_bytes(k[..].try_into().unwrap())).eq((0..19_000).chain(31_000..50_000)));
+    let overlapping = integer_tree(40_000..40_001, BTreeNodeTag::BasicLeaf, BTreeNodeTag::BasicInner);
+    assert!(matches!(tree.merge(overlapping), Err(MergeError::Overlap(t)) if t.len() == 1));
+}
+
+fn check_split_off(tree: &mut BTree, reference: &mut BTreeMap<Vec<u8>, Vec<u8>>, key: &[u8]) {
+    let splits_path = reference.range(..key.to_vec()).next().is_some() && reference.range(key.to_vec()..).next().is_some();
+    let mut upper = if splits_path {
+        // splits inside the key range must not fall back to moving entries
+        let mut upper = BTree::new();
+        upper.inner_tag = tree.inner_tag;
+        upper.key_length_bounds = tree.key_length_bounds;
+        assert_eq!(unsafe { tree.split_path(&mut upper, key) }, Ok(()), "split at {key:?}");
+        upper
+    } else {
+        tree.split_off(key).unwrap()
+    };
+    let upper_reference = reference.split_off(key);
+    for (tree, reference) in [(&mut *tree, &*reference), (&mut upper, &upper_reference)] {
+        unsafe { tree.force_validate() };
+        assert_eq!(tree.verify_balanced(), Ok(()));
+        assert_eq!(tree.len(), reference.len(), "split at {key:?}");
+        assert!(tree.iter().eq(reference.iter().map(|(k, v)| (k.clone(), v.clone()))), "split at {key:?}");
+    }
+    // both trees stay usable and can be joined again
+    tree.merge(upper).ok().unwrap();
+    reference.extend(upper_reference);
+    unsafe { tree.force_validate() };
+    assert_eq!(tree.len(), reference.len());
+}
+
+#[test]
+fn split_off_matches_btree_map() {
+    crate::ensure_init();
+    let mut rng = Xoshiro128PlusPlus::seed_from_u64(11);
+    for (leaf, inner) in [(BTreeNodeTag::BasicLeaf, BTreeNodeTag::BasicInner), (BTreeNodeTag::HashLeaf, BTreeNodeTag::ArtInner)] {
+        // long prefixes shared below the root make split parts lose many bytes to their widened fences
+        let padding = "x".repeat(200);
+        let shapes: [(u32, &dyn Fn(u32, u32) -> String); 4] = [
+            (1, &|_, n| format!("https://example.com/{n:08}")),
+            (200, &|_, n| format!("https://example.com/{n:08}")),
+            (30_000, &|_, n| format!("https://example.com/{n:08}")),
+            (20_000, &|i, n| format!("{}/{padding}/{n:08}", i % 3)),
+        ];
+        for (count, shape) in shapes {
+            let mut tree = BTree::new_with(leaf, inner);
+            let mut reference = BTreeMap::new();
+            for i in 0..count {
+                let key = shape(i, rng.gen_range(0..count * 4)).into_bytes();
+                tree.insert(&key, i.to_le_bytes()).unwrap();
+                reference.insert(key, i.to_le_bytes().to_vec());
+            }
+            let existing: Vec<_> = reference.keys().step_by((count as usize / 7).max(1)).cloned().collect();
+            for key in &existing {
+                check_split_off(&mut tree, &mut reference, key);
+                let mut between = key.clone();
+                between.push(0);
+                check_split_off(&mut tree, &mut reference, &between);
+            }
+            for key in [&b""[..], b"a", b"https://example.com/", b"https://example.com/9", b"zzz"] {
+                check_split_off(&mut tree, &mut reference, key);
+            }
+        }
+    }
+}
@@ -0,0 +1,200 @@
+//! tests for leaf contents: dumps, compaction, access hints and the value arena
+
+use super::*;
+
+#[test]
+fn dumped_leaf_round_trips() {
+    crate::ensure_init();
+    if STRIP_PREFIX {
+        // the prefix is restored from the unstripped lower fence
+        return;
+    }
+    let mut tree = BTree::new();
+    for i in 0..5000u32 {
+        tree.insert(format!("key/{:05}", i * 13 % 5000), i.to_be_bytes()).unwrap();
+    }
+    // range scans may adapt leaves, so collect the entries before taking the node type snapshot
+    let entries: Vec<_> = tree.iter().collect();
+    let tag_counts = crate::node_stats::node_tag_counts(&tree);
+    for probe in ["key/00000", "key/02500", "key/04999", "zzz"] {
+        let data = crate::node_stats::decode_leaf_page(&tree.dump_leaf_for(probe));
+        let [lower, upper] = &data.fences;
+        let prefix = &lower[..data.prefix_len];
+        let decoded: Vec<(Vec<u8>, Vec<u8>)> = data.keys.iter().zip(&data.payloads)
+            .map(|(k, v)| ([prefix, &k[..]].concat(), v.clone()))
+            .collect();
+        let expected: Vec<_> = entries.iter()
+            .filter(|(k, _)| (lower.is_empty() || k > lower) && (upper.is_empty() || k <= upper))
+            .cloned()
+            .collect();
+        assert!(!decoded.is_empty());
+        assert_eq!(decoded, expected);
+        assert!(decoded.iter().any(|(k, _)| k == probe.as_bytes()) || probe == "zzz");
+    }
+    // dumping must not adapt or otherwise change nodes
+    assert_eq!(crate::node_stats::node_tag_counts(&tree), tag_counts);
+}
+
+#[test]
+fn compact_leaf_for_defragments_one_leaf() {
+    crate::ensure_init();
+    let target = 2_500u64.to_be_bytes();
+    // contiguous free space and free space after compaction of the leaf holding `target`
+    let spaces = |tree: &mut BTree| unsafe {
+        let (leaf, _, _) = (*tree.root).descend(&target, |_| false, &mut tree.branch_cache);
+        match (*leaf).tag() {
+            BTreeNodeTag::BasicLeaf => ((*leaf).basic.free_space(), (*leaf).basic.free_space_after_compaction()),
+            _ => ((*leaf).hash_leaf.free_space(), (*leaf).hash_leaf.free_space_after_compaction()),
+        }
+    };
+    for leaf in [BTreeNodeTag::BasicLeaf, BTreeNodeTag::HashLeaf] {
+        let mut tree = integer_tree(0..5_000, leaf, BTreeNodeTag::BasicInner);
+        // every update writes the new value to free space and leaves the old one behind
+        for i in 2_450..2_550u64 {
+            tree.insert(i.to_be_bytes(), [1u8; 3]).unwrap();
+        }
+        let (free, after_compaction) = spaces(&mut tree);
+        assert!(free < after_compaction, "{leaf:?}");
+        tree.compact_leaf_for(target);
+        assert_eq!(spaces(&mut tree), (after_compaction, after_compaction), "{leaf:?}");
+        for i in 0..5_000u64 {
+            let expected = if (2_450..2_550).contains(&i) { vec![1u8; 3] } else { (i as u32).to_le_bytes().to_vec() };
+            assert_eq!(tree.get(i.to_be_bytes()), Some(&expected[..]), "{leaf:?} {i}");
+        }
+    }
+}
+
+#[test]
+fn point_hint_keeps_prefix_in_hash_leaves() {
+    crate::ensure_init();
+    let key = |prefix: &str, i: u32| format!("{prefix}/{i:06}").into_bytes();
+    let mut tree = BTree::new_with(BTreeNodeTag::BasicLeaf, BTreeNodeTag::BasicInner);
+    for i in 0..10_000 {
+        tree.insert(key("ts", i), i.to_le_bytes()).unwrap();
+        tree.insert(key("user", i), i.to_le_bytes()).unwrap();
+    }
+    tree.hint_access_pattern(b"ts/", AccessPattern::Point);
+    let leaf_tag = |tree: &mut BTree, key: &[u8]| tree.debug_find_key_path(key).leaf_tag;
+    assert!((0..10_000).all(|i| leaf_tag(&mut tree, &key("ts", i)) == BTreeNodeTag::HashLeaf));
+    // range scans push unhinted leaves towards basic leaves, new leaves from splits inherit the hint
+    for round in 0..100 {
+        tree.range_lookup(b"", &mut |_, _| true);
+        for i in 0..100 {
+            tree.insert(key("ts", 10_000 + round * 100 + i), [0u8; 4]).unwrap();
+        }
+    }
+    assert!((0..20_000).all(|i| leaf_tag(&mut tree, &key("ts", i)) == BTreeNodeTag::HashLeaf));
+    if cfg!(feature = "leaf_adapt") {
+        let basic = (0..10_000).filter(|&i| leaf_tag(&mut tree, &key("user", i)) == BTreeNodeTag::BasicLeaf).count();
+        assert!(basic > 5_000, "{basic} user keys in basic leaves");
+    }
+    assert_eq!(tree.len(), 30_000);
+}
+
+#[test]
+fn arena_values_survive_leaf_compaction() {
+    crate::ensure_init();
+    // point operations keep the root a hash leaf
+    let mut tree = BTree::new_with(BTreeNodeTag::HashLeaf, BTreeNodeTag::BasicInner);
+    unsafe {
+        let leaf = |tree: &BTree| &*(*tree.root).hash_leaf;
+        let mut count = 0u64;
+        while leaf(&tree).free_space() >= 64 {
+            tree.insert(count.to_be_bytes(), [count as u8; 4]).unwrap();
+            count += 1;
+        }
+        // the last entry sits at the front of the data area and is moved by a compaction
+        let held_key = (count - 1).to_be_bytes();
+        let mut len = 0;
+        let held = tree.lookup(&mut len, held_key);
+        for i in 0..count / 2 {
+            assert!(tree.remove(i.to_be_bytes()));
+        }
+        let free = leaf(&tree).free_space();
+        assert!(free < leaf(&tree).free_space_after_compaction());
+        // does not fit into the contiguous free space, but into the removed entries
+        tree.insert(vec![u8::MAX; free], [1u8; 4]).unwrap();
+        assert_eq!((*tree.root).tag(), BTreeNodeTag::HashLeaf);
+        assert_eq!(leaf(&tree).free_space(), leaf(&tree).free_space_after_compaction());
+        let value = tree.get(held_key).unwrap();
+        assert_eq!(value.as_ptr() == held, VALUE_ARENA);
+        if VALUE_ARENA {
+            assert_eq!(std::slice::from_raw_parts(held, len as usize), &[(count - 1) as u8; 4]);
+        }
+        assert_eq!(value, &[(count - 1) as u8; 4]);
+    }
+}
+
+#[test]
+fn arena_space_is_reused_on_update_and_remove() {
+    crate::ensure_init();
+    if !VALUE_ARENA {
+        return;
+    }
+    let mut tree = BTree::new();
+    let value_ptr = |tree: &BTree, key: &str| tree.get(key).unwrap().as_ptr();
+    tree.insert("a", [1u8; 100]).unwrap();
+    let first = value_ptr(&tree, "a");
+    // identical values are compared after resolving the reference and not stored again
+    tree.insert("a", [1u8; 100]).unwrap();
+    assert_eq!(value_ptr(&tree, "a"), first);
+    // shorter values are written in place
+    tree.insert("a", [2u8; 60]).unwrap();
+    assert_eq!(value_ptr(&tree, "a"), first);
+    assert_eq!(tree.get("a"), Some(&[2u8; 60][..]));
+    // longer values free the old space, which the next value of its length takes over
+    tree.insert("a", [3u8; 200]).unwrap();
+    assert_ne!(value_ptr(&tree, "a"), first);
+    tree.insert("b", [4u8; 60]).unwrap();
+    assert_eq!(value_ptr(&tree, "b"), first);
+    let removed = value_ptr(&tree, "a");
+    assert!(unsafe { tree.remove("a") });
+    tree.insert("c", [5u8; 200]).unwrap();
+    assert_eq!(value_ptr(&tree, "c"), removed);
+    assert_eq!(tree.get("b"), Some(&[4u8; 60][..]));
+    assert_eq!(tree.get("c"), Some(&[5u8; 200][..]));
+}
+
+#[test]
+fn failed_insert_stores_no_value() {
+    crate::ensure_init();
+    if !VALUE_ARENA {
+        return;
+    }
+    let mut tree = BTree::new();
+    tree.insert("a", [1u8; 8]).unwrap();
+    let old = tree.get("a").unwrap().as_ptr();
+    assert!(unsafe { tree.remove("a") });
+    // a key that cannot be placed in any leaf fails without taking the freed space
+    let long_key = vec![b'k'; MAX_KEY_LEN - value_arena::ARENA_REF_SIZE + 1];
+    assert_eq!(tree.insert(&long_key, [2u8; 8]), Err(NodeOpError::TooLarge));
+    tree.insert("b", [3u8; 8]).unwrap();
+    assert_eq!(tree.get("b").unwrap().as_ptr(), old);
+}
+
+#[test]
+fn grow_shrink_updates_count_compactions() {
+    crate::ensure_init();
+    if VALUE_ARENA {
+        // leaves only hold fixed size references, so updates leave no holes
+        return;
+    }
+    // counters are shared with concurrently running tests, so only their growth is checked
+    let leaf_compactions = |tree: &BTree| {
+        let stats = tree.fragmentation_stats();
+        stats.basic_leaf.compacted + stats.hash_leaf.compacted
+    };
+    let mut tree = BTree::new_with(BTreeNodeTag::BasicLeaf, BTreeNodeTag::BasicInner);
+    for i in 0..10u8 {
+        tree.insert([i], [i; 8]).unwrap();
+    }
+    let before = leaf_compactions(&tree);
+    // each update leaves the previous value behind as a hole in the data area
+    for round in 0..1000usize {
+        let len = if round % 2 == 0 { 200 } else { 8 };
+        tree.insert([(round % 10) as u8], vec![round as u8; len]).unwrap();
+    }
+    assert!(unsafe { (*tree.root).tag() }.is_leaf());
+    assert!(leaf_compactions(&tree) > before);
+    assert_eq!(tree.get([9]), Some(&[999usize as u8; 8][..]));
+}
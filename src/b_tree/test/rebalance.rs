@@ -0,0 +1,286 @@
+//! tests for merging and borrowing after removes
+
+use super::*;
+use crate::test_util::INNER_TAGS;
+use rand::SeedableRng;
+use rand::seq::SliceRandom;
+use rand_xoshiro::Xoshiro128PlusPlus;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// removes `key` from its leaf without merging or borrowing
+unsafe fn remove_in_leaf(tree: &mut BTree, key: &[u8]) {
+    let (leaf, _, _) = tree.node_at_level(key, 0).unwrap();
+    assert!((*leaf).to_leaf_mut().remove(key).is_some());
+    tree.count -= 1;
+}
+
+#[test]
+fn borrow_refills_underfull_nodes() {
+    crate::ensure_init();
+    let count = 100_000u64;
+    let key = (count / 2).to_be_bytes();
+    for leaf in [BTreeNodeTag::BasicLeaf, BTreeNodeTag::HashLeaf] {
+        for inner in INNER_TAGS {
+            for level in [0, 1] {
+                // random insertion order leaves the neighbors about two thirds full, sequential inserts only half
+                let mut tree = BTree::new_with(leaf, inner);
+                let mut keys: Vec<u64> = (0..count).collect();
+                keys.shuffle(&mut Xoshiro128PlusPlus::seed_from_u64(5));
+                for i in keys {
+                    tree.insert(i.to_be_bytes(), (i as u32).to_le_bytes()).unwrap();
+                }
+                assert!(tree.height() > 2);
+                unsafe {
+                    // make the node on the path of key just underfull, like a remove or a merge below it would
+                    let (node, parent, index) = tree.node_at_level(&key, level).unwrap();
+                    let (lower, upper) = full_fences(&*node, &key);
+                    let in_node = |i: &u64| &i.to_be_bytes()[..] > &lower[..] && (upper.is_empty() || &i.to_be_bytes()[..] <= &upper[..]);
+                    let mut removed = Vec::new();
+                    if level == 0 {
+                        for i in (0..count).filter(in_node) {
+                            if (*node).is_underfull() {
+                                break;
+                            }
+                            remove_in_leaf(&mut tree, &i.to_be_bytes());
+                            removed.push(i);
+                        }
+                    } else {
+                        removed.extend((0..count).filter(in_node));
+                        for i in &removed {
+                            remove_in_leaf(&mut tree, &i.to_be_bytes());
+                        }
+                        while !(*node).is_underfull() {
+                            (*node).to_inner_mut().merge_children(0).unwrap();
+                        }
+                    }
+                    assert!((*node).is_underfull());
+                    let neighbor_keys = node_key_count(&*(*parent).to_inner().get_child(if index > 0 { index - 1 } else { 1 }));
+                    assert_eq!(borrow_from_sibling(parent, &key, index), Ok(()), "{leaf:?} {inner:?} {level}");
+                    tree.force_validate();
+                    assert_eq!(tree.verify_balanced(), Ok(()));
+                    let (receiver, neighbor) = ((*parent).to_inner().get_child(index), (*parent).to_inner().get_child(if index > 0 { index - 1 } else { 1 }));
+                    assert!(!(*receiver).is_underfull() && !(*neighbor).is_underfull());
+                    // only boundary entries moved, the neighbor keeps most of its entries
+                    assert!(node_key_count(&*neighbor) > neighbor_keys / 2, "{leaf:?} {inner:?} {level}");
+                    assert_eq!(tree.len(), (count as usize) - removed.len());
+                    let expected = (0..count).filter(|i| removed.binary_search(i).is_err());
+                    assert!(tree.keys().map(|k| u64::from_be_bytes(k[..].try_into().unwrap())).eq(expected));
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn remove_borrows_before_merging() {
+    crate::ensure_init();
+    let count = 20_000u64;
+    let key = (count / 2).to_be_bytes();
+    for neighbor_underfull in [true, false] {
+        let mut tree = integer_tree(0..count, BTreeNodeTag::BasicLeaf, BTreeNodeTag::BasicInner);
+        unsafe {
+            let (leaf, parent, index) = tree.node_at_level(&key, 0).unwrap();
+            let neighbor = (*parent).to_inner().get_child(index + 1);
+            let leaf_count = node_key_count(&*parent) + 1;
+            for node in if neighbor_underfull { vec![neighbor, leaf] } else { vec![leaf] } {
+                let (lower, _) = full_fences(&*node, &key);
+                let mut i = u64::from_be_bytes(lower[..].try_into().unwrap()) + 1;
+                while !(*node).is_underfull() {
+                    remove_in_leaf(&mut tree, &i.to_be_bytes());
+                    i += 1;
+                }
+            }
+            let ops_before = tree.structural_ops();
+            tree.rebalance(&key, leaf, parent, index);
+            tree.force_validate();
+            let ops = tree.structural_ops();
+            if neighbor_underfull {
+                // an underfull neighbor has nothing to spare, so both are merged
+                assert_eq!((ops.merges - ops_before.merges, ops.borrows - ops_before.borrows), (1, 0));
+                assert_eq!(node_key_count(&*parent) + 1, leaf_count - 1);
+            } else {
+                assert_eq!((ops.merges - ops_before.merges, ops.borrows - ops_before.borrows), (0, 1));
+                assert_eq!(node_key_count(&*parent) + 1, leaf_count);
+                assert!(!(*leaf).is_underfull());
+            }
+        }
+    }
+}
+
+#[test]
+fn sibling_lends_keys_instead_of_merging() {
+    crate::ensure_init();
+    let count = 20_000u64;
+    let key = (count / 2).to_be_bytes();
+    for leaf_tag in [BTreeNodeTag::BasicLeaf, BTreeNodeTag::HashLeaf] {
+        for inner in INNER_TAGS {
+            let mut tree = integer_tree(0..count, leaf_tag, inner);
+            unsafe {
+                let (leaf, parent, index) = tree.node_at_level(&key, 0).unwrap();
+                let (lower, _) = full_fences(&*leaf, &key);
+                let first = u64::from_be_bytes(lower[..].try_into().unwrap()) + 1;
+                let mut i = first;
+                while !(*leaf).is_underfull() {
+                    remove_in_leaf(&mut tree, &i.to_be_bytes());
+                    i += 1;
+                }
+                let (parent_tag, children) = ((*parent).tag(), node_key_count(&*parent) + 1);
+                let neighbor = (*parent).to_inner().get_child(index + 1);
+                let neighbor_keys = node_key_count(&*neighbor);
+                assert_eq!((*parent).to_inner_mut().merge_children_check(index, &key), Ok(Rebalance::Borrowed), "{leaf_tag:?} {inner:?}");
+                assert_eq!(((*parent).tag(), node_key_count(&*parent) + 1), (parent_tag, children), "{leaf_tag:?} {inner:?}");
+                assert_eq!(((*parent).to_inner().get_child(index), (*parent).to_inner().get_child(index + 1)), (leaf, neighbor));
+                assert!(!(*leaf).is_underfull() && !(*neighbor).is_underfull(), "{leaf_tag:?} {inner:?}");
+                assert!(node_key_count(&*neighbor) < neighbor_keys, "{leaf_tag:?} {inner:?}");
+                tree.force_validate();
+                assert_eq!(tree.len(), (count - (i - first)) as usize);
+                assert!(tree.keys().map(|k| u64::from_be_bytes(k[..].try_into().unwrap())).eq((0..count).filter(|k| !(first..i).contains(k))));
+            }
+        }
+    }
+}
+
+#[test]
+fn merge_children_run_coalesces_underfull_leaves() {
+    crate::ensure_init();
+    let count = 100_000u64;
+    let key = (count / 2).to_be_bytes();
+    for leaf in [BTreeNodeTag::BasicLeaf, BTreeNodeTag::HashLeaf] {
+        for inner in INNER_TAGS {
+            let mut tree = integer_tree(0..count, leaf, inner);
+            assert!(tree.height() > 2);
+            unsafe {
+                // thin out every leaf below one parent, like a large range delete without rebalancing would
+                let (parent, _, _) = tree.node_at_level(&key, 1).unwrap();
+                let (lower, upper) = full_fences(&*parent, &key);
+                let in_parent = |i: &u64| &i.to_be_bytes()[..] > &lower[..] && (upper.is_empty() || &i.to_be_bytes()[..] <= &upper[..]);
+                let removed: Vec<u64> = (0..count).filter(in_parent).filter(|i| i % 10 != 0).collect();
+                for i in &removed {
+                    remove_in_leaf(&mut tree, &i.to_be_bytes());
+                }
+                let children_before = node_key_count(&*parent) + 1;
+                let merged = (*parent).to_inner_mut().merge_children_run(0..children_before);
+                let children_after = node_key_count(&*parent) + 1;
+                assert_eq!(children_after, children_before - merged);
+                assert!(children_after * 4 <= children_before, "{leaf:?} {inner:?}: {children_before} children merged into {children_after}");
+                tree.force_validate();
+                assert_eq!(tree.verify_balanced(), Ok(()));
+                assert_eq!(tree.audit_pointers(), Ok(()));
+                assert_eq!(tree.check_space_accounting(), Ok(()));
+                assert_eq!(tree.len(), count as usize - removed.len());
+                let expected: Vec<u64> = (0..count).filter(|i| removed.binary_search(i).is_err()).collect();
+                assert!(tree.keys().map(|k| u64::from_be_bytes(k[..].try_into().unwrap())).eq(expected.iter().copied()));
+                for i in expected {
+                    assert_eq!(tree.get(i.to_be_bytes()), Some(&(i as u32).to_le_bytes()[..]), "{leaf:?} {inner:?}");
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn removing_every_key_leaves_empty_leaf_root() {
+    crate::ensure_init();
+    for leaf in [BTreeNodeTag::BasicLeaf, BTreeNodeTag::HashLeaf] {
+        let mut tree = integer_tree(0..20_000, leaf, BTreeNodeTag::BasicInner);
+        assert!(tree.height() > 1);
+        for i in (0..20_000u64).rev().step_by(2).chain((0..20_000u64).step_by(2)) {
+            assert!(unsafe { tree.remove(i.to_be_bytes()) }, "{leaf:?} {i}");
+        }
+        assert_eq!(tree.len(), 0);
+        assert!(tree.is_empty());
+        assert_eq!(tree.height(), 1);
+        assert!(tree.root_tag().is_leaf(), "{leaf:?}");
+        let fences = leaf_fences(unsafe { &*tree.root });
+        assert!(fences.lower_fence.0.is_empty() && fences.upper_fence.0.is_empty(), "{leaf:?}");
+        assert!((0..20_000u64).all(|i| tree.get(i.to_be_bytes()).is_none()));
+        let mut scanned = 0;
+        tree.range_lookup(b"", &mut |_, _| {
+            scanned += 1;
+            true
+        });
+        assert_eq!(scanned, 0);
+        // the emptied tree grows again like a new one
+        for i in (0..5_000u64).rev() {
+            tree.insert(i.to_be_bytes(), (i as u32).to_le_bytes()).unwrap();
+        }
+        assert_eq!(tree.len(), 5_000);
+        assert_eq!(tree.verify_balanced(), Ok(()));
+        assert!((0..5_000u64).all(|i| tree.get(i.to_be_bytes()) == Some(&(i as u32).to_le_bytes()[..])));
+    }
+}
+
+#[test]
+fn merges_stay_consistent_under_inner_adaptation() {
+    crate::ensure_init();
+    let mut rng = Xoshiro128PlusPlus::seed_from_u64(3);
+    for inner in INNER_TAGS {
+        let mut tree = BTree::new_with(BTreeNodeTag::BasicLeaf, inner);
+        let mut reference = BTreeMap::new();
+        for i in 0..60_000u32 {
+            let key = format!("{:05}", rng.gen_range(0..20_000u32)).into_bytes();
+            // mostly removes in the second half, so targeted descents for merges are frequent
+            if rng.gen_bool(if i < 30_000 { 0.8 } else { 0.15 }) {
+                tree.insert(&key, i.to_le_bytes()).unwrap();
+                reference.insert(key, i.to_le_bytes().to_vec());
+            } else {
+                assert_eq!(unsafe { tree.remove(&key) }, reference.remove(&key).is_some(), "{inner:?}");
+            }
+            // lookups descend with adaptation enabled, interleaving it with the structural changes
+            let probe = format!("{:05}", rng.gen_range(0..20_000u32)).into_bytes();
+            let mut len = 0u64;
+            let found = unsafe { tree.lookup(&mut len, &probe) };
+            match reference.get(&probe) {
+                Some(value) => assert_eq!(unsafe { std::slice::from_raw_parts(found, len as usize) }, &value[..], "{inner:?}"),
+                None => assert!(found.is_null(), "{inner:?}"),
+            }
+            if i % 5_000 == 0 {
+                assert_eq!(tree.verify_balanced(), Ok(()), "{inner:?}");
+                assert_eq!(tree.audit_pointers(), Ok(()), "{inner:?}");
+            }
+        }
+        assert!(tree.structural_ops().merges > 0, "{inner:?}");
+        assert_eq!(tree.count_entries(), reference.len(), "{inner:?}");
+        for (key, value) in &reference {
+            assert_eq!(tree.get(key), Some(&value[..]), "{inner:?}");
+        }
+    }
+}
+
+#[test]
+fn child_pointers_stay_valid_under_churn() {
+    crate::ensure_init();
+    fn check_children(node: *mut BTreeNode) {
+        let node = unsafe { &*node };
+        if node.tag().is_inner() {
+            let inner = node.to_inner();
+            for i in 0..inner.key_count() + 1 {
+                let child = inner.get_child(i);
+                assert!(!child.is_null() && child.is_aligned());
+                assert!(BTreeNodeTag::try_from(unsafe { (*child).raw_bytes[0] }).is_ok());
+                check_children(child);
+            }
+        }
+    }
+    let mut rng = Xoshiro128PlusPlus::seed_from_u64(10);
+    for inner in INNER_TAGS {
+        let mut tree = BTree::new_with(BTreeNodeTag::BasicLeaf, inner);
+        let mut present = BTreeSet::new();
+        for step in 0..60_000u32 {
+            let key = rng.gen_range(0..20_000u64);
+            // grow during the first half, shrink during the second
+            if rng.gen_bool(if step < 30_000 { 0.8 } else { 0.2 }) {
+                tree.insert(key.to_be_bytes(), step.to_le_bytes()).unwrap();
+                present.insert(key);
+            } else {
+                assert_eq!(unsafe { tree.remove(key.to_be_bytes()) }, present.remove(&key));
+            }
+            if step % 1_000 == 0 {
+                check_children(tree.root);
+            }
+        }
+        check_children(tree.root);
+        assert_eq!(tree.audit_pointers(), Ok(()));
+        assert!(tree.keys().map(|k| u64::from_be_bytes(k[..].try_into().unwrap())).eq(present.iter().copied()), "{inner:?}");
+    }
+}
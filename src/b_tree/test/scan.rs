@@ -0,0 +1,141 @@
+//! tests for range scans, unordered traversal and sampling
+
+use super::*;
+use rand::SeedableRng;
+use rand_xoshiro::Xoshiro128PlusPlus;
+use std::collections::{BTreeMap, BTreeSet};
+
+#[test]
+fn scan_stops_after_callback_returns_false() {
+    crate::ensure_init();
+    for leaf in [BTreeNodeTag::BasicLeaf, BTreeNodeTag::HashLeaf] {
+        let mut tree = integer_tree(0..20_000, leaf, BTreeNodeTag::BasicInner);
+        assert!(tree.height() > 1);
+        // limits around typical leaf sizes, so many scans stop at or next to a leaf boundary
+        for limit in (1..400).chain([1_000, 5_000, 20_000]) {
+            let mut seen = Vec::new();
+            tree.range_lookup(100u64.to_be_bytes(), &mut |key, _| {
+                seen.push(u64::from_be_bytes(key.try_into().unwrap()));
+                seen.len() < limit
+            });
+            assert!(seen.iter().copied().eq(100..(100 + limit as u64).min(20_000)), "{leaf:?} ascending {limit}");
+            let mut seen = Vec::new();
+            tree.range_lookup_desc(19_900u64.to_be_bytes(), &mut |key, _| {
+                seen.push(u64::from_be_bytes(key.try_into().unwrap()));
+                seen.len() < limit
+            });
+            assert!(seen.iter().copied().eq((19_901u64.saturating_sub(limit as u64)..19_901).rev()), "{leaf:?} descending {limit}");
+        }
+    }
+}
+
+#[test]
+fn scans_continue_after_maximal_separators() {
+    crate::ensure_init();
+    // payload is empty, so the key takes all the room an entry has
+    let key_len = MAX_KEY_LEN - if VALUE_ARENA { value_arena::ARENA_REF_SIZE } else { 0 };
+    // keys only differ in their last byte, so separators are as long as the keys
+    let key = |i: u32| {
+        let mut key = vec![b'k'; key_len - 4];
+        key.extend_from_slice(&i.to_be_bytes());
+        key
+    };
+    let scan = |tree: &mut BTree, start: &[u8], ascending: bool| {
+        let mut seen = Vec::new();
+        let callback = &mut |key: &[u8], _: &[u8]| {
+            seen.push(u32::from_be_bytes(key[key_len - 4..].try_into().unwrap()));
+            true
+        };
+        if ascending { tree.range_lookup(start, callback) } else { tree.range_lookup_desc(start, callback) }
+        seen
+    };
+    for leaf in [BTreeNodeTag::BasicLeaf, BTreeNodeTag::HashLeaf] {
+        let mut tree = BTree::new_with(leaf, BTreeNodeTag::BasicInner);
+        for i in 0..300u32 {
+            tree.insert(key(i), b"").unwrap();
+        }
+        assert!(tree.height() > 1);
+        for i in (0..300u32).step_by(7) {
+            assert!(scan(&mut tree, &key(i), true).into_iter().eq(i..300), "{leaf:?} ascending {i}");
+            assert!(scan(&mut tree, &key(i), false).into_iter().eq((0..=i).rev()), "{leaf:?} descending {i}");
+            // longer than any stored key, sorts between key(i) and key(i + 1)
+            let long_start = [key(i), vec![0xff; MAX_KEY_LEN]].concat();
+            assert!(scan(&mut tree, &long_start, true).into_iter().eq(i + 1..300), "{leaf:?} long ascending {i}");
+            assert!(scan(&mut tree, &long_start, false).into_iter().eq((0..=i).rev()), "{leaf:?} long descending {i}");
+        }
+    }
+}
+
+#[test]
+fn for_each_unordered_visits_every_entry() {
+    crate::ensure_init();
+    if STRIP_PREFIX {
+        return;
+    }
+    for leaf in [BTreeNodeTag::BasicLeaf, BTreeNodeTag::HashLeaf] {
+        let mut tree = BTree::new_with(leaf, BTreeNodeTag::BasicInner);
+        let mut reference = BTreeMap::new();
+        // shared prefixes, so leaves must restore them from their fences
+        for i in 0..10_000u32 {
+            let key = format!("user/{:03}/{}", i % 97, i).into_bytes();
+            tree.insert(&key, i.to_le_bytes()).unwrap();
+            reference.insert(key, i.to_le_bytes().to_vec());
+        }
+        assert!(tree.height() > 1);
+        let mut seen = Vec::new();
+        tree.for_each_unordered(&mut |key, value| seen.push((key.to_vec(), value.to_vec())));
+        seen.sort();
+        assert!(seen.into_iter().eq(reference.into_iter()), "{leaf:?}");
+    }
+}
+
+#[test]
+fn for_each_in_range_matches_btree_map() {
+    crate::ensure_init();
+    let mut rng = Xoshiro128PlusPlus::seed_from_u64(8);
+    let mut tree = BTree::new();
+    let mut reference = BTreeMap::new();
+    for i in 0..5_000u32 {
+        let key = format!("{:x}", rng.gen_range(0..20_000u32)).into_bytes();
+        tree.insert(&key, i.to_le_bytes()).unwrap();
+        reference.insert(key, i.to_le_bytes().to_vec());
+    }
+    let bounds = [&b""[..], b"1", b"4e20", b"a", b"ff", b"zz"];
+    for &lo in &bounds {
+        // `BTreeMap::range` rejects empty ranges with both ends excluded
+        for &hi in bounds.iter().filter(|&&hi| lo < hi) {
+            for (start, end) in [
+                (Bound::Included(lo), Bound::Included(hi)),
+                (Bound::Excluded(lo), Bound::Excluded(hi)),
+                (Bound::Included(lo), Bound::Unbounded),
+                (Bound::Unbounded, Bound::Excluded(hi)),
+            ] {
+                let mut scanned = Vec::new();
+                tree.for_each_in_range((start, end), &mut |key, value| scanned.push((key.to_vec(), value.to_vec())));
+                let expected: Vec<_> = reference.range::<[u8], _>((start, end)).map(|(k, v)| (k.clone(), v.clone())).collect();
+                assert_eq!(scanned, expected, "{start:?}..{end:?}");
+            }
+        }
+    }
+}
+
+#[test]
+fn sample_keys_draws_existing_keys() {
+    crate::ensure_init();
+    if STRIP_PREFIX {
+        return;
+    }
+    let mut rng = Xoshiro128PlusPlus::seed_from_u64(7);
+    for leaf in [BTreeNodeTag::BasicLeaf, BTreeNodeTag::HashLeaf] {
+        let tree = integer_tree(0..5_000, leaf, BTreeNodeTag::BasicInner);
+        for n in [0, 1, 100, 4_999, 5_000, 8_000] {
+            let sample = tree.sample_keys(n, &mut rng);
+            assert_eq!(sample.len(), n.min(5_000), "{leaf:?} {n}");
+            let distinct: BTreeSet<_> = sample.iter().collect();
+            assert_eq!(distinct.len(), sample.len(), "{leaf:?} {n}");
+            for key in &sample {
+                assert!(tree.get(key).is_some(), "{leaf:?} {key:?}");
+            }
+        }
+    }
+}
@@ -0,0 +1,158 @@
+//! tests for node splits
+
+use super::*;
+use crate::test_util::{INNER_TAGS, LEAF_TAGS};
+
+#[test]
+#[cfg(feature = "trace-structural_true")]
+#[cfg_attr(not(debug_assertions), ignore = "events are compiled out of release builds")]
+fn split_emits_structural_event() {
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use tracing::field::{Field, Visit};
+    use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+
+    type Fields = HashMap<String, String>;
+    struct Capture(Arc<Mutex<Vec<Fields>>>);
+    struct Recorder(Fields);
+    impl Visit for Recorder {
+        fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+            self.0.insert(field.name().to_string(), format!("{value:?}"));
+        }
+    }
+    impl<S: tracing::Subscriber> Layer<S> for Capture {
+        fn on_event(&self, event: &tracing::Event<'_>, _: Context<'_, S>) {
+            if event.metadata().target() == "structural" {
+                let mut recorder = Recorder(HashMap::new());
+                event.record(&mut recorder);
+                self.0.lock().unwrap().push(recorder.0);
+            }
+        }
+    }
+
+    crate::ensure_init();
+    let events = Arc::new(Mutex::new(Vec::new()));
+    tracing::subscriber::with_default(tracing_subscriber::registry().with(Capture(events.clone())), || {
+        let tree = integer_tree(0..1_000, BTreeNodeTag::BasicLeaf, BTreeNodeTag::BasicInner);
+        assert!(tree.height() > 1);
+    });
+    let events = events.lock().unwrap();
+    let split = events.iter().find(|e| e["message"] == "split").expect("no split event");
+    for field in ["tag_before", "left_tag", "right_tag", "separator_len", "converted"] {
+        assert!(split.contains_key(field), "{field} missing from {split:?}");
+    }
+    let count = |field: &str| split[field].parse::<usize>().unwrap();
+    // the first split is the root leaf, which hands all its keys to the two halves
+    assert_eq!(count("left_key_count") + count("right_key_count"), count("key_count_before"));
+    assert!(count("separator_len") > 0);
+}
+
+#[test]
+fn inner_key_cap_makes_tree_taller() {
+    crate::ensure_init();
+    // enough leaves that the root of an uncapped two level tree holds more keys than the cap allows
+    let mut uncapped = BTree::new_with(BTreeNodeTag::BasicLeaf, BTreeNodeTag::BasicInner);
+    let mut count = 0u64;
+    while uncapped.height() < 2 || unsafe { (*uncapped.root).to_inner().key_count() } < 100 {
+        uncapped.insert(count.to_be_bytes(), [1u8; 4]).unwrap();
+        count += 1;
+    }
+    assert_eq!(uncapped.height(), 2);
+    let mut capped = BTree::new_with(BTreeNodeTag::BasicLeaf, BTreeNodeTag::BasicInner);
+    {
+        let _cap = crate::test_util::InnerKeyCapGuard::new(64);
+        for i in 0..count {
+            capped.insert(i.to_be_bytes(), [1u8; 4]).unwrap();
+        }
+    }
+    assert!(capped.height() > uncapped.height());
+    for tree in [&mut uncapped, &mut capped] {
+        assert_eq!(tree.len(), count as usize);
+        assert_eq!(tree.verify_balanced(), Ok(()));
+        unsafe { (*tree.root).validate_tree(&[], &[]) };
+        assert!((0..count).all(|i| tree.get(i.to_be_bytes()).is_some()));
+    }
+}
+
+#[test]
+fn splits_and_merges_conserve_keys_for_every_node_type() {
+    crate::ensure_init();
+    // the conservation assertions run on every split and merge in debug builds
+    for leaf in LEAF_TAGS {
+        for inner in INNER_TAGS {
+            let mut tree = BTree::new_with(leaf, inner);
+            for i in 0..30_000u64 {
+                tree.insert(format!("{:x}", i.wrapping_mul(0x9e37_79b9_7f4a_7c15)), i.to_le_bytes()).unwrap();
+            }
+            assert!(tree.height() > 1, "{leaf:?} {inner:?}");
+            for i in (0..30_000u64).filter(|i| i % 10 != 0) {
+                assert!(unsafe { tree.remove(format!("{:x}", i.wrapping_mul(0x9e37_79b9_7f4a_7c15))) }, "{leaf:?} {inner:?}");
+            }
+            let ops = tree.structural_ops();
+            assert!(ops.splits > 0 && ops.merges > 0, "{leaf:?} {inner:?}: {ops:?}");
+            assert_eq!(tree.count_entries(), 3_000, "{leaf:?} {inner:?}");
+            assert_eq!(tree.verify_balanced(), Ok(()));
+        }
+    }
+}
+
+#[test]
+fn degenerate_split_is_reported() {
+    crate::ensure_init();
+    let mut tree = BTree::new();
+    let key = [7u8; MAX_KEY_LEN - 1];
+    tree.insert(key, [1]).unwrap();
+    // inserting into a single entry leaf never needs a split, force one directly
+    let root = tree.root;
+    assert_eq!(unsafe { tree.split_node(root, ptr::null_mut(), &key, 0) }, Err(NodeOpError::DegenerateSplit));
+    assert_eq!(tree.root, root);
+    assert_eq!(tree.iter().collect::<Vec<_>>(), vec![(key.to_vec(), vec![1])]);
+}
+
+#[test]
+fn split_that_does_not_shrink_a_half_is_reported() {
+    crate::ensure_init();
+    // the separator is the long middle key, which becomes the upper fence of the left half
+    let keys = [b"a".to_vec(), [&b"a"[..], &[1u8; 999]].concat(), b"b".to_vec()];
+    let mut tree = BTree::new_with(BTreeNodeTag::BasicLeaf, BTreeNodeTag::BasicInner);
+    for key in &keys {
+        tree.insert(key, b"").unwrap();
+    }
+    let root = tree.root;
+    let bytes_before = node_content_bytes(unsafe { &*root });
+    assert_eq!(unsafe { tree.split_node(root, ptr::null_mut(), &keys[0], 0) }, Err(NodeOpError::DegenerateSplit));
+    // the split itself is valid, it just did not help
+    let left = unsafe { (*tree.root).to_inner().get_child(0) };
+    assert!(node_content_bytes(unsafe { &*left }) >= bytes_before);
+    assert_eq!(tree.verify_balanced(), Ok(()));
+    assert!(tree.keys().eq(keys.iter().cloned()));
+}
+
+#[test]
+fn inner_node_with_single_key_is_not_split() {
+    crate::ensure_init();
+    use crate::test_util::{ManualInnerSource, ManualLeafSource};
+    // near identical keys, the separator is as long as the keys on either side
+    let key_len = MAX_KEY_LEN - 1 - if VALUE_ARENA { value_arena::ARENA_REF_SIZE } else { 0 };
+    let long = |last: u8| [&vec![7u8; key_len - 1][..], &[last]].concat();
+    let separator = long(2);
+    let mut tree = BTree::new_with(BTreeNodeTag::BasicLeaf, BTreeNodeTag::BasicInner);
+    unsafe {
+        let left = ManualLeafSource { lower_fence: Vec::new(), upper_fence: separator.clone(), entries: vec![(long(1), Vec::new()), (long(2), Vec::new())] };
+        let right = ManualLeafSource { lower_fence: separator.clone(), upper_fence: Vec::new(), entries: vec![(long(3), Vec::new())] };
+        let children = vec![left.build(BTreeNodeTag::BasicLeaf).unwrap(), right.build(BTreeNodeTag::BasicLeaf).unwrap()];
+        let root = ManualInnerSource { lower_fence: Vec::new(), upper_fence: Vec::new(), keys: vec![separator.clone()], children }
+            .build(BTreeNodeTag::BasicInner)
+            .unwrap();
+        BTreeNode::dealloc(tree.root);
+        tree.root = root;
+        tree.count = 3;
+        assert_eq!(tree.split_node(root, ptr::null_mut(), &separator, 0), Err(NodeOpError::DegenerateSplit));
+        assert_eq!(tree.root, root);
+    }
+    assert_eq!(tree.verify_balanced(), Ok(()));
+    assert!(tree.keys().eq([long(1), long(2), long(3)]));
+    // splitting the left leaf moves its second key into the right half, both halves shrink
+    tree.insert(long(0), b"").unwrap();
+    assert!(tree.keys().eq([long(0), long(1), long(2), long(3)]));
+}
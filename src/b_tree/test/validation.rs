@@ -0,0 +1,95 @@
+//! tests for the consistency checks and page accounting
+
+use super::*;
+use crate::test_util::{INNER_TAGS, LEAF_TAGS};
+use rand::SeedableRng;
+use rand_xoshiro::Xoshiro128PlusPlus;
+
+#[test]
+fn hand_built_unbalanced_tree_is_reported() {
+    use crate::test_util::ManualInnerSource;
+    crate::ensure_init();
+    let inner = |lower: &[u8], key: &[u8], children: Vec<*mut BTreeNode>| {
+        ManualInnerSource { lower_fence: lower.to_vec(), upper_fence: Vec::new(), keys: vec![key.to_vec()], children }
+            .build(BTreeNodeTag::BasicInner)
+            .unwrap()
+    };
+    // the root leaf of the empty tree becomes the shallow leaf
+    let mut tree = BTree::new();
+    let right = inner(b"m", b"t", vec![BTreeNode::new_leaf(), BTreeNode::new_leaf()]);
+    tree.root = inner(b"", b"m", vec![tree.root, right]);
+    assert_eq!(tree.verify_balanced(), Err(()));
+    let mut tree = BTree::new();
+    let left = inner(b"", b"f", vec![tree.root, BTreeNode::new_leaf()]);
+    let right = inner(b"m", b"t", vec![BTreeNode::new_leaf(), BTreeNode::new_leaf()]);
+    tree.root = inner(b"", b"m", vec![left, right]);
+    assert_eq!(tree.verify_balanced(), Ok(()));
+}
+
+#[test]
+fn duplicated_child_pointer_is_reported() {
+    use crate::test_util::ManualInnerSource;
+    crate::ensure_init();
+    let tree = integer_tree(0..20_000, BTreeNodeTag::HashLeaf, BTreeNodeTag::BasicInner);
+    assert!(tree.height() > 1);
+    assert_eq!(tree.audit_pointers(), Ok(()));
+    // the root leaf of the empty tree is referenced by both children of the hand built root
+    let mut tree = BTree::new();
+    let leaf = tree.root;
+    let root = ManualInnerSource { lower_fence: Vec::new(), upper_fence: Vec::new(), keys: vec![b"m".to_vec()], children: vec![leaf, leaf] }
+        .build(BTreeNodeTag::BasicInner)
+        .unwrap();
+    tree.root = root;
+    assert_eq!(tree.audit_pointers(), Err(()));
+    // dropping the tree would free the leaf twice
+    tree.root = leaf;
+    unsafe { BTreeNode::dealloc(root) };
+}
+
+#[test]
+fn space_accounting_holds_after_churn() {
+    crate::ensure_init();
+    for (leaf, inner) in [(BTreeNodeTag::BasicLeaf, BTreeNodeTag::BasicInner), (BTreeNodeTag::HashLeaf, BTreeNodeTag::U64ExplicitHead)] {
+        let mut rng = Xoshiro128PlusPlus::seed_from_u64(2);
+        let mut tree = BTree::new_with(leaf, inner);
+        for round in 0..100_000u32 {
+            let key = format!("key{}", rng.gen_range(0..20_000u32));
+            // growing and shrinking updates, removes and scans that adapt leaves
+            match rng.gen_range(0..10) {
+                0..=5 => tree.insert(&key, vec![round as u8; rng.gen_range(0..40)]).unwrap(),
+                6..=8 => {
+                    unsafe { tree.remove(&key) };
+                }
+                _ => tree.range_lookup(&key, &mut |_, _| rng.gen_bool(0.95)),
+            }
+            if round % 10_000 == 0 {
+                assert_eq!(tree.check_space_accounting(), Ok(()), "{leaf:?} round {round}");
+            }
+        }
+        assert_eq!(tree.check_space_accounting(), Ok(()), "{leaf:?}");
+    }
+    // a drifted counter is detected
+    let tree = BTree::new_with(BTreeNodeTag::BasicLeaf, BTreeNodeTag::BasicInner);
+    assert_eq!(tree.check_space_accounting(), Ok(()));
+    unsafe { (*tree.root).basic.head.space_used += 1 };
+    assert_eq!(tree.check_space_accounting(), Err(()));
+    unsafe { (*tree.root).basic.head.space_used -= 1 };
+}
+
+#[test]
+fn destroyed_trees_free_every_page() {
+    crate::ensure_init();
+    let live_pages = || crate::btree_node::LIVE_PAGES.with(|live| live.get());
+    for leaf in LEAF_TAGS {
+        for inner in INNER_TAGS {
+            let before = live_pages();
+            let mut tree = integer_tree(0..5_000, leaf, inner);
+            for i in (0..5_000u64).step_by(3) {
+                assert!(unsafe { tree.remove(i.to_be_bytes()) });
+            }
+            assert!(live_pages() > before + 1, "{leaf:?} {inner:?}");
+            unsafe { crate::btree_destroy(Box::into_raw(Box::new(tree))) };
+            assert_eq!(live_pages(), before, "{leaf:?} {inner:?}");
+        }
+    }
+}
@@ -1,16 +1,17 @@
-use crate::btree_node::{AdaptionState, BASIC_PREFIX, BTreeNode, BTreeNodeHead, PAGE_SIZE};
+use crate::btree_node::{AdaptionState, BASIC_PREFIX, BTreeNode, BTreeNodeHead, LAZY_ZERO, PAGE_SIZE, PageBytes, VALUE_ALIGN};
 use crate::find_separator::find_separator;
 
-use crate::node_traits::{FenceData, FenceRef, InnerConversionSink, InnerConversionSource, InnerNode, LeafNode, merge, Node, SeparableInnerConversionSource, split_in_place};
-use crate::util::{common_prefix_len, get_key_from_slice, head, MergeFences, partial_restore, reinterpret_mut, short_slice, SmallBuff, SplitFences, trailing_bytes};
+use crate::node_traits::{debug_assert_children_valid, merge_candidates, FenceData, FenceRef, InnerConversionSink, InnerConversionSource, InnerNode, LeafNode, merge, Node, SeparableInnerConversionSource, split_in_place, NodeOpError, inner_key_cap_reached, debug_assert_split_conserves_keys, debug_assert_merge_conserves_keys};
+use crate::util::{aligned_value_end, common_prefix_len, get_key_from_slice, head, KeyBuffer, MergeFences, partial_restore, reinterpret_mut, SmallBuff, SplitFences, trailing_bytes};
 use crate::{FatTruncatedKey, PrefixTruncatedKey};
-use std::mem::{size_of, transmute};
+use std::mem::{MaybeUninit, size_of};
 
-use std::{mem, ptr};
+use std::ptr;
 use std::cmp::Ordering;
 use std::ops::Range;
 use crate::adaptive::{infrequent};
 use crate::branch_cache::BranchCacheAccessor;
+use crate::node_stats::{BASIC_INNER_REQUEST_SPACE, BASIC_LEAF_REQUEST_SPACE};
 use crate::vtables::BTreeNodeTag;
 
 #[derive(Clone, Copy)]
@@ -25,12 +26,12 @@ pub struct BasicSlot {
 }
 
 impl BasicSlot {
-    pub fn key<'a>(&self, page: &'a [u8; PAGE_SIZE]) -> PrefixTruncatedKey<'a> {
-        PrefixTruncatedKey(short_slice(page, self.offset, self.key_len))
+    pub fn key<'a>(&self, page: &'a PageBytes) -> PrefixTruncatedKey<'a> {
+        PrefixTruncatedKey(page.short_slice(self.offset, self.key_len))
     }
 
-    pub fn value<'a>(&self, page: &'a [u8; PAGE_SIZE]) -> &'a [u8] {
-        short_slice(page, self.offset + self.key_len, self.val_len)
+    pub fn value<'a>(&self, page: &'a PageBytes) -> &'a [u8] {
+        page.short_slice(self.offset + self.key_len, self.val_len)
     }
 
     pub fn head_len(&self) -> usize {
@@ -45,6 +46,11 @@ pub struct FenceKeySlot {
 }
 
 pub const HINT_COUNT: usize = 16;
+
+/// below this count all hints would sample the first few slots, so they are neither maintained nor used
+fn hints_used(count: usize) -> bool {
+    count > HINT_COUNT * 2
+}
 const DYNAMIC_PREFIX: bool = cfg!(feature = "dynamic-prefix_true");
 
 #[derive(Clone, Copy, Debug)]
@@ -67,8 +73,8 @@ pub struct BasicNodeHead {
 #[derive(Clone, Copy)]
 #[repr(C)]
 pub union BasicNodeData {
-    bytes: [u8; PAGE_SIZE - size_of::<BasicNodeHead>()],
-    slots: [BasicSlot; (PAGE_SIZE - size_of::<BasicNodeHead>()) / size_of::<BasicSlot>()],
+    bytes: MaybeUninit<[u8; PAGE_SIZE - size_of::<BasicNodeHead>()]>,
+    slots: MaybeUninit<[BasicSlot; (PAGE_SIZE - size_of::<BasicNodeHead>()) / size_of::<BasicSlot>()]>,
 }
 
 #[derive(Clone, Copy)]
@@ -78,18 +84,20 @@ pub struct BasicNode {
     pub data: BasicNodeData,
 }
 
+const _: () = assert!(size_of::<BasicNode>() == PAGE_SIZE);
+
 impl BasicNode {
     fn new(leaf: bool) -> Self {
         BasicNode {
             head: BasicNodeHead {
-                head: BTreeNodeHead {
-                    tag: if leaf {
+                head: BTreeNodeHead::new(
+                    if leaf {
                         BTreeNodeTag::BasicLeaf
                     } else {
                         BTreeNodeTag::BasicInner
                     },
-                    adaption_state: AdaptionState::new(),
-                },
+                    AdaptionState::new(),
+                ),
                 upper: ptr::null_mut(),
                 lower_fence: FenceKeySlot { offset: 0, len: 0 },
                 upper_fence: FenceKeySlot { offset: 0, len: 0 },
@@ -101,8 +109,9 @@ impl BasicNode {
                 hint: [0; HINT_COUNT],
                 dynamic_prefix_len: 0,
             },
+            // basic nodes only read data referenced by slots and fences, so unused space may stay uninitialized
             data: BasicNodeData {
-                bytes: unsafe { mem::zeroed() },
+                bytes: if LAZY_ZERO { MaybeUninit::uninit() } else { MaybeUninit::zeroed() },
             },
         }
     }
@@ -113,19 +122,22 @@ impl BasicNode {
             for w in self.slots().windows(2) {
                 assert!(w[0].key(self.as_bytes()).0 <= w[1].key(self.as_bytes()).0);
             }
-            assert_eq!(
-                self.head.space_used,
-                self.slots()
-                    .iter()
-                    .map(|s| s.key_len + s.val_len)
-                    .sum::<u16>()
-                    + self.head.lower_fence.len
-                    + self.head.upper_fence.len
-            );
+            assert!(self.check_space_accounting().is_ok());
             self.assert_no_collide();
         }
     }
 
+    /// checks that `space_used` matches the space taken by fences, keys and payloads
+    pub fn check_space_accounting(&self) -> Result<(), ()> {
+        let used = self.slots()
+            .iter()
+            .map(|s| (s.key_len + s.val_len) as usize + self.value_padding())
+            .sum::<usize>()
+            + self.head.lower_fence.len as usize
+            + self.head.upper_fence.len as usize;
+        if used == self.head.space_used as usize { Ok(()) } else { Err(()) }
+    }
+
     pub fn upper(&self) -> *mut BTreeNode {
         self.head.upper
     }
@@ -140,14 +152,39 @@ impl BasicNode {
         r
     }
 
-    pub fn as_bytes(&self) -> &[u8; PAGE_SIZE] {
-        assert_eq!(PAGE_SIZE, size_of::<Self>());
-        unsafe { transmute(self as *const Self) }
+    pub fn as_bytes(&self) -> &PageBytes {
+        PageBytes::of(self)
+    }
+
+    fn as_bytes_mut(&mut self) -> &mut PageBytes {
+        PageBytes::of_mut(self)
     }
 
-    unsafe fn as_bytes_mut(&mut self) -> &mut [u8; PAGE_SIZE] {
-        assert_eq!(PAGE_SIZE, size_of::<Self>());
-        transmute(self as *mut Self)
+    /// copies head, slots, fences and entries to the same positions in `out` field by field,
+    /// so padding and unused space of `out` keep their previous contents
+    pub fn copy_written_to(&self, out: &mut BasicNode) {
+        let (src, dst) = (&self.head, &mut out.head);
+        src.head.copy_fields_to(&mut dst.head);
+        dst.count = src.count;
+        dst.space_used = src.space_used;
+        dst.data_offset = src.data_offset;
+        dst.upper = src.upper;
+        dst.lower_fence = src.lower_fence;
+        dst.upper_fence = src.upper_fence;
+        dst.prefix_len = src.prefix_len;
+        dst.dynamic_prefix_len = src.dynamic_prefix_len;
+        #[cfg(any(feature = "basic-use-hint_true", feature = "basic-use-hint_naive"))]{
+            dst.hint = src.hint;
+        }
+        for fence in [self.head.lower_fence, self.head.upper_fence] {
+            out.as_bytes_mut().write(fence.offset as usize, self.as_bytes().short_slice(fence.offset, fence.len));
+        }
+        // slots are packed, so copying them copies no padding
+        out.slots_mut().copy_from_slice(self.slots());
+        for s in self.slots() {
+            out.as_bytes_mut().write(s.offset as usize, s.key(self.as_bytes()).0);
+            out.as_bytes_mut().write((s.offset + s.key_len) as usize, s.value(self.as_bytes()));
+        }
     }
 
     pub fn prefix<'a>(&self, src: &'a [u8]) -> &'a [u8] {
@@ -160,11 +197,11 @@ impl BasicNode {
     }
 
     pub fn slots(&self) -> &[BasicSlot] {
-        unsafe { &self.data.slots[..self.head.count as usize] }
+        unsafe { std::slice::from_raw_parts(self.data.slots.as_ptr() as *const BasicSlot, self.head.count as usize) }
     }
 
     pub fn slots_mut(&mut self) -> &mut [BasicSlot] {
-        unsafe { &mut self.data.slots[..self.head.count as usize] }
+        unsafe { std::slice::from_raw_parts_mut(self.data.slots.as_mut_ptr() as *mut BasicSlot, self.head.count as usize) }
     }
 
     fn dynamic_prefix(&self) -> &[u8] {
@@ -219,7 +256,7 @@ impl BasicNode {
     fn search_hint(&self, head: u32) -> (usize, usize) {
         #[cfg(any(feature = "basic-use-hint_true",feature = "basic-use-hint_naive"))]{
             debug_assert!(self.head.count > 0);
-            if self.head.count as usize > HINT_COUNT * 2 {
+            if hints_used(self.head.count as usize) {
                 let dist = self.head.count as usize / (HINT_COUNT + 1);
                 let pos = (0..HINT_COUNT)
                     .find(|&hi| self.head.hint[hi] >= head)
@@ -260,7 +297,7 @@ impl BasicNode {
         self.validate();
     }
 
-    fn free_space(&self) -> usize {
+    pub fn free_space(&self) -> usize {
         self.head.data_offset as usize
             - size_of::<BasicNodeHead>()
             - self.slots().len() * size_of::<BasicSlot>()
@@ -273,14 +310,18 @@ impl BasicNode {
             - self.slots().len() * size_of::<BasicSlot>()
     }
 
-    pub fn request_space(&mut self, space: usize) -> Result<usize, ()> {
+    pub fn request_space(&mut self, space: usize) -> Result<usize, NodeOpError> {
+        let counters = if self.head.head.tag.is_leaf() { &BASIC_LEAF_REQUEST_SPACE } else { &BASIC_INNER_REQUEST_SPACE };
         if space <= self.free_space() {
+            counters.count_fit();
             Ok(self.head.prefix_len as usize)
         } else if space <= self.free_space_after_compaction() {
+            counters.count_compacted();
             self.compactify();
             Ok(self.head.prefix_len as usize)
         } else {
-            Err(())
+            counters.count_failed();
+            Err(NodeOpError::NoSpace)
         }
     }
 
@@ -293,7 +334,8 @@ impl BasicNode {
         tmp.head.upper = self.head.upper;
         *self = tmp;
         self.make_hint();
-        debug_assert!(self.free_space() == should);
+        // padding may turn out smaller than charged
+        debug_assert!(self.free_space() == should || VALUE_ALIGN > 1 && self.free_space() > should);
     }
 
     fn copy_key_value_range(
@@ -323,23 +365,25 @@ impl BasicNode {
         let previous_offset = dst.head.data_offset;
         let offset = if self.head.prefix_len <= dst.head.prefix_len {
             // string shrinks or stays same length
-            dst.write_data(src_slot.value(self.as_bytes()));
+            dst.write_value(src_slot.value(self.as_bytes()));
             dst.write_data(&trailing_bytes(
                 src_slot.key(self.as_bytes()).0,
                 new_key_len as usize,
             ))
         } else {
             // string grows
-            dst.write_data(src_slot.value(self.as_bytes()));
+            // restored bytes are taken from prefix_src, which must cover dst's prefix
+            debug_assert!(prefix_src.prefix_len <= dst.head.prefix_len as usize);
+            debug_assert!(prefix_src.prefix_len + prefix_src.remainder.len() >= self.head.prefix_len as usize);
+            dst.write_value(src_slot.value(self.as_bytes()));
             dst.write_data(src_slot.key(self.as_bytes()).0);
             dst.write_data(trailing_bytes(
                 &prefix_src.remainder[..self.head.prefix_len as usize - prefix_src.prefix_len],
                 (self.head.prefix_len - dst.head.prefix_len) as usize,
             ))
         };
-        debug_assert_eq!(offset + new_key_len + src_slot.val_len, previous_offset);
-        let (head, _) = head(short_slice(
-            dst.as_bytes(),
+        debug_assert!(previous_offset - (offset + new_key_len + src_slot.val_len) <= dst.value_padding() as u16);
+        let (head, _) = head(dst.as_bytes().short_slice(
             offset + dst.head.dynamic_prefix_len,
             new_key_len,
         ));
@@ -378,7 +422,7 @@ impl BasicNode {
         prefix_truncated_key: PrefixTruncatedKey,
         payload: &[u8],
     ) {
-        self.write_data(payload);
+        self.write_value(payload);
         let key_offset = self.write_data(prefix_truncated_key.0);
         self.slots_mut()[slot_id] = BasicSlot {
             offset: key_offset,
@@ -396,20 +440,35 @@ impl BasicNode {
         debug_assert!(slot_end <= data_start);
     }
 
+    /// space charged per entry for aligning its payload, regardless of the padding actually needed
+    fn value_padding(&self) -> usize {
+        if self.head.head.tag.is_leaf() { VALUE_ALIGN - 1 } else { 0 }
+    }
+
+    fn write_value(&mut self, d: &[u8]) -> u16 {
+        let padding = self.value_padding();
+        if padding > 0 {
+            self.head.data_offset = aligned_value_end(self.head.data_offset as usize, d.len()) as u16;
+            self.head.space_used += padding as u16;
+        }
+        self.write_data(d)
+    }
+
     fn write_data(&mut self, d: &[u8]) -> u16 {
         self.head.data_offset -= d.len() as u16;
         self.head.space_used += d.len() as u16;
         self.assert_no_collide();
         let offset = self.head.data_offset;
-        unsafe {
-            self.as_bytes_mut()[offset as usize..][..d.len()].copy_from_slice(d);
-        }
+        self.as_bytes_mut().write(offset as usize, d);
         offset
     }
 
     fn update_hint(&mut self, slot_id: usize) {
          #[cfg(feature = "basic-use-hint_true")]{
             let count = self.head.count as usize;
+            if !hints_used(count) {
+                return;
+            }
             let dist = count / (HINT_COUNT + 1);
             let begin = if (count > HINT_COUNT * 2 + 1)
                 && (((count - 1) / (HINT_COUNT + 1)) == dist)
@@ -432,7 +491,7 @@ impl BasicNode {
     pub fn make_hint(&mut self) {
         #[cfg(any(feature = "basic-use-hint_true", feature = "basic-use-hint_naive"))]{
             let count = self.head.count as usize;
-            if count == 0 {
+            if !hints_used(count) {
                 return;
             }
             let dist = count / (HINT_COUNT + 1);
@@ -444,7 +503,12 @@ impl BasicNode {
     }
 
     pub fn space_needed(&self, key_length: usize, payload_length: usize) -> usize {
-        key_length + payload_length + size_of::<BasicSlot>() - self.head.prefix_len as usize
+        key_length + payload_length + self.value_padding() + size_of::<BasicSlot>() - self.head.prefix_len as usize
+    }
+
+    /// number of uniformly sized entries an empty leaf with empty fences can hold
+    pub fn leaf_capacity(prefix_len: usize, key_length: usize, payload_length: usize) -> usize {
+        (PAGE_SIZE - size_of::<BasicNodeHead>()) / (key_length + payload_length + VALUE_ALIGN - 1 + size_of::<BasicSlot>() - prefix_len)
     }
 
     pub fn merge_right(
@@ -452,7 +516,7 @@ impl BasicNode {
         is_inner: bool,
         right_any: &mut BTreeNode,
         separator: FatTruncatedKey,
-    ) -> Result<(), ()> {
+    ) -> Result<(), NodeOpError> {
         if self.head.head.tag.is_leaf() {
             debug_assert!(right_any.tag() == self.head.head.tag);
         } else {
@@ -460,7 +524,7 @@ impl BasicNode {
                 let mut dst = BTreeNode::new_uninit();
                 let right = right_any.to_inner();
                 if !right.is_underfull() {
-                    return Err(());
+                    return Err(NodeOpError::NotApplicable);
                 }
                 merge::<Self, dyn InnerNode, dyn InnerNode>(&mut dst, self, right, separator)?;
                 ptr::write(right_any, dst);
@@ -484,7 +548,7 @@ impl BasicNode {
             + right_grow as usize
             + if is_inner { separator_space } else { 0 };
         if space_upper_bound > PAGE_SIZE {
-            return Err(());
+            return Err(NodeOpError::NoSpace);
         }
         let mut tmp = BasicNode::new(self.head.head.tag.is_leaf());
         tmp.head.head.adaption_state = right.head.head.adaption_state;
@@ -495,12 +559,13 @@ impl BasicNode {
         self.copy_key_value_range(self.slots(), &mut tmp, separator);
         right.copy_key_value_range(right.slots(), &mut tmp, separator);
         tmp.make_hint();
+        debug_assert_merge_conserves_keys(true, self.head.count as usize, right.head.count as usize, tmp.head.count as usize);
         right_any.basic = tmp;
         Ok(())
     }
 
     pub fn remove_slot(&mut self, index: usize) {
-        self.head.space_used -= self.slots()[index].key_len + self.slots()[index].val_len;
+        self.head.space_used -= self.slots()[index].key_len + self.slots()[index].val_len + self.value_padding() as u16;
         let back_slots = &mut self.slots_mut()[index..];
         back_slots.copy_within(1.., 0);
         self.head.count -= 1;
@@ -549,14 +614,8 @@ impl BasicNode {
 impl InnerConversionSource for BasicNode {
     fn fences(&self) -> FenceData {
         FenceData {
-            lower_fence: FenceRef(
-                &self.as_bytes()[self.head.lower_fence.offset as usize..]
-                    [..self.head.lower_fence.len as usize],
-            ),
-            upper_fence: FenceRef(
-                &self.as_bytes()[self.head.upper_fence.offset as usize..]
-                    [..self.head.upper_fence.len as usize],
-            ),
+            lower_fence: FenceRef(self.as_bytes().short_slice(self.head.lower_fence.offset, self.head.lower_fence.len)),
+            upper_fence: FenceRef(self.as_bytes().short_slice(self.head.upper_fence.offset, self.head.upper_fence.len)),
             prefix_len: self.head.prefix_len as usize,
         }
     }
@@ -578,10 +637,14 @@ impl InnerConversionSource for BasicNode {
         }
     }
 
-    fn get_key(&self, index: usize, dst: &mut [u8], strip_prefix: usize) -> Result<usize, ()> {
+    fn get_key(&self, index: usize, dst: &mut [u8], strip_prefix: usize) -> Result<usize, NodeOpError> {
         get_key_from_slice(self.slots()[index].key(self.as_bytes()), dst, strip_prefix)
     }
 
+    fn get_key_ref(&self, index: usize) -> Option<PrefixTruncatedKey> {
+        Some(self.slots()[index].key(self.as_bytes()))
+    }
+
     fn get_key_length_sum(&self, range: Range<usize>) -> usize {
         self.slots()[range].iter().map(|s| s.key_len as usize).sum()
     }
@@ -597,7 +660,7 @@ unsafe impl Node for BasicNode {
         parent: &mut dyn InnerNode,
         index_in_parent: usize,
         key_in_node: &[u8],
-    ) -> Result<(), ()> {
+    ) -> Result<(), NodeOpError> {
         if self.head.head.tag.is_inner() {
             type Dst = crate::btree_node::DefaultInnerNodeConversionSink;
             return split_in_place::<BasicNode, Dst, Dst>(
@@ -611,7 +674,7 @@ unsafe impl Node for BasicNode {
         // split
         let (sep_slot, truncated_sep_key) = self.find_separator();
         let full_sep_key_len = truncated_sep_key.0.len() + self.head.prefix_len as usize;
-        let parent_prefix_len = parent.request_space_for_child(full_sep_key_len)?;
+        let parent_prefix_len = parent.request_space_for_child(full_sep_key_len).map_err(|_| NodeOpError::NeedsSplit)?;
         let node_left_raw;
         let node_left = unsafe {
             node_left_raw = BTreeNode::alloc();
@@ -626,9 +689,9 @@ unsafe impl Node for BasicNode {
         node_left.head.head.adaption_state = self.head.head.adaption_state;
         node_right.head.head.adaption_state = self.head.head.adaption_state;
         unsafe {
-            if let Err(()) = parent.insert_child(index_in_parent, split_fences.separator(), node_left_raw) {
+            if let Err(_) = parent.insert_child(index_in_parent, split_fences.separator(), node_left_raw) {
                 BTreeNode::dealloc(node_left_raw);
-                return Err(());
+                return Err(NodeOpError::NeedsSplit);
             }
         }
 
@@ -644,6 +707,7 @@ unsafe impl Node for BasicNode {
         );
         node_left.make_hint();
         node_right.make_hint();
+        debug_assert_split_conserves_keys(true, self.head.count as usize, node_left.head.count as usize, node_right.head.count as usize);
         *self = node_right;
         Ok(())
     }
@@ -674,6 +738,7 @@ unsafe impl Node for BasicNode {
             upper_fence: FenceRef(upper),
         }.restrip());
         if self.head.head.tag.is_inner() {
+            debug_assert_children_valid(self);
             let mut current_lower: SmallBuff = lower.into();
             for (i, s) in self.slots().iter().enumerate() {
                 let current_upper =
@@ -688,41 +753,45 @@ unsafe impl Node for BasicNode {
 }
 
 unsafe impl InnerConversionSink for BasicNode {
-    fn create(dst: &mut BTreeNode, src: &(impl InnerConversionSource + ?Sized)) -> Result<(), ()> {
+    fn create(dst: &mut BTreeNode, src: &(impl InnerConversionSource + ?Sized)) -> Result<(), NodeOpError> {
         let key_count = src.key_count();
+        let fences = src.fences();
+        if size_of::<BasicNodeHead>() + fences.lower_fence.0.len() + fences.upper_fence.0.len() > PAGE_SIZE {
+            return Err(NodeOpError::NoSpace);
+        }
         let this = dst.write_inner(BasicNode::new_inner(src.get_child(key_count)));
-        this.set_fences(src.fences());
+        this.set_fences(fences);
 
         if this.free_space() < size_of::<BasicSlot>() * key_count {
-            return Err(());
+            return Err(NodeOpError::NoSpace);
         };
         let old_count = this.head.count as usize;
         this.head.count += key_count as u16;
         let mut offset = this.head.data_offset as usize;
         let min_offset = offset - this.free_space();
-        unsafe {
-            for i in 0..key_count {
-                let dynamic_prefix_len = this.head.dynamic_prefix_len as usize;
-                let bytes = this.as_bytes_mut();
-                let child_bytes = (src.get_child(i) as usize).to_ne_bytes();
-                let val_len = get_key_from_slice(
-                    PrefixTruncatedKey(child_bytes.as_slice()),
-                    &mut bytes[min_offset..offset],
-                    0,
-                )?;
-                debug_assert_eq!(val_len, 8);
-                offset -= val_len;
-                let key_len = src.get_key(i, &mut bytes[min_offset..offset], 0)?;
-                offset -= key_len;
+        // keys are written through `&mut [u8]`, which must not point to uninitialized space
+        this.as_bytes_mut().zero(min_offset..offset);
+        for i in 0..key_count {
+            let dynamic_prefix_len = this.head.dynamic_prefix_len as usize;
+            let bytes = this.as_bytes_mut();
+            let child_bytes = (src.get_child(i) as usize).to_ne_bytes();
+            let val_len = get_key_from_slice(
+                PrefixTruncatedKey(child_bytes.as_slice()),
+                bytes.range_mut(min_offset..offset),
+                0,
+            )?;
+            debug_assert_eq!(val_len, 8);
+            offset -= val_len;
+            let key_len = src.get_key(i, bytes.range_mut(min_offset..offset), 0)?;
+            offset -= key_len;
+            #[cfg(feature = "basic-heads_true")]
+            let head = head(&bytes.range(offset..offset + key_len)[dynamic_prefix_len..]).0;
+            this.slots_mut()[old_count + i] = BasicSlot {
+                offset: offset as u16,
+                key_len: key_len as u16,
+                val_len: val_len as u16,
                 #[cfg(feature = "basic-heads_true")]
-                let head = head(&bytes[offset..][..key_len][dynamic_prefix_len..]).0;
-                this.slots_mut()[old_count + i] = BasicSlot {
-                    offset: offset as u16,
-                    key_len: key_len as u16,
-                    val_len: val_len as u16,
-                    #[cfg(feature = "basic-heads_true")]
-                    head,
-                }
+                head,
             }
         }
         this.head.space_used += this.head.data_offset - offset as u16;
@@ -746,28 +815,9 @@ impl SeparableInnerConversionSource for BasicNode {
 }
 
 impl InnerNode for BasicNode {
-    fn merge_children_check(&mut self, mut child_index: usize) -> Result<(), ()> {
+    fn merge_children(&mut self, child_index: usize) -> Result<(), NodeOpError> {
         unsafe {
-            let left;
-            let right;
-            if child_index == self.key_count() {
-                if child_index == 0 {
-                    // only one child
-                    return Err(());
-                }
-                child_index -= 1;
-                left = &mut *self.get_child(child_index);
-                right = &mut *self.get_child(child_index + 1);
-                if !left.is_underfull() {
-                    return Err(());
-                }
-            } else {
-                left = &mut *self.get_child(child_index);
-                right = &mut *self.get_child(child_index + 1);
-                if !right.is_underfull() {
-                    return Err(());
-                }
-            }
+            let (child_index, left, right) = merge_candidates(self, child_index)?;
             left.try_merge_right(
                 right,
                 FatTruncatedKey {
@@ -782,7 +832,7 @@ impl InnerNode for BasicNode {
         }
     }
 
-    unsafe fn insert_child(&mut self, index: usize, key: PrefixTruncatedKey, child: *mut BTreeNode) -> Result<(), ()> {
+    unsafe fn insert_child(&mut self, index: usize, key: PrefixTruncatedKey, child: *mut BTreeNode) -> Result<(), NodeOpError> {
         #[cfg(feature = "basic-heads_true")]
         if DYNAMIC_PREFIX {
             let reset_dynamic_prefix = if self.key_count() == 0 { self.head.dynamic_prefix_len > 0 } else {
@@ -796,7 +846,10 @@ impl InnerNode for BasicNode {
         Ok(())
     }
 
-    fn request_space_for_child(&mut self, key_length: usize) -> Result<usize, ()> {
+    fn request_space_for_child(&mut self, key_length: usize) -> Result<usize, NodeOpError> {
+        if inner_key_cap_reached(self.slots().len()) {
+            return Err(NodeOpError::NoSpace);
+        }
         self.request_space(self.space_needed(key_length, size_of::<*mut BTreeNode>())
         )
     }
@@ -805,19 +858,24 @@ impl InnerNode for BasicNode {
         #[cfg(feature = "basic-heads_true")]
         self.maybe_grow_dynamic_prefix();
         let truncated = self.truncate(key);
-        let index = bc.predict().filter(|&i| {
+        let predicted = bc.predict().filter(|&i| {
             i <= self.slots().len()
                 && (i == 0 || self.slots()[i - 1].key(self.as_bytes()) < truncated)
                 && (i >= self.slots().len() || truncated <= self.slots()[i].key(self.as_bytes()))
-        })
-            .unwrap_or_else(|| self.lower_bound(truncated).0);
+        });
+        let index = predicted.unwrap_or_else(|| self.lower_bound(truncated).0);
+        debug_assert!(predicted.is_none() || index == self.lower_bound(truncated).0, "accepted branch cache prediction {index} disagrees with search");
         bc.store(index);
         index
     }
+
+    fn lookup_child_index(&self, key: &[u8]) -> usize {
+        self.lower_bound(self.truncate(key)).0
+    }
 }
 
 unsafe impl LeafNode for BasicNode {
-    fn insert(&mut self, key: &[u8], payload: &[u8]) -> Result<(), ()> {
+    fn insert(&mut self, key: &[u8], payload: &[u8]) -> Result<bool, NodeOpError> {
         // leaf nodes have no dynamic prefix
         debug_assert_eq!(self.head.dynamic_prefix_len, 0);
         if cfg!(feature="strip-prefix_false") {
@@ -825,26 +883,33 @@ unsafe impl LeafNode for BasicNode {
             assert!(key > self.fences().lower_fence.0 || self.fences().lower_fence.0.is_empty());
         }
 
-        self.request_space(self.space_needed(key.len(), payload.len()))?;
+        let space_needed = self.space_needed(key.len(), payload.len());
         let key = self.truncate(key);
         let (slot_id, found) = self.lower_bound(key);
+        if found {
+            let s = self.slots()[slot_id];
+            if s.value(self.as_bytes()) == payload {
+                // value unchanged, avoid rewriting
+                return Ok(false);
+            }
+        }
+        // compaction preserves slot order
+        self.request_space(space_needed)?;
         if found {
             let s = &self.slots()[slot_id];
-            self.head.space_used -= s.key_len + s.val_len;
+            self.head.space_used -= s.key_len + s.val_len + self.value_padding() as u16;
             self.store_key_value(slot_id, key, payload);
         } else {
             self.raw_insert(slot_id, key, payload);
         }
-        Ok(())
+        Ok(!found)
     }
 
-    fn lookup(&mut self, key: &[u8]) -> Option<&mut [u8]> {
+    fn lookup_mut(&mut self, key: &[u8]) -> Option<&mut [u8]> {
         let (index, found) = self.lower_bound(self.truncate(key));
         if found {
             let slot = self.slots()[index];
-            unsafe {
-                Some(&mut self.as_bytes_mut()[(slot.offset + slot.key_len) as usize..][..slot.val_len as usize])
-            }
+            Some(self.as_bytes_mut().range_mut((slot.offset + slot.key_len) as usize..(slot.offset + slot.key_len + slot.val_len) as usize))
         } else {
             None
         }
@@ -859,31 +924,176 @@ unsafe impl LeafNode for BasicNode {
         Some(())
     }
 
-    unsafe fn range_lookup(&mut self, start: &[u8], key_out: *mut u8, callback: &mut dyn FnMut(usize, &[u8]) -> bool) -> bool {
-        debug_assert!(!key_out.is_null());
-        key_out.copy_from_nonoverlapping(start.as_ptr(), self.head.prefix_len as usize);
+    fn lookup_slot(&self, key: &[u8]) -> Option<usize> {
+        let (index, found) = self.lower_bound(self.truncate(key));
+        found.then_some(index)
+    }
+
+    fn value_at(&self, index: usize) -> &[u8] {
+        let value = self.slots()[index].value(self.as_bytes());
+        debug_assert!(value.as_ptr() as usize % VALUE_ALIGN == 0);
+        value
+    }
+
+    fn set_value_at(&mut self, index: usize, value: &[u8]) -> Result<(), NodeOpError> {
+        let s = self.slots()[index];
+        if value.len() > s.val_len as usize {
+            return Err(NodeOpError::NoSpace);
+        }
+        self.as_bytes_mut().write((s.offset + s.key_len) as usize, value);
+        // shrinking leaves unused bytes until compaction
+        self.head.space_used -= s.val_len - value.len() as u16;
+        self.slots_mut()[index].val_len = value.len() as u16;
+        Ok(())
+    }
+
+    fn compactify(&mut self) {
+        BasicNode::compactify(self)
+    }
+
+    fn range_lookup(&mut self, start: &[u8], key_out: &mut KeyBuffer, callback: &mut dyn FnMut(&[u8], &[u8]) -> bool) -> bool {
+        key_out.set_suffix(0, &start[..self.head.prefix_len as usize]);
         let start_index = self.lower_bound(self.truncate(start)).0;
         for s in &self.slots()[start_index..] {
-            let k = s.key(self.as_bytes());
-            key_out.offset(self.head.prefix_len as isize).copy_from_nonoverlapping(k.0.as_ptr(), k.0.len());
-            if !callback((s.key_len + self.head.prefix_len) as usize, s.value(self.as_bytes())) {
+            let key = key_out.set_suffix(self.head.prefix_len as usize, s.key(self.as_bytes()).0);
+            if !callback(key, s.value(self.as_bytes())) {
                 return false;
             }
         }
         true
     }
 
-    unsafe fn range_lookup_desc(&mut self, start: &[u8], key_out: *mut u8, callback: &mut dyn FnMut(usize, &[u8]) -> bool) -> bool {
-        debug_assert!(!key_out.is_null());
-        key_out.copy_from_nonoverlapping(start.as_ptr(), self.head.prefix_len as usize);
-        let start_index = self.lower_bound(self.truncate(start)).0.min(self.key_count() - 1);
-        for s in self.slots()[..=start_index].iter().rev() {
-            let k = s.key(self.as_bytes());
-            key_out.offset(self.head.prefix_len as isize).copy_from_nonoverlapping(k.0.as_ptr(), k.0.len());
-            if !callback((s.key_len + self.head.prefix_len) as usize, s.value(self.as_bytes())) {
+    fn range_lookup_desc(&mut self, start: &[u8], key_out: &mut KeyBuffer, callback: &mut dyn FnMut(&[u8], &[u8]) -> bool) -> bool {
+        if self.key_count() == 0 {
+            return true;
+        }
+        key_out.set_suffix(0, &start[..self.head.prefix_len as usize]);
+        let (lower_bound, found) = self.lower_bound(self.truncate(start));
+        // keys from lower_bound on are greater than start, unless it is start itself
+        let end = if found { lower_bound + 1 } else { lower_bound };
+        for s in self.slots()[..end].iter().rev() {
+            let key = key_out.set_suffix(self.head.prefix_len as usize, s.key(self.as_bytes()).0);
+            if !callback(key, s.value(self.as_bytes())) {
                 return false;
             }
         }
         true
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::{ManualLeafSource, LEAF_TAGS};
+
+    fn check_lower_bound(node: &BasicNode, keys: &[Vec<u8>]) {
+        assert_eq!(node.head.count as usize, keys.len());
+        assert_eq!(node.lower_bound(PrefixTruncatedKey(b"")), (0, false), "count {}", keys.len());
+        for (i, key) in keys.iter().enumerate() {
+            assert_eq!(node.lower_bound(PrefixTruncatedKey(key)), (i, true), "count {}", keys.len());
+            let gap = [&key[..], &[0]].concat();
+            assert_eq!(node.lower_bound(PrefixTruncatedKey(&gap)), (i + 1, false), "count {}", keys.len());
+        }
+    }
+
+    #[test]
+    fn lower_bound_at_hint_threshold_counts() {
+        crate::ensure_init();
+        let counts = [1, HINT_COUNT - 1, HINT_COUNT, HINT_COUNT + 1, 2 * HINT_COUNT, 2 * HINT_COUNT + 1, 2 * HINT_COUNT + 2, 3 * HINT_COUNT + 5];
+        let key = |i: usize| (i as u32 * 2).to_be_bytes().to_vec();
+        // each count is reached by inserts
+        for count in counts {
+            let mut node = BasicNode::new_leaf();
+            for i in 0..count {
+                node.insert(&key(i), &[1]).unwrap();
+            }
+            check_lower_bound(&node, &(0..count).map(key).collect::<Vec<_>>());
+        }
+        // and by removals from the middle
+        let mut node = BasicNode::new_leaf();
+        let mut keys: Vec<Vec<u8>> = (0..*counts.last().unwrap()).map(key).collect();
+        for k in &keys {
+            node.insert(k, &[1]).unwrap();
+        }
+        while !keys.is_empty() {
+            if counts.contains(&keys.len()) {
+                check_lower_bound(&node, &keys);
+            }
+            let removed = keys.remove(keys.len() / 2);
+            assert_eq!(node.remove(&removed), Some(()));
+        }
+    }
+
+    #[test]
+    fn lazily_zeroed_nodes_do_not_read_unused_space() {
+        crate::ensure_init();
+        // with lazy-zero_true, Miri reports any read of space a node has not written
+        let count = if cfg!(miri) { 2_000 } else { 30_000u32 };
+        let value = |i: u32| vec![i as u8; i as usize % 9];
+        for leaf in LEAF_TAGS {
+            let mut tree = crate::b_tree::BTree::new_with(leaf, BTreeNodeTag::BasicInner);
+            // descending inserts split off a new node for nearly every leaf and inner node
+            for i in (0..count).rev() {
+                tree.insert(format!("key{i:06}"), value(i)).unwrap();
+            }
+            // removals and updates reuse and compact the space of existing entries
+            for i in (0..count).step_by(3) {
+                assert!(unsafe { tree.remove(format!("key{i:06}")) });
+            }
+            for i in (1..count).step_by(3) {
+                tree.insert(format!("key{i:06}"), value(i + 1)).unwrap();
+            }
+            assert_eq!(tree.check_space_accounting(), Ok(()));
+            assert_eq!(tree.verify_balanced(), Ok(()));
+            for i in 0..count {
+                let expected = match i % 3 {
+                    0 => None,
+                    1 => Some(value(i + 1)),
+                    _ => Some(value(i)),
+                };
+                assert_eq!(tree.get(format!("key{i:06}")).map(<[u8]>::to_vec), expected, "{leaf:?} {i}");
+            }
+        }
+    }
+
+    #[test]
+    fn merge_restores_keys_when_prefix_shrinks() {
+        crate::ensure_init();
+        // (lower fence, separator, upper fence, prefix length of the parent)
+        let cases: [(&[u8], &[u8], &[u8], usize); 6] = [
+            (b"", b"m", b"", 0),
+            (b"aaaa0", b"aaaa5", b"aab", 0),
+            (b"aaaa0", b"aaaa5", b"aab", 2),
+            (b"pre/a/00", b"pre/a/50", b"pre/b", 4),
+            (b"k12", b"k1234569", b"k12346", 3),
+            (b"x", b"xyz/1", b"xyz/2", 1),
+        ];
+        for (lower, separator, upper, parent_prefix) in cases {
+            let entry = |base: &[u8], i: u8| ([base, &[i]].concat(), vec![i; 3]);
+            let left = ManualLeafSource { lower_fence: lower.to_vec(), upper_fence: separator.to_vec(), entries: (1..=20).map(|i| entry(lower, i)).collect() };
+            let right = ManualLeafSource { lower_fence: separator.to_vec(), upper_fence: upper.to_vec(), entries: (1..=20).map(|i| entry(separator, i)).collect() };
+            let expected: Vec<(Vec<u8>, Vec<u8>)> = left.entries.iter().chain(&right.entries).cloned().collect();
+            unsafe {
+                let (left_node, right_node) = (left.build(BTreeNodeTag::BasicLeaf).unwrap(), right.build(BTreeNodeTag::BasicLeaf).unwrap());
+                let separator_key = FatTruncatedKey { remainder: &separator[parent_prefix..], prefix_len: parent_prefix };
+                (*left_node).basic.merge_right(false, &mut *right_node, separator_key).unwrap();
+                let merged = &mut (*right_node).basic;
+                let expected_prefix = if BASIC_PREFIX && !upper.is_empty() { common_prefix_len(lower, upper) } else { 0 };
+                assert_eq!(merged.head.prefix_len as usize, expected_prefix, "{lower:?} {upper:?}");
+                assert_eq!(merged.head.count as usize, expected.len());
+                assert_eq!(merged.check_space_accounting(), Ok(()));
+                for (key, value) in &expected {
+                    assert_eq!(merged.lookup(key), Some(&value[..]), "{key:?}");
+                }
+                let mut scanned = Vec::new();
+                merged.range_lookup(lower, &mut KeyBuffer::new(), &mut |key, value| {
+                    scanned.push((key.to_vec(), value.to_vec()));
+                    true
+                });
+                assert_eq!(scanned, expected, "{lower:?} {separator:?} {upper:?}");
+                BTreeNode::dealloc(left_node);
+                BTreeNode::dealloc(right_node);
+            }
+        }
+    }
+}
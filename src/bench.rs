@@ -1,11 +1,12 @@
 use std::collections::{BTreeSet, HashMap};
 use std::hint::black_box;
-use std::io::BufRead;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
 use std::process::Command;
 use std::ptr;
 use std::sync::atomic::Ordering;
 use bumpalo::Bump;
-use rand::{RngCore, SeedableRng};
+use rand::{Rng, RngCore, SeedableRng};
 use rand::distributions::{WeightedIndex};
 use rand::distributions::Distribution;
 use rand::prelude::SliceRandom;
@@ -16,6 +17,10 @@ use perf_event::{Counter, Group};
 use perf_event::events::{Cache, CacheOp, CacheResult, Hardware, Software, WhichCache};
 use serde_json::json;
 use crate::{BTree, btree_print_info, ensure_init, PAGE_SIZE};
+use crate::node_stats::{btree_to_inner_node_stats, INNER_FALLBACK_CREATED};
+use crate::btree_node::{inner_fallback_order, set_inner_fallback_order};
+use crate::node_traits::set_inner_key_cap;
+use crate::vtables::BTreeNodeTag;
 
 fn build_info() -> serde_json::Map<String, serde_json::Value> {
     let header = include_str!("../build-info.h");
@@ -60,6 +65,7 @@ struct StatAggregator {
     count: u64,
 }
 
+#[derive(Default)]
 struct Perf {
     counters: Vec<(&'static str, Counter)>,
 }
@@ -105,7 +111,15 @@ impl StatAggregator {
 struct Bench {
     stats: [StatAggregator; Op::CARDINALITY],
     sample_op: WeightedIndex<usize>,
+    /// used instead of `sample_op` in every other phase of `phase_len` operations
+    sample_op_alt: Option<WeightedIndex<usize>>,
+    phase_len: usize,
     instruction_buffer: Vec<u8>,
+    /// every executed batch of `instruction_buffer` is appended here
+    record: Option<BufWriter<File>>,
+    /// operations are read from here instead of being sampled
+    replay: Option<BufReader<File>>,
+    batch_size: usize,
     initial_size: usize,
     value_length: usize,
     range_length: usize,
@@ -124,6 +138,11 @@ struct Bench {
 impl Bench {
     fn init(
         sample_op: WeightedIndex<usize>,
+        sample_op_alt: Option<WeightedIndex<usize>>,
+        phase_len: usize,
+        record: Option<BufWriter<File>>,
+        replay: Option<BufReader<File>>,
+        batch_size: usize,
         initial_size: usize,
         value_length: usize,
         range_length: usize,
@@ -138,13 +157,18 @@ impl Bench {
         let mut tree = BTree::new();
         data.shuffle(&mut rng);
         for x in &data[..initial_size] {
-            tree.insert(x, &value);
+            tree.insert(x, &value).unwrap();
         }
         unsafe { btree_print_info(&mut tree) };
         Bench {
             stats: Default::default(),
             sample_op,
+            sample_op_alt,
+            phase_len,
             instruction_buffer: Vec::new(),
+            record,
+            replay,
+            batch_size,
             initial_size,
             value_length,
             range_length,
@@ -161,7 +185,7 @@ impl Bench {
             },
             data,
             payload: value,
-            perf: Perf::new(),
+            perf: Perf::default(),
             rng,
             tree,
         }
@@ -179,11 +203,13 @@ impl Bench {
     }
 
     fn run_buffered(&mut self) {
+        if let Some(record) = &mut self.record {
+            record.write_all(&self.instruction_buffer).unwrap();
+        }
         let mut i = 0;
         for c in &mut self.perf.counters {
             c.1.enable().unwrap();
         }
-        let mut range_lookup_key_out = [0u8; PAGE_SIZE];
         while i < self.instruction_buffer.len() {
             let op = Self::op_from_usize(self.instruction_buffer[i] as usize);
             let len_bytes: &[u8; 2] = self.instruction_buffer[i + 1..][..2].try_into().unwrap();
@@ -212,12 +238,12 @@ impl Bench {
                 Op::Update => {
                     self.stats[op as usize].time_fn(||
                         black_box(self.tree.insert(black_box(key), black_box(&self.payload)))
-                    );
+                    ).unwrap();
                 }
                 Op::Insert => {
                     self.stats[op as usize].time_fn(||
                         black_box(self.tree.insert(black_box(key), black_box(&self.payload)))
-                    );
+                    ).unwrap();
                     #[cfg(debug_assertions)]{
                         self.std_set.insert(key.to_owned());
                     }
@@ -239,9 +265,9 @@ impl Bench {
                     let mut count = 0;
                     self.stats[op as usize].time_fn(||
                         black_box(
-                            self.tree.range_lookup(&key, range_lookup_key_out.as_mut_ptr(), &mut |key_len, _value| {
+                            self.tree.range_lookup(&key, &mut |_key, _value| {
                                 #[cfg(debug_assertions)]{
-                                    assert!(expected[count] == &range_lookup_key_out[..key_len])
+                                    assert!(expected[count] == _key)
                                 }
                                 count += 1;
                                 count < self.range_length
@@ -260,39 +286,340 @@ impl Bench {
         self.instruction_buffer.clear();
     }
 
-    fn run(mut self, op_count: usize) -> ([StatAggregator; Op::CARDINALITY], Perf) {
-        for _ in 0..op_count {
-            let op = self.sample_op.sample(&mut self.rng);
-            let index = match Self::op_from_usize(op) {
-                Op::Hit | Op::Update | Op::Range => (self.inserted_start + self.inserted_count - 1 - self.zipf_sample(self.inserted_count)) % self.data.len(),
-                Op::Miss => (self.inserted_start + self.inserted_count + self.zipf_sample(self.data.len() - self.inserted_count)) % self.data.len(),
-                Op::Insert => {
-                    let index = (self.inserted_start + self.inserted_count) % self.data.len();
-                    self.inserted_count += 1;
-                    index
-                }
-                Op::Remove => {
-                    let index = self.inserted_start;
-                    self.inserted_count -= 1;
-                    self.inserted_start = (self.inserted_start + 1) % self.data.len();
-                    index
-                }
-            };
-            self.instruction_buffer.push(op as u8);
-            self.instruction_buffer.extend_from_slice(&(self.data[index].len() as u16).to_ne_bytes());
-            self.instruction_buffer.extend_from_slice(&self.data[index]);
-            const INSTRUCTION_BUFFER_SIZE: usize = if cfg!(debug_assertions) { 1 } else { 100_000 };
-            if self.instruction_buffer.len() >= INSTRUCTION_BUFFER_SIZE {
+    /// fraction of inner nodes that are head nodes
+    fn head_node_rate(&self) -> f64 {
+        let nodes = btree_to_inner_node_stats(&self.tree);
+        let heads = nodes.iter().filter(|n| !matches!(n.tag, BTreeNodeTag::BasicInner | BTreeNodeTag::ArtInner)).count();
+        heads as f64 / nodes.len() as f64
+    }
+
+    /// appends the next recorded operation to `instruction_buffer`
+    fn replay_op(&mut self) {
+        let replay = self.replay.as_mut().unwrap();
+        let mut op_header = [0u8; 3];
+        replay.read_exact(&mut op_header).expect("replay file has fewer than OP_COUNT operations");
+        let len = u16::from_ne_bytes([op_header[1], op_header[2]]) as usize;
+        self.instruction_buffer.extend_from_slice(&op_header);
+        let key_start = self.instruction_buffer.len();
+        self.instruction_buffer.resize(key_start + len, 0);
+        replay.read_exact(&mut self.instruction_buffer[key_start..]).unwrap();
+    }
+
+    /// samples the `i`th operation and appends it to `instruction_buffer`
+    fn generate_op(&mut self, i: usize) {
+        let op = match &self.sample_op_alt {
+            Some(alt) if i / self.phase_len % 2 == 1 => alt.sample(&mut self.rng),
+            _ => self.sample_op.sample(&mut self.rng),
+        };
+        let index = match Self::op_from_usize(op) {
+            Op::Hit | Op::Update | Op::Range => (self.inserted_start + self.inserted_count - 1 - self.zipf_sample(self.inserted_count)) % self.data.len(),
+            Op::Miss => (self.inserted_start + self.inserted_count + self.zipf_sample(self.data.len() - self.inserted_count)) % self.data.len(),
+            Op::Insert => {
+                let index = (self.inserted_start + self.inserted_count) % self.data.len();
+                self.inserted_count += 1;
+                index
+            }
+            Op::Remove => {
+                let index = self.inserted_start;
+                self.inserted_count -= 1;
+                self.inserted_start = (self.inserted_start + 1) % self.data.len();
+                index
+            }
+        };
+        self.instruction_buffer.push(op as u8);
+        self.instruction_buffer.extend_from_slice(&(self.data[index].len() as u16).to_ne_bytes());
+        self.instruction_buffer.extend_from_slice(&self.data[index]);
+    }
+
+    /// generates or replays `op_count` operations and applies them to the tree in batches
+    fn execute(&mut self, op_count: usize) {
+        for i in 0..op_count {
+            if self.replay.is_some() {
+                self.replay_op();
+            } else {
+                self.generate_op(i);
+            }
+            if self.instruction_buffer.len() >= self.batch_size {
                 self.run_buffered();
             }
         }
         self.run_buffered();
+        if let Some(record) = &mut self.record {
+            record.flush().unwrap();
+        }
+    }
+
+    fn run(mut self, op_count: usize) -> ([StatAggregator; Op::CARDINALITY], Perf, f64, usize) {
+        self.perf = Perf::new();
+        self.execute(op_count);
         unsafe { btree_print_info(&mut self.tree) };
+        let head_node_rate = self.head_node_rate();
+        let height = self.tree.height();
         std::mem::forget(self.tree);
-        (self.stats, self.perf)
+        (self.stats, self.perf, head_node_rate, height)
     }
 }
 
+/// `count` distinct random keys with lengths uniformly distributed in `min_len..=max_len`
+pub fn mixed_length_keys(min_len: usize, max_len: usize, count: usize) -> Vec<Vec<u8>> {
+    assert!(min_len <= max_len && max_len <= PAGE_SIZE / 4);
+    let mut rng = Xoshiro128PlusPlus::seed_from_u64(456);
+    let mut keys = BTreeSet::new();
+    // short lengths may not have enough distinct keys
+    for _ in 0..count.saturating_mul(4) {
+        if keys.len() == count {
+            break;
+        }
+        let mut key = vec![0u8; rng.gen_range(min_len..=max_len)];
+        rng.fill_bytes(&mut key);
+        keys.insert(key);
+    }
+    keys.into_iter().collect()
+}
+
+/// times removing every key in random order from a tree holding all of them, so the tree shrinks through merges at every level
+fn delete_workload(mut keys: Vec<Vec<u8>>, value_len: usize) -> Vec<serde_json::Value> {
+    let mut rng = Xoshiro128PlusPlus::seed_from_u64(123);
+    let mut value = vec![0u8; value_len];
+    rng.fill_bytes(&mut value);
+    let mut tree = BTree::new();
+    keys.shuffle(&mut rng);
+    for x in &keys {
+        tree.insert(x, &value).unwrap();
+    }
+    let height = tree.height();
+    keys.shuffle(&mut rng);
+    let mut stat = StatAggregator::default();
+    let mut perf = Perf::new();
+    for c in &mut perf.counters {
+        c.1.enable().unwrap();
+    }
+    for x in &keys {
+        let found = unsafe { stat.time_fn(|| black_box(tree.remove(black_box(x)))) };
+        debug_assert!(found);
+    }
+    for c in &mut perf.counters {
+        c.1.disable().unwrap();
+    }
+    debug_assert!(tree.is_empty());
+    let op_info = json!({
+        "op": "Remove",
+        "op_count": stat.count,
+        "time": stat.sum as f64 / stat.count as f64,
+        "height": height,
+    });
+    vec![op_info, perf.to_json()]
+}
+
+/// starts from a tree holding all keys and alternates removing a random present key with `LOOKUPS_PER_REMOVE` lookups of random keys,
+/// half of which were removed before. Run with `leaf_hash` to compare tombstones against swap-remove in hash leaves.
+fn delete_lookup_workload(mut keys: Vec<Vec<u8>>, value_len: usize, op_count: usize) -> Vec<serde_json::Value> {
+    let mut rng = Xoshiro128PlusPlus::seed_from_u64(123);
+    let mut value = vec![0u8; value_len];
+    rng.fill_bytes(&mut value);
+    let lookups_per_remove: usize = std::env::var("LOOKUPS_PER_REMOVE").as_deref().unwrap_or("4").parse().unwrap();
+    keys.sort();
+    keys.dedup();
+    keys.shuffle(&mut rng);
+    let mut tree = BTree::new();
+    for x in &keys {
+        tree.insert(x, &value).unwrap();
+    }
+    // keys[..present] are in the tree
+    let mut present = keys.len();
+    let mut remove_stat = StatAggregator::default();
+    let mut lookup_stat = StatAggregator::default();
+    let mut perf = Perf::new();
+    for c in &mut perf.counters {
+        c.1.enable().unwrap();
+    }
+    for _ in 0..op_count.min(keys.len()) {
+        let index = rng.gen_range(0..present);
+        present -= 1;
+        keys.swap(index, present);
+        let found = unsafe { remove_stat.time_fn(|| black_box(tree.remove(black_box(&keys[present])))) };
+        debug_assert!(found);
+        for i in 0..lookups_per_remove {
+            let index = if i % 2 == 0 || present == keys.len() { rng.gen_range(0..present.max(1)) } else { rng.gen_range(present..keys.len()) };
+            let found = lookup_stat.time_fn(|| black_box(tree.get(black_box(&keys[index]))).is_some());
+            debug_assert_eq!(found, index < present);
+        }
+    }
+    for c in &mut perf.counters {
+        c.1.disable().unwrap();
+    }
+    let op_info = json!({
+        "op": "DeleteLookup",
+        "op_count": remove_stat.count,
+        "remove_time": remove_stat.sum as f64 / remove_stat.count as f64,
+        "lookup_time": lookup_stat.sum as f64 / lookup_stat.count as f64,
+        "lookups_per_remove": lookups_per_remove,
+        "height": tree.height(),
+    });
+    vec![op_info, perf.to_json()]
+}
+
+/// times point lookups that read the whole value and updates alternating between two value lengths.
+/// Run once with and once without value-arena_true to measure the cost of storing values out of line.
+fn value_indirection_workload(mut keys: Vec<Vec<u8>>, value_len: usize, op_count: usize) -> Vec<serde_json::Value> {
+    let mut rng = Xoshiro128PlusPlus::seed_from_u64(123);
+    let mut value = vec![0u8; value_len];
+    rng.fill_bytes(&mut value);
+    let longer_value = vec![1u8; value_len * 2];
+    keys.sort();
+    keys.dedup();
+    keys.shuffle(&mut rng);
+    let mut tree = BTree::new();
+    for x in &keys {
+        tree.insert(x, &value).unwrap();
+    }
+    let mut lookup_stat = StatAggregator::default();
+    let mut update_stat = StatAggregator::default();
+    let mut perf = Perf::new();
+    for c in &mut perf.counters {
+        c.1.enable().unwrap();
+    }
+    for i in 0..op_count {
+        let key = &keys[rng.gen_range(0..keys.len())];
+        let sum = lookup_stat.time_fn(|| black_box(tree.get(black_box(key))).unwrap().iter().map(|&b| b as u64).sum::<u64>());
+        black_box(sum);
+        let new_value = if i % 2 == 0 { &longer_value } else { &value };
+        update_stat.time_fn(|| black_box(tree.insert(black_box(key), new_value)).unwrap());
+    }
+    for c in &mut perf.counters {
+        c.1.disable().unwrap();
+    }
+    let op_info = json!({
+        "op": "ValueIndirection",
+        "op_count": lookup_stat.count,
+        "lookup_time": lookup_stat.sum as f64 / lookup_stat.count as f64,
+        "update_time": update_stat.sum as f64 / update_stat.count as f64,
+        "value_arena": cfg!(feature = "value-arena_true"),
+        "height": tree.height(),
+    });
+    vec![op_info, perf.to_json()]
+}
+
+/// splits the sorted keys into `MERGE_PARTS` adjacent ranges, builds a tree for each and times joining them in key order,
+/// once by `BTree::merge` and once by reinserting the entries of each part
+fn merge_workload(mut keys: Vec<Vec<u8>>, value_len: usize) -> Vec<serde_json::Value> {
+    let mut rng = Xoshiro128PlusPlus::seed_from_u64(123);
+    let mut value = vec![0u8; value_len];
+    rng.fill_bytes(&mut value);
+    let part_count: usize = std::env::var("MERGE_PARTS").as_deref().unwrap_or("16").parse().unwrap();
+    keys.sort();
+    keys.dedup();
+    let build_parts = |rng: &mut Xoshiro128PlusPlus| -> Vec<BTree> {
+        keys.chunks(keys.len().div_ceil(part_count).max(1)).map(|chunk| {
+            let mut chunk = chunk.to_vec();
+            chunk.shuffle(rng);
+            let mut tree = BTree::new();
+            for x in &chunk {
+                tree.insert(x, &value).unwrap();
+            }
+            tree
+        }).collect()
+    };
+    let mut graft_stat = StatAggregator::default();
+    let mut parts = build_parts(&mut rng).into_iter();
+    let mut grafted = parts.next().unwrap();
+    for part in parts {
+        graft_stat.time_fn(|| black_box(grafted.merge(part)).ok().unwrap());
+    }
+    let mut reinsert_stat = StatAggregator::default();
+    let mut parts = build_parts(&mut rng).into_iter();
+    let mut reinserted = parts.next().unwrap();
+    for mut part in parts {
+        reinsert_stat.time_fn(|| part.for_each_in_range(.., &mut |key, value| reinserted.insert(key, value).unwrap()));
+    }
+    assert_eq!(grafted.len(), keys.len());
+    assert_eq!(reinserted.len(), keys.len());
+    let op_info = json!({
+        "op": "Merge",
+        "op_count": graft_stat.count,
+        "graft_time": graft_stat.sum as f64 / graft_stat.count as f64,
+        "reinsert_time": reinsert_stat.sum as f64 / reinsert_stat.count as f64,
+        "height": grafted.height(),
+        "reinsert_height": reinserted.height(),
+        "parts": part_count,
+    });
+    vec![op_info]
+}
+
+/// starts from a tree holding half of the keys and alternates removing a random present key and inserting a random absent one.
+/// Reports the splits, merges and borrows per operation, the tree is checked after each operation outside of the timed section.
+fn churn_workload(mut keys: Vec<Vec<u8>>, value_len: usize, op_count: usize) -> Vec<serde_json::Value> {
+    let mut rng = Xoshiro128PlusPlus::seed_from_u64(123);
+    let mut value = vec![0u8; value_len];
+    rng.fill_bytes(&mut value);
+    keys.sort();
+    keys.dedup();
+    keys.shuffle(&mut rng);
+    let mut absent = keys.split_off(keys.len() / 2);
+    let mut present = keys;
+    assert!(!present.is_empty() && !absent.is_empty());
+    let mut tree = BTree::new();
+    for x in &present {
+        tree.insert(x, &value).unwrap();
+    }
+    let ops_before = tree.structural_ops();
+    let mut stat = StatAggregator::default();
+    for i in 0..op_count {
+        if i % 2 == 0 {
+            let key = present.swap_remove(rng.gen_range(0..present.len()));
+            let found = unsafe { stat.time_fn(|| black_box(tree.remove(black_box(&key)))) };
+            assert!(found);
+            absent.push(key);
+        } else {
+            let key = absent.swap_remove(rng.gen_range(0..absent.len()));
+            stat.time_fn(|| tree.insert(black_box(&key), &value)).unwrap();
+            present.push(key);
+        }
+        assert_eq!(tree.len(), present.len());
+        assert_eq!(tree.verify_balanced(), Ok(()));
+        assert_eq!(tree.audit_pointers(), Ok(()));
+        assert_eq!(tree.check_space_accounting(), Ok(()));
+    }
+    let ops = tree.structural_ops();
+    let per_op = |count: u64| count as f64 / stat.count as f64;
+    vec![json!({
+        "op": "Churn",
+        "op_count": stat.count,
+        "time": stat.sum as f64 / stat.count as f64,
+        "splits": per_op(ops.splits - ops_before.splits),
+        "merges": per_op(ops.merges - ops_before.merges),
+        "borrows": per_op(ops.borrows - ops_before.borrows),
+        "height": tree.height(),
+    })]
+}
+
+/// inserts all keys and reports the average number of children of inner nodes by node type
+fn fanout_workload(mut keys: Vec<Vec<u8>>, value_len: usize) -> Vec<serde_json::Value> {
+    let mut rng = Xoshiro128PlusPlus::seed_from_u64(123);
+    let mut value = vec![0u8; value_len];
+    rng.fill_bytes(&mut value);
+    let mut tree = BTree::new();
+    keys.shuffle(&mut rng);
+    for x in &keys {
+        tree.insert(x, &value).unwrap();
+    }
+    let nodes = btree_to_inner_node_stats(&tree);
+    let mut by_tag: HashMap<String, (usize, usize)> = HashMap::new();
+    for n in &nodes {
+        let entry = by_tag.entry(format!("{:?}", n.tag)).or_default();
+        entry.0 += 1;
+        entry.1 += n.keys.len() + 1;
+    }
+    let fanout: serde_json::Map<String, serde_json::Value> = by_tag.iter().map(|(tag, &(count, children))| (tag.clone(), (children as f64 / count as f64).into())).collect();
+    let children: usize = by_tag.values().map(|x| x.1).sum();
+    vec![json!({
+        "inner_count": nodes.len(),
+        "fanout": children as f64 / nodes.len() as f64,
+        "fanout_by_tag": fanout,
+        "height": tree.height(),
+    })]
+}
+
 pub fn bench_main() {
     ensure_init();
     let mut data: Option<(Vec<Vec<u8>>, String)> = None;
@@ -310,19 +637,83 @@ pub fn bench_main() {
         let file = std::io::BufReader::new(std::fs::File::open(&var).unwrap());
         data = Some((file.lines().map(|l| { l.unwrap().into_bytes() }).collect(), format!("FILE-{}", var)));
     }
+    if let Ok(var) = std::env::var("MIXED") {
+        assert!(data.is_none());
+        let (min_len, max_len) = var.split_once(',').expect("MIXED must be min,max");
+        let (min_len, max_len): (usize, usize) = (min_len.parse().unwrap(), max_len.parse().unwrap());
+        let count = std::env::var("MIXED_COUNT").map(|x| x.parse::<f64>().unwrap()).unwrap_or(1e6) as usize;
+        data = Some((mixed_length_keys(min_len, max_len, count), format!("MIXED-{}-{}-{}", min_len, max_len, count)));
+    }
     let (keys, data_name) = data.expect("no bench");
 
     let total_count = std::env::var("OP_COUNT").map(|x| x.parse().unwrap()).unwrap_or(1e6) as usize;
     let value_len: usize = std::env::var("VALUE_LEN").as_deref().unwrap_or("8").parse().unwrap();
+    // WORKLOAD selects a dedicated benchmark instead of the mixed operation stream configured below:
+    // delete removes all keys from a full tree, fanout reports the children per inner node after inserting all keys,
+    // merge joins trees over adjacent key ranges by grafting and by reinsertion,
+    // churn alternates OP_COUNT removes and inserts and reports structural operations,
+    // delete-lookup alternates up to OP_COUNT removes with lookups of present and removed keys,
+    // value-indirection alternates OP_COUNT lookups reading the whole value with updates
+    let workload = std::env::var("WORKLOAD").unwrap_or_else(|_| "mixed".to_string());
+    if workload != "mixed" {
+        let results = match workload.as_str() {
+            "delete" => delete_workload(keys, value_len),
+            "fanout" => fanout_workload(keys, value_len),
+            "merge" => merge_workload(keys, value_len),
+            "churn" => churn_workload(keys, value_len, total_count),
+            "delete-lookup" => delete_lookup_workload(keys, value_len, total_count),
+            "value-indirection" => value_indirection_workload(keys, value_len, total_count),
+            _ => panic!("unknown WORKLOAD: {workload}"),
+        };
+        let common_info = json!({
+            "data":data_name,
+            "workload":workload,
+            "value_len":value_len,
+            "inner_order":inner_fallback_order().iter().map(|t| format!("{t:?}")).collect::<Vec<_>>(),
+            "host": host_name(),
+            "run_start":  std::time::SystemTime::now()
+        });
+        let build_info = build_info().into();
+        let mem_info = mem_info();
+        let mut objects = vec![&build_info, &common_info, &mem_info];
+        objects.extend(&results);
+        print_joint_objects(&objects);
+        return;
+    }
     let range_len: usize = std::env::var("RANGE_LEN").as_deref().unwrap_or("10").parse().unwrap();
     let zipf_exponent: f64 = std::env::var("ZIPF_EXPONENT").as_deref().unwrap_or("0.15").parse().unwrap();
     let op_rates: Vec<usize> = serde_json::from_str(std::env::var("OP_RATES").as_deref().unwrap_or("[40,40,5,5,5,5]")).unwrap();
     assert!(op_rates.len() == 6);
     let sample_op = WeightedIndex::new(op_rates.clone()).unwrap();
+    // if OP_RATES_ALT is set, the workload switches between the two rates every PHASE_LEN operations, e.g. between point and range heavy phases
+    let op_rates_alt: Option<Vec<usize>> = std::env::var("OP_RATES_ALT").ok().map(|x| serde_json::from_str(&x).unwrap());
+    assert!(op_rates_alt.as_ref().map_or(true, |r| r.len() == 6));
+    let sample_op_alt = op_rates_alt.clone().map(|r| WeightedIndex::new(r).unwrap());
+    let phase_len: usize = std::env::var("PHASE_LEN").map(|x| x.parse::<f64>().unwrap()).unwrap_or(1e5) as usize;
+    assert!(phase_len > 0);
 
+    // operations are generated into a buffer of BENCH_BATCH bytes, which is executed once full.
+    // Large batches keep the generator's cache footprint out of the measured operations, but operations then run on a cache state warmed by the previous batch.
+    // 0 disables buffering, each operation is executed right after it is generated.
+    let batch_size: usize = std::env::var("BENCH_BATCH").map(|x| x.parse().unwrap()).unwrap_or(if cfg!(debug_assertions) { 1 } else { 100_000 });
     let initial_size = if std::env::var("START_EMPTY").as_deref().unwrap_or("0") == "1" { 0 } else { keys.len() / 2 };
+    // BENCH_RECORD writes the executed operation stream to a file, BENCH_REPLAY executes a recorded stream instead of sampling operations.
+    // The initial tree is built from the data set as usual, so a replay must use the same data set, VALUE_LEN and START_EMPTY as the recording.
+    let record_file = std::env::var("BENCH_RECORD").ok();
+    let replay_file = std::env::var("BENCH_REPLAY").ok();
+    let record = record_file.as_ref().map(|f| BufWriter::new(File::create(f).unwrap()));
+    let replay = replay_file.as_ref().map(|f| BufReader::new(File::open(f).unwrap()));
+    // INNER_KEY_CAP limits the key count of inner nodes, to compare tree height against node size
+    let inner_key_cap: Option<usize> = std::env::var("INNER_KEY_CAP").ok().map(|x| x.parse().unwrap());
+    set_inner_key_cap(inner_key_cap);
+    // INNER_ORDER is a list of inner node tags tried in order when creating inner nodes, e.g. [5,3,1]. Only used with feature inner_runtime.
+    if let Ok(var) = std::env::var("INNER_ORDER") {
+        let tags: Vec<u8> = serde_json::from_str(&var).unwrap();
+        set_inner_fallback_order(&tags.into_iter().map(|t| BTreeNodeTag::try_from(t).unwrap()).collect::<Vec<_>>());
+    }
+    let inner_order: Vec<String> = inner_fallback_order().iter().map(|t| format!("{t:?}")).collect();
 
-    let (stats, mut perf) = Bench::init(sample_op, initial_size, value_len, range_len, zipf_exponent, keys).run(total_count);
+    let (stats, mut perf, head_node_rate, height) = Bench::init(sample_op, sample_op_alt, phase_len, record, replay, batch_size, initial_size, value_len, range_len, zipf_exponent, keys).run(total_count);
     let mem_info = mem_info();
     let build_info = build_info().into();
     let common_info = json!({
@@ -332,6 +723,12 @@ pub fn bench_main() {
         "range_len":range_len,
         "zipf_exponent":zipf_exponent,
         "op_rates":op_rates,
+        "op_rates_alt":op_rates_alt,
+        "phase_len":phase_len,
+        "bench_batch":batch_size,
+        "bench_replay":replay_file,
+        "inner_key_cap":inner_key_cap,
+        "inner_order":inner_order,
         "host": host_name(),
         "run_start":  std::time::SystemTime::now()
     });
@@ -347,7 +744,9 @@ pub fn bench_main() {
         print_joint_objects(&[&build_info, &common_info, &op_info]);
     }
     let perf_info = perf.to_json();
-    print_joint_objects(&[&build_info, &common_info, &perf_info, &mem_info]);
+    let inner_fallback_created: Vec<u64> = INNER_FALLBACK_CREATED.iter().map(|c| c.load(Ordering::Relaxed)).collect();
+    let tree_info = json!({"head_node_rate": head_node_rate, "height": height, "inner_fallback_created": inner_fallback_created});
+    print_joint_objects(&[&build_info, &common_info, &perf_info, &mem_info, &tree_info]);
 }
 
 pub fn print_tpcc_result(time: f64, tx_count: u64, warehouses: u64) {
@@ -363,8 +762,81 @@ pub fn print_tpcc_result(time: f64, tx_count: u64, warehouses: u64) {
 }
 
 fn print_joint_objects(objects: &[&serde_json::Value]) {
-    // this is just a convenient place to set the flag, as all benchmarks call this at the end.
-    crate::MEASUREMENT_COMPLETE.store(true, Ordering::Relaxed);
     let joint: serde_json::Map<_, _> = objects.iter().flat_map(|o| o.as_object().unwrap().iter()).map(|(s, v)| (s.clone(), v.clone())).collect();
     println!("{}", serde_json::to_string(&joint).unwrap());
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn batch_size_does_not_change_tree_contents() {
+        ensure_init();
+        let keys = mixed_length_keys(4, 32, 20_000);
+        let mut benches: Vec<Bench> = [0, 1, 64, 1 << 20].into_iter().map(|batch_size| {
+            let sample_op = WeightedIndex::new([10, 5, 5, 20, 15, 5]).unwrap();
+            let mut bench = Bench::init(sample_op, None, 1, None, None, batch_size, 10_000, 8, 10, 1.0, keys.clone());
+            bench.execute(20_000);
+            bench
+        }).collect();
+        let (first, rest) = benches.split_first_mut().unwrap();
+        assert!(first.tree.len() > 10_000);
+        for other in rest {
+            assert_eq!(first.tree.len(), other.tree.len());
+            assert!(first.tree.contents_eq(&mut other.tree), "batch size {}", other.batch_size);
+        }
+    }
+
+    #[test]
+    fn replay_applies_recorded_operations() {
+        ensure_init();
+        let keys = mixed_length_keys(4, 32, 20_000);
+        let dir = std::env::temp_dir();
+        let (recorded, rerecorded) = (dir.join(format!("bench-record-{}", std::process::id())), dir.join(format!("bench-rerecord-{}", std::process::id())));
+        let mut original = Bench::init(
+            WeightedIndex::new([10, 5, 5, 20, 15, 5]).unwrap(), None, 1,
+            Some(BufWriter::new(File::create(&recorded).unwrap())), None,
+            64, 10_000, 8, 10, 1.0, keys.clone(),
+        );
+        original.execute(20_000);
+        // different rates would sample a different workload, the replay must ignore them
+        let mut replayed = Bench::init(
+            WeightedIndex::new([1, 1, 1, 1, 1, 1]).unwrap(), None, 1,
+            Some(BufWriter::new(File::create(&rerecorded).unwrap())), Some(BufReader::new(File::open(&recorded).unwrap())),
+            1, 10_000, 8, 10, 1.0, keys,
+        );
+        replayed.execute(20_000);
+        assert!(original.tree.len() > 10_000);
+        assert_eq!(original.tree.len(), replayed.tree.len());
+        assert!(original.tree.contents_eq(&mut replayed.tree));
+        let (recorded_ops, rerecorded_ops) = (std::fs::read(&recorded).unwrap(), std::fs::read(&rerecorded).unwrap());
+        assert!(!recorded_ops.is_empty());
+        assert!(recorded_ops == rerecorded_ops);
+        std::fs::remove_file(recorded).unwrap();
+        std::fs::remove_file(rerecorded).unwrap();
+    }
+
+    #[test]
+    fn mixed_length_keys_respect_bounds() {
+        ensure_init();
+        let keys = mixed_length_keys(2, 24, 30_000);
+        assert_eq!(keys.len(), 30_000);
+        assert!(keys.iter().all(|k| (2..=24).contains(&k.len())));
+        assert!(keys.iter().any(|k| k.len() == 2) && keys.iter().any(|k| k.len() == 24));
+        // keys longer than any head node can hold force fallbacks to basic inner nodes
+        assert!(keys.iter().filter(|k| k.len() > 9).count() > keys.len() / 2);
+        let mut tree = BTree::new();
+        for (i, key) in keys.iter().enumerate() {
+            tree.insert(key, (i as u32).to_le_bytes()).unwrap();
+        }
+        assert_eq!(tree.len(), keys.len());
+        assert_eq!(tree.verify_balanced(), Ok(()));
+        for (i, key) in keys.iter().enumerate() {
+            assert_eq!(tree.get(key), Some(&(i as u32).to_le_bytes()[..]));
+        }
+        assert!(tree.keys().eq(keys.iter().cloned()));
+        // only 256 distinct keys of length one exist
+        assert_eq!(mixed_length_keys(1, 1, 1_000).len(), 256);
+    }
+}
@@ -1,14 +1,14 @@
 use crate::basic_node::BasicNode;
 use crate::hash_leaf::HashLeaf;
-use crate::node_traits::{FenceData, InnerConversionSink, InnerConversionSource, merge_to_right};
+use crate::node_traits::{FenceData, InnerConversionSink, InnerConversionSource, LeafNode, merge_to_right, NodeOpError};
 use crate::{FatTruncatedKey};
 use num_enum::{TryFromPrimitive};
 use std::intrinsics::transmute;
-use std::mem::{ManuallyDrop};
+use std::mem::{ManuallyDrop, MaybeUninit};
 use std::{mem, ptr};
 use std::ops::Range;
 use std::simd::Simd;
-use std::sync::atomic::Ordering;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use rand::{Rng};
 use rand::distributions::Uniform;
 use rand::distributions::uniform::{UniformInt, UniformSampler};
@@ -19,6 +19,7 @@ use crate::branch_cache::BranchCacheAccessor;
 use crate::vtables::BTreeNodeTag;
 #[allow(unused_imports)]
 use crate::head_node;
+use crate::node_stats;
 #[allow(unused_imports)]
 use crate::node_traits::FallbackInnerConversionSink;
 use crate::util::reinterpret_mut;
@@ -34,6 +35,91 @@ pub type DefaultInnerNodeConversionSink = FallbackInnerConversionSink<FallbackIn
 pub type DefaultInnerNodeConversionSink = FallbackInnerConversionSink<FallbackInnerConversionSink<head_node::U32ExplicitHeadNode, head_node::U64ExplicitHeadNode>, BasicNode>;
 #[cfg(feature = "inner_ascii")]
 pub type DefaultInnerNodeConversionSink = FallbackInnerConversionSink<head_node::AsciiHeadNode, BasicNode>;
+#[cfg(feature = "inner_runtime")]
+pub type DefaultInnerNodeConversionSink = RuntimeOrderInnerConversionSink;
+
+/// runtime counterpart to `DefaultInnerNodeConversionSink`, head nodes fall back to `BasicNode`
+pub fn create_inner_of(tag: BTreeNodeTag, dst: &mut BTreeNode, src: &(impl InnerConversionSource + ?Sized)) -> Result<(), NodeOpError> {
+    match tag {
+        BTreeNodeTag::BasicInner => BasicNode::create(dst, src),
+        BTreeNodeTag::U64ExplicitHead => FallbackInnerConversionSink::<head_node::U64ExplicitHeadNode, BasicNode>::create(dst, src),
+        BTreeNodeTag::U32ExplicitHead => FallbackInnerConversionSink::<head_node::U32ExplicitHeadNode, BasicNode>::create(dst, src),
+        BTreeNodeTag::U64ZeroPaddedHead => FallbackInnerConversionSink::<head_node::U64ZeroPaddedHeadNode, BasicNode>::create(dst, src),
+        BTreeNodeTag::U32ZeroPaddedHead => FallbackInnerConversionSink::<head_node::U32ZeroPaddedHeadNode, BasicNode>::create(dst, src),
+        BTreeNodeTag::AsciiHead => FallbackInnerConversionSink::<head_node::AsciiHeadNode, BasicNode>::create(dst, src),
+        BTreeNodeTag::ArtInner => ArtNode::create(dst, src),
+        BTreeNodeTag::BasicLeaf | BTreeNodeTag::HashLeaf | BTreeNodeTag::HybridLeaf => panic!("{tag:?} is not an inner node type"),
+    }
+}
+
+/// creates a node of type `tag` without falling back to `BasicNode`
+pub fn create_inner_exact(tag: BTreeNodeTag, dst: &mut BTreeNode, src: &(impl InnerConversionSource + ?Sized)) -> Result<(), NodeOpError> {
+    match tag {
+        BTreeNodeTag::BasicInner => BasicNode::create(dst, src),
+        BTreeNodeTag::U64ExplicitHead => head_node::U64ExplicitHeadNode::create(dst, src),
+        BTreeNodeTag::U32ExplicitHead => head_node::U32ExplicitHeadNode::create(dst, src),
+        BTreeNodeTag::U64ZeroPaddedHead => head_node::U64ZeroPaddedHeadNode::create(dst, src),
+        BTreeNodeTag::U32ZeroPaddedHead => head_node::U32ZeroPaddedHeadNode::create(dst, src),
+        BTreeNodeTag::AsciiHead => head_node::AsciiHeadNode::create(dst, src),
+        BTreeNodeTag::ArtInner => ArtNode::create(dst, src),
+        BTreeNodeTag::BasicLeaf | BTreeNodeTag::HashLeaf | BTreeNodeTag::HybridLeaf => panic!("{tag:?} is not an inner node type"),
+    }
+}
+
+/// cleared to force the scalar search in hash leaves. Layout still follows their `USE_SIMD`.
+pub(crate) static SIMD_FIND: AtomicBool = AtomicBool::new(true);
+
+/// selects simd or scalar hash search at runtime, for narrowing down bugs in the simd path.
+/// Shared by both hash leaf variants.
+pub fn set_simd_find(enabled: bool) {
+    SIMD_FIND.store(enabled, Ordering::Relaxed);
+}
+
+/// maximum length of an inner fallback order, one tag per byte of `INNER_FALLBACK_ORDER`
+pub const MAX_INNER_FALLBACK_ORDER: usize = 8;
+
+/// tags tried by `RuntimeOrderInnerConversionSink`, first tag in the lowest byte.
+/// Inner tags are odd, so a zero byte ends the list.
+static INNER_FALLBACK_ORDER: AtomicU64 = AtomicU64::new(
+    BTreeNodeTag::U32ExplicitHead as u64 | (BTreeNodeTag::U64ExplicitHead as u64) << 8 | (BTreeNodeTag::BasicInner as u64) << 16
+);
+
+/// sets the node types tried by `RuntimeOrderInnerConversionSink`, in order.
+/// The list must end with `BasicInner`, splits rely on the conversion succeeding.
+pub fn set_inner_fallback_order(order: &[BTreeNodeTag]) {
+    assert!(order.len() <= MAX_INNER_FALLBACK_ORDER);
+    assert_eq!(order.last(), Some(&BTreeNodeTag::BasicInner));
+    assert!(order.iter().all(|t| t.is_inner()), "fallback order contains leaf tag");
+    let packed = order.iter().rev().fold(0u64, |acc, &t| acc << 8 | u8::from(t) as u64);
+    INNER_FALLBACK_ORDER.store(packed, Ordering::Relaxed);
+}
+
+pub fn inner_fallback_order() -> Vec<BTreeNodeTag> {
+    let packed = INNER_FALLBACK_ORDER.load(Ordering::Relaxed);
+    packed.to_le_bytes().into_iter().take_while(|&b| b != 0).map(|b| BTreeNodeTag::try_from_primitive(b).unwrap()).collect()
+}
+
+/// tries the node types set by `set_inner_fallback_order`, so orders can be compared without recompiling
+pub struct RuntimeOrderInnerConversionSink;
+
+unsafe impl InnerConversionSink for RuntimeOrderInnerConversionSink {
+    fn create(dst: &mut BTreeNode, src: &(impl InnerConversionSource + ?Sized)) -> Result<(), NodeOpError> {
+        let mut packed = INNER_FALLBACK_ORDER.load(Ordering::Relaxed);
+        let mut position = 0;
+        loop {
+            let tag = BTreeNodeTag::try_from_primitive(packed as u8).unwrap();
+            packed >>= 8;
+            match create_inner_exact(tag, dst, src) {
+                Ok(()) => {
+                    node_stats::INNER_FALLBACK_CREATED[position].fetch_add(1, Ordering::Relaxed);
+                    return Ok(());
+                }
+                Err(e) if packed == 0 => return Err(e),
+                Err(_) => position += 1,
+            }
+        }
+    }
+}
 
 #[cfg(feature = "basic-prefix_true")]
 pub const BASIC_PREFIX: bool = true;
@@ -47,6 +133,78 @@ pub const STRIP_PREFIX: bool = false;
 
 pub const PAGE_SIZE: usize = 4096;
 
+/// leaf payloads start at a multiple of this, payloads in inner nodes are not aligned
+#[cfg(feature = "value-align_1")]
+pub const VALUE_ALIGN: usize = 1;
+#[cfg(feature = "value-align_8")]
+pub const VALUE_ALIGN: usize = 8;
+#[cfg(feature = "value-align_16")]
+pub const VALUE_ALIGN: usize = 16;
+
+/// new basic nodes leave their unused space uninitialized and new hash leaves are constructed in the page instead of on the stack
+#[cfg(feature = "lazy-zero_true")]
+pub const LAZY_ZERO: bool = true;
+#[cfg(feature = "lazy-zero_false")]
+pub const LAZY_ZERO: bool = false;
+
+/// the bytes of a leaf page, of which only the ranges the node has written are initialized (see `LAZY_ZERO`).
+/// Nodes hand this out instead of `&[u8; PAGE_SIZE]`, so only ranges referenced by slots, fences or hashes are read.
+/// Run the tests under Miri with `lazy-zero_true` to check that nothing else is.
+#[repr(transparent)]
+pub struct PageBytes([MaybeUninit<u8>; PAGE_SIZE]);
+
+impl PageBytes {
+    /// views a page sized node as bytes
+    pub fn of<T>(node: &T) -> &Self {
+        debug_assert_eq!(mem::size_of::<T>(), PAGE_SIZE);
+        unsafe { &*(node as *const T as *const Self) }
+    }
+
+    pub fn of_mut<T>(node: &mut T) -> &mut Self {
+        debug_assert_eq!(mem::size_of::<T>(), PAGE_SIZE);
+        unsafe { &mut *(node as *mut T as *mut Self) }
+    }
+
+    /// `range` must have been written
+    pub fn range(&self, range: Range<usize>) -> &[u8] {
+        unsafe { MaybeUninit::slice_assume_init_ref(&self.0[range]) }
+    }
+
+    /// `range` must have been written
+    pub fn range_mut(&mut self, range: Range<usize>) -> &mut [u8] {
+        unsafe { MaybeUninit::slice_assume_init_mut(&mut self.0[range]) }
+    }
+
+    pub fn short_slice(&self, offset: u16, len: u16) -> &[u8] {
+        self.range(offset as usize..(offset + len) as usize)
+    }
+
+    pub fn write(&mut self, offset: usize, data: &[u8]) {
+        MaybeUninit::write_slice(&mut self.0[offset..][..data.len()], data);
+    }
+
+    pub fn zero(&mut self, range: Range<usize>) {
+        self.0[range].fill(MaybeUninit::new(0));
+    }
+
+    /// like `slice::copy_within`, `src` must have been written
+    pub fn copy_within(&mut self, src: Range<usize>, dst: usize) {
+        self.0.copy_within(src, dst);
+    }
+}
+
+/// descending through more inner nodes than this indicates a broken tree, checked in debug builds
+pub const MAX_TREE_HEIGHT: usize = 64;
+
+/// limit for the combined length of key and payload of an entry, checked on insert
+pub const MAX_KEY_LEN: usize = PAGE_SIZE / 4;
+
+#[cfg(test)]
+thread_local! {
+    /// pages allocated minus pages freed by this thread, for leak checks in tests
+    pub static LIVE_PAGES: std::cell::Cell<isize> = std::cell::Cell::new(0);
+}
+
 #[repr(C)]
 pub union BTreeNode {
     pub raw_bytes: [u8; PAGE_SIZE],
@@ -56,17 +214,88 @@ pub union BTreeNode {
     pub art_node: ManuallyDrop<ArtNode>,
 }
 
+const _: () = assert!(mem::size_of::<BTreeNode>() == PAGE_SIZE);
+// page offsets are only aligned if the page is
+const _: () = assert!(mem::align_of::<BTreeNode>() >= VALUE_ALIGN);
+
 #[derive(Clone, Copy, Debug)]
 #[repr(C)]
 pub struct BTreeNodeHead {
     pub tag: BTreeNodeTag,
     pub adaption_state: AdaptionState,
+    /// replaced whenever slots of a leaf may have moved, see `BTreeNode::leaf_generation`
+    #[cfg(feature = "leaf-generation_true")]
+    generation: u32,
+}
+
+#[cfg(feature = "leaf-generation_true")]
+pub const LEAF_GENERATION: bool = true;
+#[cfg(feature = "leaf-generation_false")]
+pub const LEAF_GENERATION: bool = false;
+
+/// shared by all trees, so a node rebuilt in place never gets the generation it had before
+#[cfg(feature = "leaf-generation_true")]
+static NEXT_GENERATION: std::sync::atomic::AtomicU32 = std::sync::atomic::AtomicU32::new(1);
+
+impl BTreeNodeHead {
+    /// new nodes get a fresh generation
+    pub fn new(tag: BTreeNodeTag, adaption_state: AdaptionState) -> Self {
+        let mut head = BTreeNodeHead {
+            tag,
+            adaption_state,
+            #[cfg(feature = "leaf-generation_true")]
+            generation: 0,
+        };
+        head.touch();
+        head
+    }
+
+    /// assigns a fresh generation, cursors into this node must descend again
+    pub fn touch(&mut self) {
+        #[cfg(feature = "leaf-generation_true")]{
+            self.generation = NEXT_GENERATION.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// assigns each field, unlike a plain assignment this leaves the padding of `dst` as it was
+    pub fn copy_fields_to(&self, dst: &mut Self) {
+        dst.tag = self.tag;
+        dst.adaption_state = self.adaption_state;
+        #[cfg(feature = "leaf-generation_true")]{
+            dst.generation = self.generation;
+        }
+    }
+
+    /// always 0 if leaf generations are disabled
+    pub fn generation(&self) -> u32 {
+        #[cfg(feature = "leaf-generation_true")]{
+            self.generation
+        }
+        #[cfg(feature = "leaf-generation_false")]{
+            0
+        }
+    }
 }
 
+/// for leaves, bits 0-4 count range operations, bits 5-6 hold the `AccessPattern` and bit 7 marks short keys
 #[derive(Clone, Copy, Debug)]
 #[repr(transparent)]
 pub struct AdaptionState(u8);
 
+/// user provided hint overriding leaf adaption
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AccessPattern {
+    /// adapt to observed operations
+    Observed = 0,
+    /// keep leaves as hash leaves
+    Point = 1,
+    /// keep leaves as basic leaves
+    Range = 2,
+}
+
+const ACCESS_PATTERN_SHIFT: u32 = 5;
+const ACCESS_PATTERN_MASK: u8 = 0b11 << ACCESS_PATTERN_SHIFT;
+
 impl AdaptionState {
     pub fn new() -> Self {
         AdaptionState(0)
@@ -79,6 +308,19 @@ impl AdaptionState {
     pub fn is_adapted(&self) -> bool {
         self.0 != 0
     }
+
+    pub fn access_pattern(&self) -> AccessPattern {
+        match (self.0 & ACCESS_PATTERN_MASK) >> ACCESS_PATTERN_SHIFT {
+            0 => AccessPattern::Observed,
+            1 => AccessPattern::Point,
+            2 => AccessPattern::Range,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn set_access_pattern(&mut self, pattern: AccessPattern) {
+        self.0 = self.0 & !ACCESS_PATTERN_MASK | (pattern as u8) << ACCESS_PATTERN_SHIFT;
+    }
 }
 
 const LEAVE_NOTIFY_POINT_WEIGHT: f64 = 0.0083333333333333333333333333333 * LEAVE_ADAPTION_RANGE as f64;
@@ -92,6 +334,7 @@ const LEAVE_ADAPTION_RANGE: u8 = 7;
 const LEAVE_ADAPTION_RANGE: u8 = 15;
 #[cfg(feature = "leave-adapt-range_31")]
 const LEAVE_ADAPTION_RANGE: u8 = 31;
+const _: () = assert!(LEAVE_ADAPTION_RANGE < 1 << ACCESS_PATTERN_SHIFT);
 const BITS_PER_RAND: u32 = 32;
 const RAND_BIT: u64 = 1 << BITS_PER_RAND;
 
@@ -111,7 +354,7 @@ impl BTreeNode {
                         let indices: u16x8 = UniformInt::<u16x8>::sample_single(u16x8::splat(0), u16x8::splat(slots.len() as u16), unsafe { &mut *RAND });
                         (0..u16x8::lanes()).all(|i| slots[indices.extract(i) as usize].key_len <= 4)
                     }
-                    BTreeNodeTag::HashLeaf => {
+                    BTreeNodeTag::HashLeaf | BTreeNodeTag::HybridLeaf => {
                         let slots = unsafe { self.hash_leaf.slots() };
                         if slots.len() == 0 {
                             break 'key_scan;
@@ -124,13 +367,27 @@ impl BTreeNode {
                 self.head_mut().adaption_state.0 = self.head_mut().adaption_state.0 % 128 + if is_short { 128 } else { 0 };
             }
         }
+        self.leave_apply_adaption();
+    }
+
+    fn leave_apply_adaption(&mut self) {
+        let state = self.head_mut().adaption_state;
+        let observed = state.0 & !ACCESS_PATTERN_MASK;
+        let (to_hash, to_basic) = match state.access_pattern() {
+            AccessPattern::Observed => (observed == 0, observed >= LEAVE_ADAPTION_RANGE),
+            AccessPattern::Point => (true, false),
+            AccessPattern::Range => (false, true),
+        };
         match self.tag() {
-            BTreeNodeTag::BasicLeaf => if self.head_mut().adaption_state.0 == 0 {
+            BTreeNodeTag::BasicLeaf => if to_hash {
                 HashLeaf::from_basic(self);
+                node_stats::LEAF_TO_HASH.fetch_add(1, Ordering::Relaxed);
             }
-            BTreeNodeTag::HashLeaf => if self.head_mut().adaption_state.0 >= LEAVE_ADAPTION_RANGE {
+            BTreeNodeTag::HashLeaf => if to_basic {
                 use std::sync::atomic::*;
                 let is_err = HashLeaf::to_basic(self).is_err();
+                let counter = if is_err { &node_stats::LEAF_TO_BASIC_FAILED } else { &node_stats::LEAF_TO_BASIC };
+                counter.fetch_add(1, Ordering::Relaxed);
                 if cfg!(debug_assertions) {
                     static TOTAL: AtomicUsize = AtomicUsize::new(0);
                     static FAILED: AtomicUsize = AtomicUsize::new(0);
@@ -141,17 +398,25 @@ impl BTreeNode {
                     }
                 }
             }
+            // serves both access patterns without conversion
+            BTreeNodeTag::HybridLeaf => {}
             _ => unreachable!()
         }
     }
 
+    /// sets the access pattern hint of a leaf and converts it if the hint demands it
+    pub fn leave_set_access_pattern(&mut self, pattern: AccessPattern) {
+        self.head_mut().adaption_state.set_access_pattern(pattern);
+        self.leave_apply_adaption();
+    }
+
     pub fn leave_notify_point_op(&mut self) {
         #[cfg(feature = "leaf_adapt")]{
             const THRESHOLD: u64 = (LEAVE_NOTIFY_POINT_WEIGHT * RAND_BIT as f64) as u64;
             let rand = unsafe { &mut *RAND }.gen::<u64>();
             if rand & (RAND_BIT - 1) < THRESHOLD {
                 let head = self.head_mut();
-                if head.adaption_state.0 % 128 > 0 {
+                if head.adaption_state.0 % (1 << ACCESS_PATTERN_SHIFT) > 0 {
                     head.adaption_state.0 -= 1;
                 }
             }
@@ -165,7 +430,7 @@ impl BTreeNode {
             let rand = unsafe { &mut *RAND }.gen::<u64>();
             if rand & (RAND_BIT - 1) < THRESHOLD {
                 let head = self.head_mut();
-                if head.adaption_state.0 % 128 < LEAVE_ADAPTION_RANGE {
+                if head.adaption_state.0 % (1 << ACCESS_PATTERN_SHIFT) < LEAVE_ADAPTION_RANGE {
                     head.adaption_state.0 += 1;
                 }
             }
@@ -185,6 +450,19 @@ impl BTreeNode {
         BTreeNode { uninit: () }
     }
 
+    /// copy of a leaf or basic inner page in which unused space and padding are zero, see `BTree::dump_leaf_for`
+    pub fn written_bytes(&self) -> [u8; PAGE_SIZE] {
+        let mut page = BTreeNode { raw_bytes: [0; PAGE_SIZE] };
+        unsafe {
+            match self.tag() {
+                BTreeNodeTag::BasicLeaf | BTreeNodeTag::BasicInner => self.basic.copy_written_to(&mut page.basic),
+                BTreeNodeTag::HashLeaf | BTreeNodeTag::HybridLeaf => self.hash_leaf.copy_written_to(&mut page.hash_leaf),
+                tag => panic!("not a leaf or basic node: {:?}", tag),
+            }
+            page.raw_bytes
+        }
+    }
+
     pub fn tag(&self) -> BTreeNodeTag {
         BTreeNodeTag::try_from_primitive(unsafe { self.raw_bytes[0] }).unwrap()
     }
@@ -196,69 +474,165 @@ impl BTreeNode {
         unsafe { &mut *(self as *mut BTreeNode as *mut BTreeNodeHead) }
     }
 
+    /// changes whenever the slots of this leaf may have moved without a split or merge, which are tracked by the tree's generation
+    pub fn leaf_generation(&self) -> u32 {
+        debug_assert!(self.tag().is_leaf());
+        unsafe { (*(self as *const BTreeNode as *const BTreeNodeHead)).generation() }
+    }
+
     pub fn adaption_state(&mut self) -> &mut AdaptionState {
         unsafe { reinterpret_mut::<u8, AdaptionState>(&mut self.raw_bytes[1]) }
     }
 
     /// descends to target node, returns target node, parent, and index within parent
     pub fn descend(
+        &mut self,
+        key: &[u8],
+        filter: impl FnMut(*mut BTreeNode) -> bool,
+        bc: &mut BranchCacheAccessor,
+    ) -> (*mut BTreeNode, *mut BTreeNode, usize) {
+        self.descend_impl(key, filter, bc, true)
+    }
+
+    /// descends to `target`, which must lie on the path of `key`.
+    /// Does not adapt inner nodes, so the path and child indices seen by the caller's previous descent stay valid.
+    pub fn descend_to_node(
+        &mut self,
+        key: &[u8],
+        target: *mut BTreeNode,
+        bc: &mut BranchCacheAccessor,
+    ) -> (*mut BTreeNode, *mut BTreeNode, usize) {
+        let (node, parent, index) = self.descend_impl(key, |n| n == target, bc, false);
+        debug_assert!(node == target);
+        (node, parent, index)
+    }
+
+    fn descend_impl(
         mut self: &mut Self,
         key: &[u8],
         mut filter: impl FnMut(*mut BTreeNode) -> bool,
         bc: &mut BranchCacheAccessor,
+        adapt: bool,
     ) -> (*mut BTreeNode, *mut BTreeNode, usize) {
         let mut parent = ptr::null_mut();
         let mut index = 0;
+        let mut depth = 0;
         bc.reset();
         while self.tag().is_inner() && !filter(self) {
-            index = self.to_inner_mut().find_child_index(key, bc);
-            parent = self;
-            if cfg!(feature = "descend-adapt-inner_10") {
-                if !self.adaption_state().is_adapted() && infrequent(10) {
-                    adapt_inner(self);
-                    self.adaption_state().set_adapted(true);
-                }
-            } else if cfg!(feature = "descend-adapt-inner_100") {
-                if !self.adaption_state().is_adapted() && infrequent(100) {
-                    adapt_inner(self);
-                    self.adaption_state().set_adapted(true);
-                }
-            } else if cfg!(feature = "descend-adapt-inner_1000") {
-                if !self.adaption_state().is_adapted() && infrequent(1000) {
-                    adapt_inner(self);
-                    self.adaption_state().set_adapted(true);
+            depth += 1;
+            debug_assert!(depth <= MAX_TREE_HEIGHT, "descent exceeded {MAX_TREE_HEIGHT} levels, key: {key:?}");
+            // adapt before searching, so the index refers to the node as the caller will see it
+            if adapt {
+                if cfg!(feature = "descend-adapt-inner_10") {
+                    if !self.adaption_state().is_adapted() && infrequent(10) {
+                        adapt_inner(self);
+                        self.adaption_state().set_adapted(true);
+                    }
+                } else if cfg!(feature = "descend-adapt-inner_100") {
+                    if !self.adaption_state().is_adapted() && infrequent(100) {
+                        adapt_inner(self);
+                        self.adaption_state().set_adapted(true);
+                    }
+                } else if cfg!(feature = "descend-adapt-inner_1000") {
+                    if !self.adaption_state().is_adapted() && infrequent(1000) {
+                        adapt_inner(self);
+                        self.adaption_state().set_adapted(true);
+                    }
+                } else {
+                    assert!(cfg!(feature = "descend-adapt-inner_none"))
                 }
-            } else {
-                assert!(cfg!(feature = "descend-adapt-inner_none"))
             }
+            index = self.to_inner_mut().find_child_index(key, bc);
+            parent = self;
             self = unsafe { &mut *self.to_inner().get_child(index) };
         }
         (self, parent, index)
     }
 
+    /// leaf responsible for key, found without branch cache, adaptation or other node changes
+    pub fn find_leaf(&self, key: &[u8]) -> &BTreeNode {
+        let mut node = self;
+        let mut depth = 0;
+        while node.tag().is_inner() {
+            depth += 1;
+            debug_assert!(depth <= MAX_TREE_HEIGHT, "descent exceeded {MAX_TREE_HEIGHT} levels, key: {key:?}");
+            let inner = node.to_inner();
+            node = unsafe { &*inner.get_child(inner.lookup_child_index(key)) };
+        }
+        node
+    }
+
     pub unsafe fn alloc() -> *mut BTreeNode {
+        #[cfg(test)]
+        LIVE_PAGES.with(|live| live.set(live.get() + 1));
         Box::into_raw(Box::new(BTreeNode::new_uninit()))
     }
 
     pub unsafe fn dealloc(node: *mut BTreeNode) {
+        #[cfg(test)]
+        LIVE_PAGES.with(|live| live.set(live.get() - 1));
         drop(Box::from_raw(node));
     }
 
     pub fn new_leaf() -> *mut BTreeNode {
+        if cfg!(feature = "leaf_hash") || cfg!(feature = "leaf_adapt") {
+            Self::new_leaf_of(BTreeNodeTag::HashLeaf)
+        } else if cfg!(feature = "leaf_hybrid") {
+            Self::new_leaf_of(BTreeNodeTag::HybridLeaf)
+        } else if cfg!(feature = "leaf_basic") {
+            Self::new_leaf_of(BTreeNodeTag::BasicLeaf)
+        } else {
+            panic!();
+        }
+    }
+
+    /// creates an empty leaf of type `tag` regardless of the configured leaf type
+    pub fn new_leaf_of(tag: BTreeNodeTag) -> *mut BTreeNode {
         unsafe {
             let leaf = Self::alloc();
-            if cfg!(feature = "leaf_hash") || cfg!(feature = "leaf_adapt") {
-                (*leaf).hash_leaf = ManuallyDrop::new(HashLeaf::new())
-            } else if cfg!(feature = "leaf_basic") {
-                (*leaf).basic = BasicNode::new_leaf();
-            } else {
-                panic!();
+            match tag {
+                BTreeNodeTag::HashLeaf | BTreeNodeTag::HybridLeaf if LAZY_ZERO => HashLeaf::init_in_place(ptr::addr_of_mut!((*leaf).hash_leaf) as *mut HashLeaf, tag),
+                BTreeNodeTag::HashLeaf => (*leaf).hash_leaf = ManuallyDrop::new(HashLeaf::new()),
+                BTreeNodeTag::HybridLeaf => (*leaf).hash_leaf = ManuallyDrop::new(HashLeaf::new_hybrid()),
+                BTreeNodeTag::BasicLeaf => (*leaf).basic = BasicNode::new_leaf(),
+                _ => panic!("not a leaf type: {:?}", tag),
             }
             leaf
         }
     }
 
+    /// creates a leaf of type `tag` holding `entries`, which must lie within `fences`.
+    /// Fails if the entries do not fit.
+    pub fn leaf_from_entries<'a>(tag: BTreeNodeTag, fences: FenceData, entries: impl IntoIterator<Item=(&'a [u8], &'a [u8])>) -> Result<BTreeNode, NodeOpError> {
+        Self::leaf_from_fn(tag, fences, |basic| {
+            for (key, value) in entries {
+                basic.insert(key, value)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// creates a leaf of type `tag`, `fill` inserts the entries into a basic leaf with the given fences first
+    pub fn leaf_from_fn(tag: BTreeNodeTag, fences: FenceData, fill: impl FnOnce(&mut BasicNode) -> Result<(), NodeOpError>) -> Result<BTreeNode, NodeOpError> {
+        assert!(tag.is_leaf());
+        let mut basic = BasicNode::new_leaf();
+        basic.set_fences(fences);
+        fill(&mut basic)?;
+        let mut node = BTreeNode { basic };
+        match tag {
+            BTreeNodeTag::HashLeaf => HashLeaf::from_basic(&mut node),
+            BTreeNodeTag::HybridLeaf => HashLeaf::hybrid_from_basic(&mut node),
+            _ => {}
+        }
+        Ok(node)
+    }
+
     pub fn new_inner(child: *mut BTreeNode) -> *mut BTreeNode {
+        Self::new_inner_of(None, child)
+    }
+
+    /// creates an inner node of type `inner_tag`, or using `DefaultInnerNodeConversionSink` if it is `None`
+    pub fn new_inner_of(inner_tag: Option<BTreeNodeTag>, child: *mut BTreeNode) -> *mut BTreeNode {
         struct RootSource {
             child: *mut BTreeNode,
         }
@@ -276,7 +650,7 @@ impl BTreeNode {
                 self.child
             }
 
-            fn get_key(&self, _index: usize, _dst: &mut [u8], _strip_prefix: usize) -> Result<usize, ()> {
+            fn get_key(&self, _index: usize, _dst: &mut [u8], _strip_prefix: usize) -> Result<usize, NodeOpError> {
                 panic!()
             }
 
@@ -290,7 +664,10 @@ impl BTreeNode {
         }
         unsafe {
             let node = Self::alloc();
-            DefaultInnerNodeConversionSink::create(&mut *node, &RootSource { child }).unwrap();
+            match inner_tag {
+                Some(tag) => create_inner_of(tag, &mut *node, &RootSource { child }).unwrap(),
+                None => DefaultInnerNodeConversionSink::create(&mut *node, &RootSource { child }).unwrap(),
+            }
             node
         }
     }
@@ -301,7 +678,7 @@ impl BTreeNode {
         &mut self,
         right: &mut BTreeNode,
         separator: FatTruncatedKey,
-    ) -> Result<(), ()> {
+    ) -> Result<(), NodeOpError> {
         debug_assert!(self.is_underfull());
         if right.tag().is_leaf() {
             debug_assert!(right.is_underfull());
@@ -316,13 +693,203 @@ impl BTreeNode {
                     if rt == BTreeNodeTag::BasicLeaf {
                         HashLeaf::from_basic(right);
                     }
-                    debug_assert!(self.tag() == BTreeNodeTag::HashLeaf);
-                    debug_assert!(right.tag() == BTreeNodeTag::HashLeaf);
+                    debug_assert!(matches!(self.tag(), BTreeNodeTag::HashLeaf | BTreeNodeTag::HybridLeaf));
+                    debug_assert!(matches!(right.tag(), BTreeNodeTag::HashLeaf | BTreeNodeTag::HybridLeaf));
                     self.hash_leaf.try_merge_right(&mut (*right).hash_leaf, separator)
                 } else {
                     debug_assert!(rt.is_inner());
-                    merge_to_right::<BasicNode>(self, right, separator)
+                    match lt {
+                        BTreeNodeTag::U64ExplicitHead => reinterpret_mut::<Self, head_node::U64ExplicitHeadNode>(self).merge_right(right, separator),
+                        BTreeNodeTag::U32ExplicitHead => reinterpret_mut::<Self, head_node::U32ExplicitHeadNode>(self).merge_right(right, separator),
+                        BTreeNodeTag::U64ZeroPaddedHead => reinterpret_mut::<Self, head_node::U64ZeroPaddedHeadNode>(self).merge_right(right, separator),
+                        BTreeNodeTag::U32ZeroPaddedHead => reinterpret_mut::<Self, head_node::U32ZeroPaddedHeadNode>(self).merge_right(right, separator),
+                        BTreeNodeTag::AsciiHead => reinterpret_mut::<Self, head_node::AsciiHeadNode>(self).merge_right(right, separator),
+                        _ => merge_to_right::<BasicNode>(self, right, separator),
+                    }
+                }
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::head_node::{AsciiHeadNode, U32ExplicitHeadNode, U32ZeroPaddedHeadNode, U64ExplicitHeadNode, U64ZeroPaddedHeadNode};
+    use crate::test_util::{free_leaf, LEAF_TAGS};
+    use crate::PAGE_SIZE;
+
+    /// size of the node type behind `tag`, the match is exhaustive so new tags must be added here
+    fn node_size(tag: BTreeNodeTag) -> usize {
+        match tag {
+            BTreeNodeTag::BasicLeaf | BTreeNodeTag::BasicInner => mem::size_of::<BasicNode>(),
+            BTreeNodeTag::HashLeaf | BTreeNodeTag::HybridLeaf => mem::size_of::<HashLeaf>(),
+            BTreeNodeTag::U64ExplicitHead => mem::size_of::<U64ExplicitHeadNode>(),
+            BTreeNodeTag::U32ExplicitHead => mem::size_of::<U32ExplicitHeadNode>(),
+            BTreeNodeTag::U64ZeroPaddedHead => mem::size_of::<U64ZeroPaddedHeadNode>(),
+            BTreeNodeTag::U32ZeroPaddedHead => mem::size_of::<U32ZeroPaddedHeadNode>(),
+            BTreeNodeTag::AsciiHead => mem::size_of::<AsciiHeadNode>(),
+            BTreeNodeTag::ArtInner => mem::size_of::<ArtNode>(),
+        }
+    }
+
+    #[test]
+    fn every_node_layout_fills_a_page() {
+        assert_eq!(mem::size_of::<BTreeNode>(), PAGE_SIZE);
+        let tags: Vec<BTreeNodeTag> = (0..=u8::MAX).filter_map(|t| BTreeNodeTag::try_from_primitive(t).ok()).collect();
+        assert_eq!(tags.len(), 10);
+        for tag in tags {
+            assert_eq!(node_size(tag), PAGE_SIZE, "{tag:?}");
+        }
+    }
+
+    #[test]
+    fn new_leaf_of_creates_requested_type() {
+        crate::ensure_init();
+        for tag in LEAF_TAGS {
+            unsafe {
+                let leaf = BTreeNode::new_leaf_of(tag);
+                assert_eq!((*leaf).tag(), tag);
+                for i in 0..100u64 {
+                    assert_eq!((*leaf).to_leaf_mut().insert(&i.to_be_bytes(), &(i as u32).to_le_bytes()), Ok(true), "{tag:?}");
+                }
+                for i in (0..100u64).step_by(2) {
+                    assert_eq!((*leaf).to_leaf_mut().remove(&i.to_be_bytes()), Some(()), "{tag:?}");
+                }
+                assert_eq!((*leaf).to_leaf_mut().remove(&0u64.to_be_bytes()), None, "{tag:?}");
+                for i in 0..100u64 {
+                    let expected = (i % 2 == 1).then(|| (i as u32).to_le_bytes());
+                    assert_eq!((*leaf).to_leaf().lookup(&i.to_be_bytes()), expected.as_ref().map(|v| &v[..]), "{tag:?}");
+                }
+                assert_eq!((*leaf).tag(), tag);
+                free_leaf(leaf);
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "not a leaf type")]
+    fn new_leaf_of_rejects_inner_tags() {
+        BTreeNode::new_leaf_of(BTreeNodeTag::BasicInner);
+    }
+
+    #[test]
+    fn values_are_aligned() {
+        crate::ensure_init();
+        for tag in LEAF_TAGS {
+            unsafe {
+                let leaf = BTreeNode::new_leaf_of(tag);
+                // odd key and value lengths would misalign tightly packed values
+                let entry = |i: usize| (vec![i as u8; 1 + i % 7], vec![i as u8; 1 + i % 5]);
+                let mut count = 0;
+                while (*leaf).to_leaf_mut().insert(&entry(count).0, &entry(count).1).is_ok() {
+                    count += 1;
+                }
+                assert!(count > 20);
+                // compaction moves values, they must stay aligned
+                for i in (0..count).step_by(3) {
+                    (*leaf).to_leaf_mut().remove(&entry(i).0);
                 }
+                (*leaf).to_leaf_mut().compactify();
+                for i in (0..count).filter(|i| i % 3 != 0) {
+                    let (key, value) = entry(i);
+                    let found = (*leaf).to_leaf().lookup(&key).unwrap();
+                    assert_eq!(found, &value[..]);
+                    assert_eq!(found.as_ptr() as usize % VALUE_ALIGN, 0, "{tag:?} entry {i}");
+                }
+                free_leaf(leaf);
+            }
+        }
+    }
+
+    #[test]
+    fn reinserting_identical_values_leaves_page_untouched() {
+        crate::ensure_init();
+        for tag in LEAF_TAGS {
+            unsafe {
+                let leaf = BTreeNode::new_leaf_of(tag);
+                for i in 0..100u64 {
+                    (*leaf).to_leaf_mut().insert(&i.to_be_bytes(), &(i as u32).to_le_bytes()).unwrap();
+                }
+                // unused space may be uninitialized, so only written bytes are compared
+                let before = (*leaf).written_bytes();
+                for i in 0..100u64 {
+                    (*leaf).to_leaf_mut().insert(&i.to_be_bytes(), &(i as u32).to_le_bytes()).unwrap();
+                }
+                assert!((*leaf).written_bytes() == before, "{tag:?}");
+                (*leaf).to_leaf_mut().insert(&7u64.to_be_bytes(), &[1, 2, 3, 4]).unwrap();
+                assert!((*leaf).written_bytes() != before, "{tag:?}");
+                assert_eq!((*leaf).to_leaf().lookup(&7u64.to_be_bytes()), Some(&[1, 2, 3, 4][..]));
+                free_leaf(leaf);
+            }
+        }
+    }
+
+    #[test]
+    fn slot_lookup_updates_values_in_place() {
+        crate::ensure_init();
+        for tag in LEAF_TAGS {
+            unsafe {
+                let leaf = BTreeNode::new_leaf_of(tag);
+                for i in 0..100u64 {
+                    (*leaf).to_leaf_mut().insert(&i.to_be_bytes(), &(i as u32).to_le_bytes()).unwrap();
+                }
+                let slots: Vec<Option<usize>> = (0..100u64).map(|i| (*leaf).to_leaf().lookup_slot(&i.to_be_bytes())).collect();
+                assert!(slots.iter().all(Option::is_some), "{tag:?}");
+                assert_eq!((*leaf).to_leaf().lookup_slot(&1000u64.to_be_bytes()), None);
+                let index = slots[42].unwrap();
+                assert_eq!((*leaf).to_leaf().value_at(index), &42u32.to_le_bytes());
+                (*leaf).to_leaf_mut().set_value_at(index, &[9, 9, 9, 9]).unwrap();
+                assert_eq!((*leaf).to_leaf_mut().set_value_at(index, &[1; 5]), Err(NodeOpError::NoSpace));
+                assert_eq!((*leaf).to_leaf().lookup(&42u64.to_be_bytes()), Some(&[9, 9, 9, 9][..]), "{tag:?}");
+                (*leaf).to_leaf_mut().set_value_at(index, &[7]).unwrap();
+                assert_eq!((*leaf).to_leaf().lookup(&42u64.to_be_bytes()), Some(&[7][..]), "{tag:?}");
+                // no entry moved and no other value changed
+                for i in 0..100u64 {
+                    assert_eq!((*leaf).to_leaf().lookup_slot(&i.to_be_bytes()), slots[i as usize], "{tag:?}");
+                    if i != 42 {
+                        assert_eq!((*leaf).to_leaf().lookup(&i.to_be_bytes()), Some(&(i as u32).to_le_bytes()[..]), "{tag:?}");
+                    }
+                }
+                if tag == BTreeNodeTag::BasicLeaf {
+                    assert_eq!((*leaf).basic.check_space_accounting(), Ok(()));
+                } else {
+                    assert_eq!((*leaf).hash_leaf.check_space_accounting(), Ok(()));
+                }
+                free_leaf(leaf);
+            }
+        }
+    }
+
+    #[test]
+    fn runtime_fallback_order_picks_first_representable_type() {
+        use crate::test_util::ManualInnerSource;
+        crate::ensure_init();
+        let source = |keys: &[&[u8]]| ManualInnerSource::with_keys(keys.iter().map(|k| k.to_vec()).collect());
+        // short ascii keys fit every head, the others only fit some of them
+        let sources = [
+            source(&[&b"ab"[..], b"cd", b"ef"]),
+            source(&[&b"abcdef"[..], b"abcdeg", b"b"]),
+            source(&[&b"a\xff"[..], b"b\xff", b"c"]),
+            source(&[&b"abcdefghijkl"[..], b"b", b"c"]),
+        ];
+        let orders: [&[BTreeNodeTag]; 4] = [
+            &[BTreeNodeTag::BasicInner],
+            &[BTreeNodeTag::U32ExplicitHead, BTreeNodeTag::U64ExplicitHead, BTreeNodeTag::BasicInner],
+            &[BTreeNodeTag::AsciiHead, BTreeNodeTag::U32ZeroPaddedHead, BTreeNodeTag::BasicInner],
+            &[BTreeNodeTag::U64ZeroPaddedHead, BTreeNodeTag::ArtInner, BTreeNodeTag::BasicInner],
+        ];
+        let _restore = crate::test_util::InnerFallbackOrderGuard::save();
+        for order in orders {
+            set_inner_fallback_order(order);
+            assert_eq!(inner_fallback_order(), order);
+            for source in &sources {
+                let expected = *order.iter().find(|&&tag| create_inner_exact(tag, &mut unsafe { BTreeNode::new_uninit() }, source).is_ok()).unwrap();
+                let mut node = unsafe { BTreeNode::new_uninit() };
+                RuntimeOrderInnerConversionSink::create(&mut node, source).unwrap();
+                assert_eq!(node.tag(), expected, "{order:?} {:?}", source.keys);
+                assert!(source.matches(node.to_inner()), "{order:?} {:?}", source.keys);
             }
         }
     }
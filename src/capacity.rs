@@ -0,0 +1,40 @@
+use crate::basic_node::BasicNode;
+use crate::hash_leaf::HashLeaf;
+use crate::vtables::BTreeNodeTag;
+
+/// number of entries with `key_len` byte keys sharing a `prefix_len` byte prefix and `val_len` byte values that fit into a single leaf of type `tag`.
+/// Fence keys are assumed to be empty, as in a root leaf, so leaves with fences may hold slightly fewer.
+pub fn leaf_capacity(tag: BTreeNodeTag, prefix_len: usize, key_len: usize, val_len: usize) -> usize {
+    assert!(prefix_len <= key_len);
+    match tag {
+        BTreeNodeTag::BasicLeaf => BasicNode::leaf_capacity(prefix_len, key_len, val_len),
+        BTreeNodeTag::HashLeaf | BTreeNodeTag::HybridLeaf => HashLeaf::leaf_capacity(prefix_len, key_len, val_len),
+        _ => panic!("not a leaf type: {:?}", tag),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::{free_leaf, LEAF_TAGS};
+    use crate::BTreeNode;
+
+    #[test]
+    fn predicted_capacity_matches_filled_leaf() {
+        crate::ensure_init();
+        for tag in LEAF_TAGS {
+            for (key_len, val_len) in [(8, 0), (8, 8), (12, 100), (40, 3), (200, 500)] {
+                unsafe {
+                    let leaf = BTreeNode::new_leaf_of(tag);
+                    let mut count = 0u64;
+                    let key = |i: u64| [vec![0u8; key_len - 8], i.to_be_bytes().to_vec()].concat();
+                    while (*leaf).to_leaf_mut().insert(&key(count), &vec![1u8; val_len]).is_ok() {
+                        count += 1;
+                    }
+                    assert_eq!(count as usize, leaf_capacity(tag, 0, key_len, val_len), "{tag:?} {key_len} {val_len}");
+                    free_leaf(leaf);
+                }
+            }
+        }
+    }
+}
@@ -34,22 +34,65 @@ pub fn find_separator<'a, K: KeyRef<'a>, F: FnMut(usize) -> K>(
         // inner nodes are split in the middle
         // do not truncate separator to retain fence keys in children
         let slot_id = count as usize / 2;
+        debug_assert!(!is_degenerate_split(count, slot_id, false), "degenerate split of {count} slots at {slot_id}");
         return (slot_id, k(slot_id));
     }
 
     let best_slot = if count >= 16 {
-        let lower = count / 2 - count / 16;
-        let upper = count / 2;
-        let best_prefix_len = k(0).common_prefix_len(k(lower));
-        (lower + 1..=upper)
-            .rev()
-            .find(|&i| k(0).common_prefix_len(k(i)) == best_prefix_len)
-            .unwrap_or(lower)
+        max_prefix_slot(count / 2 - count / 16, count / 2, &mut k)
     } else {
         (count - 1) / 2
     };
+    truncate_separator(count, best_slot, k)
+}
+
+/// like `find_separator` for leaves, but splits where the cumulative `slot_size` crosses half instead of at half the count
+pub fn find_separator_by_size<'a, K: KeyRef<'a>, F: FnMut(usize) -> K>(
+    count: usize,
+    mut k: F,
+    slot_size: impl Fn(usize) -> usize,
+) -> (usize, K) {
+    debug_assert!(count > 1);
+    let total: usize = (0..count).map(&slot_size).sum();
+    let mut cumulative = 0;
+    // upper range must not be empty
+    let middle = (0..count - 1).find(|&i| {
+        cumulative += slot_size(i);
+        cumulative * 2 >= total
+    }).unwrap_or(count - 2);
+    let best_slot = if count >= 16 {
+        max_prefix_slot(middle.saturating_sub(count / 16), middle, &mut k)
+    } else {
+        middle
+    };
+    truncate_separator(count, best_slot, k)
+}
 
-    // try to truncate separator
+/// true if splitting `count` slots at `slot_id` leaves one side empty.
+/// The remaining side would be as full as the original node, so splitting it again could not make progress.
+pub fn is_degenerate_split(count: usize, slot_id: usize, is_leaf: bool) -> bool {
+    if is_leaf {
+        slot_id + 1 >= count
+    } else {
+        // children ..=slot_id go left, slot_id + 1..=count go right
+        slot_id >= count
+    }
+}
+
+/// picks the highest slot in `lower..=upper` that shares as long a prefix with slot 0 as `lower` does.
+/// Keys are sorted, so the lower half keeps the longest possible common prefix
+/// and the upper half starts where the prefix changes, which allows a short separator.
+fn max_prefix_slot<'a, K: KeyRef<'a>, F: FnMut(usize) -> K>(lower: usize, upper: usize, k: &mut F) -> usize {
+    let best_prefix_len = k(0).common_prefix_len(k(lower));
+    (lower + 1..=upper)
+        .rev()
+        .find(|&i| k(0).common_prefix_len(k(i)) == best_prefix_len)
+        .unwrap_or(lower)
+}
+
+/// try to truncate separator
+fn truncate_separator<'a, K: KeyRef<'a>, F: FnMut(usize) -> K>(count: usize, best_slot: usize, mut k: F) -> (usize, K) {
+    debug_assert!(!is_degenerate_split(count, best_slot, true), "degenerate split of {count} slots at {best_slot}");
     if best_slot + 1 < count {
         let common = k(best_slot).common_prefix_len(k(best_slot + 1));
         if k(best_slot).len() > common && k(best_slot + 1).len() > common + 1 {
@@ -58,3 +101,54 @@ pub fn find_separator<'a, K: KeyRef<'a>, F: FnMut(usize) -> K>(
     }
     (best_slot, k(best_slot))
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn size_balanced_separator_splits_at_half_the_bytes() {
+        let keys: Vec<[u8; 4]> = (0..100u32).map(|i| i.to_be_bytes()).collect();
+        let k = |i: usize| PrefixTruncatedKey(&keys[i]);
+        // the first 20 entries take as much space as the other 80
+        let skewed = |i: usize| if i < 20 { 100 } else { 25 };
+        let (slot, separator) = find_separator_by_size(keys.len(), k, skewed);
+        assert_eq!(slot, 19);
+        assert_eq!(separator.0, &keys[19]);
+        let (count_slot, _) = find_separator(keys.len(), true, k);
+        assert_eq!(count_slot, 50);
+        // with equal sizes both split in the middle
+        assert_eq!(find_separator_by_size(keys.len(), k, |_| 10).0, 49);
+        // the upper half is never empty, even if the last entry dominates
+        assert_eq!(find_separator_by_size(keys.len(), k, |i| if i == 99 { 10_000 } else { 1 }).0, 98);
+    }
+
+    #[test]
+    fn separator_separates_halves() {
+        use rand::{Rng, SeedableRng};
+        let mut rng = rand_xoshiro::Xoshiro128PlusPlus::seed_from_u64(12);
+        for count in 2..200 {
+            // few distinct leading bytes, so the window often contains a change of prefix
+            let mut keys: Vec<Vec<u8>> = (0..count).map(|_| {
+                let mut key = vec![rng.gen_range(b'a'..b'd'); rng.gen_range(1..4)];
+                key.extend((0..rng.gen_range(0..12)).map(|_| rng.gen_range(b'a'..b'z')));
+                key
+            }).collect();
+            keys.sort();
+            keys.dedup();
+            if keys.len() < 2 {
+                continue;
+            }
+            let k = |i: usize| PrefixTruncatedKey(&keys[i]);
+            let by_count = find_separator(keys.len(), true, k);
+            let by_size = find_separator_by_size(keys.len(), k, |i| keys[i].len() + 8);
+            for (slot, separator) in [by_count, by_size] {
+                assert!(slot + 1 < keys.len(), "{count}: upper half is empty");
+                assert!(keys[slot][..] <= *separator.0 && *separator.0 < keys[slot + 1][..], "{count}: {separator:?} does not separate slot {slot}");
+                // a truncated separator is no longer than needed to tell the halves apart
+                let common = common_prefix_len(&keys[slot], &keys[slot + 1]);
+                assert!(separator.0.len() <= (common + 1).max(keys[slot].len()), "{count}: {separator:?}");
+            }
+        }
+    }
+}
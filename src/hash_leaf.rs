@@ -1,32 +1,49 @@
-use crate::find_separator::find_separator;
-use crate::node_traits::{FenceData, FenceRef, InnerConversionSource, InnerNode, LeafNode, Node};
-use crate::util::{head, MergeFences, partial_restore, reinterpret_mut, short_slice, SplitFences};
+use crate::find_separator::{find_separator, find_separator_by_size};
+use crate::node_traits::{FenceData, FenceRef, InnerConversionSource, InnerNode, LeafNode, Node, NodeOpError, debug_assert_split_conserves_keys, debug_assert_merge_conserves_keys};
+use crate::util::{aligned_value_end, head, KeyBuffer, MergeFences, partial_restore, reinterpret_mut, SplitFences};
 use crate::{BTreeNode, FatTruncatedKey, PAGE_SIZE, PrefixTruncatedKey};
 use std::io::Write;
-use std::mem::{align_of, ManuallyDrop, MaybeUninit, size_of, transmute};
+use std::mem::{align_of, ManuallyDrop, MaybeUninit, size_of};
+use std::cell::RefCell;
 use std::ptr;
+use std::sync::atomic::Ordering;
+use std::ops::Range;
 use std::simd::SimdPartialEq;
 use libc::key_t;
 use crate::basic_node::{BasicNode, BasicNodeHead, BasicSlot};
-use crate::btree_node::{AdaptionState, BTreeNodeHead};
+use crate::btree_node::{AdaptionState, BTreeNodeHead, LAZY_ZERO, PageBytes, SIMD_FIND, VALUE_ALIGN};
 use crate::vtables::BTreeNodeTag;
 use crate::basic_node::FenceKeySlot;
+use crate::node_stats::HASH_LEAF_REQUEST_SPACE;
 
 
 #[derive(Clone, Copy)]
+#[repr(C)]
 pub struct HashSlot {
     offset: u16,
     pub key_len: u16,
     val_len: u16,
+    #[cfg(feature = "hash-leaf-layout_interleaved")]
+    hash: u8,
+    /// makes the padding explicit, so the slot bytes the simd search gathers from are all initialized
+    #[cfg(feature = "hash-leaf-layout_interleaved")]
+    padding: u8,
 }
 
+/// position of `HashSlot::hash` within the slot
+#[cfg(feature = "hash-leaf-layout_interleaved")]
+const SLOT_HASH_OFFSET: usize = 3 * size_of::<u16>();
+
+/// upper bound on the entries of a page, each one takes at least a slot
+const MAX_SLOT_COUNT: usize = PAGE_SIZE / size_of::<HashSlot>();
+
 impl HashSlot {
-    pub fn key<'a>(&self, page: &'a [u8; PAGE_SIZE]) -> PrefixTruncatedKey<'a> {
-        PrefixTruncatedKey(short_slice(page, self.offset, self.key_len))
+    pub fn key<'a>(&self, page: &'a PageBytes) -> PrefixTruncatedKey<'a> {
+        PrefixTruncatedKey(page.short_slice(self.offset, self.key_len))
     }
 
-    pub fn value<'a>(&self, page: &'a [u8; PAGE_SIZE]) -> &'a [u8] {
-        short_slice(page, self.offset + self.key_len, self.val_len)
+    pub fn value<'a>(&self, page: &'a PageBytes) -> &'a [u8] {
+        page.short_slice(self.offset + self.key_len, self.val_len)
     }
 }
 
@@ -41,6 +58,7 @@ struct HashLeafHead {
     space_used: u16,
     data_offset: u16,
     prefix_len: u16,
+    tombstone_count: u16,
 }
 
 #[derive(Clone)]
@@ -48,9 +66,15 @@ struct HashLeafHead {
 #[repr(align(64))]
 pub struct HashLeaf {
     head: HashLeafHead,
-    data: [u8; PAGE_SIZE - size_of::<HashLeafHead>()],
+    data: MaybeUninit<[u8; PAGE_SIZE - size_of::<HashLeafHead>()]>,
 }
 
+const _: () = assert!(size_of::<HashLeaf>() == PAGE_SIZE);
+
+/// a `HashLeaf` tagged `BTreeNodeTag::HybridLeaf`.
+/// It inserts new keys at their sorted position, so point lookups use the hashes and range lookups need no sort.
+pub type HybridLeaf = HashLeaf;
+
 struct LayoutInfo {
     slots_start: usize,
     hash_start: usize,
@@ -60,6 +84,7 @@ struct LayoutInfo {
 const SLOTS_FIRST: bool = true;
 const USE_SIMD: bool = true;
 
+
 #[cfg(feature = "hash-leaf-simd_32")]
 const SIMD_WIDTH: usize = 32;
 #[cfg(feature = "hash-leaf-simd_64")]
@@ -67,21 +92,72 @@ const SIMD_WIDTH: usize = 64;
 
 const SIMD_ALIGN: usize = 64;
 
+/// store each hash in its slot instead of a separate hash array, so a confirmed match touches a single cache line.
+/// The simd search then gathers hashes with the slot size as stride.
+#[cfg(feature = "hash-leaf-layout_interleaved")]
+const INTERLEAVED: bool = true;
+#[cfg(feature = "hash-leaf-layout_split")]
+const INTERLEAVED: bool = false;
+
+/// in tombstone mode, removed slots are only marked by this hash and reclaimed on the next compaction or sort
+#[cfg(feature = "hash-leaf-tombstone_true")]
+const TOMBSTONES: bool = true;
+#[cfg(feature = "hash-leaf-tombstone_false")]
+const TOMBSTONES: bool = false;
+
+const TOMBSTONE_HASH: u8 = 0;
+
+/// hash all keys of a range copy in one pass, remapping tombstone collisions with simd
+#[cfg(feature = "hash-batch_true")]
+const HASH_BATCH: bool = true;
+#[cfg(feature = "hash-batch_false")]
+const HASH_BATCH: bool = false;
+
+/// split where the halves use the same space instead of holding the same number of entries
+#[cfg(feature = "split-balance-bytes_true")]
+const SPLIT_BALANCE_BYTES: bool = true;
+#[cfg(feature = "split-balance-bytes_false")]
+const SPLIT_BALANCE_BYTES: bool = false;
+
+thread_local! {
+    /// unsorted slots and their hashes during `HashLeaf::sort`, reused so the page sized buffer is neither on the stack nor allocated per sort
+    static SORT_BUFFER: RefCell<Vec<(HashSlot, u8)>> = RefCell::new(Vec::with_capacity(PAGE_SIZE / size_of::<(HashSlot, u8)>()));
+}
+
 impl HashLeaf {
-    pub fn space_needed(&self, key_length: usize, payload_length: usize) -> usize {
-        assert!(SLOTS_FIRST);
-        let head_growth = if USE_SIMD {
+    /// space requested for the slot and hash of a new entry
+    fn head_growth() -> usize {
+        if INTERLEAVED {
+            size_of::<HashSlot>()
+        } else if USE_SIMD {
             SIMD_ALIGN.max(size_of::<HashSlot>()) + 1
         } else {
             size_of::<HashSlot>() + 1
-        };
-        key_length - self.head.prefix_len as usize + payload_length + head_growth
+        }
+    }
+
+    pub fn space_needed(&self, key_length: usize, payload_length: usize) -> usize {
+        assert!(SLOTS_FIRST);
+        key_length - self.head.prefix_len as usize + payload_length + VALUE_ALIGN - 1 + Self::head_growth()
+    }
+
+    /// number of uniformly sized entries an empty leaf with empty fences can hold
+    pub fn leaf_capacity(prefix_len: usize, key_length: usize, payload_length: usize) -> usize {
+        let entry_len = key_length - prefix_len + payload_length + VALUE_ALIGN - 1;
+        let mut count = 0;
+        while entry_len + Self::head_growth() + Self::layout(count).data_start + count * entry_len <= PAGE_SIZE {
+            count += 1;
+        }
+        count
     }
 
     fn layout(count: usize) -> LayoutInfo {
         debug_assert!(SLOTS_FIRST);
         let slots_start = size_of::<HashLeafHead>();
         let hash_start = slots_start + size_of::<HashSlot>() * count;
+        if INTERLEAVED {
+            return LayoutInfo { slots_start, hash_start, data_start: hash_start };
+        }
         let hash_start = if USE_SIMD {
             hash_start.next_multiple_of(SIMD_ALIGN)
         } else {
@@ -95,24 +171,48 @@ impl HashLeaf {
         }
     }
 
-    fn free_space(&self) -> usize {
+    pub fn free_space(&self) -> usize {
         self.head.data_offset as usize - Self::layout(self.head.count as usize).data_start
     }
 
     pub fn free_space_after_compaction(&self) -> usize {
         PAGE_SIZE
-            - Self::layout(self.head.count as usize).data_start
+            - Self::layout((self.head.count - self.head.tombstone_count) as usize).data_start
             - self.head.space_used as usize
     }
 
-    pub fn as_bytes(&self) -> &[u8; PAGE_SIZE] {
-        assert_eq!(PAGE_SIZE, size_of::<Self>());
-        unsafe { transmute(self as *const Self) }
+    pub fn as_bytes(&self) -> &PageBytes {
+        PageBytes::of(self)
     }
 
-    unsafe fn as_bytes_mut(&mut self) -> &mut [u8; PAGE_SIZE] {
-        assert_eq!(PAGE_SIZE, size_of::<Self>());
-        transmute(self as *mut Self)
+    fn as_bytes_mut(&mut self) -> &mut PageBytes {
+        PageBytes::of_mut(self)
+    }
+
+    /// copies head, slots, hashes, fences and entries to the same positions in `out` field by field,
+    /// so padding and unused space of `out` keep their previous contents
+    pub fn copy_written_to(&self, out: &mut HashLeaf) {
+        let (src, dst) = (&self.head, &mut out.head);
+        src.head.copy_fields_to(&mut dst.head);
+        dst.count = src.count;
+        dst.sorted_count = src.sorted_count;
+        dst.lower_fence = src.lower_fence;
+        dst.upper_fence = src.upper_fence;
+        dst.space_used = src.space_used;
+        dst.data_offset = src.data_offset;
+        dst.prefix_len = src.prefix_len;
+        dst.tombstone_count = src.tombstone_count;
+        for fence in [self.head.lower_fence, self.head.upper_fence] {
+            out.as_bytes_mut().write(fence.offset as usize, self.as_bytes().short_slice(fence.offset, fence.len));
+        }
+        out.slots_mut().copy_from_slice(self.slots());
+        for i in 0..self.head.count as usize {
+            out.set_hash(i, self.hash(i));
+        }
+        for s in self.slots() {
+            out.as_bytes_mut().write(s.offset as usize, s.key(self.as_bytes()).0);
+            out.as_bytes_mut().write((s.offset + s.key_len) as usize, s.value(self.as_bytes()));
+        }
     }
 
     pub fn slots(&self) -> &[HashSlot] {
@@ -137,57 +237,103 @@ impl HashLeaf {
         }
     }
 
+    /// the separate hash array, not available in the interleaved layout
     pub fn hashes(&self) -> &[u8] {
+        assert!(!INTERLEAVED);
         let count = self.head.count as usize;
-        &self.as_bytes()[Self::layout(count).hash_start..][..count]
+        let start = Self::layout(count).hash_start;
+        self.as_bytes().range(start..start + count)
     }
 
     pub fn hashes_mut(&mut self) -> &mut [u8] {
-        unsafe {
-            let count = self.head.count as usize;
-            &mut self.as_bytes_mut()[Self::layout(count).hash_start..][..count]
+        assert!(!INTERLEAVED);
+        let count = self.head.count as usize;
+        let start = Self::layout(count).hash_start;
+        self.as_bytes_mut().range_mut(start..start + count)
+    }
+
+    #[cfg(feature = "hash-leaf-layout_interleaved")]
+    fn hash(&self, index: usize) -> u8 {
+        self.slots()[index].hash
+    }
+
+    #[cfg(feature = "hash-leaf-layout_split")]
+    fn hash(&self, index: usize) -> u8 {
+        self.hashes()[index]
+    }
+
+    #[cfg(feature = "hash-leaf-layout_interleaved")]
+    fn set_hash(&mut self, index: usize, hash: u8) {
+        self.slots_mut()[index].hash = hash;
+    }
+
+    #[cfg(feature = "hash-leaf-layout_split")]
+    fn set_hash(&mut self, index: usize, hash: u8) {
+        debug_assert!(index < self.head.count as usize);
+        // the hash of a new slot is not initialized yet
+        let start = Self::layout(self.head.count as usize).hash_start;
+        self.as_bytes_mut().write(start + index, &[hash]);
+    }
+
+    /// moves slots and their hashes like `copy_within`
+    fn copy_entries_within(&mut self, src: Range<usize>, dst: usize) {
+        self.slots_mut().copy_within(src.clone(), dst);
+        if !INTERLEAVED {
+            let start = Self::layout(self.head.count as usize).hash_start;
+            self.as_bytes_mut().copy_within(start + src.start..start + src.end, start + dst);
+        }
+    }
+
+    /// moves the first `len` hashes of the separate hash array to where it starts for `new_count` slots
+    fn move_hash_array(&mut self, old_count: usize, new_count: usize, len: usize) {
+        if INTERLEAVED {
+            return;
         }
+        let old_start = Self::layout(old_count).hash_start;
+        let new_start = Self::layout(new_count).hash_start;
+        self.as_bytes_mut().copy_within(old_start..old_start + len, new_start);
     }
 
-    pub fn request_space(&mut self, space: usize) -> Result<(), ()> {
+    pub fn request_space(&mut self, space: usize) -> Result<(), NodeOpError> {
         if space <= self.free_space() {
+            HASH_LEAF_REQUEST_SPACE.count_fit();
             Ok(())
         } else if space <= self.free_space_after_compaction() {
+            HASH_LEAF_REQUEST_SPACE.count_compacted();
             self.compactify();
             Ok(())
         } else {
-            Err(())
+            HASH_LEAF_REQUEST_SPACE.count_failed();
+            Err(NodeOpError::NoSpace)
         }
     }
 
     fn compactify(&mut self) {
         // do not validate here, called from insert_truncated with some invariants validated
         //eprintln!("{:?} compactify",self as *const Self);
+        self.purge_tombstones();
         let mut buffer = [0u8; PAGE_SIZE];
         let fences_len = self.head.lower_fence.len as usize + self.head.upper_fence.len as usize;
-        let new_data_offset = PAGE_SIZE - self.head.space_used as usize;
-        let new_data_range = new_data_offset..PAGE_SIZE - fences_len;
-        let mut write = &mut buffer[new_data_range.clone()];
+        let data_end = PAGE_SIZE - fences_len;
+        let mut new_data_offset = data_end;
         for i in 0..self.head.count as usize {
-            let new_offset = (PAGE_SIZE - fences_len - write.len()) as u16;
-            debug_assert!(new_offset >= new_data_offset as u16);
-            write
-                .write_all(self.slots()[i].key(self.as_bytes()).0)
-                .unwrap();
-            write
-                .write_all(self.slots()[i].value(self.as_bytes()))
-                .unwrap();
-            self.slots_mut()[i].offset = new_offset;
-        }
-        debug_assert!(write.is_empty());
-        unsafe {
-            self.as_bytes_mut()[new_data_range.clone()].copy_from_slice(&buffer[new_data_range])
-        };
+            let s = self.slots()[i];
+            let value_end = aligned_value_end(new_data_offset, s.val_len as usize);
+            new_data_offset = value_end - (s.key_len + s.val_len) as usize;
+            let mut write = &mut buffer[new_data_offset..value_end];
+            write.write_all(s.key(self.as_bytes()).0).unwrap();
+            write.write_all(s.value(self.as_bytes())).unwrap();
+            self.slots_mut()[i].offset = new_data_offset as u16;
+        }
+        // padding may turn out smaller than charged
+        debug_assert!(new_data_offset >= PAGE_SIZE - self.head.space_used as usize);
+        debug_assert!(VALUE_ALIGN > 1 || new_data_offset == PAGE_SIZE - self.head.space_used as usize);
+        self.as_bytes_mut().write(new_data_offset, &buffer[new_data_offset..data_end]);
         self.head.data_offset = new_data_offset as u16;
     }
 
     #[cfg(feature = "hash_fx")]
-    fn compute_hash(key: PrefixTruncatedKey) -> u8 {
+    fn compute_raw_hash(key: PrefixTruncatedKey) -> u8 {
         use std::hash::Hasher;
         use rustc_hash::FxHasher;
         let mut hasher = FxHasher::default();
@@ -196,7 +342,7 @@ impl HashLeaf {
     }
 
     #[cfg(feature = "hash_wyhash")]
-    fn compute_hash(key: PrefixTruncatedKey) -> u8 {
+    fn compute_raw_hash(key: PrefixTruncatedKey) -> u8 {
         use std::hash::Hasher;
         let mut hasher = wyhash::WyHash::default();
         hasher.write(key.0);
@@ -204,70 +350,179 @@ impl HashLeaf {
     }
 
     #[cfg(feature = "hash_crc32")]
-    fn compute_hash(key: PrefixTruncatedKey) -> u8 {
+    fn compute_raw_hash(key: PrefixTruncatedKey) -> u8 {
         crc32fast::hash(key.0) as u8
     }
 
+    fn compute_hash(key: PrefixTruncatedKey) -> u8 {
+        let hash = Self::compute_raw_hash(key);
+        if TOMBSTONES && hash == TOMBSTONE_HASH {
+            TOMBSTONE_HASH + 1
+        } else {
+            hash
+        }
+    }
+
+    /// writes the same hashes as calling `compute_hash` on each key to `hashes`, which must have one byte per key.
+    /// The raw hashes are computed per key, the tombstone remap runs `SIMD_WIDTH` hashes at a time.
+    fn compute_hashes_batch<'a>(keys: impl ExactSizeIterator<Item=PrefixTruncatedKey<'a>>, hashes: &mut [u8]) {
+        debug_assert_eq!(keys.len(), hashes.len());
+        for (h, key) in hashes.iter_mut().zip(keys) {
+            *h = Self::compute_raw_hash(key);
+        }
+        if TOMBSTONES {
+            type SimdDtype = std::simd::Simd<u8, SIMD_WIDTH>;
+            let mut chunks = hashes.chunks_exact_mut(SIMD_WIDTH);
+            for chunk in &mut chunks {
+                let raw = SimdDtype::from_slice(chunk);
+                let tombstone = SimdDtype::splat(TOMBSTONE_HASH);
+                raw.simd_eq(tombstone).select(tombstone + SimdDtype::splat(1), raw).copy_to_slice(chunk);
+            }
+            for h in chunks.into_remainder() {
+                if *h == TOMBSTONE_HASH {
+                    *h = TOMBSTONE_HASH + 1;
+                }
+            }
+        }
+        debug_assert!(hashes.iter().all(|&h| !TOMBSTONES || h != TOMBSTONE_HASH));
+    }
+
+    fn is_tombstone(&self, index: usize) -> bool {
+        TOMBSTONES && self.hash(index) == TOMBSTONE_HASH
+    }
+
+    /// physically removes tombstoned slots, preserving the order of the remaining slots
+    fn purge_tombstones(&mut self) {
+        if self.head.tombstone_count == 0 {
+            return;
+        }
+        self.head.head.touch();
+        let count = self.head.count as usize;
+        let sorted_count = self.head.sorted_count as usize;
+        let mut write = 0;
+        for read in 0..count {
+            if self.is_tombstone(read) {
+                if read < sorted_count {
+                    self.head.sorted_count -= 1;
+                }
+            } else {
+                self.copy_entries_within(read..read + 1, write);
+                write += 1;
+            }
+        }
+        debug_assert_eq!(write + self.head.tombstone_count as usize, count);
+        self.move_hash_array(count, write, write);
+        self.head.count = write as u16;
+        self.head.tombstone_count = 0;
+    }
+
     fn store_key_value(
         &mut self,
         slot_id: usize,
         prefix_truncated_key: PrefixTruncatedKey,
         payload: &[u8],
     ) {
-        self.write_data(payload);
+        self.store_key_value_hashed(slot_id, prefix_truncated_key, payload, Self::compute_hash(prefix_truncated_key));
+    }
+
+    fn store_key_value_hashed(
+        &mut self,
+        slot_id: usize,
+        prefix_truncated_key: PrefixTruncatedKey,
+        payload: &[u8],
+        hash: u8,
+    ) {
+        self.write_value(payload);
         let key_offset = self.write_data(prefix_truncated_key.0);
         self.slots_mut()[slot_id] = HashSlot {
             offset: key_offset,
             key_len: prefix_truncated_key.0.len() as u16,
             val_len: payload.len() as u16,
+            #[cfg(feature = "hash-leaf-layout_interleaved")]
+            hash,
+            #[cfg(feature = "hash-leaf-layout_interleaved")]
+            padding: 0,
         };
-        self.hashes_mut()[slot_id] = Self::compute_hash(prefix_truncated_key);
+        self.set_hash(slot_id, hash);
     }
 
-    fn insert_truncated(&mut self, key: PrefixTruncatedKey, payload: &[u8]) -> Result<(), ()> {
-        let index = if let Some(found) = self.find_index(key) {
+    fn insert_truncated(&mut self, key: PrefixTruncatedKey, payload: &[u8]) -> Result<bool, NodeOpError> {
+        let mut existing = self.find_index(key);
+        if let Some(found) = existing {
+            let s = self.slots()[found];
+            if s.value(self.as_bytes()) == payload {
+                // value unchanged, avoid rewriting
+                return Ok(false);
+            }
+            if self.head.tombstone_count > 0 {
+                // compaction in request_space purges tombstones, which would move the slot found above
+                self.purge_tombstones();
+                existing = self.find_index(key);
+            }
+        }
+        let index = if let Some(found) = existing {
+            let old_slot = self.slots()[found];
             let s = &mut self.slots_mut()[found];
-            let old_use = s.key_len + s.val_len;
+            let old_use = s.key_len + s.val_len + (VALUE_ALIGN - 1) as u16;
             s.key_len = 0;
             s.val_len = 0;
             self.head.space_used -= old_use;
-            self.request_space(key.0.len() + payload.len())?;
+            if let Err(e) = self.request_space(key.0.len() + payload.len() + VALUE_ALIGN - 1) {
+                // the caller splits and retries, the old entry must survive
+                self.slots_mut()[found] = old_slot;
+                self.head.space_used += old_use;
+                return Err(e);
+            }
             found
         } else {
             self.request_space(
                 self.space_needed(key.0.len() + self.head.prefix_len as usize, payload.len()),
             )?;
-            self.increase_size(1);
-            self.head.count as usize - 1
+            if self.keeps_sorted() {
+                // a tombstone may still hold an equal key
+                self.purge_tombstones();
+                let index = self.lower_bound(key).0;
+                self.increase_size(1);
+                let count = self.head.count as usize;
+                self.copy_entries_within(index..count - 1, index + 1);
+                self.head.sorted_count += 1;
+                index
+            } else {
+                self.increase_size(1);
+                self.head.count as usize - 1
+            }
         };
         self.store_key_value(index, key, payload);
         // self.print();
         self.validate();
-        Ok(())
+        Ok(existing.is_none())
+    }
+
+    /// true for hybrid leaves, which keep all slots sorted
+    fn keeps_sorted(&self) -> bool {
+        self.head.head.tag == BTreeNodeTag::HybridLeaf
     }
 
     fn increase_size(&mut self, delta: usize) {
         assert!(SLOTS_FIRST);
         let count = self.head.count as usize;
-        let old_layout = Self::layout(count as usize);
-        let new_layout = Self::layout(count + delta);
-        unsafe {
-            self.as_bytes_mut().copy_within(
-                old_layout.hash_start..old_layout.hash_start + count,
-                new_layout.hash_start,
-            );
-        }
+        self.move_hash_array(count, count + delta, count);
         self.head.count += delta as u16;
     }
 
+    /// charges `VALUE_ALIGN - 1` bytes of padding, regardless of the padding actually needed
+    fn write_value(&mut self, d: &[u8]) -> u16 {
+        self.head.data_offset = aligned_value_end(self.head.data_offset as usize, d.len()) as u16;
+        self.head.space_used += (VALUE_ALIGN - 1) as u16;
+        self.write_data(d)
+    }
+
     fn write_data(&mut self, d: &[u8]) -> u16 {
         self.head.data_offset -= d.len() as u16;
         self.head.space_used += d.len() as u16;
         self.assert_no_collide();
         let offset = self.head.data_offset;
-        unsafe {
-            self.as_bytes_mut()[offset as usize..][..d.len()].copy_from_slice(d);
-        }
+        self.as_bytes_mut().write(offset as usize, d);
         offset
     }
 
@@ -297,17 +552,33 @@ impl HashLeaf {
         };
     }
 
-    fn copy_key_value_range(&self, src_slots: &[HashSlot], dst: &mut Self) {
+    fn copy_key_value_range(&self, src_range: Range<usize>, dst: &mut Self) {
         assert!(dst.head.prefix_len >= self.head.prefix_len);
         let dst_base = dst.head.count as usize;
-        dst.increase_size(src_slots.len());
+        dst.increase_size(src_range.len());
         let prefix_growth = (dst.head.prefix_len - self.head.prefix_len) as usize;
-        for (i, s) in src_slots.iter().enumerate() {
-            dst.store_key_value(
-                dst_base + i,
-                PrefixTruncatedKey(&s.key(self.as_bytes()).0[prefix_growth..]),
-                s.value(self.as_bytes()),
-            );
+        if HASH_BATCH && prefix_growth != 0 {
+            let keys = src_range.clone().map(|i| PrefixTruncatedKey(&self.slots()[i].key(self.as_bytes()).0[prefix_growth..]));
+            let mut hash_buffer = [0u8; MAX_SLOT_COUNT];
+            let hashes = &mut hash_buffer[..src_range.len()];
+            Self::compute_hashes_batch(keys, hashes);
+            for ((dst_index, i), &hash) in (dst_base..).zip(src_range).zip(hashes.iter()) {
+                let s = &self.slots()[i];
+                let key = PrefixTruncatedKey(&s.key(self.as_bytes()).0[prefix_growth..]);
+                debug_assert_eq!(hash, Self::compute_hash(key));
+                dst.store_key_value_hashed(dst_index, key, s.value(self.as_bytes()), hash);
+            }
+            return;
+        }
+        for (dst_index, i) in (dst_base..).zip(src_range) {
+            let s = &self.slots()[i];
+            let key = PrefixTruncatedKey(&s.key(self.as_bytes()).0[prefix_growth..]);
+            if prefix_growth == 0 {
+                // same truncated key, same hash
+                dst.store_key_value_hashed(dst_index, key, s.value(self.as_bytes()), self.hash(i));
+            } else {
+                dst.store_key_value(dst_index, key, s.value(self.as_bytes()));
+            }
         }
     }
 
@@ -317,13 +588,11 @@ impl HashLeaf {
 
     pub fn fences(&self) -> FenceData {
         FenceData {
-            lower_fence: FenceRef(short_slice(
-                self.as_bytes(),
+            lower_fence: FenceRef(self.as_bytes().short_slice(
                 self.head.lower_fence.offset,
                 self.head.lower_fence.len,
             )),
-            upper_fence: FenceRef(short_slice(
-                self.as_bytes(),
+            upper_fence: FenceRef(self.as_bytes().short_slice(
                 self.head.upper_fence.offset,
                 self.head.upper_fence.len,
             )),
@@ -331,27 +600,45 @@ impl HashLeaf {
         }
     }
 
+    fn new_head(tag: BTreeNodeTag) -> HashLeafHead {
+        HashLeafHead {
+            head: BTreeNodeHead::new(tag, AdaptionState::new()),
+            count: 0,
+            sorted_count: 0,
+            lower_fence: FenceKeySlot { offset: 0, len: 0 },
+            upper_fence: FenceKeySlot { offset: 0, len: 0 },
+            space_used: 0,
+            data_offset: PAGE_SIZE as u16,
+            prefix_len: 0,
+            tombstone_count: 0,
+        }
+    }
+
     pub fn new() -> Self {
         assert_eq!(align_of::<Self>(), SIMD_ALIGN);
         HashLeaf {
-            head: HashLeafHead {
-                head: BTreeNodeHead { tag: BTreeNodeTag::HashLeaf, adaption_state: AdaptionState::new() },
-                count: 0,
-                sorted_count: 0,
-                lower_fence: FenceKeySlot { offset: 0, len: 0 },
-                upper_fence: FenceKeySlot { offset: 0, len: 0 },
-                space_used: 0,
-                data_offset: PAGE_SIZE as u16,
-                prefix_len: 0,
-            },
-            data: [0u8; PAGE_SIZE - size_of::<HashLeafHead>()],
+            head: Self::new_head(BTreeNodeTag::HashLeaf),
+            data: MaybeUninit::zeroed(),
         }
     }
 
+    /// writes an empty leaf of type `tag` to `dst` without building it on the stack first.
+    /// Only the head is written, the data area stays uninitialized like that of basic nodes.
+    pub unsafe fn init_in_place(dst: *mut Self, tag: BTreeNodeTag) {
+        debug_assert!(matches!(tag, BTreeNodeTag::HashLeaf | BTreeNodeTag::HybridLeaf));
+        ptr::addr_of_mut!((*dst).head).write(Self::new_head(tag));
+    }
+
+    pub fn new_hybrid() -> Self {
+        let mut leaf = Self::new();
+        leaf.head.head.tag = BTreeNodeTag::HybridLeaf;
+        leaf
+    }
+
     fn find_index(&self, key: PrefixTruncatedKey) -> Option<usize> {
         let needle_hash = Self::compute_hash(key);
         //eprintln!("find {:?} -> {}",key,needle_hash);
-        if USE_SIMD {
+        if USE_SIMD && SIMD_FIND.load(Ordering::Relaxed) {
             debug_assert_eq!(
                 self.find_simd(key, needle_hash),
                 self.find_no_simd(key, needle_hash)
@@ -363,47 +650,76 @@ impl HashLeaf {
     }
 
     fn find_no_simd(&self, key: PrefixTruncatedKey, needle_hash: u8) -> Option<usize> {
-        for (i, hash) in self.hashes().iter().enumerate() {
-            if *hash == needle_hash && self.slots()[i].key(self.as_bytes()) == key {
+        for i in 0..self.head.count as usize {
+            if self.hash(i) == needle_hash && self.slots()[i].key(self.as_bytes()) == key {
                 return Some(i);
             }
         }
         None
     }
 
+    #[cfg(feature = "hash-leaf-layout_interleaved")]
     fn find_simd(&self, key: PrefixTruncatedKey, needle_hash: u8) -> Option<usize> {
-        unsafe {
-            use std::simd::ToBitMask;
-            type SimdDtype = std::simd::Simd<u8, SIMD_WIDTH>;
-            let count = self.head.count as usize;
-            let mut hash_ptr = (self as *const Self as *const u8)
-                .offset(Self::layout(count).hash_start as isize)
-                as *const SimdDtype;
-            let needle = SimdDtype::splat(needle_hash);
-            debug_assert!(hash_ptr.is_aligned());
-            let mut shift = 0;
-            while shift < count {
-                let candidates = *(hash_ptr);
-                let mut matches = candidates.simd_eq(needle).to_bitmask();
-                loop {
-                    let trailing_zeros = matches.trailing_zeros();
-                    if trailing_zeros == SIMD_WIDTH as u32 {
-                        shift = shift - shift % SIMD_WIDTH + SIMD_WIDTH;
-                        hash_ptr = hash_ptr.offset(1);
-                        break;
-                    } else {
-                        shift += trailing_zeros as usize;
-                        matches >>= trailing_zeros;
-                        matches = matches & !1;
-                        if shift >= count {
-                            return None;
-                        }
-                        if self.slots()[shift].key(self.as_bytes()) == key {
-                            return Some(shift);
-                        }
-                    }
+        use std::simd::ToBitMask;
+        type SimdDtype = std::simd::Simd<u8, SIMD_WIDTH>;
+        type SimdIndex = std::simd::Simd<usize, SIMD_WIDTH>;
+        let count = self.head.count as usize;
+        let needle = SimdDtype::splat(needle_hash);
+        let lane_offsets = SimdIndex::from_array(std::array::from_fn(|i| i * size_of::<HashSlot>()));
+        let slots_start = Self::layout(0).slots_start;
+        let slot_bytes = self.as_bytes().range(slots_start..slots_start + count * size_of::<HashSlot>());
+        let mut base = 0;
+        while base < count {
+            let first_hash = base * size_of::<HashSlot>() + SLOT_HASH_OFFSET;
+            // lanes past the last slot are out of bounds and read the default, they are masked below
+            let candidates = SimdDtype::gather_or_default(slot_bytes, lane_offsets + SimdIndex::splat(first_hash));
+            let mut matches = candidates.simd_eq(needle).to_bitmask() as u64;
+            if count - base < SIMD_WIDTH {
+                matches &= (1u64 << (count - base)) - 1;
+            }
+            while matches != 0 {
+                let index = base + matches.trailing_zeros() as usize;
+                if self.slots()[index].key(self.as_bytes()) == key {
+                    return Some(index);
                 }
+                matches &= matches - 1;
             }
+            base += SIMD_WIDTH;
+        }
+        None
+    }
+
+    #[cfg(feature = "hash-leaf-layout_split")]
+    fn find_simd(&self, key: PrefixTruncatedKey, needle_hash: u8) -> Option<usize> {
+        use std::simd::ToBitMask;
+        type SimdDtype = std::simd::Simd<u8, SIMD_WIDTH>;
+        let count = self.head.count as usize;
+        let hashes = self.hashes();
+        let needle = SimdDtype::splat(needle_hash);
+        debug_assert!(hashes.as_ptr().is_aligned_to(SIMD_ALIGN));
+        let mut base = 0;
+        while base < count {
+            let candidates = if count - base >= SIMD_WIDTH {
+                // the hash array is aligned and whole vectors within it only cover written hashes
+                unsafe { *(hashes.as_ptr().add(base) as *const SimdDtype) }
+            } else {
+                // the bytes after the last hash may be uninitialized, so the tail is copied
+                let mut tail = [0u8; SIMD_WIDTH];
+                tail[..count - base].copy_from_slice(&hashes[base..]);
+                SimdDtype::from_array(tail)
+            };
+            let mut matches = candidates.simd_eq(needle).to_bitmask() as u64;
+            if count - base < SIMD_WIDTH {
+                matches &= (1u64 << (count - base)) - 1;
+            }
+            while matches != 0 {
+                let index = base + matches.trailing_zeros() as usize;
+                if self.slots()[index].key(self.as_bytes()) == key {
+                    return Some(index);
+                }
+                matches &= matches - 1;
+            }
+            base += SIMD_WIDTH;
         }
         None
     }
@@ -414,8 +730,8 @@ impl HashLeaf {
             let mut counts = [0; 256];
             let average = self.head.count as f32 / 256.0;
             let mut acc = 0.0;
-            for h in self.hashes() {
-                counts[*h as usize] += 1;
+            for i in 0..self.head.count as usize {
+                counts[self.hash(i) as usize] += 1;
             }
             for c in counts {
                 acc += (c as f32 - average).powi(2);
@@ -427,51 +743,92 @@ impl HashLeaf {
         for s in self.slots() {
             debug_assert!(s.offset >= self.head.data_offset);
         }
-        for (s, h) in self.slots().iter().zip(self.hashes().iter()) {
-            debug_assert_eq!(Self::compute_hash(s.key(self.as_bytes())), *h);
+        for (i, s) in self.slots().iter().enumerate() {
+            let h = self.hash(i);
+            debug_assert!(TOMBSTONES && h == TOMBSTONE_HASH || Self::compute_hash(s.key(self.as_bytes())) == h);
         }
         debug_assert_eq!(
-            self.head.space_used as usize,
-            self.head.lower_fence.len as usize
-                + self.head.upper_fence.len as usize
-                + self
-                .slots()
-                .iter()
-                .map(|s| (s.key_len + s.val_len) as usize)
-                .sum::<usize>()
+            self.head.tombstone_count as usize,
+            (0..self.head.count as usize).filter(|&i| self.is_tombstone(i)).count()
         );
+        debug_assert!(self.check_space_accounting().is_ok());
         debug_assert!(self.head.sorted_count <= self.head.count);
+        debug_assert!(!self.keeps_sorted() || self.head.sorted_count == self.head.count);
         debug_assert!(self.slots()[..self.head.sorted_count as usize].is_sorted_by_key(|s| s.key(self.as_bytes())));
     }
 
+    /// checks that `space_used` matches the space taken by fences, keys and payloads of live slots
+    pub fn check_space_accounting(&self) -> Result<(), ()> {
+        let used = self.head.lower_fence.len as usize
+            + self.head.upper_fence.len as usize
+            + self
+            .slots()
+            .iter()
+            .enumerate()
+            .filter(|&(i, _)| !self.is_tombstone(i))
+            .map(|(_, s)| (s.key_len + s.val_len) as usize + VALUE_ALIGN - 1)
+            .sum::<usize>();
+        if used == self.head.space_used as usize { Ok(()) } else { Err(()) }
+    }
+
+    /// number of slots that are not tombstones
+    pub fn live_count(&self) -> usize {
+        (self.head.count - self.head.tombstone_count) as usize
+    }
+
+    /// calls `callback` with the prefix truncated key and value of every live slot in physical slot order.
+    /// Unlike `range_lookup`, this does not sort the node.
+    pub fn for_each(&self, callback: &mut dyn FnMut(PrefixTruncatedKey, &[u8])) {
+        for (i, s) in self.slots().iter().enumerate() {
+            if !self.is_tombstone(i) {
+                callback(s.key(self.as_bytes()), s.value(self.as_bytes()));
+            }
+        }
+    }
+
     pub fn try_merge_right(
-        &self,
+        &mut self,
         right: &mut Self,
         separator: FatTruncatedKey,
-    ) -> Result<(), ()> {
+    ) -> Result<(), NodeOpError> {
+        self.purge_tombstones();
+        right.purge_tombstones();
         //eprintln!("### {:?} merge right {:?}",self as *const Self,right as *const Self);
         // self.print();
         // right.print();
-        //TODO optimize
-        // if prefix length does not change, hashes can be copied
         let mut tmp = Self::new();
-        tmp.head.head.adaption_state = right.head.head.adaption_state;
+        tmp.head.head = right.head.head;
         tmp.set_fences(MergeFences::new(self.fences(), separator, right.fences()).fences());
-        let left = self.slots().iter().map(|s| (s, &*self));
-        let right_iter = right.slots().iter().map(|s| (s, &*right));
-        for (s, this) in left.chain(right_iter) {
-            let segments = &[
-                &separator.remainder[..this.head.prefix_len as usize - separator.prefix_len],
-                s.key(this.as_bytes()).0,
-            ];
-            let reconstructed =
-                partial_restore(separator.prefix_len, segments, tmp.head.prefix_len as usize);
-            tmp.insert_truncated(PrefixTruncatedKey(&reconstructed), s.value(this.as_bytes()))?;
+        let prefix_len = tmp.head.prefix_len;
+        if self.head.prefix_len == prefix_len && right.head.prefix_len == prefix_len {
+            // keys and hashes can be copied unchanged
+            let count = (self.head.count + right.head.count) as usize;
+            let data_len: usize = self.slots().iter().chain(right.slots()).map(|s| (s.key_len + s.val_len) as usize + VALUE_ALIGN - 1).sum();
+            if Self::layout(count).data_start + data_len > tmp.head.data_offset as usize {
+                return Err(NodeOpError::NoSpace);
+            }
+            self.copy_key_value_range(0..self.head.count as usize, &mut tmp);
+            right.copy_key_value_range(0..right.head.count as usize, &mut tmp);
+        } else {
+            let left = self.slots().iter().map(|s| (s, &*self));
+            let right_iter = right.slots().iter().map(|s| (s, &*right));
+            for (s, this) in left.chain(right_iter) {
+                let segments = &[
+                    &separator.remainder[..this.head.prefix_len as usize - separator.prefix_len],
+                    s.key(this.as_bytes()).0,
+                ];
+                let reconstructed =
+                    partial_restore(separator.prefix_len, segments, tmp.head.prefix_len as usize);
+                tmp.insert_truncated(PrefixTruncatedKey(&reconstructed), s.value(this.as_bytes()))?;
+            }
         }
         tmp.head.sorted_count = self.head.sorted_count;
+        if tmp.keeps_sorted() {
+            tmp.sort();
+        }
         tmp.validate();
         // tmp.print();
-        debug_assert_eq!(tmp.head.count, self.head.count + right.head.count);
+        debug_assert_merge_conserves_keys(true, self.head.count as usize, right.head.count as usize, tmp.head.count as usize);
         *right = tmp;
         Ok(())
     }
@@ -481,41 +838,42 @@ impl HashLeaf {
     }
 
     fn sort(&mut self) {
+        self.purge_tombstones();
         let unsorted_count = (self.head.count - self.head.sorted_count) as usize;
         if unsorted_count == 0 {
             return;
         }
+        self.head.head.touch();
         assert!(self.head.sorted_count <= self.head.count);
-        let mut slots_space = MaybeUninit::<(HashSlot, u8)>::uninit_array::<{ PAGE_SIZE / size_of::<(HashSlot, u8)>() }>();
-        for i in 0..unsorted_count {
-            slots_space[i].write((self.slots()[self.head.sorted_count as usize + i], self.hashes()[self.head.sorted_count as usize + i]));
-        }
-        let unsorted_slots = unsafe { MaybeUninit::slice_assume_init_mut(&mut slots_space[..unsorted_count]) };
-        unsorted_slots.sort_unstable_by_key(|s| s.0.key(self.as_bytes()));
-
-        let mut unmerged_remaining = self.head.count as usize;
-        let mut sorted_remaining = self.head.sorted_count as usize;
-        let mut unsorted_remaining = unsorted_count;
-        while sorted_remaining > 0 && unsorted_remaining > 0 {
-            assert_eq!(unmerged_remaining, sorted_remaining + unsorted_remaining);
-            if self.slots()[sorted_remaining - 1].key(self.as_bytes()) > unsorted_slots[unsorted_remaining - 1].0.key(self.as_bytes()) {
-                self.slots_mut().copy_within(sorted_remaining - 1..=sorted_remaining - 1, unmerged_remaining - 1);
-                self.hashes_mut().copy_within(sorted_remaining - 1..=sorted_remaining - 1, unmerged_remaining - 1);
-                sorted_remaining -= 1;
-                unmerged_remaining -= 1;
-            } else {
+        SORT_BUFFER.with(|buffer| {
+            let mut unsorted_slots = buffer.borrow_mut();
+            unsorted_slots.clear();
+            unsorted_slots.extend((self.head.sorted_count as usize..self.head.count as usize).map(|i| (self.slots()[i], self.hash(i))));
+            unsorted_slots.sort_unstable_by_key(|s| s.0.key(self.as_bytes()));
+
+            let mut unmerged_remaining = self.head.count as usize;
+            let mut sorted_remaining = self.head.sorted_count as usize;
+            let mut unsorted_remaining = unsorted_count;
+            while sorted_remaining > 0 && unsorted_remaining > 0 {
+                assert_eq!(unmerged_remaining, sorted_remaining + unsorted_remaining);
+                if self.slots()[sorted_remaining - 1].key(self.as_bytes()) > unsorted_slots[unsorted_remaining - 1].0.key(self.as_bytes()) {
+                    self.copy_entries_within(sorted_remaining - 1..sorted_remaining, unmerged_remaining - 1);
+                    sorted_remaining -= 1;
+                    unmerged_remaining -= 1;
+                } else {
+                    self.slots_mut()[unmerged_remaining - 1] = unsorted_slots[unsorted_remaining - 1].0;
+                    self.set_hash(unmerged_remaining - 1, unsorted_slots[unsorted_remaining - 1].1);
+                    unsorted_remaining -= 1;
+                    unmerged_remaining -= 1;
+                }
+            }
+            while unsorted_remaining > 0 {
                 self.slots_mut()[unmerged_remaining - 1] = unsorted_slots[unsorted_remaining - 1].0;
-                self.hashes_mut()[unmerged_remaining - 1] = unsorted_slots[unsorted_remaining - 1].1;
+                self.set_hash(unmerged_remaining - 1, unsorted_slots[unsorted_remaining - 1].1);
                 unsorted_remaining -= 1;
                 unmerged_remaining -= 1;
             }
-        }
-        while unsorted_remaining > 0 {
-            self.slots_mut()[unmerged_remaining - 1] = unsorted_slots[unsorted_remaining - 1].0;
-            self.hashes_mut()[unmerged_remaining - 1] = unsorted_slots[unsorted_remaining - 1].1;
-            unsorted_remaining -= 1;
-            unmerged_remaining -= 1;
-        }
+        });
         self.head.sorted_count = self.head.count;
         self.validate();
     }
@@ -541,7 +899,7 @@ impl HashLeaf {
     fn from_basic_ext(src: &BasicNode) -> Self {
         let mut dst = HashLeaf {
             head: HashLeafHead {
-                head: BTreeNodeHead { tag: BTreeNodeTag::HashLeaf, adaption_state: src.head.head.adaption_state },
+                head: BTreeNodeHead::new(BTreeNodeTag::HashLeaf, src.head.head.adaption_state),
                 count: src.head.count,
                 sorted_count: src.head.count,
                 lower_fence: FenceKeySlot { offset: 0, len: 0 },
@@ -549,8 +907,9 @@ impl HashLeaf {
                 space_used: 0,
                 data_offset: PAGE_SIZE as u16,
                 prefix_len: 0,
+                tombstone_count: 0,
             },
-            data: [0u8; PAGE_SIZE - size_of::<HashLeafHead>()],
+            data: if LAZY_ZERO { MaybeUninit::uninit() } else { MaybeUninit::zeroed() },
         };
         dst.set_fences(src.fences());
         for (i, s) in src.slots().iter().enumerate() {
@@ -559,16 +918,17 @@ impl HashLeaf {
         dst
     }
 
-    pub fn to_basic(node: &mut BTreeNode) -> Result<(), ()> {
+    pub fn to_basic(node: &mut BTreeNode) -> Result<(), NodeOpError> {
         unsafe {
             let hash = reinterpret_mut::<BTreeNode, HashLeaf>(node);
+            hash.purge_tombstones();
             let basic_space_use = size_of::<BasicNodeHead>() + hash.head.count as usize * size_of::<BasicSlot>();
             if (hash.head.data_offset as usize) < basic_space_use {
                 if PAGE_SIZE - (hash.head.space_used as usize) < basic_space_use {
                     hash.compactify();
                 }
                 if (hash.head.data_offset as usize) < basic_space_use {
-                    return Err(());
+                    return Err(NodeOpError::NoSpace);
                 }
             }
             hash.sort();
@@ -590,7 +950,7 @@ impl HashLeaf {
             let hash_head = ptr::read(&reinterpret_mut::<BTreeNode, HashLeaf>(node).head);
             let basic = reinterpret_mut::<BTreeNode, BasicNode>(node);
             basic.head = BasicNodeHead {
-                head: BTreeNodeHead { tag: BTreeNodeTag::BasicLeaf, adaption_state: hash_head.head.adaption_state },
+                head: BTreeNodeHead::new(BTreeNodeTag::BasicLeaf, hash_head.head.adaption_state),
                 /// only used in inner nodes, points to last child
                 count: hash_head.count,
                 space_used: hash_head.space_used,
@@ -617,6 +977,14 @@ impl HashLeaf {
             dst.validate();
         }
     }
+
+    /// like `from_basic`, but creates a hybrid leaf
+    pub fn hybrid_from_basic(node: &mut BTreeNode) {
+        Self::from_basic(node);
+        unsafe {
+            reinterpret_mut::<BTreeNode, HashLeaf>(node).head.head.tag = BTreeNodeTag::HybridLeaf;
+        }
+    }
 }
 
 unsafe impl Node for HashLeaf {
@@ -625,21 +993,34 @@ unsafe impl Node for HashLeaf {
         parent: &mut dyn InnerNode,
         index_in_parent: usize,
         key_in_self: &[u8],
-    ) -> Result<(), ()> {
-        //TODO if prefix length does not change, hashes can be copied
+    ) -> Result<(), NodeOpError> {
         self.sort();
 
         // split
-        let (sep_slot, truncated_sep_key) =
+        let (sep_slot, truncated_sep_key) = if SPLIT_BALANCE_BYTES {
+            find_separator_by_size(
+                self.head.count as usize,
+                |i: usize| self.slots()[i].key(self.as_bytes()),
+                |i: usize| {
+                    let s = &self.slots()[i];
+                    (s.key_len + s.val_len) as usize + size_of::<HashSlot>() + 1
+                },
+            )
+        } else {
             find_separator(self.head.count as usize, true, |i: usize| {
                 self.slots()[i].key(self.as_bytes())
-            });
+            })
+        };
         let full_sep_key_len = truncated_sep_key.0.len() + self.head.prefix_len as usize;
-        let parent_prefix_len = parent.request_space_for_child(full_sep_key_len)?;
+        let parent_prefix_len = parent.request_space_for_child(full_sep_key_len).map_err(|_| NodeOpError::NeedsSplit)?;
         let node_left_raw;
         let node_left = unsafe {
             node_left_raw = BTreeNode::alloc();
-            (*node_left_raw).hash_leaf = ManuallyDrop::new(Self::new());
+            if LAZY_ZERO {
+                Self::init_in_place(ptr::addr_of_mut!((*node_left_raw).hash_leaf) as *mut Self, BTreeNodeTag::HashLeaf);
+            } else {
+                (*node_left_raw).hash_leaf = ManuallyDrop::new(Self::new());
+            }
             &mut (*node_left_raw).hash_leaf
         };
 
@@ -647,26 +1028,23 @@ unsafe impl Node for HashLeaf {
         node_left.set_fences(split_fences.lower());
         let mut node_right = Self::new();
         node_right.set_fences(split_fences.upper());
-        node_left.head.head.adaption_state = self.head.head.adaption_state;
-        node_right.head.head.adaption_state = self.head.head.adaption_state;
+        node_left.head.head = self.head.head;
+        node_right.head.head = self.head.head;
         unsafe {
-            if let Err(()) = parent.insert_child(index_in_parent, split_fences.separator(), node_left_raw) {
+            if let Err(_) = parent.insert_child(index_in_parent, split_fences.separator(), node_left_raw) {
                 BTreeNode::dealloc(node_left_raw);
-                return Err(());
+                return Err(NodeOpError::NeedsSplit);
             }
         }
-        self.copy_key_value_range(&self.slots()[..=sep_slot], node_left);
-        self.copy_key_value_range(&self.slots()[sep_slot + 1..], &mut node_right);
+        self.copy_key_value_range(0..sep_slot + 1, node_left);
+        self.copy_key_value_range(sep_slot + 1..self.head.count as usize, &mut node_right);
         node_left.head.sorted_count = node_left.head.count;
         node_right.head.sorted_count = node_right.head.count;
         node_left.validate();
         node_right.validate();
         // node_left.print();
         // node_right.print();
-        debug_assert_eq!(
-            self.head.count,
-            node_left.head.count + node_right.head.count
-        );
+        debug_assert_split_conserves_keys(true, self.head.count as usize, node_left.head.count as usize, node_right.head.count as usize);
         *self = node_right;
         Ok(())
     }
@@ -681,7 +1059,7 @@ unsafe impl Node for HashLeaf {
             eprintln!(
                 "{:?}|{:3?}|{:3?}",
                 i,
-                self.hashes()[i],
+                self.hash(i),
                 s.key(self.as_bytes())
             );
         }
@@ -697,20 +1075,18 @@ unsafe impl Node for HashLeaf {
 }
 
 unsafe impl LeafNode for HashLeaf {
-    fn insert(&mut self, key: &[u8], payload: &[u8]) -> Result<(), ()> {
+    fn insert(&mut self, key: &[u8], payload: &[u8]) -> Result<bool, NodeOpError> {
         // self.print();
         //eprintln!("{:?} insert {:?}",self as *const Self,key);
         let key = self.truncate(key);
         self.insert_truncated(key, payload)
     }
-    fn lookup(&mut self, key: &[u8]) -> Option<&mut [u8]> {
+    fn lookup_mut(&mut self, key: &[u8]) -> Option<&mut [u8]> {
         self.validate();
         self.find_index(self.truncate(key))
             .map(|i| {
                 let slot = self.slots()[i];
-                unsafe {
-                    &mut self.as_bytes_mut()[(slot.offset + slot.key_len) as usize..][..slot.val_len as usize]
-                }
+                self.as_bytes_mut().range_mut((slot.offset + slot.key_len) as usize..(slot.offset + slot.key_len + slot.val_len) as usize)
             })
     }
 
@@ -721,64 +1097,419 @@ unsafe impl LeafNode for HashLeaf {
         let index = self.find_index(self.truncate(key))?;
         let new_count = self.head.count as usize - 1;
         let slot = self.slots()[index];
-        self.head.space_used -= slot.key_len + slot.val_len;
+        self.head.space_used -= slot.key_len + slot.val_len + (VALUE_ALIGN - 1) as u16;
+        if TOMBSTONES {
+            self.set_hash(index, TOMBSTONE_HASH);
+            self.head.tombstone_count += 1;
+            self.validate();
+            return Some(());
+        }
         let mut swap_remove_slot = index;
         let sorted_count = self.head.sorted_count as usize;
         if swap_remove_slot < sorted_count {
-            self.slots_mut()[swap_remove_slot..sorted_count].copy_within(1.., 0);
-            self.hashes_mut()[swap_remove_slot..sorted_count].copy_within(1.., 0);
+            self.copy_entries_within(swap_remove_slot + 1..sorted_count, swap_remove_slot);
             swap_remove_slot = sorted_count - 1;
             self.head.sorted_count -= 1;
         }
-        {
-            let slots = self.slots_mut();
-            slots[swap_remove_slot] = slots[new_count];
-            let hashes = self.hashes_mut();
-            hashes[swap_remove_slot] = hashes[new_count];
-        }
+        self.copy_entries_within(new_count..new_count + 1, swap_remove_slot);
         assert!(SLOTS_FIRST);
-        let old_layout = Self::layout(new_count + 1);
-        let new_layout = Self::layout(new_count);
-        unsafe {
-            self.as_bytes_mut().copy_within(
-                old_layout.hash_start..old_layout.hash_start + new_count,
-                new_layout.hash_start,
-            );
-        }
-        debug_assert_eq!(old_layout.slots_start, new_layout.slots_start);
+        self.move_hash_array(new_count + 1, new_count, new_count);
         self.head.count -= 1;
         self.validate();
         // self.print();
         Some(())
     }
 
-    unsafe fn range_lookup(&mut self, start: &[u8], key_out: *mut u8, callback: &mut dyn FnMut(usize, &[u8]) -> bool) -> bool {
+    fn lookup_slot(&self, key: &[u8]) -> Option<usize> {
+        self.find_index(self.truncate(key))
+    }
+
+    fn value_at(&self, index: usize) -> &[u8] {
+        let value = self.slots()[index].value(self.as_bytes());
+        debug_assert!(value.as_ptr() as usize % VALUE_ALIGN == 0);
+        value
+    }
+
+    fn set_value_at(&mut self, index: usize, value: &[u8]) -> Result<(), NodeOpError> {
+        let s = self.slots()[index];
+        if value.len() > s.val_len as usize {
+            return Err(NodeOpError::NoSpace);
+        }
+        self.as_bytes_mut().write((s.offset + s.key_len) as usize, value);
+        // shrinking leaves unused bytes until compaction
+        self.head.space_used -= s.val_len - value.len() as u16;
+        self.slots_mut()[index].val_len = value.len() as u16;
+        Ok(())
+    }
+
+    fn compactify(&mut self) {
+        HashLeaf::compactify(self)
+    }
+
+    fn range_lookup(&mut self, start: &[u8], key_out: &mut KeyBuffer, callback: &mut dyn FnMut(&[u8], &[u8]) -> bool) -> bool {
         self.sort();
-        debug_assert!(!key_out.is_null());
-        key_out.copy_from_nonoverlapping(start.as_ptr(), self.head.prefix_len as usize);
+        key_out.set_suffix(0, &start[..self.head.prefix_len as usize]);
         let start_index = self.lower_bound(self.truncate(start)).0;
         for s in &self.slots()[start_index..] {
-            let k = s.key(self.as_bytes());
-            key_out.offset(self.head.prefix_len as isize).copy_from_nonoverlapping(k.0.as_ptr(), k.0.len());
-            if !callback((s.key_len + self.head.prefix_len) as usize, s.value(self.as_bytes())) {
+            let key = key_out.set_suffix(self.head.prefix_len as usize, s.key(self.as_bytes()).0);
+            if !callback(key, s.value(self.as_bytes())) {
                 return false;
             }
         }
         true
     }
 
-    unsafe fn range_lookup_desc(&mut self, start: &[u8], key_out: *mut u8, callback: &mut dyn FnMut(usize, &[u8]) -> bool) -> bool {
+    fn range_lookup_desc(&mut self, start: &[u8], key_out: &mut KeyBuffer, callback: &mut dyn FnMut(&[u8], &[u8]) -> bool) -> bool {
         self.sort();
-        debug_assert!(!key_out.is_null());
-        key_out.copy_from_nonoverlapping(start.as_ptr(), self.head.prefix_len as usize);
-        let start_index = self.lower_bound(self.truncate(start)).0.min(self.head.count as usize - 1);
-        for s in self.slots()[..=start_index].iter().rev() {
-            let k = s.key(self.as_bytes());
-            key_out.offset(self.head.prefix_len as isize).copy_from_nonoverlapping(k.0.as_ptr(), k.0.len());
-            if !callback((s.key_len + self.head.prefix_len) as usize, s.value(self.as_bytes())) {
+        if self.head.count == 0 {
+            return true;
+        }
+        key_out.set_suffix(0, &start[..self.head.prefix_len as usize]);
+        let (lower_bound, found) = self.lower_bound(self.truncate(start));
+        // keys from lower_bound on are greater than start, unless it is start itself
+        let end = if found { lower_bound + 1 } else { lower_bound };
+        for s in self.slots()[..end].iter().rev() {
+            let key = key_out.set_suffix(self.head.prefix_len as usize, s.key(self.as_bytes()).0);
+            if !callback(key, s.value(self.as_bytes())) {
                 return false;
             }
         }
         true
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::{free_leaf, ManualInnerSource, ManualLeafSource};
+
+    type Entries = Vec<(Vec<u8>, Vec<u8>)>;
+
+    fn user_entries(range: Range<u32>) -> Entries {
+        range.map(|i| (format!("user/k{i:03}").into_bytes(), i.to_le_bytes().to_vec())).collect()
+    }
+
+    /// checks contents and that every stored hash matches the stored key
+    fn check_entries(leaf: &HashLeaf, entries: &Entries) {
+        assert_eq!(leaf.head.count as usize, entries.len());
+        for (i, s) in leaf.slots().iter().enumerate() {
+            assert_eq!(leaf.hash(i), HashLeaf::compute_hash(s.key(leaf.as_bytes())), "slot {i}");
+        }
+        for (key, value) in entries {
+            assert_eq!(leaf.lookup(key), Some(&value[..]));
+        }
+    }
+
+    #[test]
+    fn removed_keys_are_not_found_and_compaction_reclaims_space() {
+        crate::ensure_init();
+        let keys: Vec<Vec<u8>> = (0..60u32).map(|i| format!("key{i:03}").into_bytes()).collect();
+        for new in [HashLeaf::new, HashLeaf::new_hybrid] {
+            let mut leaf = new();
+            for key in &keys {
+                leaf.insert(key, &[7u8; 20]).unwrap();
+            }
+            let mut expected = new();
+            for (i, key) in keys.iter().enumerate() {
+                if i % 2 == 0 {
+                    assert!(leaf.remove(key).is_some());
+                } else {
+                    expected.insert(key, &[7u8; 20]).unwrap();
+                }
+            }
+            assert_eq!(leaf.head.tombstone_count as usize, if TOMBSTONES { keys.len() / 2 } else { 0 });
+            assert_eq!(leaf.live_count(), keys.len() / 2);
+            for (i, key) in keys.iter().enumerate() {
+                assert_eq!(leaf.lookup(key).is_some(), i % 2 == 1);
+            }
+            // removed entries count as free after compaction even while their slots remain
+            assert_eq!(leaf.free_space_after_compaction(), expected.free_space_after_compaction());
+            leaf.compactify();
+            assert_eq!(leaf.head.tombstone_count, 0);
+            assert_eq!(leaf.free_space(), expected.free_space_after_compaction());
+            for (i, key) in keys.iter().enumerate() {
+                assert_eq!(leaf.lookup(key).is_some(), i % 2 == 1);
+            }
+            leaf.validate();
+        }
+    }
+
+    #[test]
+    fn update_after_removes_in_full_leaf_keeps_other_entries() {
+        crate::ensure_init();
+        for new in [HashLeaf::new, HashLeaf::new_hybrid] {
+            let mut leaf = new();
+            let mut expected = std::collections::BTreeMap::new();
+            for i in 0u32.. {
+                let key = format!("key{i:03}").into_bytes();
+                if leaf.insert(&key, &[i as u8; 20]).is_err() {
+                    break;
+                }
+                expected.insert(key, vec![i as u8; 20]);
+            }
+            // with tombstones, the removed slots stay in front of the updated key until compaction
+            for i in (0..expected.len() as u32 / 2).step_by(3) {
+                let key = format!("key{i:03}").into_bytes();
+                assert!(leaf.remove(&key).is_some());
+                expected.remove(&key);
+            }
+            let key = expected.keys().last().unwrap().clone();
+            let value = [0xee; 300];
+            // only fits once the removed entries are compacted away
+            assert!(value.len() > leaf.free_space());
+            leaf.insert(&key, &value).unwrap();
+            expected.insert(key, value.to_vec());
+            assert_eq!(leaf.live_count(), expected.len());
+            for (key, value) in &expected {
+                assert_eq!(leaf.lookup(key), Some(&value[..]), "{key:?}");
+            }
+            leaf.validate();
+        }
+    }
+
+    #[test]
+    fn splits_with_skewed_value_sizes_keep_the_tree_valid() {
+        crate::ensure_init();
+        let value = |i: u32| vec![i as u8; if i % 16 < 3 { 300 } else { 4 }];
+        let mut keys: Vec<u32> = (0..20_000).collect();
+        keys.sort_by_key(|&i| i.wrapping_mul(0x9e37_79b9));
+        let mut tree = crate::BTree::new_with(BTreeNodeTag::HashLeaf, BTreeNodeTag::BasicInner);
+        for &i in &keys {
+            tree.insert(i.to_be_bytes(), value(i)).unwrap();
+        }
+        assert_eq!(tree.verify_balanced(), Ok(()));
+        assert_eq!(tree.check_space_accounting(), Ok(()));
+        for i in 0..20_000u32 {
+            assert_eq!(tree.get(i.to_be_bytes()), Some(&value(i)[..]));
+        }
+    }
+
+    #[test]
+    fn lookup_mut_changes_value_in_place() {
+        crate::ensure_init();
+        for new in [HashLeaf::new, HashLeaf::new_hybrid] {
+            let mut leaf = new();
+            for i in 0..40u32 {
+                leaf.insert(&i.to_be_bytes(), &[i as u8; 8]).unwrap();
+            }
+            let free = leaf.free_space();
+            leaf.lookup_mut(&7u32.to_be_bytes()).unwrap().copy_from_slice(b"modified");
+            assert!(leaf.lookup_mut(&40u32.to_be_bytes()).is_none());
+            assert_eq!(leaf.free_space(), free);
+            for i in 0..40u32 {
+                let expected = if i == 7 { *b"modified" } else { [i as u8; 8] };
+                assert_eq!(leaf.lookup(&i.to_be_bytes()), Some(&expected[..]));
+            }
+            leaf.validate();
+        }
+    }
+
+    #[test]
+    fn hybrid_leaf_serves_point_and_range_lookups() {
+        crate::ensure_init();
+        let key = |i: u32| format!("key{:05}", i.wrapping_mul(0x9e37_79b9) % 100_000).into_bytes();
+        let mut leaf = HashLeaf::new_hybrid();
+        let mut reference = std::collections::BTreeMap::new();
+        for i in 0..120u32 {
+            if leaf.insert(&key(i), &i.to_le_bytes()).is_err() {
+                break;
+            }
+            reference.insert(key(i), i.to_le_bytes().to_vec());
+            if i % 3 == 2 {
+                assert_eq!(leaf.remove(&key(i - 1)).is_some(), reference.remove(&key(i - 1)).is_some());
+            }
+            // keys arrive in hash order, but the slots stay sorted
+            assert_eq!(leaf.head.sorted_count, leaf.head.count);
+            assert!(leaf.slots().is_sorted_by_key(|s| s.key(leaf.as_bytes())));
+        }
+        assert!(reference.len() > 40);
+        for i in 0..240u32 {
+            assert_eq!(leaf.lookup(&key(i)), reference.get(&key(i)).map(|v| &v[..]), "{i}");
+        }
+
+        let mut tree = crate::BTree::new_with(BTreeNodeTag::HybridLeaf, BTreeNodeTag::BasicInner);
+        let mut reference = std::collections::BTreeMap::new();
+        for i in 0..20_000u32 {
+            tree.insert(key(i), i.to_le_bytes()).unwrap();
+            reference.insert(key(i), i.to_le_bytes().to_vec());
+        }
+        let tag_counts = crate::node_stats::node_tag_counts(&tree);
+        assert!(tag_counts[BTreeNodeTag::HybridLeaf as usize] > 1);
+        for start in (0..100_000u32).step_by(997) {
+            let start = format!("key{start:05}").into_bytes();
+            let mut seen = Vec::new();
+            tree.range_lookup(&start, &mut |k, v| {
+                seen.push((k.to_vec(), v.to_vec()));
+                seen.len() < 300
+            });
+            let expected: Vec<_> = reference.range(start.clone()..).take(300).map(|(k, v)| (k.clone(), v.clone())).collect();
+            assert_eq!(seen, expected, "{start:?}");
+            assert_eq!(tree.get(&start), reference.get(&start).map(|v| &v[..]));
+        }
+        // range lookups neither sort nor convert hybrid leaves
+        assert_eq!(crate::node_stats::node_tag_counts(&tree), tag_counts);
+    }
+
+    #[test]
+    fn merge_copies_hashes_like_reinsert() {
+        crate::ensure_init();
+        let separator = b"user/k030".to_vec();
+        // the first pair shares the prefix "user/" with the merged leaf, in the second only the left leaf has it
+        for (lower, upper) in [(&b"user/a"[..], &b"user/z"[..]), (&b"user/a"[..], &b"z"[..])] {
+            let (left_entries, right_entries) = (user_entries(0..31), user_entries(31..60));
+            unsafe {
+                let left = ManualLeafSource { lower_fence: lower.to_vec(), upper_fence: separator.clone(), entries: left_entries.clone() }
+                    .build(BTreeNodeTag::HashLeaf).unwrap();
+                let right = ManualLeafSource { lower_fence: separator.clone(), upper_fence: upper.to_vec(), entries: right_entries.clone() }
+                    .build(BTreeNodeTag::HashLeaf).unwrap();
+                (*left).hash_leaf.try_merge_right(&mut (*right).hash_leaf, FatTruncatedKey::full(&separator)).unwrap();
+                check_entries(&(*right).hash_leaf, &[left_entries, right_entries].concat());
+                for node in [left, right] {
+                    free_leaf(node);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn split_copies_hashes_like_reinsert() {
+        crate::ensure_init();
+        let entries = user_entries(0..60);
+        // the first leaf keeps its prefix "user/" in both halves, the second gains it in the lower half
+        for (lower, upper) in [(&b"user/a"[..], &b"user/z"[..]), (&b"user/a"[..], &b"z"[..])] {
+            unsafe {
+                let leaf = ManualLeafSource { lower_fence: lower.to_vec(), upper_fence: upper.to_vec(), entries: entries.clone() }
+                    .build(BTreeNodeTag::HashLeaf).unwrap();
+                let parent = ManualInnerSource { lower_fence: Vec::new(), upper_fence: Vec::new(), keys: Vec::new(), children: vec![leaf] }
+                    .build(BTreeNodeTag::BasicInner).unwrap();
+                (*leaf).hash_leaf.split_node((*parent).to_inner_mut(), 0, &entries[0].0).unwrap();
+                let parent_inner = (*parent).to_inner();
+                assert_eq!(parent_inner.key_count(), 1);
+                let (left, right) = (parent_inner.get_child(0), parent_inner.get_child(1));
+                assert_eq!(right, leaf);
+                let left_count = (*left).hash_leaf.head.count as usize;
+                check_entries(&(*left).hash_leaf, &entries[..left_count].to_vec());
+                check_entries(&(*right).hash_leaf, &entries[left_count..].to_vec());
+                for node in [left, right] {
+                    free_leaf(node);
+                }
+                BTreeNode::dealloc(parent);
+            }
+        }
+    }
+
+    #[test]
+    fn batch_hashes_match_compute_hash() {
+        crate::ensure_init();
+        let keys: Vec<Vec<u8>> = (0..5_000u32).map(|i| format!("{}{i}", "k".repeat(i as usize % 13)).into_bytes()).collect();
+        // with tombstones, some raw hashes collide with the tombstone and must be remapped like compute_hash does
+        if TOMBSTONES {
+            assert!(keys.iter().any(|k| HashLeaf::compute_raw_hash(PrefixTruncatedKey(k)) == TOMBSTONE_HASH));
+        }
+        // lengths around multiples of the simd width, so the scalar remainder is covered too
+        for len in (0..3 * SIMD_WIDTH + 2).chain([keys.len()]) {
+            let mut batch = vec![0u8; len];
+            HashLeaf::compute_hashes_batch(keys[..len].iter().map(|k| PrefixTruncatedKey(k)), &mut batch);
+            let single: Vec<u8> = keys[..len].iter().map(|k| HashLeaf::compute_hash(PrefixTruncatedKey(k))).collect();
+            assert_eq!(batch, single, "{len} keys");
+        }
+    }
+
+    #[test]
+    fn repeated_sorts_keep_every_entry() {
+        crate::ensure_init();
+        let scrambled = |range: Range<u32>| {
+            let mut entries = user_entries(range);
+            entries.sort_by_key(|(_, v)| u32::from_le_bytes(v[..].try_into().unwrap()).wrapping_mul(0x9e37_79b9));
+            entries
+        };
+        for new in [HashLeaf::new, HashLeaf::new_hybrid] {
+            let mut leaf = new();
+            let mut expected = std::collections::BTreeMap::new();
+            // each round merges new unsorted entries into a sorted run that removes have shortened
+            for round in 0..4u32 {
+                for (key, value) in scrambled(round * 25..round * 25 + 25) {
+                    leaf.insert(&key, &value).unwrap();
+                    expected.insert(key, value);
+                }
+                for (key, _) in user_entries(round * 25..round * 25 + 25).into_iter().step_by(4) {
+                    assert!(leaf.remove(&key).is_some());
+                    expected.remove(&key);
+                }
+                leaf.sort();
+                assert_eq!(leaf.head.sorted_count, leaf.head.count, "round {round}");
+                let keys: Vec<_> = leaf.slots().iter().map(|s| s.key(leaf.as_bytes()).0).collect();
+                assert!(keys.windows(2).all(|w| w[0] < w[1]), "round {round}");
+                check_entries(&leaf, &expected.clone().into_iter().collect());
+            }
+            leaf.validate();
+        }
+    }
+
+    #[test]
+    fn range_lookups_match_sorted_set() {
+        crate::ensure_init();
+        let key = |i: u32| format!("k{:03}", i.wrapping_mul(37) % 200).into_bytes();
+        let mut leaf = HashLeaf::new();
+        let mut reference = std::collections::BTreeSet::new();
+        for i in 0..50u32 {
+            leaf.insert(&key(i), &i.to_le_bytes()).unwrap();
+            reference.insert(key(i));
+        }
+        // sorts the first run, entries inserted afterwards start past the sorted count
+        leaf.sort();
+        for i in 50..90u32 {
+            leaf.insert(&key(i), &i.to_le_bytes()).unwrap();
+            reference.insert(key(i));
+        }
+        for i in (0..90u32).step_by(7) {
+            assert!(leaf.remove(&key(i)).is_some());
+            reference.remove(&key(i));
+        }
+        assert!(leaf.head.sorted_count < leaf.head.count);
+        // only the first lookup merges the unsorted entries, the rest scan the sorted leaf
+        for start in (0..205u32).map(|i| format!("k{i:03}").into_bytes()).chain([Vec::new(), b"k".to_vec(), b"l".to_vec()]) {
+            let mut seen = Vec::new();
+            leaf.range_lookup(&start, &mut KeyBuffer::new(), &mut |k, _| {
+                seen.push(k.to_vec());
+                true
+            });
+            assert!(seen.iter().eq(reference.range(start.clone()..)), "{start:?}");
+            let mut seen = Vec::new();
+            leaf.range_lookup_desc(&start, &mut KeyBuffer::new(), &mut |k, _| {
+                seen.push(k.to_vec());
+                true
+            });
+            assert!(seen.iter().eq(reference.range(..=start.clone()).rev()), "{start:?}");
+        }
+        leaf.validate();
+    }
+
+    #[test]
+    fn simd_and_scalar_find_agree() {
+        crate::ensure_init();
+        let key = |i: u32| format!("key{i}").into_bytes();
+        for new in [HashLeaf::new, HashLeaf::new_hybrid] {
+            let mut leaf = new();
+            // every count up to a full leaf, so the last simd block is partially filled at times
+            for count in 0..200u32 {
+                if leaf.insert(&key(count), &[count as u8]).is_err() {
+                    break;
+                }
+                for i in (0..=count + 1).chain([count * 7 + 1000]) {
+                    let needle = PrefixTruncatedKey(&key(i));
+                    let hash = HashLeaf::compute_hash(needle);
+                    assert_eq!(leaf.find_simd(needle, hash), leaf.find_no_simd(needle, hash), "count {count} key {i}");
+                }
+            }
+            // the runtime switch selects the scalar search for the whole leaf api
+            let lookups = |leaf: &HashLeaf| (0..400u32).map(|i| leaf.lookup(&key(i)).map(<[u8]>::to_vec)).collect::<Vec<_>>();
+            let with_simd = lookups(&leaf);
+            crate::btree_node::set_simd_find(false);
+            let without_simd = lookups(&leaf);
+            crate::btree_node::set_simd_find(true);
+            assert_eq!(with_simd, without_simd);
+            assert!(with_simd.iter().filter(|v| v.is_some()).count() > 100);
+        }
+    }
+}
@@ -1,6 +1,6 @@
 use crate::basic_node::BasicNode;
 use crate::find_separator::{find_separator, KeyRef};
-use crate::node_traits::{FenceData, FenceRef, InnerConversionSink, InnerConversionSource, InnerNode, merge, Node, SeparableInnerConversionSource, split_in_place};
+use crate::node_traits::{debug_assert_children_valid, merge_candidates, FallbackInnerConversionSink, FenceData, FenceRef, InnerConversionSink, InnerConversionSource, InnerNode, merge, Node, SeparableInnerConversionSource, split_in_place, NodeOpError, inner_key_cap_reached};
 use crate::util::{
     common_prefix_len, get_key_from_slice, partial_restore, reinterpret_mut, SmallBuff,
 };
@@ -22,10 +22,11 @@ pub type U64ZeroPaddedHeadNode = HeadNode<ZeroPaddedHead<u64>>;
 pub type U32ZeroPaddedHeadNode = HeadNode<ZeroPaddedHead<u32>>;
 pub type AsciiHeadNode = HeadNode<AsciiHead>;
 
-#[cfg(feature = "head-early-abort-create_true")]
-const HEAD_EARLY_ABORT_CREATE: bool = true;
-#[cfg(feature = "head-early-abort-create_false")]
-const HEAD_EARLY_ABORT_CREATE: bool = false;
+const _: () = assert!(size_of::<U64ExplicitHeadNode>() == PAGE_SIZE);
+const _: () = assert!(size_of::<U32ExplicitHeadNode>() == PAGE_SIZE);
+const _: () = assert!(size_of::<U64ZeroPaddedHeadNode>() == PAGE_SIZE);
+const _: () = assert!(size_of::<U32ZeroPaddedHeadNode>() == PAGE_SIZE);
+const _: () = assert!(size_of::<AsciiHeadNode>() == PAGE_SIZE);
 
 pub trait FullKeyHeadNoTag: Ord + Sized + Copy + KeyRef<'static> + Debug + 'static {
     const HINT_COUNT: usize;
@@ -336,17 +337,18 @@ pub struct HeadNodeHead {
 }
 
 impl<Head: FullKeyHead> HeadNode<Head> {
-    pub fn new(fences: FenceData, upper: *mut BTreeNode) -> Self {
+    pub fn new(fences: FenceData, upper: *mut BTreeNode) -> Result<Self, NodeOpError> {
         debug_assert_eq!(size_of::<Self>(), PAGE_SIZE);
-        let mut this = Self::from_fences(fences);
+        let mut this = Self::from_fences(fences)?;
         this.as_parts_mut().2[0] = upper;
-        this
+        Ok(this)
     }
 
-    fn from_fences(f: FenceData) -> Self {
+    /// fails with `NoSpace` if the fences leave no room for a single key
+    fn from_fences(f: FenceData) -> Result<Self, NodeOpError> {
         let mut this = HeadNode {
             head: HeadNodeHead {
-                head: BTreeNodeHead { tag: Head::TAG, adaption_state: AdaptionState::new() },
+                head: BTreeNodeHead::new(Head::TAG, AdaptionState::new()),
                 key_count: 0,
                 key_capacity: 0,
                 child_offset: 0,
@@ -357,12 +359,20 @@ impl<Head: FullKeyHead> HeadNode<Head> {
             _p: PhantomData,
             data: unsafe { mem::zeroed() },
         };
-        this.set_fences(f);
-        this
+        this.set_fences(f)?;
+        Ok(this)
+    }
+
+    /// below this count all hints would sample the first few keys, so they are neither maintained nor used
+    fn hints_used(count: usize) -> bool {
+        count > Head::HINT_COUNT * 2
     }
 
     fn update_hint(&mut self, slot_id: usize) {
         let count = self.head.key_count as usize;
+        if !Self::hints_used(count) {
+            return;
+        }
         let dist = count / (Head::HINT_COUNT + 1);
         let begin = if (count > Head::HINT_COUNT * 2 + 1)
             && (((count - 1) / (Head::HINT_COUNT + 1)) == dist)
@@ -377,13 +387,28 @@ impl<Head: FullKeyHead> HeadNode<Head> {
             hint[i] = keys[dist * (i + 1)];
             debug_assert!(i == 0 || hint[i - 1] <= hint[i]);
         }
+        self.validate_hints();
+    }
+
+    /// checks that every hint matches the key it samples, stale hints make `search_hint` return wrong ranges
+    fn validate_hints(&self) {
+        if cfg!(debug_assertions) {
+            let (head, keys, _, hint) = self.as_parts();
+            if !Self::hints_used(head.key_count as usize) {
+                return;
+            }
+            let dist = head.key_count as usize / (Head::HINT_COUNT + 1);
+            for i in 0..Head::HINT_COUNT {
+                assert!(hint[i] == keys[dist * (i + 1)], "hint {i} is {:?}, should be {:?}", hint[i], keys[dist * (i + 1)]);
+            }
+        }
     }
 
     /// returns half open range
     fn search_hint(&self, head_needle: Head) -> (usize, usize) {
         debug_assert!(self.head.key_count > 0);
         let (head, _, _, hint) = self.as_parts();
-        if head.key_count as usize > Head::HINT_COUNT * 2 {
+        if Self::hints_used(head.key_count as usize) {
             let dist = head.key_count as usize / (Head::HINT_COUNT + 1);
             let pos = (0..Head::HINT_COUNT)
                 .find(|&hi| hint[hi] >= head_needle)
@@ -404,8 +429,14 @@ impl<Head: FullKeyHead> HeadNode<Head> {
         }
     }
 
-    fn set_fences(&mut self, fences: FenceData) {
+    fn set_fences(&mut self, fences: FenceData) -> Result<(), NodeOpError> {
         fences.validate();
+        let fence_len = fences.lower_fence.0.len() + fences.upper_fence.0.len();
+        // one key and two children, plus padding to align the children
+        let min_content = size_of::<Head>() + 2 * size_of::<*mut BTreeNode>() + align_of::<*mut BTreeNode>() - 1;
+        if Self::KEY_OFFSET + min_content + fence_len > PAGE_SIZE {
+            return Err(NodeOpError::NoSpace);
+        }
         self.head.prefix_len = fences.prefix_len as u16;
         let upper_fence_offset = PAGE_SIZE - fences.upper_fence.0.len();
         let lower_fence_offset = upper_fence_offset - fences.lower_fence.0.len();
@@ -432,8 +463,10 @@ impl<Head: FullKeyHead> HeadNode<Head> {
             child_offset + (key_capacity + 1) * size_of::<*mut BTreeNode>() <= lower_fence_offset
         );
 
+        debug_assert!(key_capacity >= 1);
         self.head.key_capacity = key_capacity as u16;
         self.head.child_offset = child_offset as u16;
+        Ok(())
     }
 
     const KEY_OFFSET: usize = { Self::HINT_OFFSET + Head::HINT_COUNT * size_of::<Head>() };
@@ -490,12 +523,10 @@ impl<Head: FullKeyHead> HeadNode<Head> {
     }
 
     pub fn as_bytes(&self) -> &[u8; PAGE_SIZE] {
-        assert_eq!(PAGE_SIZE, size_of::<Self>());
         unsafe { transmute(self as *const Self) }
     }
 
     unsafe fn as_bytes_mut(&mut self) -> &mut [u8; PAGE_SIZE] {
-        assert_eq!(PAGE_SIZE, size_of::<Self>());
         transmute(self as *mut Self)
     }
 
@@ -508,14 +539,14 @@ impl<Head: FullKeyHead> HeadNode<Head> {
         slot: usize,
         key: PrefixTruncatedKey,
         child: *mut BTreeNode,
-    ) -> Result<(), ()> {
+    ) -> Result<(), NodeOpError> {
         let prefix_len = dst.fences().prefix_len;
         dst.request_space(dst.space_needed(key.len() + prefix_len, size_of::<*mut BTreeNode>()))?;
         dst.raw_insert(slot, key, &(child as usize).to_ne_bytes());
         Ok(())
     }
 
-    pub fn try_from_any(this: &mut BTreeNode) -> Result<(), ()> {
+    pub fn try_from_any(this: &mut BTreeNode) -> Result<(), NodeOpError> {
         let mut tmp = unsafe { BTreeNode::new_uninit() };
         Self::create(&mut tmp, this.to_inner())?;
         unsafe {
@@ -537,10 +568,10 @@ impl<Head: FullKeyHead> HeadNode<Head> {
         &mut self,
         right_any: &mut BTreeNode,
         separator: FatTruncatedKey,
-    ) -> Result<(), ()> {
+    ) -> Result<(), NodeOpError> {
         unsafe {
             let mut tmp = BTreeNode::new_uninit();
-            merge::<Self, dyn InnerNode, dyn InnerNode>(
+            merge::<FallbackInnerConversionSink<Self, BasicNode>, dyn InnerNode, dyn InnerNode>(
                 &mut tmp,
                 self,
                 right_any.to_inner(),
@@ -553,28 +584,35 @@ impl<Head: FullKeyHead> HeadNode<Head> {
 }
 
 unsafe impl<Head: FullKeyHead> InnerConversionSink for HeadNode<Head> {
-    fn create(dst: &mut BTreeNode, src: &(impl InnerConversionSource + ?Sized)) -> Result<(), ()> {
+    fn create(dst: &mut BTreeNode, src: &(impl InnerConversionSource + ?Sized)) -> Result<(), NodeOpError> {
         let len = src.key_count();
-        if HEAD_EARLY_ABORT_CREATE && src.get_key_length_max(0..len) > Head::MAX_LEN {
-            return Err(());
+        // reject before writing anything to dst, so a failed create never leaves a partially filled node
+        if src.get_key_length_max(0..len) > Head::MAX_LEN {
+            return Err(NodeOpError::Unrepresentable);
         }
-        let fences = src.fences();
-        let this = dst.write_inner(Self::from_fences(fences));
+        let this = Self::from_fences(src.fences())?;
         if (this.head.key_capacity as usize) < len {
-            return Err(());
+            return Err(NodeOpError::NoSpace);
         }
-        this.head.key_count = len as u16;
+        let this = dst.write_inner(this);
         let (_, keys, children, _) = this.as_parts_mut();
         debug_assert!(size_of::<Head>() <= 8);
         let mut buffer = [0u8; 16];
         for i in 0..len {
-            let key_len = src.get_key(i, buffer.as_mut_slice(), 0)?;
-            keys[i] = Head::make_fence_head(PrefixTruncatedKey(&buffer[buffer.len() - key_len..]))
-                .ok_or(())?;
+            let key = match src.get_key_ref(i) {
+                Some(key) => key,
+                None => {
+                    let key_len = src.get_key(i, buffer.as_mut_slice(), 0).map_err(|_| NodeOpError::Unrepresentable)?;
+                    PrefixTruncatedKey(&buffer[buffer.len() - key_len..])
+                }
+            };
+            keys[i] = Head::make_fence_head(key).ok_or(NodeOpError::Unrepresentable)?;
         }
         for i in 0..len + 1 {
             children[i] = src.get_child(i);
         }
+        // only publish the keys once all of them were converted, a failure above leaves an empty node
+        this.head.key_count = len as u16;
         this.update_hint(0);
         Ok(())
     }
@@ -603,7 +641,7 @@ impl<Head: FullKeyHead> InnerConversionSource for HeadNode<Head> {
         self.as_parts().2[index]
     }
 
-    fn get_key(&self, index: usize, dst: &mut [u8], strip_prefix: usize) -> Result<usize, ()> {
+    fn get_key(&self, index: usize, dst: &mut [u8], strip_prefix: usize) -> Result<usize, NodeOpError> {
         debug_assert!(index < self.head.key_count as usize);
         //TODO avoidable copy
         let key = self.as_parts().1[index].restore();
@@ -627,7 +665,7 @@ unsafe impl<Head: FullKeyHead> Node for HeadNode<Head> {
         parent: &mut dyn InnerNode,
         index_in_parent: usize,
         key_in_node: &[u8],
-    ) -> Result<(), ()> {
+    ) -> Result<(), NodeOpError> {
         split_in_place::<Self, Self, Self>(
             unsafe { reinterpret_mut(self) },
             parent,
@@ -664,10 +702,14 @@ unsafe impl<Head: FullKeyHead> Node for HeadNode<Head> {
             lower_fence: FenceRef(lower),
             upper_fence: FenceRef(upper),
         }.restrip());
+        self.validate_hints();
+        debug_assert_children_valid(self);
         let mut current_lower: SmallBuff = lower.into();
         let (head, keys, children, _) = self.as_parts();
         for i in 0..head.key_count as usize {
-            let current_upper = partial_restore(0, &[self.prefix(lower), &keys[i].restore()], 0);
+            let restored = keys[i].restore();
+            debug_assert_eq!(Head::make_fence_head(PrefixTruncatedKey(&restored)), Some(keys[i]), "head does not round trip through restore");
+            let current_upper = partial_restore(0, &[self.prefix(lower), &restored], 0);
             unsafe { &mut *children[i] }.validate_tree(&current_lower, &current_upper);
             current_lower = current_upper;
         }
@@ -695,45 +737,34 @@ impl<Head: FullKeyHead> InnerNode for HeadNode<Head> {
         }
         let needle_head =
             Head::make_needle_head(PrefixTruncatedKey(&key[self.head.prefix_len as usize..]));
-        let index = bc.predict().filter(|&i| {
+        let predicted = bc.predict().filter(|&i| {
             i <= self.head.key_count as usize
                 && (i == 0 || self.as_parts().1[i - 1] < needle_head)
                 && (i >= self.head.key_count as usize || needle_head <= self.as_parts().1[i])
-        })
-            .unwrap_or_else(|| {
-                let (lower, upper) = self.search_hint(needle_head);
-                match self.as_parts().1[lower..upper].binary_search(&needle_head) {
-                    Ok(i) | Err(i) => lower + i,
-                }
-            });
+        });
+        let index = predicted.unwrap_or_else(|| self.lookup_child_index(key));
+        debug_assert!(predicted.is_none() || index == self.lookup_child_index(key), "accepted branch cache prediction {index} disagrees with search");
         bc.store(index);
         index
     }
 
-    fn merge_children_check(&mut self, mut child_index: usize) -> Result<(), ()> {
+    fn lookup_child_index(&self, key: &[u8]) -> usize {
+        if self.head.key_count == 0 {
+            return 0;
+        }
+        let needle_head =
+            Head::make_needle_head(PrefixTruncatedKey(&key[self.head.prefix_len as usize..]));
+        let (lower, upper) = self.search_hint(needle_head);
+        match self.as_parts().1[lower..upper].binary_search(&needle_head) {
+            Ok(i) | Err(i) => lower + i,
+        }
+    }
+
+    fn merge_children(&mut self, child_index: usize) -> Result<(), NodeOpError> {
         debug_assert!(child_index < self.head.key_count as usize + 1);
         debug_assert!(unsafe { (&*self.get_child(child_index)).is_underfull() });
         unsafe {
-            let left;
-            let right;
-            if child_index == self.key_count() {
-                if child_index == 0 {
-                    // only one child
-                    return Err(());
-                }
-                child_index -= 1;
-                left = &mut *self.get_child(child_index);
-                right = &mut *self.get_child(child_index + 1);
-                if !left.is_underfull() {
-                    return Err(());
-                }
-            } else {
-                left = &mut *self.get_child(child_index);
-                right = &mut *self.get_child(child_index + 1);
-                if !right.is_underfull() {
-                    return Err(());
-                }
-            }
+            let (child_index, left, right) = merge_candidates(self, child_index)?;
             let sep_key = self.as_parts().1[child_index].restore();
             left.try_merge_right(
                 right,
@@ -755,7 +786,7 @@ impl<Head: FullKeyHead> InnerNode for HeadNode<Head> {
         index: usize,
         key: PrefixTruncatedKey,
         child: *mut BTreeNode,
-    ) -> Result<(), ()> {
+    ) -> Result<(), NodeOpError> {
         debug_assert!(self.head.key_count < self.head.key_capacity);
         if let Some(key) = Head::make_fence_head(key) {
             let (head, keys, children, _) = self.as_parts_mut();
@@ -779,11 +810,337 @@ impl<Head: FullKeyHead> InnerNode for HeadNode<Head> {
         }
     }
 
-    fn request_space_for_child(&mut self, _key_length: usize) -> Result<usize, ()> {
-        if self.head.key_count < self.head.key_capacity {
+    fn request_space_for_child(&mut self, _key_length: usize) -> Result<usize, NodeOpError> {
+        if self.head.key_count < self.head.key_capacity && !inner_key_cap_reached(self.head.key_count as usize) {
             Ok(self.head.prefix_len as usize)
         } else {
-            Err(())
+            Err(NodeOpError::NoSpace)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::btree_node::create_inner_exact;
+    use crate::test_util::ManualInnerSource;
+    use crate::vtables::BTreeNodeTag;
+    use crate::BTreeNode;
+    use rand::{Rng, SeedableRng};
+    use rand_xoshiro::Xoshiro128PlusPlus;
+
+    const HEAD_TAGS: [BTreeNodeTag; 5] = [
+        BTreeNodeTag::U64ExplicitHead,
+        BTreeNodeTag::U32ExplicitHead,
+        BTreeNodeTag::U64ZeroPaddedHead,
+        BTreeNodeTag::U32ZeroPaddedHead,
+        BTreeNodeTag::AsciiHead,
+    ];
+
+    #[test]
+    fn merge_with_long_separator_falls_back_to_basic() {
+        crate::ensure_init();
+        // too long for any head, but the keys on either side fit
+        let separator = b"mmmmmmmmmmmmmmmmmmmm".to_vec();
+        for tag in HEAD_TAGS {
+            let children: Vec<*mut BTreeNode> = (0..6).map(|_| BTreeNode::new_leaf()).collect();
+            let left = ManualInnerSource { lower_fence: Vec::new(), upper_fence: separator.clone(), keys: vec![b"c".to_vec(), b"f".to_vec()], children: children[..3].to_vec() };
+            let right = ManualInnerSource { lower_fence: separator.clone(), upper_fence: Vec::new(), keys: vec![b"s".to_vec(), b"v".to_vec()], children: children[3..].to_vec() };
+            let merged = ManualInnerSource {
+                lower_fence: Vec::new(),
+                upper_fence: Vec::new(),
+                keys: vec![b"c".to_vec(), b"f".to_vec(), separator.clone(), b"s".to_vec(), b"v".to_vec()],
+                children: children.clone(),
+            };
+            unsafe {
+                let (left_node, right_node) = (BTreeNode::alloc(), BTreeNode::alloc());
+                create_inner_exact(tag, &mut *left_node, &left).unwrap();
+                create_inner_exact(tag, &mut *right_node, &right).unwrap();
+                let parent = ManualInnerSource { lower_fence: Vec::new(), upper_fence: Vec::new(), keys: vec![separator.clone()], children: vec![left_node, right_node] };
+                let parent = parent.build(BTreeNodeTag::BasicInner).unwrap();
+                assert_eq!((*parent).to_inner_mut().merge_children(0), Ok(()), "{tag:?}");
+                let merged_node = (*parent).to_inner().get_child(0);
+                assert_eq!((*merged_node).tag(), BTreeNodeTag::BasicInner, "{tag:?}");
+                assert!(merged.matches((*merged_node).to_inner()), "{tag:?}");
+                for node in children.into_iter().chain([merged_node, parent]) {
+                    BTreeNode::dealloc(node);
+                }
+            }
+        }
+    }
+
+    /// forwards to a source, but hides its borrowed keys so `create` has to restore them into a buffer
+    struct BufferedSource<'a>(&'a dyn InnerConversionSource);
+
+    impl InnerConversionSource for BufferedSource<'_> {
+        fn fences(&self) -> FenceData {
+            self.0.fences()
+        }
+
+        fn key_count(&self) -> usize {
+            self.0.key_count()
+        }
+
+        fn get_child(&self, index: usize) -> *mut BTreeNode {
+            self.0.get_child(index)
+        }
+
+        fn get_key(&self, index: usize, dst: &mut [u8], strip_prefix: usize) -> Result<usize, NodeOpError> {
+            self.0.get_key(index, dst, strip_prefix)
+        }
+
+        fn get_key_length_sum(&self, range: Range<usize>) -> usize {
+            self.0.get_key_length_sum(range)
+        }
+
+        fn get_key_length_max(&self, range: Range<usize>) -> usize {
+            self.0.get_key_length_max(range)
+        }
+    }
+
+    fn check_borrowed_keys_match_buffered<Head: FullKeyHead>(source: &dyn InnerConversionSource) {
+        assert!(source.get_key_ref(0).is_some());
+        unsafe {
+            let (borrowed, buffered) = (BTreeNode::alloc(), BTreeNode::alloc());
+            create_inner_exact(Head::TAG, &mut *borrowed, source).unwrap();
+            create_inner_exact(Head::TAG, &mut *buffered, &BufferedSource(source)).unwrap();
+            let (borrowed_head, borrowed_keys, _, _) = (*(borrowed as *mut HeadNode<Head>)).as_parts();
+            let (buffered_head, buffered_keys, _, _) = (*(buffered as *mut HeadNode<Head>)).as_parts();
+            let len = source.key_count();
+            assert_eq!((borrowed_head.key_count as usize, buffered_head.key_count as usize), (len, len));
+            assert!((0..len).all(|i| borrowed_keys[i] == buffered_keys[i]), "{:?}", Head::TAG);
+            BTreeNode::dealloc(borrowed);
+            BTreeNode::dealloc(buffered);
+        }
+    }
+
+    #[test]
+    fn borrowed_keys_pack_like_buffered_keys() {
+        crate::ensure_init();
+        let source = ManualInnerSource::with_keys((0..40u32).map(|i| format!("{:03}", i * 7).into_bytes()).collect());
+        let basic = source.build(BTreeNodeTag::BasicInner).unwrap();
+        unsafe {
+            assert_eq!((*basic).tag(), BTreeNodeTag::BasicInner);
+            for src in [&source as &dyn InnerConversionSource, &(*basic).basic] {
+                check_borrowed_keys_match_buffered::<ExplicitLengthHead<u64>>(src);
+                check_borrowed_keys_match_buffered::<ExplicitLengthHead<u32>>(src);
+                check_borrowed_keys_match_buffered::<ZeroPaddedHead<u64>>(src);
+                check_borrowed_keys_match_buffered::<ZeroPaddedHead<u32>>(src);
+                check_borrowed_keys_match_buffered::<AsciiHead>(src);
+            }
+            BTreeNode::dealloc(basic);
+        }
+    }
+
+    #[test]
+    fn failed_child_insert_frees_split_half() {
+        crate::ensure_init();
+        let parent_keys: Vec<Vec<u8>> = (0..200).map(|i| format!("{:06}", i * 10).into_bytes()).collect();
+        // separators of the child are too long for the head and there are too many keys to convert the parent to a basic node
+        let child_keys: Vec<Vec<u8>> = (0..20).map(|i| format!("z{i:039}").into_bytes()).collect();
+        let child = ManualInnerSource {
+            lower_fence: parent_keys.last().unwrap().clone(),
+            upper_fence: Vec::new(),
+            keys: child_keys.clone(),
+            children: (0..=child_keys.len()).map(|_| BTreeNode::new_leaf()).collect(),
+        }.build(BTreeNodeTag::BasicInner).unwrap();
+        // the tree frees the parent, the child and all leaves, its empty root leaf becomes the first child
+        let mut tree = crate::BTree::new();
+        let mut children: Vec<*mut BTreeNode> = vec![tree.root];
+        children.extend((1..parent_keys.len()).map(|_| BTreeNode::new_leaf()));
+        children.push(child);
+        let parent = ManualInnerSource { lower_fence: Vec::new(), upper_fence: Vec::new(), keys: parent_keys.clone(), children }
+            .build(BTreeNodeTag::U64ExplicitHead).unwrap();
+        unsafe {
+            assert_eq!((*parent).tag(), BTreeNodeTag::U64ExplicitHead);
+            let live_before = crate::btree_node::LIVE_PAGES.with(|live| live.get());
+            let result = (*child).split_node((*parent).to_inner_mut(), parent_keys.len(), &child_keys[0]);
+            assert_eq!(result, Err(NodeOpError::NeedsSplit));
+            assert_eq!(crate::btree_node::LIVE_PAGES.with(|live| live.get()), live_before);
+            assert_eq!((*parent).tag(), BTreeNodeTag::U64ExplicitHead);
+            assert_eq!((*parent).to_inner().key_count(), parent_keys.len());
+            assert_eq!((*child).to_inner().key_count(), child_keys.len());
+        }
+        tree.root = parent;
+    }
+
+    /// checks that every representable key restores to itself and its head, and that a node built from them stores the same heads
+    fn check_heads_round_trip<Head: FullKeyHead>(candidates: &[Vec<u8>]) {
+        let mut keys: Vec<Vec<u8>> = Vec::new();
+        for key in candidates {
+            let Some(head) = Head::make_fence_head(PrefixTruncatedKey(key)) else {
+                // explicit length heads represent every short enough key
+                let explicit = matches!(Head::TAG, BTreeNodeTag::U64ExplicitHead | BTreeNodeTag::U32ExplicitHead);
+                assert!(key.len() > Head::MAX_LEN || !explicit, "{:?} rejects {key:?}", Head::TAG);
+                continue;
+            };
+            assert_eq!(&head.restore()[..], &key[..], "{:?}", Head::TAG);
+            assert_eq!(Head::make_fence_head(PrefixTruncatedKey(&head.restore())), Some(head), "{:?} {key:?}", Head::TAG);
+            keys.push(key.clone());
+        }
+        keys.sort();
+        keys.dedup();
+        assert!(keys.len() > 4, "{:?}", Head::TAG);
+        let source = ManualInnerSource::with_keys(keys.clone());
+        unsafe {
+            let node = BTreeNode::alloc();
+            create_inner_exact(Head::TAG, &mut *node, &source).unwrap();
+            let (head, node_keys, _, _) = (*(node as *mut HeadNode<Head>)).as_parts();
+            assert_eq!(head.key_count as usize, keys.len());
+            for (node_key, key) in node_keys.iter().zip(&keys) {
+                assert_eq!(&node_key.restore()[..], &key[..], "{:?}", Head::TAG);
+                assert_eq!(Head::make_fence_head(PrefixTruncatedKey(&node_key.restore())), Some(*node_key), "{:?}", Head::TAG);
+            }
+            BTreeNode::dealloc(node);
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn fences_without_room_for_a_key_are_rejected() {
+        crate::ensure_init();
+        // no common prefix, so the fences keep their full length even if prefixes are stripped
+        let source = ManualInnerSource {
+            lower_fence: vec![b'a'; PAGE_SIZE / 2 - 8],
+            upper_fence: vec![b'b'; PAGE_SIZE / 2 - 8],
+            keys: vec![b"ab".to_vec()],
+            children: vec![8 as *mut BTreeNode, 16 as *mut BTreeNode],
+        };
+        for tag in HEAD_TAGS {
+            unsafe {
+                let node = BTreeNode::alloc();
+                assert_eq!(create_inner_exact(tag, &mut *node, &source), Err(NodeOpError::NoSpace), "{tag:?}");
+                // the basic fallback has no room either
+                assert_eq!(crate::btree_node::create_inner_of(tag, &mut *node, &source), Err(NodeOpError::NoSpace), "{tag:?}");
+                BTreeNode::dealloc(node);
+            }
+        }
+    }
+
+    /// a source with a single key one byte too long for `Head` is rejected as a whole and left untouched
+    fn check_single_long_key_is_rejected<Head: FullKeyHead>() {
+        let source = ManualInnerSource::with_keys(vec![b"a".to_vec(), b"b".to_vec(), vec![b'c'; Head::MAX_LEN + 1], b"d".to_vec()]);
+        unsafe {
+            let src = source.build(BTreeNodeTag::BasicInner).unwrap();
+            let before = (*src).written_bytes();
+            let dst = BTreeNode::alloc();
+            assert_eq!(HeadNode::<Head>::create(&mut *dst, (*src).to_inner()), Err(NodeOpError::Unrepresentable), "{:?}", Head::TAG);
+            assert!((*src).written_bytes() == before, "{:?}", Head::TAG);
+            assert!(source.matches((*src).to_inner()), "{:?}", Head::TAG);
+            BTreeNode::dealloc(dst);
+            BTreeNode::dealloc(src);
+        }
+    }
+
+    #[test]
+    fn single_overlong_key_is_unrepresentable() {
+        crate::ensure_init();
+        check_single_long_key_is_rejected::<ExplicitLengthHead<u64>>();
+        check_single_long_key_is_rejected::<ExplicitLengthHead<u32>>();
+        check_single_long_key_is_rejected::<ZeroPaddedHead<u64>>();
+        check_single_long_key_is_rejected::<ZeroPaddedHead<u32>>();
+        check_single_long_key_is_rejected::<AsciiHead>();
+    }
+
+    #[test]
+    fn long_fences_in_tree_fall_back_cleanly() {
+        crate::ensure_init();
+        // separators share a long prefix with their neighbours, producing inner nodes with near maximum fences
+        let key = |i: u32| {
+            let mut key = vec![b'x'; 992];
+            key.extend_from_slice(format!("{i:08}").as_bytes());
+            key
+        };
+        for tag in HEAD_TAGS {
+            let mut tree = crate::b_tree::BTree::new_with(BTreeNodeTag::BasicLeaf, tag);
+            for i in 0..2_000u32 {
+                tree.insert(key(i * 7 % 2_000), i.to_le_bytes()).unwrap();
+            }
+            assert!(tree.height() > 1, "{tag:?}");
+            assert_eq!(tree.verify_balanced(), Ok(()), "{tag:?}");
+            for i in 0..2_000u32 {
+                assert_eq!(tree.get(key(i * 7 % 2_000)), Some(&i.to_le_bytes()[..]), "{tag:?} {i}");
+            }
+        }
+    }
+
+    #[test]
+    fn heads_round_trip_through_restore() {
+        crate::ensure_init();
+        // boundary lengths, trailing and inner zeros, bytes at the edge of the ascii range and all ones
+        let mut candidates: Vec<Vec<u8>> = Vec::new();
+        for len in 1..=10 {
+            candidates.push(vec![b'k'; len]);
+            candidates.push([vec![b'z'; len - 1], vec![0]].concat());
+            candidates.push([vec![0; len - 1], vec![1]].concat());
+            candidates.push(vec![0x7e; len]);
+            candidates.push(vec![0x7f; len]);
+            candidates.push(vec![0xff; len]);
+        }
+        candidates.extend([b"a\0b".to_vec(), b"\0".to_vec(), b"m\x01".to_vec()]);
+        check_heads_round_trip::<ExplicitLengthHead<u64>>(&candidates);
+        check_heads_round_trip::<ExplicitLengthHead<u32>>(&candidates);
+        check_heads_round_trip::<ZeroPaddedHead<u64>>(&candidates);
+        check_heads_round_trip::<ZeroPaddedHead<u32>>(&candidates);
+        check_heads_round_trip::<AsciiHead>(&candidates);
+    }
+
+    /// interleaves `insert_child` and `remove_slot` on an unfenced node, checking every hint after each step
+    fn check_hints_after_insert_and_remove<Head: FullKeyHead>() {
+        let mut rng = Xoshiro128PlusPlus::seed_from_u64(Head::TAG as u64);
+        let mut keys: Vec<Vec<u8>> = Vec::new();
+        // children are never dereferenced, distinct dummy pointers suffice
+        let mut children: Vec<*mut BTreeNode> = vec![8 as *mut BTreeNode];
+        unsafe {
+            let node = BTreeNode::alloc();
+            let source = ManualInnerSource { lower_fence: Vec::new(), upper_fence: Vec::new(), keys: Vec::new(), children: children.clone() };
+            create_inner_exact(Head::TAG, &mut *node, &source).unwrap();
+            let head_node = &mut *(node as *mut HeadNode<Head>);
+            for step in 0..5_000usize {
+                let grow = keys.len() < 40 || rng.gen_bool(if step < 2_000 { 0.7 } else { 0.4 });
+                if grow && head_node.request_space_for_child(3).is_ok() {
+                    let key = format!("{:03}", rng.gen_range(0..1000)).into_bytes();
+                    if let Err(index) = keys.binary_search(&key) {
+                        let child = (step + 2) as *mut BTreeNode;
+                        head_node.insert_child(index, PrefixTruncatedKey(&key), child).unwrap();
+                        keys.insert(index, key);
+                        children.insert(index, child);
+                    }
+                } else if !keys.is_empty() {
+                    let index = rng.gen_range(0..keys.len());
+                    head_node.remove_slot(index);
+                    keys.remove(index);
+                    children.remove(index);
+                }
+                assert_eq!((*node).tag(), Head::TAG);
+                let (head, node_keys, node_children, hint) = head_node.as_parts();
+                assert_eq!(head.key_count as usize, keys.len());
+                assert_eq!(&node_children[..=keys.len()], &children[..]);
+                for (node_key, key) in node_keys.iter().zip(&keys) {
+                    assert!(*node_key == Head::make_fence_head(PrefixTruncatedKey(key)).unwrap());
+                }
+                if HeadNode::<Head>::hints_used(keys.len()) {
+                    let dist = keys.len() / (Head::HINT_COUNT + 1);
+                    for i in 0..Head::HINT_COUNT {
+                        assert!(hint[i] == node_keys[dist * (i + 1)], "{:?} step {step}: hint {i} is stale", Head::TAG);
+                    }
+                }
+                for (index, key) in keys.iter().enumerate() {
+                    let (lower, upper) = head_node.search_hint(Head::make_needle_head(PrefixTruncatedKey(key)));
+                    assert!(lower <= index && index < upper, "{:?} step {step}: hint range {lower}..{upper} misses {index}", Head::TAG);
+                }
+            }
+            assert!(keys.len() > Head::HINT_COUNT * 2);
+            BTreeNode::dealloc(node);
+        }
+    }
+
+    #[test]
+    fn hints_stay_consistent_under_insert_and_remove() {
+        crate::ensure_init();
+        check_hints_after_insert_and_remove::<ExplicitLengthHead<u64>>();
+        check_hints_after_insert_and_remove::<ExplicitLengthHead<u32>>();
+        check_hints_after_insert_and_remove::<ZeroPaddedHead<u64>>();
+        check_hints_after_insert_and_remove::<ZeroPaddedHead<u32>>();
+        check_hints_after_insert_and_remove::<AsciiHead>();
+    }
+}
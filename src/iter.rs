@@ -0,0 +1,321 @@
+use crate::b_tree::BTree;
+use std::cmp::Ordering;
+use std::collections::VecDeque;
+use std::iter::Peekable;
+use std::vec;
+
+/// number of entries copied out of the tree per range lookup
+const ITER_BATCH: usize = 64;
+
+/// fetches entries in batches, each batch restarts the range lookup at the last key fetched
+struct BatchCursor<'a> {
+    tree: &'a mut BTree,
+    /// inclusive start of the next batch
+    start: Vec<u8>,
+    /// start was already passed on in the previous batch
+    skip_start: bool,
+    descending: bool,
+    exhausted: bool,
+}
+
+impl<'a> BatchCursor<'a> {
+    fn new(tree: &'a mut BTree) -> Self {
+        Self::starting_at(tree, Vec::new(), false)
+    }
+
+    fn starting_at(tree: &'a mut BTree, start: Vec<u8>, descending: bool) -> Self {
+        BatchCursor { tree, start, skip_start: false, descending, exhausted: false }
+    }
+
+    /// passes up to `ITER_BATCH` entries to `f`
+    fn next_batch(&mut self, f: &mut dyn FnMut(&[u8], &[u8])) {
+        if self.exhausted {
+            return;
+        }
+        let start = &self.start;
+        let skip_start = self.skip_start;
+        let mut fetched = 0;
+        let mut new_last = None;
+        let callback = &mut |key: &[u8], payload: &[u8]| {
+            // start is inclusive, skip the key fetched last
+            if skip_start && key == &start[..] {
+                return true;
+            }
+            f(key, payload);
+            fetched += 1;
+            if fetched == ITER_BATCH {
+                new_last = Some(key.to_vec());
+                false
+            } else {
+                true
+            }
+        };
+        if self.descending {
+            self.tree.range_lookup_desc(start, callback);
+        } else {
+            self.tree.range_lookup(start, callback);
+        }
+        match new_last {
+            Some(last) => {
+                self.start = last;
+                self.skip_start = true;
+            }
+            None => self.exhausted = true,
+        }
+    }
+}
+
+/// iterator over owned key value pairs, ascending unless created by `starting_at_back`
+pub struct Iter<'a> {
+    cursor: BatchCursor<'a>,
+    batch: VecDeque<(Vec<u8>, Vec<u8>)>,
+}
+
+impl<'a> Iter<'a> {
+    pub fn new(tree: &'a mut BTree) -> Self {
+        Iter { cursor: BatchCursor::new(tree), batch: VecDeque::with_capacity(ITER_BATCH) }
+    }
+
+    /// ascending from the first key >= `start`
+    pub fn starting_at(tree: &'a mut BTree, start: &[u8]) -> Self {
+        Iter { cursor: BatchCursor::starting_at(tree, start.to_vec(), false), batch: VecDeque::with_capacity(ITER_BATCH) }
+    }
+
+    /// descending from the last key <= `start`
+    pub fn starting_at_back(tree: &'a mut BTree, start: &[u8]) -> Self {
+        Iter { cursor: BatchCursor::starting_at(tree, start.to_vec(), true), batch: VecDeque::with_capacity(ITER_BATCH) }
+    }
+}
+
+impl Iterator for Iter<'_> {
+    type Item = (Vec<u8>, Vec<u8>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.batch.is_empty() {
+            let batch = &mut self.batch;
+            self.cursor.next_batch(&mut |key, payload| batch.push_back((key.to_vec(), payload.to_vec())));
+        }
+        self.batch.pop_front()
+    }
+}
+
+/// ascending iterator over keys, payloads are not copied
+pub struct Keys<'a> {
+    cursor: BatchCursor<'a>,
+    batch: VecDeque<Vec<u8>>,
+}
+
+impl<'a> Keys<'a> {
+    pub fn new(tree: &'a mut BTree) -> Self {
+        Keys { cursor: BatchCursor::new(tree), batch: VecDeque::with_capacity(ITER_BATCH) }
+    }
+}
+
+impl Iterator for Keys<'_> {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.batch.is_empty() {
+            let batch = &mut self.batch;
+            self.cursor.next_batch(&mut |key, _| batch.push_back(key.to_vec()));
+        }
+        self.batch.pop_front()
+    }
+}
+
+/// ascending iterator over groups of consecutive entries sharing their first `prefix_len` bytes.
+/// Keys shorter than `prefix_len` form a group of their own.
+pub struct GroupByPrefix<'a> {
+    entries: Peekable<Iter<'a>>,
+    prefix_len: usize,
+}
+
+impl<'a> GroupByPrefix<'a> {
+    pub fn new(tree: &'a mut BTree, prefix_len: usize) -> Self {
+        GroupByPrefix { entries: Iter::new(tree).peekable(), prefix_len }
+    }
+}
+
+impl Iterator for GroupByPrefix<'_> {
+    /// group key and values of the group
+    type Item = (Vec<u8>, vec::IntoIter<Vec<u8>>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (mut group_key, value) = self.entries.next()?;
+        let mut values = vec![value];
+        if group_key.len() >= self.prefix_len {
+            group_key.truncate(self.prefix_len);
+            while let Some((_, value)) = self.entries.next_if(|(key, _)| key.len() >= self.prefix_len && key[..self.prefix_len] == group_key[..]) {
+                values.push(value);
+            }
+        }
+        Some((group_key, values.into_iter()))
+    }
+}
+
+/// where a key reported by `SymmetricDifference` was found
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum DiffSide {
+    Left,
+    Right,
+    /// present in both trees with different payloads
+    Changed,
+}
+
+/// ascending keys that are present in only one of two trees or have different payloads, found by merging both iterators
+pub struct SymmetricDifference<'a, 'b> {
+    left: Peekable<Iter<'a>>,
+    right: Peekable<Iter<'b>>,
+}
+
+impl<'a, 'b> SymmetricDifference<'a, 'b> {
+    pub fn new(left: &'a mut BTree, right: &'b mut BTree) -> Self {
+        SymmetricDifference { left: Iter::new(left).peekable(), right: Iter::new(right).peekable() }
+    }
+}
+
+impl Iterator for SymmetricDifference<'_, '_> {
+    type Item = (Vec<u8>, DiffSide);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let order = match (self.left.peek(), self.right.peek()) {
+                (None, None) => return None,
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (Some((l, _)), Some((r, _))) => l.cmp(r),
+            };
+            match order {
+                Ordering::Less => return Some((self.left.next()?.0, DiffSide::Left)),
+                Ordering::Greater => return Some((self.right.next()?.0, DiffSide::Right)),
+                Ordering::Equal => {
+                    let (key, left_payload) = self.left.next()?;
+                    let (_, right_payload) = self.right.next()?;
+                    if left_payload != right_payload {
+                        return Some((key, DiffSide::Changed));
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bench::mixed_length_keys;
+    use crate::vtables::BTreeNodeTag;
+    use std::collections::{BTreeMap, BTreeSet};
+
+    #[test]
+    fn keys_match_iter_and_sorted_set() {
+        crate::ensure_init();
+        let keys = mixed_length_keys(1, 40, 20_000);
+        for leaf in [BTreeNodeTag::BasicLeaf, BTreeNodeTag::HashLeaf] {
+            let mut tree = BTree::new_with(leaf, BTreeNodeTag::BasicInner);
+            assert_eq!(tree.keys().next(), None);
+            let mut expected = BTreeSet::new();
+            for (i, key) in keys.iter().enumerate() {
+                tree.insert(key, (i as u32).to_le_bytes()).unwrap();
+                expected.insert(key.clone());
+            }
+            for key in keys.iter().step_by(3) {
+                assert!(unsafe { tree.remove(key) });
+                expected.remove(key);
+            }
+            let from_keys: Vec<Vec<u8>> = tree.keys().collect();
+            let from_iter: Vec<Vec<u8>> = tree.iter().map(|(key, _)| key).collect();
+            assert_eq!(from_keys, from_iter, "{leaf:?}");
+            assert!(from_keys.iter().eq(expected.iter()), "{leaf:?}");
+        }
+    }
+
+    #[test]
+    fn group_by_prefix_matches_manual_grouping() {
+        crate::ensure_init();
+        let keys = mixed_length_keys(1, 4, 20_000);
+        let mut tree = BTree::new();
+        assert!(tree.group_by_prefix(2).next().is_none());
+        let mut expected = BTreeMap::new();
+        for (i, key) in keys.iter().enumerate() {
+            tree.insert(key, (i as u32).to_le_bytes()).unwrap();
+            expected.insert(key.clone(), (i as u32).to_le_bytes().to_vec());
+        }
+        for prefix_len in [0, 1, 2, 3] {
+            let mut manual: Vec<(Vec<u8>, Vec<Vec<u8>>)> = Vec::new();
+            for (key, value) in &expected {
+                match manual.last_mut() {
+                    Some((group, values)) if key.len() >= prefix_len && group.len() == prefix_len && key[..prefix_len] == group[..] => values.push(value.clone()),
+                    _ => manual.push((key[..prefix_len.min(key.len())].to_vec(), vec![value.clone()])),
+                }
+            }
+            let groups: Vec<(Vec<u8>, Vec<Vec<u8>>)> = tree.group_by_prefix(prefix_len).map(|(key, values)| (key, values.collect())).collect();
+            assert_eq!(groups, manual, "prefix_len {prefix_len}");
+        }
+    }
+
+    #[test]
+    fn iter_from_matches_map_range() {
+        crate::ensure_init();
+        let keys = mixed_length_keys(1, 12, 10_000);
+        for leaf in [BTreeNodeTag::BasicLeaf, BTreeNodeTag::HashLeaf] {
+            let mut tree = BTree::new_with(leaf, BTreeNodeTag::BasicInner);
+            let mut expected = BTreeMap::new();
+            for (i, key) in keys.iter().step_by(2).enumerate() {
+                tree.insert(key, (i as u32).to_le_bytes()).unwrap();
+                expected.insert(key.clone(), (i as u32).to_le_bytes().to_vec());
+            }
+            // every other key is absent, so starts fall both on and between stored keys
+            let starts = keys.iter().step_by(97).cloned().chain([Vec::new(), vec![u8::MAX; 13]]);
+            for start in starts {
+                let forward: Vec<(Vec<u8>, Vec<u8>)> = expected.range(start.clone()..).map(|(k, v)| (k.clone(), v.clone())).collect();
+                assert!(tree.iter_from(&start).eq(forward), "{leaf:?} {start:?}");
+                let backward: Vec<(Vec<u8>, Vec<u8>)> = expected.range(..=start.clone()).rev().map(|(k, v)| (k.clone(), v.clone())).collect();
+                assert!(tree.iter_from_back(&start).eq(backward), "{leaf:?} {start:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn symmetric_difference_matches_maps() {
+        crate::ensure_init();
+        let keys = mixed_length_keys(1, 12, 20_000);
+        let (mut left, mut right) = (BTree::new(), BTree::new());
+        let (mut left_map, mut right_map) = (BTreeMap::new(), BTreeMap::new());
+        assert_eq!(left.symmetric_difference(&mut right).next(), None);
+        for (i, key) in keys.iter().enumerate() {
+            // a third only left, a third only right, the rest in both with every fourth of those changed
+            let value = (i as u32).to_le_bytes().to_vec();
+            if i % 3 != 1 {
+                left.insert(key, &value).unwrap();
+                left_map.insert(key.clone(), value.clone());
+            }
+            if i % 3 != 0 {
+                let value = if i % 12 == 2 { vec![0xff] } else { value };
+                right.insert(key, &value).unwrap();
+                right_map.insert(key.clone(), value);
+            }
+        }
+        let mut expected: Vec<(Vec<u8>, DiffSide)> = Vec::new();
+        for (key, value) in &left_map {
+            match right_map.get(key) {
+                None => expected.push((key.clone(), DiffSide::Left)),
+                Some(other) if other != value => expected.push((key.clone(), DiffSide::Changed)),
+                Some(_) => {}
+            }
+        }
+        expected.extend(right_map.keys().filter(|k| !left_map.contains_key(*k)).map(|k| (k.clone(), DiffSide::Right)));
+        expected.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(left.symmetric_difference(&mut right).collect::<Vec<_>>(), expected);
+        let mirrored: Vec<(Vec<u8>, DiffSide)> = expected
+            .iter()
+            .map(|(k, side)| (k.clone(), match side { DiffSide::Left => DiffSide::Right, DiffSide::Right => DiffSide::Left, DiffSide::Changed => DiffSide::Changed }))
+            .collect();
+        assert_eq!(right.symmetric_difference(&mut left).collect::<Vec<_>>(), mirrored);
+        let mut left_copy = BTree::new();
+        for (key, value) in &left_map {
+            left_copy.insert(key, value).unwrap();
+        }
+        assert_eq!(left.symmetric_difference(&mut left_copy).next(), None);
+    }
+}
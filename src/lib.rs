@@ -4,6 +4,7 @@
 #![feature(ptr_metadata)]
 #![feature(maybe_uninit_uninit_array)]
 #![feature(maybe_uninit_slice)]
+#![feature(maybe_uninit_write_slice)]
 #![feature(is_sorted)]
 extern crate core;
 
@@ -11,15 +12,17 @@ use crate::btree_node::{BTreeNode, PAGE_SIZE};
 use crate::vtables::init_vtables;
 use b_tree::BTree;
 use std::ops::Deref;
-use std::slice;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::ptr;
 use std::sync::Once;
-use crate::node_stats::print_stats;
+use crate::api::ApiError;
+use crate::node_stats::{print_stats, AdaptStats};
 
 
+pub mod api;
 pub mod b_tree;
 pub mod basic_node;
 pub mod btree_node;
+pub mod capacity;
 mod find_separator;
 #[cfg(feature = "hash-variant_head")]
 pub mod hash_leaf;
@@ -27,22 +30,27 @@ pub mod hash_leaf;
 #[path = "alloc_hash.rs"]
 pub mod hash_leaf;
 pub mod head_node;
+pub mod iter;
 pub mod node_traits;
 pub mod op_count;
 pub mod util;
-mod vtables;
+pub mod value_arena;
+pub mod vtables;
 pub mod node_stats;
 pub mod art_node;
 pub mod adaptive;
 pub mod branch_cache;
 pub mod bench;
-
-static MEASUREMENT_COMPLETE: AtomicBool = AtomicBool::new(false);
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util;
 
 pub fn ensure_init() {
     static INIT: Once = Once::new();
     INIT.call_once(|| {
         init_vtables();
+        if std::env::var_os("NO_SIMD_FIND").is_some() {
+            btree_node::set_simd_find(false);
+        }
     });
 }
 
@@ -52,6 +60,7 @@ pub extern "C" fn btree_new() -> *mut BTree {
     Box::leak(Box::new(BTree::new()))
 }
 
+/// returns an `api::status` code, entries larger than `api::MAX_ENTRY_SIZE` are rejected
 #[no_mangle]
 pub unsafe extern "C" fn btree_insert(
     b_tree: *mut BTree,
@@ -59,14 +68,15 @@ pub unsafe extern "C" fn btree_insert(
     key_len: u64,
     payload: *const u8,
     payload_len: u64,
-) {
-    BTree::insert(
-        &mut *b_tree,
-        slice::from_raw_parts(key, key_len as usize),
-        slice::from_raw_parts(payload, payload_len as usize),
-    )
+) -> u8 {
+    api::status((|| api::insert(
+        api::raw_tree(b_tree)?,
+        api::raw_slice(key, key_len)?,
+        api::raw_slice(payload, payload_len)?,
+    ))())
 }
 
+/// returns null if the key is not present or an argument is invalid
 #[no_mangle]
 pub unsafe extern "C" fn btree_lookup(
     b_tree: *mut BTree,
@@ -74,30 +84,85 @@ pub unsafe extern "C" fn btree_lookup(
     key_len: u64,
     payload_len_out: *mut u64,
 ) -> *mut u8 {
-    let key = slice::from_raw_parts(key, key_len as usize);
-    let b_tree = &mut *b_tree;
-    b_tree.lookup(payload_len_out, key)
+    if payload_len_out.is_null() {
+        return ptr::null_mut();
+    }
+    match (|| api::lookup(api::raw_tree(b_tree)?, api::raw_slice(key, key_len)?))() {
+        Ok(Some(payload)) => {
+            *payload_len_out = payload.len() as u64;
+            payload.as_mut_ptr()
+        }
+        Ok(None) | Err(_) => ptr::null_mut(),
+    }
 }
 
+/// returns false if the key was not present or an argument is invalid
 #[no_mangle]
 pub unsafe extern "C" fn btree_remove(b_tree: *mut BTree, key: *const u8, key_len: u64) -> bool {
-    let key = slice::from_raw_parts(key, key_len as usize);
-    let b_tree = &mut *b_tree;
-    b_tree.remove(key)
+    (|| api::remove(api::raw_tree(b_tree)?, api::raw_slice(key, key_len)?))().unwrap_or(false)
 }
 
+/// does nothing if the tree is null
 #[no_mangle]
 pub unsafe extern "C" fn btree_destroy(b_tree: *mut BTree) {
-    assert!(MEASUREMENT_COMPLETE.load(Ordering::Relaxed), "B-Tree destructor not implemented");
-    // incomplete, leaks memory
+    if api::raw_tree(b_tree).is_err() {
+        return;
+    }
     drop(Box::<BTree>::from_raw(b_tree));
 }
 
+/// does nothing if the tree is null
 #[no_mangle]
 pub unsafe extern "C" fn btree_print_info(b_tree: *mut BTree) {
+    let Ok(tree) = api::raw_tree(b_tree) else {
+        return;
+    };
     if cfg!( debug_assertions ) {
-        print_stats(&*b_tree);
+        print_stats(tree);
+    }
+}
+
+/// number of entries in the tree
+#[no_mangle]
+pub unsafe extern "C" fn btree_len(b_tree: *mut BTree) -> u64 {
+    api::raw_tree(b_tree).map_or(0, |tree| tree.len() as u64)
+}
+
+/// returns the `BTreeNodeTag` of the root node, or `api::INVALID_TAG` if the tree is null
+#[no_mangle]
+pub unsafe extern "C" fn btree_root_tag(b_tree: *mut BTree) -> u8 {
+    api::raw_tree(b_tree).map_or(api::INVALID_TAG, |tree| tree.root_tag().into())
+}
+
+/// writes the number of nodes of each type to `out_counts[tag]` for the first `len` tags, zeroing entries beyond the last tag.
+/// Returns the number of tags, so callers can size the array.
+/// Returns 0 without writing anything if the tree is null or `out_counts` is null and `len` is not.
+#[no_mangle]
+pub unsafe extern "C" fn btree_node_tag_counts(b_tree: *mut BTree, out_counts: *mut u64, len: u64) -> u64 {
+    let Ok(tree) = api::raw_tree(b_tree) else {
+        return 0;
+    };
+    if out_counts.is_null() && len != 0 {
+        return 0;
+    }
+    let counts = node_stats::node_tag_counts(tree);
+    for i in 0..len as usize {
+        *out_counts.add(i) = counts.get(i).copied().unwrap_or(0);
     }
+    counts.len() as u64
+}
+
+/// writes conversion counters and the current node type breakdown to `out`, see `node_stats::AdaptStats`.
+/// Returns an `api::status` code.
+#[no_mangle]
+pub unsafe extern "C" fn btree_adaptation_stats(b_tree: *mut BTree, out: *mut AdaptStats) -> u8 {
+    api::status((|| {
+        if out.is_null() {
+            return Err(ApiError::NullPointer);
+        }
+        out.write(node_stats::adaption_stats(api::raw_tree(b_tree)?));
+        Ok(())
+    })())
 }
 
 #[no_mangle]
@@ -105,22 +170,89 @@ pub unsafe extern "C" fn print_tpcc_result(time: f64, tx_count: u64, warehouses:
     bench::print_tpcc_result(time, tx_count, warehouses)
 }
 
+/// returns an `api::status` code
 #[no_mangle]
-pub unsafe extern "C" fn btree_scan_asc(b_tree: *mut BTree, key: *const u8, key_len: u64, key_buffer: *mut u8, continue_callback: extern "C" fn(*const u8) -> bool) {
-    let b_tree = &mut *b_tree;
-    b_tree.range_lookup(std::slice::from_raw_parts(key, key_len as usize), key_buffer, &mut |_key_len, payload| {
-        continue_callback(payload.as_ptr())
-    })
+pub unsafe extern "C" fn btree_scan_asc(b_tree: *mut BTree, key: *const u8, key_len: u64, key_buffer: *mut u8, continue_callback: extern "C" fn(*const u8) -> bool) -> u8 {
+    api::status((|| {
+        if key_buffer.is_null() {
+            return Err(ApiError::NullPointer);
+        }
+        api::scan_asc(api::raw_tree(b_tree)?, api::raw_slice(key, key_len)?, &mut |key, payload| {
+            ptr::copy_nonoverlapping(key.as_ptr(), key_buffer, key.len());
+            continue_callback(payload.as_ptr())
+        })
+    })())
 }
 
+/// like `btree_scan_asc`, but stops before the first key past `end`
 #[no_mangle]
-pub unsafe extern "C" fn btree_scan_desc(b_tree: *mut BTree, key: *const u8, key_len: u64, key_buffer: *mut u8, continue_callback: extern "C" fn(*const u8) -> bool) {
-    let b_tree = &mut *b_tree;
-    b_tree.range_lookup_desc(std::slice::from_raw_parts(key, key_len as usize), key_buffer, &mut |_key_len, payload| {
-        continue_callback(payload.as_ptr())
-    })
+pub unsafe extern "C" fn btree_scan_range(
+    b_tree: *mut BTree,
+    start: *const u8,
+    start_len: u64,
+    end: *const u8,
+    end_len: u64,
+    end_inclusive: bool,
+    key_buffer: *mut u8,
+    continue_callback: extern "C" fn(*const u8) -> bool,
+) -> u8 {
+    api::status((|| {
+        if key_buffer.is_null() {
+            return Err(ApiError::NullPointer);
+        }
+        api::scan_range(
+            api::raw_tree(b_tree)?,
+            api::raw_slice(start, start_len)?,
+            api::raw_slice(end, end_len)?,
+            end_inclusive,
+            &mut |key, payload| {
+                ptr::copy_nonoverlapping(key.as_ptr(), key_buffer, key.len());
+                continue_callback(payload.as_ptr())
+            },
+        )
+    })())
 }
 
+/// returns an `api::status` code
+#[no_mangle]
+pub unsafe extern "C" fn btree_scan_desc(b_tree: *mut BTree, key: *const u8, key_len: u64, key_buffer: *mut u8, continue_callback: extern "C" fn(*const u8) -> bool) -> u8 {
+    api::status((|| {
+        if key_buffer.is_null() {
+            return Err(ApiError::NullPointer);
+        }
+        api::scan_desc(api::raw_tree(b_tree)?, api::raw_slice(key, key_len)?, &mut |key, payload| {
+            ptr::copy_nonoverlapping(key.as_ptr(), key_buffer, key.len());
+            continue_callback(payload.as_ptr())
+        })
+    })())
+}
+
+/// returns an `api::status` code.
+/// `*next_key_len` is set to zero once the scan is complete, the next key is never empty as it follows a written record.
+#[no_mangle]
+pub unsafe extern "C" fn btree_scan_chunk(
+    b_tree: *mut BTree,
+    key: *const u8,
+    key_len: u64,
+    out_buffer: *mut u8,
+    buffer_cap: u64,
+    out_count: *mut u64,
+    next_key_buffer: *mut u8,
+    next_key_len: *mut u64,
+) -> u8 {
+    api::status((|| {
+        if out_buffer.is_null() || out_count.is_null() || next_key_buffer.is_null() || next_key_len.is_null() {
+            return Err(ApiError::NullPointer);
+        }
+        let out = std::slice::from_raw_parts_mut(out_buffer, buffer_cap as usize);
+        let chunk = api::scan_chunk(api::raw_tree(b_tree)?, api::raw_slice(key, key_len)?, out)?;
+        *out_count = chunk.count as u64;
+        let next = chunk.next.unwrap_or_default();
+        ptr::copy_nonoverlapping(next.as_ptr(), next_key_buffer, next.len());
+        *next_key_len = next.len() as u64;
+        Ok(())
+    })())
+}
 
 #[derive(Ord, PartialOrd, Eq, PartialEq, Copy, Clone, Debug)]
 pub struct PrefixTruncatedKey<'a>(pub &'a [u8]);
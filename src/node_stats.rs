@@ -1,6 +1,11 @@
 use counter::Counter;
-use crate::{BTree, BTreeNode};
-use crate::vtables::BTreeNodeTag;
+use crate::{BTree, BTreeNode, PAGE_SIZE};
+use crate::util::KeyBuffer;
+use crate::vtables::{BTreeNodeTag, NODE_TAG_COUNT};
+use crate::node_traits::InnerConversionSource;
+use crate::btree_node::MAX_INNER_FALLBACK_ORDER;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
 
 pub struct InnerNodeData {
     pub depth: usize,
@@ -49,6 +54,66 @@ pub fn btree_to_inner_node_stats(b_tree: &BTree) -> Vec<InnerNodeData> {
     ret
 }
 
+/// key bytes of all leaves and the part of them not stored due to prefix truncation
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PrefixSavings {
+    pub saved_bytes: usize,
+    pub key_bytes: usize,
+}
+
+impl PrefixSavings {
+    pub fn ratio(&self) -> f64 {
+        self.saved_bytes as f64 / self.key_bytes as f64
+    }
+}
+
+pub fn prefix_savings(b_tree: &BTree) -> PrefixSavings {
+    fn visit(node: &BTreeNode, out: &mut PrefixSavings) {
+        let (prefix_len, key_count, stored_bytes) = unsafe {
+            match node.tag() {
+                BTreeNodeTag::BasicLeaf => (
+                    node.basic.fences().prefix_len,
+                    node.basic.slots().len(),
+                    node.basic.slots().iter().map(|s| s.key_len as usize).sum::<usize>(),
+                ),
+                BTreeNodeTag::HashLeaf | BTreeNodeTag::HybridLeaf => (
+                    node.hash_leaf.fences().prefix_len,
+                    node.hash_leaf.slots().len(),
+                    node.hash_leaf.slots().iter().map(|s| s.key_len as usize).sum::<usize>(),
+                ),
+                _ => {
+                    let node = node.to_inner();
+                    for i in 0..node.key_count() + 1 {
+                        visit(&*node.get_child(i), out);
+                    }
+                    return;
+                }
+            }
+        };
+        out.saved_bytes += prefix_len * key_count;
+        out.key_bytes += prefix_len * key_count + stored_bytes;
+    }
+    let mut savings = PrefixSavings::default();
+    visit(unsafe { &*b_tree.root }, &mut savings);
+    savings
+}
+
+/// number of nodes of each type, leaves included, indexed by tag
+pub fn node_tag_counts(b_tree: &BTree) -> [u64; NODE_TAG_COUNT] {
+    fn visit(node: &BTreeNode, out: &mut [u64; NODE_TAG_COUNT]) {
+        out[node.tag() as usize] += 1;
+        if node.tag().is_inner() {
+            let node = node.to_inner();
+            for i in 0..node.key_count() + 1 {
+                visit(unsafe { &*node.get_child(i) }, out);
+            }
+        }
+    }
+    let mut counts = [0; NODE_TAG_COUNT];
+    visit(unsafe { &*b_tree.root }, &mut counts);
+    counts
+}
+
 pub fn print_stats(b_tree: &BTree) {
     let nodes = btree_to_inner_node_stats(b_tree);
     let tag_counts: counter::Counter<_> = nodes.iter().map(|n| n.tag).collect();
@@ -65,4 +130,220 @@ pub fn print_stats(b_tree: &BTree) {
         eprintln!("\t{:3}: {:5.2}%", l, c as f64 / total_inner_keys as f64 * 100.0)
     };
     eprintln!("node count: {}", total_node_count(&nodes));
-}
\ No newline at end of file
+}
+/// contents of a leaf page, keys are stored without the node prefix
+#[derive(Debug)]
+pub struct LeafPageData {
+    pub tag: BTreeNodeTag,
+    pub prefix_len: usize,
+    pub fences: [Vec<u8>; 2],
+    pub keys: Vec<Vec<u8>>,
+    pub payloads: Vec<Vec<u8>>,
+}
+
+/// decodes a page returned by `BTree::dump_leaf_for`
+pub fn decode_leaf_page(page: &[u8; PAGE_SIZE]) -> LeafPageData {
+    let mut node = unsafe { BTreeNode::new_uninit() };
+    node.raw_bytes = *page;
+    let tag = node.tag();
+    let fences = match tag {
+        BTreeNodeTag::BasicLeaf => unsafe { node.basic.fences() },
+        BTreeNodeTag::HashLeaf | BTreeNodeTag::HybridLeaf => unsafe { node.hash_leaf.fences() },
+        _ => panic!("not a leaf page: {:?}", tag),
+    };
+    let mut data = LeafPageData {
+        tag,
+        prefix_len: fences.prefix_len,
+        fences: [fences.lower_fence.0.to_vec(), fences.upper_fence.0.to_vec()],
+        keys: vec![],
+        payloads: vec![],
+    };
+    // the prefix is not needed to find the first key, so a dummy prefix is passed and stripped again
+    let start = vec![0u8; data.prefix_len];
+    node.to_leaf_mut().range_lookup(&start, &mut KeyBuffer::new(), &mut |key, payload| {
+        data.keys.push(key[data.prefix_len..].to_vec());
+        data.payloads.push(payload.to_vec());
+        true
+    });
+    data
+}
+
+/// writes the contents of a page returned by `BTree::dump_leaf_for` to `out`, one entry per line
+pub fn print_leaf_page(page: &[u8; PAGE_SIZE], out: &mut impl Write) -> io::Result<()> {
+    let data = decode_leaf_page(page);
+    writeln!(out, "{:?} prefix_len: {}", data.tag, data.prefix_len)?;
+    writeln!(out, "lower fence: {:?}", bstr::BStr::new(&data.fences[0]))?;
+    writeln!(out, "upper fence: {:?}", bstr::BStr::new(&data.fences[1]))?;
+    for (i, (k, p)) in data.keys.iter().zip(data.payloads.iter()).enumerate() {
+        writeln!(out, "{:4}|{:?}|{:?}", i, bstr::BStr::new(k), bstr::BStr::new(p))?;
+    }
+    Ok(())
+}
+
+/// outcomes of `request_space` for one node type
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RequestSpaceStats {
+    /// enough contiguous free space was available
+    pub fit: u64,
+    /// the request only succeeded after compaction
+    pub compacted: u64,
+    /// not enough space even after compaction
+    pub failed: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FragmentationStats {
+    pub basic_leaf: RequestSpaceStats,
+    pub basic_inner: RequestSpaceStats,
+    pub hash_leaf: RequestSpaceStats,
+}
+
+pub struct RequestSpaceCounters {
+    fit: AtomicU64,
+    compacted: AtomicU64,
+    failed: AtomicU64,
+}
+
+impl RequestSpaceCounters {
+    const fn new() -> Self {
+        RequestSpaceCounters { fit: AtomicU64::new(0), compacted: AtomicU64::new(0), failed: AtomicU64::new(0) }
+    }
+
+    pub fn count_fit(&self) {
+        self.fit.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn count_compacted(&self) {
+        self.compacted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn count_failed(&self) {
+        self.failed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn load(&self) -> RequestSpaceStats {
+        RequestSpaceStats {
+            fit: self.fit.load(Ordering::Relaxed),
+            compacted: self.compacted.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+        }
+    }
+
+    fn reset(&self) {
+        self.fit.store(0, Ordering::Relaxed);
+        self.compacted.store(0, Ordering::Relaxed);
+        self.failed.store(0, Ordering::Relaxed);
+    }
+}
+
+pub static BASIC_LEAF_REQUEST_SPACE: RequestSpaceCounters = RequestSpaceCounters::new();
+pub static BASIC_INNER_REQUEST_SPACE: RequestSpaceCounters = RequestSpaceCounters::new();
+pub static HASH_LEAF_REQUEST_SPACE: RequestSpaceCounters = RequestSpaceCounters::new();
+
+/// counters are shared by all trees of the process
+pub fn fragmentation_stats() -> FragmentationStats {
+    FragmentationStats {
+        basic_leaf: BASIC_LEAF_REQUEST_SPACE.load(),
+        basic_inner: BASIC_INNER_REQUEST_SPACE.load(),
+        hash_leaf: HASH_LEAF_REQUEST_SPACE.load(),
+    }
+}
+
+pub fn reset_fragmentation_stats() {
+    BASIC_LEAF_REQUEST_SPACE.reset();
+    BASIC_INNER_REQUEST_SPACE.reset();
+    HASH_LEAF_REQUEST_SPACE.reset();
+}
+
+/// leaf conversions done by adaption, counted in `BTreeNode::leave_apply_adaption`
+pub static LEAF_TO_HASH: AtomicU64 = AtomicU64::new(0);
+pub static LEAF_TO_BASIC: AtomicU64 = AtomicU64::new(0);
+/// hash leaves that should have become basic leaves but did not fit
+pub static LEAF_TO_BASIC_FAILED: AtomicU64 = AtomicU64::new(0);
+/// inner nodes replaced by `adapt_inner`
+pub static INNER_ADAPTIONS: AtomicU64 = AtomicU64::new(0);
+
+/// inner nodes created by `RuntimeOrderInnerConversionSink`, indexed by position of the used tag in the fallback order
+pub static INNER_FALLBACK_CREATED: [AtomicU64; MAX_INNER_FALLBACK_ORDER] = {
+    const ZERO: AtomicU64 = AtomicU64::new(0);
+    [ZERO; MAX_INNER_FALLBACK_ORDER]
+};
+
+pub fn reset_inner_fallback_created() {
+    for c in &INNER_FALLBACK_CREATED {
+        c.store(0, Ordering::Relaxed);
+    }
+}
+
+/// adaption behaviour for external benchmark harnesses, layout is part of the C interface.
+/// Conversion counters are shared by all trees of the process, node counts are for a single tree.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AdaptStats {
+    pub basic_to_hash: u64,
+    pub hash_to_basic: u64,
+    pub hash_to_basic_failed: u64,
+    pub inner_adaptions: u64,
+    pub basic_leaves: u64,
+    pub hash_leaves: u64,
+    pub hybrid_leaves: u64,
+    pub inner_nodes: u64,
+}
+
+pub fn adaption_stats(b_tree: &BTree) -> AdaptStats {
+    let counts = node_tag_counts(b_tree);
+    AdaptStats {
+        basic_to_hash: LEAF_TO_HASH.load(Ordering::Relaxed),
+        hash_to_basic: LEAF_TO_BASIC.load(Ordering::Relaxed),
+        hash_to_basic_failed: LEAF_TO_BASIC_FAILED.load(Ordering::Relaxed),
+        inner_adaptions: INNER_ADAPTIONS.load(Ordering::Relaxed),
+        basic_leaves: counts[BTreeNodeTag::BasicLeaf as usize],
+        hash_leaves: counts[BTreeNodeTag::HashLeaf as usize],
+        hybrid_leaves: counts[BTreeNodeTag::HybridLeaf as usize],
+        inner_nodes: counts.iter().skip(1).step_by(2).sum(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::btree_node::BASIC_PREFIX;
+    use crate::test_util::{ManualInnerSource, ManualLeafSource};
+
+    #[test]
+    fn prefix_savings_of_shared_prefix() {
+        crate::ensure_init();
+        for tag in [BTreeNodeTag::BasicLeaf, BTreeNodeTag::HashLeaf] {
+            let entries = (0..10).map(|i| (format!("user/k{i:02}").into_bytes(), vec![i])).collect();
+            let middle = ManualLeafSource { lower_fence: b"user/a".to_vec(), upper_fence: b"user/z".to_vec(), entries }.build(tag).unwrap();
+            // the empty outer leaves have an empty fence, so no prefix
+            let mut tree = BTree::new();
+            let root = ManualInnerSource {
+                lower_fence: Vec::new(),
+                upper_fence: Vec::new(),
+                keys: vec![b"user/a".to_vec(), b"user/z".to_vec()],
+                children: vec![tree.root, middle, BTreeNode::new_leaf()],
+            };
+            tree.root = root.build(BTreeNodeTag::BasicInner).unwrap();
+            // ten 8 byte keys sharing the 5 byte prefix "user/"
+            let saved_bytes = if BASIC_PREFIX { 10 * 5 } else { 0 };
+            assert_eq!(prefix_savings(&tree), PrefixSavings { saved_bytes, key_bytes: 10 * 8 }, "{tag:?}");
+        }
+    }
+
+    #[test]
+    fn leaf_page_prints_one_entry_per_line() {
+        crate::ensure_init();
+        let mut tree = BTree::new_with(BTreeNodeTag::BasicLeaf, BTreeNodeTag::BasicInner);
+        tree.insert(b"key/0", b"a").unwrap();
+        tree.insert(b"key/1", b"b").unwrap();
+        let mut out = Vec::new();
+        print_leaf_page(&tree.dump_leaf_for(b"key/0"), &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines[..3], ["BasicLeaf prefix_len: 0", "lower fence: \"\"", "upper fence: \"\""]);
+        // payloads are arena references with the value arena enabled
+        assert_eq!(lines.len(), 5);
+        assert!(lines[3].starts_with("   0|\"key/0\"|") && lines[4].starts_with("   1|\"key/1\"|"), "{out}");
+    }
+}
@@ -1,26 +1,129 @@
-use crate::util::{common_prefix_len, get_key_from_slice, MergeFences, partial_restore, reinterpret, SplitFences};
+use crate::util::{common_prefix_len, get_key_from_slice, KeyBuffer, MergeFences, partial_restore, reinterpret, SplitFences};
 use crate::{BTreeNode, FatTruncatedKey, PrefixTruncatedKey};
 
 use std::ops::{Deref, Range};
 
 use std::ptr;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use crate::branch_cache::BranchCacheAccessor;
+use crate::b_tree::borrow_from_sibling;
 use crate::btree_node::{BASIC_PREFIX, STRIP_PREFIX};
+use crate::vtables::BTreeNodeTag;
+use num_enum::TryFromPrimitive;
 
 
+/// reason a node operation failed
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum NodeOpError {
+    /// not enough free space, even after compaction
+    NoSpace,
+    /// the node type can not encode a key, e.g. a key longer than the heads of a head node
+    Unrepresentable,
+    /// the parent has no space for the separator and must be split first
+    NeedsSplit,
+    /// the operation does not apply in the current state, e.g. merging a child without siblings
+    NotApplicable,
+    /// a node with fewer than two keys needs a split, which could not make either half smaller
+    DegenerateSplit,
+    /// key and payload together are longer than `MAX_KEY_LEN`, so no node can hold the entry
+    TooLarge,
+}
+
+/// inner nodes reject further children once they hold this many keys, `usize::MAX` if only limited by space
+static INNER_KEY_CAP: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+/// caps the key count of inner nodes below what fits in a page, forcing earlier splits and taller trees.
+/// The cap is only checked when inserting children, merges may still produce larger nodes.
+pub fn set_inner_key_cap(cap: Option<usize>) {
+    // a split must leave at least one key in both halves
+    assert!(cap.map_or(true, |c| c >= 3));
+    INNER_KEY_CAP.store(cap.unwrap_or(usize::MAX), Ordering::Relaxed);
+}
+
+pub fn inner_key_cap_reached(key_count: usize) -> bool {
+    key_count >= INNER_KEY_CAP.load(Ordering::Relaxed)
+}
+
+/// child index, left and right node for merging the underfull child at `child_index` with its right neighbor, or its left one if it is the last child.
+/// Fails if there is no neighbor or it is not underfull too.
+/// The child pointers are compared before any reference is created, a broken node holding the same child twice would otherwise alias.
+pub unsafe fn merge_candidates<'a>(node: &(impl InnerConversionSource + ?Sized), mut child_index: usize) -> Result<(usize, &'a mut BTreeNode, &'a mut BTreeNode), NodeOpError> {
+    let is_last = child_index == node.key_count();
+    if is_last {
+        if child_index == 0 {
+            // only one child
+            return Err(NodeOpError::NotApplicable);
+        }
+        child_index -= 1;
+    }
+    let (left, right) = (node.get_child(child_index), node.get_child(child_index + 1));
+    debug_assert!(left != right, "node merged with itself");
+    if left == right {
+        return Err(NodeOpError::NotApplicable);
+    }
+    let (left, right) = (&mut *left, &mut *right);
+    if !(if is_last { &*left } else { &*right }).is_underfull() {
+        return Err(NodeOpError::NotApplicable);
+    }
+    Ok((child_index, left, right))
+}
+
+/// how `InnerNode::merge_children_check` refilled an underfull child
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Rebalance {
+    /// its neighbor moved entries or children over, both remain
+    Borrowed,
+    /// it was merged with its neighbor
+    Merged,
+}
+
 pub trait InnerNode: InnerConversionSource + Node {
-    fn merge_children_check(&mut self, child_index: usize) -> Result<(), ()>;
+    /// merges the underfull child at `child_index` with its neighbor, see `merge_candidates`
+    fn merge_children(&mut self, child_index: usize) -> Result<(), NodeOpError>;
+
+    /// refills the underfull child at `child_index` from its neighbor, or merges both if the neighbor has nothing to spare.
+    /// `key_in_node` restores the prefix and may be any key within the fences of this node.
+    fn merge_children_check(&mut self, child_index: usize, key_in_node: &[u8]) -> Result<Rebalance, NodeOpError> {
+        // the node is rebuilt with the same type, so self stays valid
+        if unsafe { borrow_from_sibling((self as *mut Self).cast::<BTreeNode>(), key_in_node, child_index) }.is_ok() {
+            return Ok(Rebalance::Borrowed);
+        }
+        self.merge_children(child_index).map(|()| Rebalance::Merged)
+    }
+
+    /// applies `merge_children` to each underfull child within `children` in a single pass.
+    /// returns the number of merges, the run then spans that many fewer children
+    fn merge_children_run(&mut self, children: Range<usize>) -> usize {
+        debug_assert!(children.end <= self.key_count() + 1);
+        let mut end = children.end;
+        let mut index = children.start;
+        let mut merged = 0;
+        while index + 1 < end {
+            // merged child stays at index and may absorb its new right neighbor
+            let underfull = unsafe { (*self.get_child(index)).is_underfull() };
+            if underfull && self.merge_children(index).is_ok() {
+                merged += 1;
+                end -= 1;
+            } else {
+                index += 1;
+            }
+        }
+        merged
+    }
 
     /// key must be truncated to length returned from request_space
     /// node takes ownership of child on
     /// space must be checked before with `request_space_for_child`
-    unsafe fn insert_child(&mut self, index: usize, key: PrefixTruncatedKey, child: *mut BTreeNode) -> Result<(), ()>;
+    unsafe fn insert_child(&mut self, index: usize, key: PrefixTruncatedKey, child: *mut BTreeNode) -> Result<(), NodeOpError>;
 
     /// on success returns prefix length of node
     /// insert should be called with a string truncated to that length
-    fn request_space_for_child(&mut self, key_length: usize) -> Result<usize, ()>;
+    fn request_space_for_child(&mut self, key_length: usize) -> Result<usize, NodeOpError>;
 
     fn find_child_index(&mut self, key: &[u8], branch_cache: &mut BranchCacheAccessor) -> usize;
+
+    /// like `find_child_index`, but without branch cache or node adjustments, so it works through a shared reference
+    fn lookup_child_index(&self, key: &[u8]) -> usize;
 }
 
 pub trait SeparableInnerConversionSource: InnerConversionSource {
@@ -42,15 +145,29 @@ pub unsafe trait Node: 'static {
         parent: &mut dyn InnerNode,
         index_in_parent: usize,
         key_in_node: &[u8],
-    ) -> Result<(), ()>;
+    ) -> Result<(), NodeOpError>;
 }
 
 pub unsafe trait LeafNode: Node {
-    fn insert(&mut self, key: &[u8], payload: &[u8]) -> Result<(), ()>;
-    fn lookup(&mut self, key: &[u8]) -> Option<&mut [u8]>;
+    /// returns true if a new slot was created, false if an existing entry was updated
+    fn insert(&mut self, key: &[u8], payload: &[u8]) -> Result<bool, NodeOpError>;
+    fn lookup(&self, key: &[u8]) -> Option<&[u8]> {
+        self.lookup_slot(key).map(|index| self.value_at(index))
+    }
+    /// value of key for in place modification, the length can not be changed
+    fn lookup_mut(&mut self, key: &[u8]) -> Option<&mut [u8]>;
     fn remove(&mut self, key: &[u8]) -> Option<()>;
-    unsafe fn range_lookup(&mut self, start: &[u8], key_out: *mut u8, callback: &mut dyn FnMut(usize, &[u8]) -> bool) -> bool;
-    unsafe fn range_lookup_desc(&mut self, start: &[u8], key_out: *mut u8, callback: &mut dyn FnMut(usize, &[u8]) -> bool) -> bool;
+    /// index of the slot holding key, only valid until the node is modified
+    fn lookup_slot(&self, key: &[u8]) -> Option<usize>;
+    fn value_at(&self, index: usize) -> &[u8];
+    /// overwrites the value of a slot in place, fails if value is longer than the current one
+    fn set_value_at(&mut self, index: usize, value: &[u8]) -> Result<(), NodeOpError>;
+    /// moves all free space into one contiguous region
+    fn compactify(&mut self);
+    /// returns false if `callback` stopped the scan, the caller must not continue in the next leaf
+    /// full keys are reconstructed in `key_out` from the prefix of `start`
+    fn range_lookup(&mut self, start: &[u8], key_out: &mut KeyBuffer, callback: &mut dyn FnMut(&[u8], &[u8]) -> bool) -> bool;
+    fn range_lookup_desc(&mut self, start: &[u8], key_out: &mut KeyBuffer, callback: &mut dyn FnMut(&[u8], &[u8]) -> bool) -> bool;
 }
 
 pub trait InnerConversionSource {
@@ -60,7 +177,11 @@ pub trait InnerConversionSource {
 
     /// key will be written to end of dst
     /// returns length of stripped key
-    fn get_key(&self, index: usize, dst: &mut [u8], strip_prefix: usize) -> Result<usize, ()>;
+    fn get_key(&self, index: usize, dst: &mut [u8], strip_prefix: usize) -> Result<usize, NodeOpError>;
+    /// key without copying, for sources that store it contiguously
+    fn get_key_ref(&self, _index: usize) -> Option<PrefixTruncatedKey> {
+        None
+    }
     fn get_key_length_sum(&self, range: Range<usize>) -> usize;
     fn get_key_length_max(&self, range: Range<usize>) -> usize;
 }
@@ -111,6 +232,19 @@ impl<'a> FenceRef<'a> {
     }
 }
 
+/// checks that all children are non-null, aligned and have a valid tag
+pub fn debug_assert_children_valid(node: &(impl InnerConversionSource + ?Sized)) {
+    if cfg!(debug_assertions) {
+        for i in 0..node.key_count() + 1 {
+            let child = node.get_child(i);
+            assert!(!child.is_null(), "child {i} is null");
+            assert!(child.is_aligned(), "child {i} is misaligned: {child:?}");
+            let tag = unsafe { (*child).raw_bytes[0] };
+            assert!(BTreeNodeTag::try_from_primitive(tag).is_ok(), "child {i} has invalid tag {tag}");
+        }
+    }
+}
+
 pub unsafe fn node_print(node: *const BTreeNode) {
     (&*node).print()
 }
@@ -143,6 +277,20 @@ impl FenceData<'_> {
         }
     }
 
+    /// `key_in_node` may be any key within the node, it is used to restore a stripped prefix
+    pub fn contains(&self, key: &[u8], key_in_node: &[u8]) -> bool {
+        if key.len() < self.prefix_len || key[..self.prefix_len] != key_in_node[..self.prefix_len] {
+            return false;
+        }
+        if STRIP_PREFIX {
+            self.lower_fence.0 < &key[self.prefix_len..]
+                && (&key[self.prefix_len..] <= self.upper_fence.0 || self.upper_fence.0.is_empty() && self.prefix_len == 0)
+        } else {
+            self.lower_fence.0 < key
+                && (key <= self.upper_fence.0 || self.upper_fence.0.is_empty() && self.prefix_len == 0)
+        }
+    }
+
     pub fn debug_assert_contains(&self, key: &[u8]) {
         if cfg!(debug_assertions) {
             if STRIP_PREFIX {
@@ -159,7 +307,18 @@ impl FenceData<'_> {
 pub unsafe trait InnerConversionSink {
     /// on error, state of dst is unspecified
     /// on success, dst must be initialized
-    fn create(dst: &mut BTreeNode, src: &(impl InnerConversionSource + ?Sized)) -> Result<(), ()>;
+    fn create(dst: &mut BTreeNode, src: &(impl InnerConversionSource + ?Sized)) -> Result<(), NodeOpError>;
+}
+
+/// checks that a split of a node with `before` keys kept all of them.
+/// An inner node split moves its separator to the parent, a leaf split keeps it in the left node.
+pub fn debug_assert_split_conserves_keys(is_leaf: bool, before: usize, left: usize, right: usize) {
+    debug_assert_eq!(before, left + right + (!is_leaf) as usize, "split of {before} keys produced {left} and {right}");
+}
+
+/// checks that a merge kept all keys, an inner node merge pulls the separator down from the parent
+pub fn debug_assert_merge_conserves_keys(is_leaf: bool, left: usize, right: usize, after: usize) {
+    debug_assert_eq!(left + right + (!is_leaf) as usize, after, "merge of {left} and {right} keys produced {after}");
 }
 
 pub fn merge<Dst: InnerConversionSink, Left: InnerConversionSource + ?Sized, Right: InnerConversionSource + ?Sized>(
@@ -167,7 +326,7 @@ pub fn merge<Dst: InnerConversionSink, Left: InnerConversionSource + ?Sized, Rig
     left: &Left,
     right: &Right,
     separator: FatTruncatedKey,
-) -> Result<(), ()> {
+) -> Result<(), NodeOpError> {
     struct MergeView<'a, Left: InnerConversionSource + ?Sized, Right: InnerConversionSource + ?Sized> {
         left: &'a Left,
         left_count: usize,
@@ -197,7 +356,7 @@ pub fn merge<Dst: InnerConversionSink, Left: InnerConversionSource + ?Sized, Rig
             }
         }
 
-        fn get_key(&self, index: usize, dst: &mut [u8], strip_prefix: usize) -> Result<usize, ()> {
+        fn get_key(&self, index: usize, dst: &mut [u8], strip_prefix: usize) -> Result<usize, NodeOpError> {
             debug_assert!(strip_prefix == 0);
             let dst_len = dst.len();
             if index < self.left_count {
@@ -246,9 +405,9 @@ pub fn merge<Dst: InnerConversionSink, Left: InnerConversionSource + ?Sized, Rig
         fn get_key_length_max(&self, range: Range<usize>) -> usize {
             debug_assert_eq!(range, 0..self.key_count());
             [
-                self.left.get_key_length_sum(0..self.left_count) + (self.left_fences.prefix_len - self.new_prefix_len),
+                self.left.get_key_length_max(0..self.left_count) + (self.left_fences.prefix_len - self.new_prefix_len),
                 (self.separator.remainder.len() - (self.new_prefix_len - self.separator.prefix_len)),
-                self.right.get_key_length_sum(0..self.right_count) + (self.right_fences.prefix_len - self.new_prefix_len),
+                self.right.get_key_length_max(0..self.right_count) + (self.right_fences.prefix_len - self.new_prefix_len),
             ].into_iter().max().unwrap()
         }
     }
@@ -268,11 +427,15 @@ pub fn merge<Dst: InnerConversionSink, Left: InnerConversionSource + ?Sized, Rig
         separator,
         fences: MergeFences::new(left_fences, separator, right_fences),
     };
-    Dst::create(dst, &merge_src)
+    Dst::create(dst, &merge_src)?;
+    if cfg!(debug_assertions) {
+        debug_assert_merge_conserves_keys(false, merge_src.left_count, merge_src.right_count, dst.to_inner().key_count());
+    }
+    Ok(())
 }
 
 pub fn merge_to_right<Dst: InnerConversionSink>
-(left: &BTreeNode, right: &mut BTreeNode, separator: FatTruncatedKey) -> Result<(), ()> {
+(left: &BTreeNode, right: &mut BTreeNode, separator: FatTruncatedKey) -> Result<(), NodeOpError> {
     debug_assert!(left.is_underfull());
     debug_assert!(right.is_underfull());
     unsafe {
@@ -296,7 +459,7 @@ pub fn split_at<
     separator: PrefixTruncatedKey<'a>,
     prefix_src: &'a [u8],
     parent_prefix_len: usize,
-) -> Result<SplitFences<'a>, ()> {
+) -> Result<SplitFences<'a>, NodeOpError> {
     struct SliceView<'a, S> {
         offset: usize,
         len: usize,
@@ -319,13 +482,19 @@ pub fn split_at<
             self.src.get_child(self.offset + index)
         }
 
-        fn get_key(&self, index: usize, dst: &mut [u8], strip_prefix: usize) -> Result<usize, ()> {
+        fn get_key(&self, index: usize, dst: &mut [u8], strip_prefix: usize) -> Result<usize, NodeOpError> {
             debug_assert!(strip_prefix == 0);
             debug_assert!(index < self.len + 1);
             self.src
                 .get_key(self.offset + index, dst, self.strip_prefix)
         }
 
+        fn get_key_ref(&self, index: usize) -> Option<PrefixTruncatedKey> {
+            debug_assert!(index < self.len + 1);
+            let key = self.src.get_key_ref(self.offset + index)?;
+            Some(PrefixTruncatedKey(&key.0[self.strip_prefix..]))
+        }
+
         fn get_key_length_sum(&self, range: Range<usize>) -> usize {
             debug_assert_eq!(range, 0..self.key_count());
             self.src.get_key_length_sum(self.offset..self.offset + self.len) - self.strip_prefix * self.len
@@ -375,7 +544,7 @@ pub fn split_in_place<
     parent: &mut dyn InnerNode,
     index_in_parent: usize,
     key_in_node: &[u8],
-) -> Result<(), ()> {
+) -> Result<(), NodeOpError> {
     unsafe {
         let mut right;
         {
@@ -383,10 +552,10 @@ pub fn split_in_place<
             let (split_index, separator) = src.find_separator();
             let separator = &*separator;
             let parent_prefix_len =
-                parent.request_space_for_child(separator.len() + src.fences().prefix_len)?;
+                parent.request_space_for_child(separator.len() + src.fences().prefix_len).map_err(|_| NodeOpError::NeedsSplit)?;
             let left = BTreeNode::alloc();
             right = BTreeNode::new_uninit();
-            split_at::<Src, Left, Right>(
+            if let Err(e) = split_at::<Src, Left, Right>(
                 src,
                 &mut *left,
                 &mut right,
@@ -394,17 +563,27 @@ pub fn split_in_place<
                 PrefixTruncatedKey(separator),
                 key_in_node,
                 parent_prefix_len,
-            )?;
+            ) {
+                BTreeNode::dealloc(left);
+                return Err(e);
+            }
+            if cfg!(debug_assertions) {
+                debug_assert_split_conserves_keys(false, src.key_count(), (*left).to_inner().key_count(), right.to_inner().key_count());
+            }
             let restored_separator = partial_restore(
                 0,
                 &[&key_in_node[..src.fences().prefix_len], separator],
                 parent_prefix_len,
             );
-            parent.insert_child(
+            // left is not reachable from the tree yet, free it so a retried split does not leak it
+            if let Err(_) = parent.insert_child(
                 index_in_parent,
                 PrefixTruncatedKey(&restored_separator),
                 left,
-            )?;
+            ) {
+                BTreeNode::dealloc(left);
+                return Err(NodeOpError::NeedsSplit);
+            }
             (&mut *((parent as *mut (dyn InnerNode) as *mut BTreeNode))).adaption_state().set_adapted(false);
         }
         ptr::write(node, right);
@@ -418,10 +597,10 @@ pub union FallbackInnerConversionSink<A: InnerConversionSink, B: InnerConversion
 }
 
 unsafe impl<A: InnerConversionSink, B: InnerConversionSink> InnerConversionSink for FallbackInnerConversionSink<A, B> {
-    fn create(dst: &mut BTreeNode, src: &(impl InnerConversionSource + ?Sized)) -> Result<(), ()> {
+    fn create(dst: &mut BTreeNode, src: &(impl InnerConversionSource + ?Sized)) -> Result<(), NodeOpError> {
         match A::create(dst, src) {
             Ok(()) => Ok(()),
-            Err(()) => B::create(dst, src),
+            Err(_) => B::create(dst, src),
         }
     }
 }
@@ -437,6 +616,17 @@ impl<'a, T: InnerConversionSource> InnerInsertSource<'a, T> {
     pub fn new(src: &'a T, index: usize, key: PrefixTruncatedKey<'a>, child: *mut BTreeNode) -> Self {
         InnerInsertSource { src, index, key, child }
     }
+
+    /// ranges of `src` keys making up `range`, excluding the inserted key
+    fn src_ranges(&self, range: Range<usize>) -> [Range<usize>; 2] {
+        if range.end <= self.index {
+            [range, 0..0]
+        } else if range.start > self.index {
+            [range.start - 1..range.end - 1, 0..0]
+        } else {
+            [range.start..self.index, self.index..range.end - 1]
+        }
+    }
 }
 
 impl<'a, T: InnerConversionSource> InnerConversionSource for InnerInsertSource<'a, T> {
@@ -458,7 +648,7 @@ impl<'a, T: InnerConversionSource> InnerConversionSource for InnerInsertSource<'
         }
     }
 
-    fn get_key(&self, index: usize, dst: &mut [u8], strip_prefix: usize) -> Result<usize, ()> {
+    fn get_key(&self, index: usize, dst: &mut [u8], strip_prefix: usize) -> Result<usize, NodeOpError> {
         if index < self.index {
             self.src.get_key(index, dst, strip_prefix)
         } else if index == self.index {
@@ -468,11 +658,492 @@ impl<'a, T: InnerConversionSource> InnerConversionSource for InnerInsertSource<'
         }
     }
 
-    fn get_key_length_sum(&self, _range: Range<usize>) -> usize {
-        unimplemented!()
+    fn get_key_ref(&self, index: usize) -> Option<PrefixTruncatedKey> {
+        if index < self.index {
+            self.src.get_key_ref(index)
+        } else if index == self.index {
+            Some(self.key)
+        } else {
+            self.src.get_key_ref(index - 1)
+        }
+    }
+
+    fn get_key_length_sum(&self, range: Range<usize>) -> usize {
+        let inserted = if range.contains(&self.index) { self.key.0.len() } else { 0 };
+        inserted + self.src_ranges(range).into_iter().map(|r| self.src.get_key_length_sum(r)).sum::<usize>()
+    }
+
+    fn get_key_length_max(&self, range: Range<usize>) -> usize {
+        let inserted = if range.contains(&self.index) { self.key.0.len() } else { 0 };
+        self.src_ranges(range).into_iter().map(|r| self.src.get_key_length_max(r)).fold(inserted, usize::max)
+    }
+}
+/// `src` without the key at `index` and the child left of it, the counterpart to `InnerInsertSource`.
+/// Used to drop the left child after merging it into its right sibling.
+pub struct InnerRemoveSource<'a, T: InnerConversionSource + ?Sized> {
+    src: &'a T,
+    index: usize,
+}
+
+impl<'a, T: InnerConversionSource + ?Sized> InnerRemoveSource<'a, T> {
+    pub fn new(src: &'a T, index: usize) -> Self {
+        debug_assert!(index < src.key_count());
+        InnerRemoveSource { src, index }
+    }
+
+    /// ranges of `src` keys making up `range`, skipping the removed key
+    fn src_ranges(&self, range: Range<usize>) -> [Range<usize>; 2] {
+        if range.end <= self.index {
+            [range, 0..0]
+        } else if range.start >= self.index {
+            [range.start + 1..range.end + 1, 0..0]
+        } else {
+            [range.start..self.index, self.index + 1..range.end + 1]
+        }
+    }
+}
+
+impl<'a, T: InnerConversionSource + ?Sized> InnerConversionSource for InnerRemoveSource<'a, T> {
+    fn fences(&self) -> FenceData {
+        self.src.fences()
+    }
+
+    fn key_count(&self) -> usize {
+        self.src.key_count() - 1
+    }
+
+    fn get_child(&self, index: usize) -> *mut BTreeNode {
+        if index < self.index {
+            self.src.get_child(index)
+        } else {
+            self.src.get_child(index + 1)
+        }
+    }
+
+    fn get_key(&self, index: usize, dst: &mut [u8], strip_prefix: usize) -> Result<usize, NodeOpError> {
+        if index < self.index {
+            self.src.get_key(index, dst, strip_prefix)
+        } else {
+            self.src.get_key(index + 1, dst, strip_prefix)
+        }
+    }
+
+    fn get_key_ref(&self, index: usize) -> Option<PrefixTruncatedKey> {
+        if index < self.index {
+            self.src.get_key_ref(index)
+        } else {
+            self.src.get_key_ref(index + 1)
+        }
+    }
+
+    fn get_key_length_sum(&self, range: Range<usize>) -> usize {
+        self.src_ranges(range).into_iter().map(|r| self.src.get_key_length_sum(r)).sum()
+    }
+
+    fn get_key_length_max(&self, range: Range<usize>) -> usize {
+        self.src_ranges(range).into_iter().map(|r| self.src.get_key_length_max(r)).max().unwrap_or(0)
+    }
+}
+
+/// `src` with the key at `index` replaced, children are unchanged
+pub struct InnerReplaceKeySource<'a, T: InnerConversionSource + ?Sized> {
+    src: &'a T,
+    index: usize,
+    key: PrefixTruncatedKey<'a>,
+}
+
+impl<'a, T: InnerConversionSource + ?Sized> InnerReplaceKeySource<'a, T> {
+    pub fn new(src: &'a T, index: usize, key: PrefixTruncatedKey<'a>) -> Self {
+        debug_assert!(index < src.key_count());
+        InnerReplaceKeySource { src, index, key }
+    }
+}
+
+impl<'a, T: InnerConversionSource + ?Sized> InnerConversionSource for InnerReplaceKeySource<'a, T> {
+    fn fences(&self) -> FenceData {
+        self.src.fences()
+    }
+
+    fn key_count(&self) -> usize {
+        self.src.key_count()
+    }
+
+    fn get_child(&self, index: usize) -> *mut BTreeNode {
+        self.src.get_child(index)
+    }
+
+    fn get_key(&self, index: usize, dst: &mut [u8], strip_prefix: usize) -> Result<usize, NodeOpError> {
+        if index == self.index {
+            get_key_from_slice(self.key, dst, strip_prefix)
+        } else {
+            self.src.get_key(index, dst, strip_prefix)
+        }
+    }
+
+    fn get_key_ref(&self, index: usize) -> Option<PrefixTruncatedKey> {
+        if index == self.index {
+            Some(self.key)
+        } else {
+            self.src.get_key_ref(index)
+        }
+    }
+
+    fn get_key_length_sum(&self, range: Range<usize>) -> usize {
+        if range.contains(&self.index) {
+            self.src.get_key_length_sum(range) - self.src.get_key_length_sum(self.index..self.index + 1) + self.key.0.len()
+        } else {
+            self.src.get_key_length_sum(range)
+        }
+    }
+
+    fn get_key_length_max(&self, range: Range<usize>) -> usize {
+        if range.contains(&self.index) {
+            [range.start..self.index, self.index + 1..range.end].into_iter()
+                .map(|r| self.src.get_key_length_max(r))
+                .fold(self.key.0.len(), usize::max)
+        } else {
+            self.src.get_key_length_max(range)
+        }
+    }
+}
+
+/// a run of children in an `InnerConcatSource`
+#[derive(Clone)]
+pub enum ConcatPart<'a> {
+    /// children `children` of `src` and the keys between them.
+    /// `prefix` must start with the prefix of `src`, any key in `src` will do.
+    Node { src: &'a dyn InnerNode, prefix: &'a [u8], children: Range<usize> },
+    /// a single child without keys
+    Child(*mut BTreeNode),
+}
+
+/// runs of children joined by full separator keys, presented with new fences.
+/// Keys are truncated or restored to the new prefix on the fly, so subtrees can be regrouped without copying their keys first.
+/// `separators` must have one element less than `parts` and all keys must lie within `fences`.
+pub struct InnerConcatSource<'a> {
+    fences: FenceData<'a>,
+    parts: &'a [ConcatPart<'a>],
+    separators: &'a [&'a [u8]],
+}
+
+enum ConcatKey<'a> {
+    Node { src: &'a dyn InnerNode, prefix: &'a [u8], index: usize },
+    Separator(&'a [u8]),
+}
+
+impl<'a> InnerConcatSource<'a> {
+    pub fn new(fences: FenceData<'a>, parts: &'a [ConcatPart<'a>], separators: &'a [&'a [u8]]) -> Self {
+        debug_assert_eq!(parts.len(), separators.len() + 1);
+        InnerConcatSource { fences, parts, separators }
+    }
+
+    fn locate_key(&self, mut index: usize) -> ConcatKey<'a> {
+        for (i, part) in self.parts.iter().enumerate() {
+            if let ConcatPart::Node { src, prefix, children } = part {
+                if index + 1 < children.len() {
+                    return ConcatKey::Node { src: *src, prefix, index: children.start + index };
+                }
+                index -= children.len() - 1;
+            }
+            if index == 0 {
+                return ConcatKey::Separator(self.separators[i]);
+            }
+            index -= 1;
+        }
+        panic!("key index out of bounds")
+    }
+
+    /// length of key `index` after truncating the new prefix
+    fn key_len(&self, index: usize) -> usize {
+        match self.locate_key(index) {
+            ConcatKey::Node { src, index, .. } => src.get_key_length_sum(index..index + 1) + src.fences().prefix_len - self.fences.prefix_len,
+            ConcatKey::Separator(key) => key.len() - self.fences.prefix_len,
+        }
+    }
+}
+
+impl<'a> InnerConversionSource for InnerConcatSource<'a> {
+    fn fences(&self) -> FenceData {
+        self.fences
+    }
+
+    fn key_count(&self) -> usize {
+        self.separators.len() + self.parts.iter().map(|p| match p {
+            ConcatPart::Node { children, .. } => children.len() - 1,
+            ConcatPart::Child(_) => 0,
+        }).sum::<usize>()
+    }
+
+    fn get_child(&self, mut index: usize) -> *mut BTreeNode {
+        for part in self.parts {
+            match part {
+                ConcatPart::Node { src, children, .. } => {
+                    if index < children.len() {
+                        return src.get_child(children.start + index);
+                    }
+                    index -= children.len();
+                }
+                ConcatPart::Child(child) => {
+                    if index == 0 {
+                        return *child;
+                    }
+                    index -= 1;
+                }
+            }
+        }
+        panic!("child index out of bounds")
+    }
+
+    fn get_key(&self, index: usize, dst: &mut [u8], strip_prefix: usize) -> Result<usize, NodeOpError> {
+        let strip = self.fences.prefix_len + strip_prefix;
+        match self.locate_key(index) {
+            ConcatKey::Node { src, prefix, index } => {
+                let src_prefix_len = src.fences().prefix_len;
+                if strip >= src_prefix_len {
+                    return src.get_key(index, dst, strip - src_prefix_len);
+                }
+                let key_len = src.get_key(index, dst, 0)?;
+                let dst_len = dst.len();
+                let restored_len = get_key_from_slice(PrefixTruncatedKey(&prefix[strip..src_prefix_len]), &mut dst[..dst_len - key_len], 0)?;
+                Ok(restored_len + key_len)
+            }
+            ConcatKey::Separator(key) => get_key_from_slice(PrefixTruncatedKey(key), dst, strip),
+        }
     }
 
-    fn get_key_length_max(&self, _range: Range<usize>) -> usize {
-        unimplemented!()
+    fn get_key_length_sum(&self, range: Range<usize>) -> usize {
+        range.map(|i| self.key_len(i)).sum()
     }
-}
\ No newline at end of file
+
+    fn get_key_length_max(&self, range: Range<usize>) -> usize {
+        range.map(|i| self.key_len(i)).max().unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::btree_node::create_inner_exact;
+    use crate::head_node::U32ExplicitHeadNode;
+    use crate::test_util::{free_leaf, ManualInnerSource, INNER_TAGS, LEAF_TAGS};
+
+    #[test]
+    fn remove_source_skips_child() {
+        crate::ensure_init();
+        let children: Vec<*mut BTreeNode> = (0..5).map(|_| BTreeNode::new_leaf()).collect();
+        let source = ManualInnerSource {
+            lower_fence: b"a".to_vec(),
+            upper_fence: b"b".to_vec(),
+            keys: vec![b"ab".to_vec(), b"ac".to_vec(), b"ad".to_vec(), b"ae".to_vec()],
+            children: children.clone(),
+        };
+        for index in 0..source.keys.len() {
+            let mut expected = ManualInnerSource {
+                lower_fence: source.lower_fence.clone(),
+                upper_fence: source.upper_fence.clone(),
+                keys: source.keys.clone(),
+                children: source.children.clone(),
+            };
+            expected.keys.remove(index);
+            expected.children.remove(index);
+            for tag in INNER_TAGS {
+                let mut node = unsafe { BTreeNode::new_uninit() };
+                create_inner_exact(tag, &mut node, &InnerRemoveSource::new(&source, index)).unwrap();
+                assert_eq!(node.tag(), tag);
+                assert!(expected.matches(node.to_inner()), "{tag:?} removing {index}");
+            }
+        }
+        for child in children {
+            unsafe { BTreeNode::dealloc(child) };
+        }
+    }
+
+    /// compares the key length methods to the lengths of the restored keys and builds every inner node type from `source`
+    fn check_key_lengths(source: &(impl InnerConversionSource + ?Sized), name: &str) {
+        let mut buffer = [0u8; crate::PAGE_SIZE / 4];
+        let lengths: Vec<usize> = (0..source.key_count()).map(|i| source.get_key(i, &mut buffer, 0).unwrap()).collect();
+        for start in 0..=lengths.len() {
+            for end in start..=lengths.len() {
+                let expected = &lengths[start..end];
+                assert_eq!(source.get_key_length_sum(start..end), expected.iter().sum::<usize>(), "{name} {start}..{end}");
+                assert_eq!(source.get_key_length_max(start..end), expected.iter().copied().max().unwrap_or(0), "{name} {start}..{end}");
+            }
+        }
+        for tag in INNER_TAGS {
+            let mut node = unsafe { BTreeNode::new_uninit() };
+            let result = create_inner_exact(tag, &mut node, source);
+            assert!(matches!(result, Ok(()) | Err(NodeOpError::Unrepresentable)), "{name} {tag:?}: {result:?}");
+        }
+    }
+
+    #[test]
+    fn source_key_lengths_match_keys() {
+        crate::ensure_init();
+        // keys of varying length
+        let source = ManualInnerSource {
+            lower_fence: b"k".to_vec(),
+            upper_fence: b"l".to_vec(),
+            ..ManualInnerSource::with_keys((0..12usize).map(|i| format!("k{:02}{}", i * 2, "x".repeat(i % 5)).into_bytes()).collect())
+        };
+        let prefix_len = source.fences().prefix_len;
+        // sorts between the keys at 2 and 3, and a shorter replacement for the key at 4
+        let inserted = PrefixTruncatedKey(&b"k05xxxxxxxx"[prefix_len..]);
+        let replacement = PrefixTruncatedKey(&b"k08"[prefix_len..]);
+        let child = 8 as *mut BTreeNode;
+        let art = source.build(BTreeNodeTag::ArtInner).unwrap();
+        unsafe {
+            assert_eq!((*art).tag(), BTreeNodeTag::ArtInner);
+            let art_node = &**(*art).art_node;
+            check_key_lengths(&source, "manual");
+            check_key_lengths(art_node, "art");
+            check_key_lengths(&InnerInsertSource::new(&source, 3, inserted, child), "insert");
+            check_key_lengths(&InnerInsertSource::new(art_node, 3, inserted, child), "art insert");
+            check_key_lengths(&InnerReplaceKeySource::new(&source, 4, replacement), "replace");
+            check_key_lengths(&InnerReplaceKeySource::new(art_node, 4, replacement), "art replace");
+            for index in 0..source.keys.len() {
+                check_key_lengths(&InnerRemoveSource::new(&source, index), "remove");
+                check_key_lengths(&InnerRemoveSource::new(art_node, index), "art remove");
+            }
+            BTreeNode::dealloc(art);
+        }
+    }
+
+    #[test]
+    fn errors_name_the_failure() {
+        crate::ensure_init();
+        for tag in LEAF_TAGS {
+            let leaf = BTreeNode::new_leaf_of(tag);
+            unsafe {
+                let result = (0u32..).map(|i| (*leaf).to_leaf_mut().insert(&i.to_be_bytes(), &[0u8; 16])).find(|r| r.is_err()).unwrap();
+                assert_eq!(result, Err(NodeOpError::NoSpace), "{tag:?}");
+                free_leaf(leaf);
+            }
+        }
+        // fits a page easily, but no head can hold the middle key
+        let source = ManualInnerSource::with_keys(vec![b"a".to_vec(), b"mmmmmmmmmmmmmmmmmmmm".to_vec(), b"z".to_vec()]);
+        for tag in INNER_TAGS {
+            let mut node = unsafe { BTreeNode::new_uninit() };
+            let expected = match tag {
+                BTreeNodeTag::BasicInner | BTreeNodeTag::ArtInner => Ok(()),
+                _ => Err(NodeOpError::Unrepresentable),
+            };
+            assert_eq!(create_inner_exact(tag, &mut node, &source), expected, "{tag:?}");
+        }
+    }
+
+    #[test]
+    fn conservation_checks_account_for_separator() {
+        debug_assert_split_conserves_keys(true, 10, 4, 6);
+        debug_assert_split_conserves_keys(false, 10, 4, 5);
+        debug_assert_merge_conserves_keys(true, 4, 6, 10);
+        debug_assert_merge_conserves_keys(false, 4, 5, 10);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "split of 10 keys produced 4 and 6")]
+    fn inner_split_losing_separator_is_reported() {
+        debug_assert_split_conserves_keys(false, 10, 4, 6);
+    }
+
+    #[test]
+    #[cfg_attr(debug_assertions, should_panic(expected = "node merged with itself"))]
+    fn self_merge_is_rejected() {
+        crate::ensure_init();
+        let child = BTreeNode::new_leaf();
+        let source = ManualInnerSource {
+            lower_fence: Vec::new(),
+            upper_fence: Vec::new(),
+            keys: vec![b"m".to_vec()],
+            children: vec![child, child],
+        };
+        for tag in INNER_TAGS {
+            let mut node = unsafe { BTreeNode::new_uninit() };
+            create_inner_exact(tag, &mut node, &source).unwrap();
+            assert_eq!(node.to_inner_mut().merge_children(0), Err(NodeOpError::NotApplicable), "{tag:?}");
+            assert_eq!(node.to_inner_mut().merge_children(1), Err(NodeOpError::NotApplicable), "{tag:?}");
+            assert!(source.matches(node.to_inner()), "{tag:?}");
+        }
+        unsafe { BTreeNode::dealloc(child) };
+    }
+
+    #[test]
+    fn merge_bounds_key_length_by_longest_key() {
+        crate::ensure_init();
+        let children: Vec<*mut BTreeNode> = (0..8).map(|_| BTreeNode::new_leaf()).collect();
+        let separator = b"m".to_vec();
+        // every key fits a U32ExplicitHead, but the key lengths of either side sum to more than it can hold
+        let left = ManualInnerSource { lower_fence: Vec::new(), upper_fence: separator.clone(), keys: vec![b"ab".to_vec(), b"ac".to_vec(), b"ad".to_vec()], children: children[..4].to_vec() };
+        let mut right = ManualInnerSource { lower_fence: separator.clone(), upper_fence: Vec::new(), keys: vec![b"sa".to_vec(), b"sb".to_vec(), b"sc".to_vec()], children: children[4..].to_vec() };
+        let merged = ManualInnerSource {
+            lower_fence: Vec::new(),
+            upper_fence: Vec::new(),
+            keys: [&left.keys[..], &[separator.clone()], &right.keys[..]].concat(),
+            children: children.clone(),
+        };
+        let separator_key = || FatTruncatedKey { remainder: &separator, prefix_len: 0 };
+        let mut node = unsafe { BTreeNode::new_uninit() };
+        assert_eq!(merge::<U32ExplicitHeadNode, _, _>(&mut node, &left, &right, separator_key()), Ok(()));
+        assert_eq!(node.tag(), BTreeNodeTag::U32ExplicitHead);
+        assert!(merged.matches(node.to_inner()));
+        // a single key that is too long is still rejected
+        right.keys[1] = b"sbbb".to_vec();
+        let mut node = unsafe { BTreeNode::new_uninit() };
+        assert_eq!(merge::<U32ExplicitHeadNode, _, _>(&mut node, &left, &right, separator_key()), Err(NodeOpError::Unrepresentable));
+        for child in children {
+            unsafe { BTreeNode::dealloc(child) };
+        }
+    }
+
+    #[test]
+    fn branch_cache_predictions_match_search() {
+        crate::ensure_init();
+        let keys: Vec<Vec<u8>> = (10..40).map(|i| format!("{i:02}0").into_bytes()).collect();
+        let children: Vec<*mut BTreeNode> = (0..keys.len() + 1).map(|_| BTreeNode::new_leaf()).collect();
+        let source = ManualInnerSource { lower_fence: Vec::new(), upper_fence: Vec::new(), keys: keys.clone(), children: children.clone() };
+        // runs of lookups routed to the same child make the cache predict, switching runs tests rejecting stale predictions
+        let mut needles: Vec<Vec<u8>> = Vec::new();
+        for target in [12, 13, 30, 0, 29, 30, 5, 6] {
+            let base = format!("{:02}", 10 + target);
+            for round in 0..30 {
+                needles.push(match round % 4 {
+                    0 => format!("{base}0"),
+                    1 => format!("{base}00"),
+                    2 => format!("{base}5"),
+                    _ => format!("{base}"),
+                }.into_bytes());
+            }
+        }
+        needles.extend([b"0".to_vec(), b"99".to_vec(), Vec::new()]);
+        for tag in INNER_TAGS {
+            let mut node = unsafe { BTreeNode::new_uninit() };
+            create_inner_exact(tag, &mut node, &source).unwrap();
+            let mut bc = BranchCacheAccessor::new();
+            for needle in &needles {
+                bc.reset();
+                let expected = keys.partition_point(|k| k < needle);
+                assert_eq!(node.to_inner_mut().find_child_index(needle, &mut bc), expected, "{tag:?} {needle:?}");
+            }
+        }
+        for child in children {
+            unsafe { BTreeNode::dealloc(child) };
+        }
+    }
+
+    #[test]
+    #[cfg_attr(debug_assertions, should_panic(expected = "child 1 is misaligned"))]
+    fn misaligned_child_is_reported() {
+        crate::ensure_init();
+        let child = BTreeNode::new_leaf();
+        let source = ManualInnerSource {
+            lower_fence: Vec::new(),
+            upper_fence: Vec::new(),
+            keys: vec![b"m".to_vec()],
+            children: vec![child, unsafe { (child as *mut u8).add(1) as *mut BTreeNode }],
+        };
+        let mut node = unsafe { BTreeNode::new_uninit() };
+        create_inner_exact(BTreeNodeTag::BasicInner, &mut node, &source).unwrap();
+        debug_assert_children_valid(node.to_inner());
+        unsafe { BTreeNode::dealloc(child) };
+    }
+}
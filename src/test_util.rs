@@ -0,0 +1,261 @@
+use crate::btree_node::{create_inner_of, inner_fallback_order, set_inner_fallback_order};
+use crate::node_traits::{set_inner_key_cap, FenceData, FenceRef, InnerConversionSource, NodeOpError};
+use crate::util::get_key_from_slice;
+use crate::vtables::BTreeNodeTag;
+use crate::{BTreeNode, PrefixTruncatedKey, PAGE_SIZE};
+use std::mem::ManuallyDrop;
+use std::ops::Range;
+use std::ptr;
+use std::sync::{Mutex, MutexGuard};
+
+/// every inner node type, for tests that check all of them
+pub const INNER_TAGS: [BTreeNodeTag; 7] = [
+    BTreeNodeTag::BasicInner,
+    BTreeNodeTag::U64ExplicitHead,
+    BTreeNodeTag::U32ExplicitHead,
+    BTreeNodeTag::U64ZeroPaddedHead,
+    BTreeNodeTag::U32ZeroPaddedHead,
+    BTreeNodeTag::AsciiHead,
+    BTreeNodeTag::ArtInner,
+];
+
+/// every leaf node type, for tests that check all of them
+pub const LEAF_TAGS: [BTreeNodeTag; 3] = [BTreeNodeTag::BasicLeaf, BTreeNodeTag::HashLeaf, BTreeNodeTag::HybridLeaf];
+
+/// frees a leaf allocated by a test, hash and hybrid leaves own a heap allocation that is dropped first.
+/// # Safety
+/// `leaf` must be an initialized leaf from `BTreeNode::alloc` that nothing else refers to.
+pub unsafe fn free_leaf(leaf: *mut BTreeNode) {
+    if (*leaf).tag() != BTreeNodeTag::BasicLeaf {
+        ManuallyDrop::drop(&mut (*leaf).hash_leaf);
+    }
+    BTreeNode::dealloc(leaf);
+}
+
+/// held while a test caps inner nodes, so such tests do not reset each others cap
+static INNER_KEY_CAP_LOCK: Mutex<()> = Mutex::new(());
+
+/// caps the key count of inner nodes until it is dropped, also if the test panics, see `set_inner_key_cap`.
+/// The cap is process wide, it should be large enough not to disturb tests running concurrently.
+pub struct InnerKeyCapGuard {
+    _lock: MutexGuard<'static, ()>,
+}
+
+impl InnerKeyCapGuard {
+    pub fn new(cap: usize) -> Self {
+        // a test panicking while holding the lock has already reset the cap on unwinding
+        let lock = INNER_KEY_CAP_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        set_inner_key_cap(Some(cap));
+        InnerKeyCapGuard { _lock: lock }
+    }
+}
+
+impl Drop for InnerKeyCapGuard {
+    fn drop(&mut self) {
+        set_inner_key_cap(None);
+    }
+}
+
+/// held while a test changes the inner fallback order, so such tests do not overwrite each others order
+static INNER_FALLBACK_ORDER_LOCK: Mutex<()> = Mutex::new(());
+
+/// restores the inner fallback order it found when dropped, also if the test panics, see `set_inner_fallback_order`.
+/// The order is process wide, tests running concurrently may build other node types meanwhile.
+pub struct InnerFallbackOrderGuard {
+    previous: Vec<BTreeNodeTag>,
+    _lock: MutexGuard<'static, ()>,
+}
+
+impl InnerFallbackOrderGuard {
+    pub fn save() -> Self {
+        // a test panicking while holding the lock has already restored the order on unwinding
+        let lock = INNER_FALLBACK_ORDER_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        InnerFallbackOrderGuard { previous: inner_fallback_order(), _lock: lock }
+    }
+}
+
+impl Drop for InnerFallbackOrderGuard {
+    fn drop(&mut self) {
+        set_inner_fallback_order(&self.previous);
+    }
+}
+
+fn fence_data<'a>(lower: &'a [u8], upper: &'a [u8]) -> FenceData<'a> {
+    FenceData {
+        prefix_len: 0,
+        lower_fence: FenceRef(lower),
+        upper_fence: FenceRef(upper),
+    }.restrip()
+}
+
+/// inner node contents given as full keys and children, for building nodes of a specific type in tests.
+/// Keys must be sorted and lie within the fences, `children` must have one more element than `keys`.
+pub struct ManualInnerSource {
+    pub lower_fence: Vec<u8>,
+    pub upper_fence: Vec<u8>,
+    pub keys: Vec<Vec<u8>>,
+    pub children: Vec<*mut BTreeNode>,
+}
+
+impl ManualInnerSource {
+    /// unfenced source with distinct dummy children, for tests that never dereference the children
+    pub fn with_keys(keys: Vec<Vec<u8>>) -> Self {
+        let children = (1..=keys.len() + 1).map(|i| (i * 8) as *mut BTreeNode).collect();
+        ManualInnerSource { lower_fence: Vec::new(), upper_fence: Vec::new(), keys, children }
+    }
+
+    fn truncated_key(&self, index: usize) -> PrefixTruncatedKey {
+        PrefixTruncatedKey(&self.keys[index][self.fences().prefix_len..])
+    }
+
+    /// creates a node of type `tag`, head nodes may fall back to `BasicNode`.
+    /// The children are owned by the new node afterwards.
+    pub fn build(&self, tag: BTreeNodeTag) -> Result<*mut BTreeNode, NodeOpError> {
+        assert_eq!(self.children.len(), self.keys.len() + 1);
+        unsafe {
+            let node = BTreeNode::alloc();
+            if let Err(e) = create_inner_of(tag, &mut *node, self) {
+                BTreeNode::dealloc(node);
+                return Err(e);
+            }
+            debug_assert!(self.matches(&*(*node).to_inner()), "{tag:?} node does not read back its source");
+            Ok(node)
+        }
+    }
+
+    /// true if `node` has the same fences, keys and children
+    pub fn matches(&self, node: &(impl InnerConversionSource + ?Sized)) -> bool {
+        if node.key_count() != self.keys.len() {
+            return false;
+        }
+        let (expected, actual) = (self.fences(), node.fences());
+        if expected.prefix_len != actual.prefix_len
+            || expected.lower_fence.to_stripped(expected.prefix_len) != actual.lower_fence.to_stripped(actual.prefix_len)
+            || expected.upper_fence.to_stripped(expected.prefix_len) != actual.upper_fence.to_stripped(actual.prefix_len) {
+            return false;
+        }
+        let mut buffer = [0u8; PAGE_SIZE / 4];
+        (0..self.keys.len()).all(|i| {
+            let key_len = node.get_key(i, &mut buffer, 0).unwrap();
+            buffer[buffer.len() - key_len..] == *self.truncated_key(i).0
+        }) && (0..self.children.len()).all(|i| node.get_child(i) == self.children[i])
+    }
+}
+
+impl InnerConversionSource for ManualInnerSource {
+    fn fences(&self) -> FenceData {
+        fence_data(&self.lower_fence, &self.upper_fence)
+    }
+
+    fn key_count(&self) -> usize {
+        self.keys.len()
+    }
+
+    fn get_child(&self, index: usize) -> *mut BTreeNode {
+        self.children[index]
+    }
+
+    fn get_key(&self, index: usize, dst: &mut [u8], strip_prefix: usize) -> Result<usize, NodeOpError> {
+        get_key_from_slice(self.truncated_key(index), dst, strip_prefix)
+    }
+
+    fn get_key_ref(&self, index: usize) -> Option<PrefixTruncatedKey> {
+        Some(self.truncated_key(index))
+    }
+
+    fn get_key_length_sum(&self, range: Range<usize>) -> usize {
+        range.map(|i| self.truncated_key(i).0.len()).sum()
+    }
+
+    fn get_key_length_max(&self, range: Range<usize>) -> usize {
+        range.map(|i| self.truncated_key(i).0.len()).max().unwrap_or(0)
+    }
+}
+
+/// leaf contents given as full keys and values, for building leaves of a specific type in tests.
+/// Keys must lie within the fences.
+pub struct ManualLeafSource {
+    pub lower_fence: Vec<u8>,
+    pub upper_fence: Vec<u8>,
+    pub entries: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+impl ManualLeafSource {
+    /// creates a leaf of type `tag`, fails if the entries do not fit
+    pub fn build(&self, tag: BTreeNodeTag) -> Result<*mut BTreeNode, NodeOpError> {
+        let entries = self.entries.iter().map(|(k, v)| (&k[..], &v[..]));
+        let leaf = BTreeNode::leaf_from_entries(tag, fence_data(&self.lower_fence, &self.upper_fence), entries)?;
+        debug_assert!(self.entries.iter().all(|(k, v)| leaf.to_leaf().lookup(k) == Some(&v[..])), "{tag:?} leaf does not read back its source");
+        unsafe {
+            let node = BTreeNode::alloc();
+            ptr::write(node, leaf);
+            Ok(node)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn manual_inner_source_reads_back() {
+        crate::ensure_init();
+        let keys: Vec<Vec<u8>> = (0..40u32).map(|i| format!("key{:04}", i * 7).into_bytes()).collect();
+        let children: Vec<*mut BTreeNode> = (0..=keys.len()).map(|_| BTreeNode::new_leaf()).collect();
+        let source = ManualInnerSource { lower_fence: b"key".to_vec(), upper_fence: b"kez".to_vec(), keys, children: children.clone() };
+        let mut other_key = ManualInnerSource {
+            lower_fence: source.lower_fence.clone(),
+            upper_fence: source.upper_fence.clone(),
+            keys: source.keys.clone(),
+            children: children.clone(),
+        };
+        other_key.keys[3] = b"key0022".to_vec();
+        let mut other_child = ManualInnerSource {
+            lower_fence: source.lower_fence.clone(),
+            upper_fence: source.upper_fence.clone(),
+            keys: source.keys.clone(),
+            children: children.clone(),
+        };
+        other_child.children.swap(0, 1);
+        for tag in INNER_TAGS {
+            let node = source.build(tag).unwrap();
+            unsafe {
+                let inner = (*node).to_inner();
+                assert_eq!(inner.fences().prefix_len, 2, "{tag:?}");
+                assert!(source.matches(inner), "{tag:?}");
+                assert!(!other_key.matches(inner), "{tag:?}");
+                assert!(!other_child.matches(inner), "{tag:?}");
+                BTreeNode::dealloc(node);
+            }
+        }
+        for child in children {
+            unsafe { BTreeNode::dealloc(child) };
+        }
+    }
+
+    #[test]
+    fn manual_leaf_source_reads_back() {
+        crate::ensure_init();
+        let entries: Vec<(Vec<u8>, Vec<u8>)> = (0..50u32).map(|i| (format!("key{:04}", i * 3).into_bytes(), i.to_le_bytes().to_vec())).collect();
+        let source = ManualLeafSource { lower_fence: b"key".to_vec(), upper_fence: b"kez".to_vec(), entries };
+        for tag in LEAF_TAGS {
+            let node = source.build(tag).unwrap();
+            unsafe {
+                assert_eq!((*node).tag(), tag);
+                let leaf = (*node).to_leaf();
+                for (key, value) in &source.entries {
+                    assert_eq!(leaf.lookup(key), Some(&value[..]), "{tag:?}");
+                }
+                assert_eq!(leaf.lookup(b"key0001"), None, "{tag:?}");
+                free_leaf(node);
+            }
+        }
+        let too_large = ManualLeafSource {
+            lower_fence: Vec::new(),
+            upper_fence: Vec::new(),
+            entries: (0..8u8).map(|i| (vec![i], vec![i; PAGE_SIZE / 4])).collect(),
+        };
+        assert_eq!(too_large.build(BTreeNodeTag::BasicLeaf).err(), Some(NodeOpError::NoSpace));
+    }
+}
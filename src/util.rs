@@ -1,7 +1,7 @@
-use crate::node_traits::{FenceData, FenceRef};
+use crate::node_traits::{FenceData, FenceRef, NodeOpError};
 use crate::{FatTruncatedKey, HeadTruncatedKey, PrefixTruncatedKey};
 use smallvec::SmallVec;
-use crate::btree_node::STRIP_PREFIX;
+use crate::btree_node::{PAGE_SIZE, STRIP_PREFIX, VALUE_ALIGN};
 
 pub fn head(key: &[u8]) -> (u32, HeadTruncatedKey) {
     let mut k_padded = [0u8; 4];
@@ -21,6 +21,11 @@ pub fn common_prefix_len(a: &[u8], b: &[u8]) -> usize {
     a.iter().zip(b.iter()).take_while(|(a, b)| a == b).count()
 }
 
+/// end of a `len` byte value placed as high as possible below `end` such that it starts at a multiple of `VALUE_ALIGN`
+pub fn aligned_value_end(end: usize, len: usize) -> usize {
+    (end - len) / VALUE_ALIGN * VALUE_ALIGN + len
+}
+
 pub fn trailing_bytes(b: &[u8], count: usize) -> &[u8] {
     &b[b.len() - count..]
 }
@@ -205,10 +210,10 @@ pub fn get_key_from_slice(
     src: PrefixTruncatedKey,
     dst: &mut [u8],
     strip_prefix: usize,
-) -> Result<usize, ()> {
+) -> Result<usize, NodeOpError> {
     let src = &src.0[strip_prefix..];
     if dst.len() < src.len() {
-        return Err(());
+        return Err(NodeOpError::NoSpace);
     }
     let dst_len = dst.len();
     dst[dst_len - src.len()..].copy_from_slice(src);
@@ -222,3 +227,46 @@ pub unsafe fn reinterpret<'a, A: 'a, B: 'a>(a: &'a A) -> &'a B {
 pub unsafe fn reinterpret_mut<'a, A: 'a, B: 'a>(a: &'a mut A) -> &'a mut B {
     &mut *(a as *mut A as usize as *mut B)
 }
+
+/// buffer range scans reconstruct full keys in.
+/// Writes are bounds checked, a key longer than `PAGE_SIZE / 4` panics instead of overflowing the buffer.
+pub struct KeyBuffer(Box<[u8; PAGE_SIZE / 4]>);
+
+impl KeyBuffer {
+    pub fn new() -> Self {
+        KeyBuffer(Box::new([0u8; PAGE_SIZE / 4]))
+    }
+
+    /// replaces everything from `offset` on by `suffix` and returns the resulting key
+    pub fn set_suffix(&mut self, offset: usize, suffix: &[u8]) -> &[u8] {
+        let len = offset + suffix.len();
+        assert!(len <= self.0.len(), "key of length {len} does not fit the key buffer");
+        self.0[offset..len].copy_from_slice(suffix);
+        &self.0[..len]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn key_buffer_keeps_prefix_and_replaces_suffix() {
+        let mut buffer = KeyBuffer::new();
+        assert_eq!(buffer.set_suffix(0, b"user/"), b"user/");
+        assert_eq!(buffer.set_suffix(5, b"alice"), b"user/alice");
+        assert_eq!(buffer.set_suffix(5, b"bob"), b"user/bob");
+        assert_eq!(buffer.set_suffix(2, b""), b"us");
+        let full = vec![7u8; PAGE_SIZE / 4];
+        assert_eq!(buffer.set_suffix(0, &full), &full[..]);
+    }
+
+    #[test]
+    #[should_panic(expected = "does not fit the key buffer")]
+    fn key_buffer_rejects_overlong_key() {
+        let mut buffer = KeyBuffer::new();
+        buffer.set_suffix(0, b"prefix");
+        // one byte past the end, which must not be written
+        buffer.set_suffix(6, &vec![1u8; PAGE_SIZE / 4 - 5]);
+    }
+}
@@ -0,0 +1,103 @@
+use std::{ptr, slice};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// size of the reference stored in place of a value
+pub const ARENA_REF_SIZE: usize = 12;
+const CHUNK_SIZE: usize = 1 << 16;
+
+/// storage for values.
+/// Chunks are never reallocated, so values keep their address until they are freed or the last arena holding them is dropped.
+/// Freed space is reused by later values of the same length.
+pub struct ValueArena {
+    /// chunks shared with another arena are no longer appended to
+    chunks: Vec<Arc<Vec<u8>>>,
+    /// addresses of freed values by length
+    free: HashMap<u32, Vec<u64>>,
+}
+
+fn make_ref(address: u64, len: usize) -> [u8; ARENA_REF_SIZE] {
+    let mut reference = [0u8; ARENA_REF_SIZE];
+    reference[..8].copy_from_slice(&address.to_ne_bytes());
+    reference[8..].copy_from_slice(&(len as u32).to_ne_bytes());
+    reference
+}
+
+impl ValueArena {
+    pub fn new() -> Self {
+        ValueArena { chunks: Vec::new(), free: HashMap::new() }
+    }
+
+    /// copies `value` into the arena and returns the reference to store in the leaf
+    pub fn store(&mut self, value: &[u8]) -> [u8; ARENA_REF_SIZE] {
+        if let Some(address) = self.free.get_mut(&(value.len() as u32)).and_then(Vec::pop) {
+            unsafe { ptr::copy_nonoverlapping(value.as_ptr(), address as *mut u8, value.len()) };
+            return make_ref(address, value.len());
+        }
+        let fits = self.chunks.last_mut().and_then(Arc::get_mut).is_some_and(|c| c.capacity() - c.len() >= value.len());
+        if !fits {
+            self.chunks.push(Arc::new(Vec::with_capacity(CHUNK_SIZE.max(value.len()))));
+        }
+        let chunk = Arc::get_mut(self.chunks.last_mut().unwrap()).unwrap();
+        let start = chunk.len();
+        chunk.extend_from_slice(value);
+        let address = unsafe { chunk.as_mut_ptr().add(start) } as u64;
+        make_ref(address, value.len())
+    }
+
+    /// makes the space of the value of `reference` available to later values, the reference becomes invalid
+    pub fn free(&mut self, reference: &[u8]) {
+        let value = unsafe { resolve(reference) };
+        if !value.is_empty() {
+            self.free.entry(value.len() as u32).or_default().push(value.as_mut_ptr() as u64);
+        }
+    }
+
+    /// replaces the value of `reference` by `value` and returns the reference to store instead.
+    /// A value that is not longer than the old one is written in its place and the rest of the space is freed.
+    pub fn replace(&mut self, reference: &[u8], value: &[u8]) -> [u8; ARENA_REF_SIZE] {
+        let old = unsafe { resolve(reference) };
+        if value.len() > old.len() {
+            self.free(reference);
+            return self.store(value);
+        }
+        let (reused, rest) = old.split_at_mut(value.len());
+        reused.copy_from_slice(value);
+        if !rest.is_empty() {
+            self.free(&make_ref(rest.as_mut_ptr() as u64, rest.len()));
+        }
+        make_ref(reused.as_mut_ptr() as u64, value.len())
+    }
+
+    /// takes over the chunks and freed space of `other`, references returned by either arena stay valid
+    pub fn append(&mut self, other: &mut ValueArena) {
+        self.chunks.append(&mut other.chunks);
+        for (len, mut addresses) in other.free.drain() {
+            self.free.entry(len).or_default().append(&mut addresses);
+        }
+    }
+
+    /// arena keeping the values of self alive, for a tree taking over some of the references.
+    /// Freed space stays with self, as it may only be handed out once.
+    ///
+    /// Both arenas write into the shared chunks through raw pointers, when a value is replaced in place or freed space is reused.
+    /// This is sound as long as every value is referenced by exactly one of the trees after the split and each tree only passes
+    /// its own references to `free` and `replace` of its own arena, so the written ranges of both arenas never overlap.
+    /// Shared chunks are not appended to, new values of either arena go to chunks it owns alone.
+    pub fn share(&self) -> ValueArena {
+        ValueArena { chunks: self.chunks.clone(), free: HashMap::new() }
+    }
+}
+
+/// value pointed to by a reference returned from `ValueArena::store`.
+/// # Safety
+/// the arena that created `reference` must outlive `'a`.
+pub unsafe fn resolve<'a>(reference: &[u8]) -> &'a mut [u8] {
+    debug_assert_eq!(reference.len(), ARENA_REF_SIZE);
+    let address = u64::from_ne_bytes(reference[..8].try_into().unwrap()) as *mut u8;
+    let len = u32::from_ne_bytes(reference[8..].try_into().unwrap()) as usize;
+    if len == 0 {
+        return slice::from_raw_parts_mut(ptr::NonNull::dangling().as_ptr(), 0);
+    }
+    slice::from_raw_parts_mut(address, len)
+}
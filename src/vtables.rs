@@ -11,8 +11,11 @@ use crate::hash_leaf::HashLeaf;
 use crate::head_node::{AsciiHeadNode, U32ExplicitHeadNode, U32ZeroPaddedHeadNode, U64ExplicitHeadNode, U64ZeroPaddedHeadNode};
 
 static mut INNER_VTABLES: [MaybeUninit<DynMetadata<dyn InnerNode>>; 7] = [MaybeUninit::uninit(); 7];
-static mut LEAF_VTABLES: [MaybeUninit<DynMetadata<dyn LeafNode>>; 2] = [MaybeUninit::uninit(); 2];
-static mut NODE_VTABLES: [MaybeUninit<DynMetadata<dyn Node>>; 14] = [MaybeUninit::uninit(); 14];
+static mut LEAF_VTABLES: [MaybeUninit<DynMetadata<dyn LeafNode>>; 3] = [MaybeUninit::uninit(); 3];
+/// one more than the largest tag value, arrays indexed by tag have this length
+pub const NODE_TAG_COUNT: usize = 14;
+
+static mut NODE_VTABLES: [MaybeUninit<DynMetadata<dyn Node>>; NODE_TAG_COUNT] = [MaybeUninit::uninit(); NODE_TAG_COUNT];
 
 /// must be called before BTreeNode methods are used
 pub fn init_vtables() {
@@ -39,6 +42,7 @@ pub fn init_vtables() {
     }
     make_leaf_vtables::<BasicNode>(BTreeNodeTag::BasicLeaf);
     make_leaf_vtables::<HashLeaf>(BTreeNodeTag::HashLeaf);
+    make_leaf_vtables::<HashLeaf>(BTreeNodeTag::HybridLeaf);
 
     make_inner_vtables::<BasicNode>(BTreeNodeTag::BasicInner);
     make_inner_vtables::<U32ExplicitHeadNode>(BTreeNodeTag::U32ExplicitHead);
@@ -56,6 +60,7 @@ pub enum BTreeNodeTag {
     BasicInner = 1,
     HashLeaf = 2,
     U64ExplicitHead = 3,
+    HybridLeaf = 4,
     U32ExplicitHead = 5,
     U64ZeroPaddedHead = 7,
     U32ZeroPaddedHead = 9,